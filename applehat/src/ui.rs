@@ -1,4 +1,4 @@
-use homie_controller::{Datatype, Device, HomieController, Node, State};
+use homie_controller::{Datatype, Devices, HomieController, Node, State};
 use log::{debug, error, trace};
 use rainbow_hat_rs::{
     alphanum4::Alphanum4,
@@ -6,7 +6,6 @@ use rainbow_hat_rs::{
     touch::Buttons,
 };
 use std::{
-    collections::HashMap,
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -186,7 +185,7 @@ pub fn spawn_button_poll_loop(
 }
 
 fn get_property<'a>(
-    devices: &'a HashMap<String, Device>,
+    devices: &'a Devices,
     device_id: &str,
     node_id: &str,
     property_id: &str,
@@ -235,7 +234,7 @@ fn print_str_decimal(alphanum: &mut Alphanum4, s: &str, unit: Option<char>) {
 }
 
 /// Finds all nodes on active devices with temperature and humidity properties.
-fn find_nodes(devices: &HashMap<String, Device>) -> Vec<(&str, &str, &Node)> {
+fn find_nodes(devices: &Devices) -> Vec<(&str, &str, &Node)> {
     let mut nodes: Vec<(&str, &str, &Node)> = vec![];
     for (device_id, device) in devices {
         if device.state == State::Ready {
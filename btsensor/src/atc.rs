@@ -2,13 +2,72 @@
 //! [atc1441 format](https://github.com/atc1441/ATC_MiThermometer#advertising-format-of-the-custom-firmware)
 //! and [pvvx custom format](https://github.com/pvvx/ATC_MiThermometer#custom-format-all-data-little-endian).
 
-use crate::uuid_from_u16;
+use crate::{uuid_from_u16, SensorDatatype};
 use std::fmt::{self, Display, Formatter};
 use uuid::Uuid;
 
 /// GATT service 0x181a, environmental sensing.
 pub const UUID: Uuid = uuid_from_u16(0x181a);
 
+/// One of the measurements included in every atc1441 or pvvx custom format reading.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Measurement {
+    Temperature,
+    Humidity,
+    BatteryPercent,
+    BatteryVoltage,
+}
+
+impl Measurement {
+    /// All the measurements, in the order they are displayed.
+    pub const ALL: [Self; 4] = [
+        Self::Temperature,
+        Self::Humidity,
+        Self::BatteryPercent,
+        Self::BatteryVoltage,
+    ];
+
+    /// Returns the name of the measurement.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Temperature => "temperature",
+            Self::Humidity => "humidity",
+            Self::BatteryPercent => "battery",
+            Self::BatteryVoltage => "battery voltage",
+        }
+    }
+
+    /// Returns the unit of the measurement.
+    pub fn unit(self) -> &'static str {
+        match self {
+            Self::Temperature => "°C",
+            Self::Humidity | Self::BatteryPercent => "%",
+            Self::BatteryVoltage => "mV",
+        }
+    }
+
+    /// Returns the type of value of the measurement.
+    ///
+    /// Humidity is `Float` as the pvvx format includes a fractional part, even though the atc1441
+    /// format doesn't.
+    pub fn datatype(self) -> SensorDatatype {
+        match self {
+            Self::Temperature | Self::Humidity => SensorDatatype::Float,
+            Self::BatteryPercent | Self::BatteryVoltage => SensorDatatype::Integer,
+        }
+    }
+
+    /// Returns a suitable Homie property ID for the measurement.
+    pub fn homie_property_id(self) -> &'static str {
+        match self {
+            Self::Temperature => "temperature",
+            Self::Humidity => "humidity",
+            Self::BatteryPercent => "battery",
+            Self::BatteryVoltage => "battery-voltage",
+        }
+    }
+}
+
 /// A sensor reading in the atc1411 or pvvx custom format.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum SensorReading {
@@ -213,6 +272,16 @@ impl SensorReading {
             Self::Pvvx { battery_mv, .. } => *battery_mv,
         }
     }
+
+    /// Returns the value of the given measurement, in its [unit](Measurement::unit).
+    pub fn value(&self, measurement: Measurement) -> f64 {
+        match measurement {
+            Measurement::Temperature => self.temperature().into(),
+            Measurement::Humidity => self.humidity().into(),
+            Measurement::BatteryPercent => self.battery_percent().into(),
+            Measurement::BatteryVoltage => self.battery_mv().into(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -339,4 +408,30 @@ mod tests {
     fn decode_empty() {
         assert_eq!(SensorReading::decode(&[]), None);
     }
+
+    #[test]
+    fn measurement_values() {
+        let reading = SensorReading::Pvvx {
+            mac: [0xa4, 0xc1, 0x38, 0x8c, 0x41, 0x93],
+            temperature: 2220,
+            humidity: 5250,
+            battery_mv: 2976,
+            battery_percent: 100,
+            counter: 136,
+            flags: 0x04,
+        };
+        let values: Vec<_> = Measurement::ALL
+            .into_iter()
+            .map(|measurement| (measurement.homie_property_id(), reading.value(measurement)))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("temperature", f64::from(22.2f32)),
+                ("humidity", 52.5),
+                ("battery", 100.0),
+                ("battery-voltage", 2976.0),
+            ]
+        );
+    }
 }
@@ -1,5 +1,6 @@
 //! Support for the BTHome format, both v1 and v2.
 
+pub mod accumulator;
 pub mod events;
 pub mod v1;
 pub mod v2;
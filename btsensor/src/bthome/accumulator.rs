@@ -0,0 +1,266 @@
+//! Merging of BTHome v2 measurements which are split across consecutive advertisements.
+
+use super::v2::{BtHomeV2, Element};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// A reading consolidated from one or more consecutive BTHome v2 advertisements from the same
+/// device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccumulatedReading {
+    /// The combined advertisement. This has the elements of all the advertisements in the order they
+    /// were received, except that there is a single packet ID element first, with the packet ID of
+    /// the last advertisement.
+    pub reading: BtHomeV2,
+    /// When each element of `reading` was received, in the same order as `reading.elements`.
+    pub received: Vec<Instant>,
+}
+
+impl AccumulatedReading {
+    /// Starts a new reading from the given advertisement, which must have a packet ID.
+    fn new(advertisement: BtHomeV2, packet_id: u8, now: Instant) -> Self {
+        let mut reading = AccumulatedReading {
+            reading: BtHomeV2 {
+                encrypted: advertisement.encrypted,
+                trigger_based: advertisement.trigger_based,
+                elements: vec![Element::PacketId(packet_id)],
+            },
+            received: vec![now],
+        };
+        reading.extend(advertisement, packet_id, now);
+        reading
+    }
+
+    /// Adds the elements of the given advertisement, other than its packet ID, to the reading.
+    fn extend(&mut self, advertisement: BtHomeV2, packet_id: u8, now: Instant) {
+        self.reading.elements[0] = Element::PacketId(packet_id);
+        self.received[0] = now;
+        for element in advertisement.elements {
+            if !matches!(element, Element::PacketId(_)) {
+                self.reading.elements.push(element);
+                self.received.push(now);
+            }
+        }
+    }
+}
+
+/// Merges BTHome v2 measurements which some devices split across several advertisements with
+/// consecutive packet IDs, so that consumers don't see partial updates.
+///
+/// Advertisements are accumulated per device, identified by some key such as its MAC address. A
+/// reading is complete once an advertisement from the same device arrives which doesn't continue
+/// it, i.e. doesn't have the next packet ID, or once the time window since its first
+/// advertisement has passed. Call [`take_expired`](Self::take_expired) periodically to get readings
+/// which are complete because their window has passed.
+///
+/// Repeated advertisements with the same packet ID are ignored, and advertisements without a packet
+/// ID are passed through unchanged, as there is no way to tell whether they belong together.
+#[derive(Debug)]
+pub struct BtHomeAccumulator<K> {
+    window: Duration,
+    pending: HashMap<K, Pending>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    started: Instant,
+    packet_id: u8,
+    reading: AccumulatedReading,
+}
+
+impl<K: Clone + Eq + Hash> BtHomeAccumulator<K> {
+    /// Creates a new accumulator which merges advertisements received within the given time window
+    /// of the first advertisement of a reading.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Adds an advertisement received from the given device at the given time.
+    ///
+    /// Returns any readings from the device which are complete as a result, oldest first. The
+    /// advertisement itself is only returned immediately if it has no packet ID.
+    pub fn add(
+        &mut self,
+        device: K,
+        advertisement: BtHomeV2,
+        now: Instant,
+    ) -> Vec<AccumulatedReading> {
+        let Some(packet_id) = packet_id(&advertisement) else {
+            let mut complete: Vec<_> = self
+                .pending
+                .remove(&device)
+                .map(|pending| pending.reading)
+                .into_iter()
+                .collect();
+            complete.push(AccumulatedReading {
+                received: vec![now; advertisement.elements.len()],
+                reading: advertisement,
+            });
+            return complete;
+        };
+
+        if let Some(pending) = self.pending.get_mut(&device) {
+            if packet_id == pending.packet_id {
+                // A repeat of the last advertisement.
+                return vec![];
+            }
+            if packet_id == pending.packet_id.wrapping_add(1)
+                && now.duration_since(pending.started) <= self.window
+            {
+                pending.packet_id = packet_id;
+                pending.reading.extend(advertisement, packet_id, now);
+                return vec![];
+            }
+        }
+
+        let new_pending = Pending {
+            started: now,
+            packet_id,
+            reading: AccumulatedReading::new(advertisement, packet_id, now),
+        };
+        self.pending
+            .insert(device, new_pending)
+            .map(|pending| pending.reading)
+            .into_iter()
+            .collect()
+    }
+
+    /// Removes and returns all readings whose time window has passed by the given time, along with
+    /// the devices they are from.
+    pub fn take_expired(&mut self, now: Instant) -> Vec<(K, AccumulatedReading)> {
+        let expired: Vec<K> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.duration_since(pending.started) > self.window)
+            .map(|(device, _)| device.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|device| {
+                let pending = self.pending.remove(&device)?;
+                Some((device, pending.reading))
+            })
+            .collect()
+    }
+}
+
+/// Returns the packet ID of the given advertisement, if it has one.
+fn packet_id(advertisement: &BtHomeV2) -> Option<u8> {
+    advertisement
+        .elements
+        .iter()
+        .find_map(|element| match element {
+            Element::PacketId(packet_id) => Some(*packet_id),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advertisement(elements: Vec<Element>) -> BtHomeV2 {
+        BtHomeV2 {
+            encrypted: false,
+            trigger_based: false,
+            elements,
+        }
+    }
+
+    #[test]
+    fn merges_consecutive_packets() {
+        let start = Instant::now();
+        let later = start + Duration::from_secs(1);
+        let mut accumulator = BtHomeAccumulator::new(Duration::from_secs(5));
+
+        let first = advertisement(vec![Element::PacketId(41), Element::TemperatureSmall(2500)]);
+        assert_eq!(accumulator.add("a", first.clone(), start), vec![]);
+        // Repeats are ignored.
+        assert_eq!(accumulator.add("a", first.clone(), start), vec![]);
+        // Other devices are independent.
+        assert_eq!(accumulator.add("b", first, start), vec![]);
+        assert_eq!(
+            accumulator.add(
+                "a",
+                advertisement(vec![Element::PacketId(42), Element::Humidity(4200)]),
+                later
+            ),
+            vec![]
+        );
+
+        // A packet which doesn't follow on completes the reading.
+        assert_eq!(
+            accumulator.add(
+                "a",
+                advertisement(vec![Element::PacketId(50), Element::Humidity(4300)]),
+                later
+            ),
+            vec![AccumulatedReading {
+                reading: advertisement(vec![
+                    Element::PacketId(42),
+                    Element::TemperatureSmall(2500),
+                    Element::Humidity(4200),
+                ]),
+                received: vec![later, start, later],
+            }]
+        );
+    }
+
+    #[test]
+    fn completes_after_window() {
+        let start = Instant::now();
+        let mut accumulator = BtHomeAccumulator::new(Duration::from_secs(5));
+
+        accumulator.add(
+            "a",
+            advertisement(vec![
+                Element::PacketId(255),
+                Element::TemperatureSmall(2500),
+            ]),
+            start,
+        );
+        accumulator.add(
+            "a",
+            advertisement(vec![Element::PacketId(0), Element::Humidity(4200)]),
+            start,
+        );
+        assert_eq!(
+            accumulator.take_expired(start + Duration::from_secs(5)),
+            vec![]
+        );
+
+        let expired = accumulator.take_expired(start + Duration::from_secs(6));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, "a");
+        assert_eq!(
+            expired[0].1.reading.elements,
+            vec![
+                Element::PacketId(0),
+                Element::TemperatureSmall(2500),
+                Element::Humidity(4200),
+            ]
+        );
+        assert_eq!(
+            accumulator.take_expired(start + Duration::from_secs(6)),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn passes_through_without_packet_id() {
+        let now = Instant::now();
+        let mut accumulator = BtHomeAccumulator::new(Duration::from_secs(5));
+        let advertisement = advertisement(vec![Element::TemperatureSmall(2500)]);
+        assert_eq!(
+            accumulator.add("a", advertisement.clone(), now),
+            vec![AccumulatedReading {
+                reading: advertisement,
+                received: vec![now],
+            }]
+        );
+    }
+}
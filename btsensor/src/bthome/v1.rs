@@ -2,7 +2,7 @@
 
 use super::events::{ButtonEventType, DimmerEventType, Event};
 use super::DecodeError;
-use crate::uuid_from_u16;
+use crate::{homie_property_id, uuid_from_u16, SensorDatatype};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use std::fmt::{self, Display, Formatter};
 use uuid::Uuid;
@@ -61,6 +61,29 @@ impl Element {
         }
     }
 
+    /// Returns the property of the sensor reading or event.
+    pub fn property(&self) -> Property {
+        match self {
+            Self::Sensor(sensor) => sensor.property,
+            Self::Event(event) => event.property(),
+        }
+    }
+
+    /// Returns the standard unit for the element's property.
+    pub fn unit(&self) -> &'static str {
+        self.property().unit()
+    }
+
+    /// Returns the type of value of the element's property.
+    pub fn datatype(&self) -> SensorDatatype {
+        self.property().datatype()
+    }
+
+    /// Returns a suitable Homie property ID for the element's property.
+    pub fn homie_property_id(&self) -> String {
+        self.property().homie_property_id()
+    }
+
     /// Attempts to decode the given service data as a BTHome v1 advertisement.
     pub fn decode_all(mut data: &[u8]) -> Result<Vec<Self>, DecodeError> {
         let mut elements = Vec::new();
@@ -416,6 +439,25 @@ impl Property {
         }
     }
 
+    /// Returns the type of value of the property.
+    pub fn datatype(self) -> SensorDatatype {
+        if self.is_boolean() {
+            SensorDatatype::Boolean
+        } else if matches!(self, Self::ButtonEvent | Self::DimmerEvent) {
+            SensorDatatype::Event
+        } else if self.denominator() == 1.0 {
+            SensorDatatype::Integer
+        } else {
+            SensorDatatype::Float
+        }
+    }
+
+    /// Returns a suitable Homie property ID for the property, derived from its name. Properties
+    /// with the same name, such as `Humidity` and `HumidityShort`, have the same ID.
+    pub fn homie_property_id(self) -> String {
+        homie_property_id(self.name())
+    }
+
     /// The denominator for fixed-point values.
     ///
     /// In other words, the value stored should be divided by this number to get the actual value.
@@ -653,4 +695,21 @@ mod tests {
             "dimmer: rotate right 42 steps"
         );
     }
+
+    #[test]
+    fn metadata() {
+        let element = Element::new_signed(Property::Temperature, 2507);
+        assert_eq!(element.unit(), "°C");
+        assert_eq!(element.datatype(), SensorDatatype::Float);
+        assert_eq!(element.homie_property_id(), "temperature");
+
+        assert_eq!(Property::HumidityShort.datatype(), SensorDatatype::Integer);
+        assert_eq!(Property::HumidityShort.homie_property_id(), "humidity");
+        assert_eq!(Property::PacketId.homie_property_id(), "packet-id");
+        assert_eq!(Property::Wet.datatype(), SensorDatatype::Boolean);
+
+        let event = Element::new_event(Event::Button(None));
+        assert_eq!(event.datatype(), SensorDatatype::Event);
+        assert_eq!(event.homie_property_id(), "button-event");
+    }
 }
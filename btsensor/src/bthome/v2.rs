@@ -2,7 +2,7 @@
 
 use super::events::{ButtonEventType, DimmerEventType, Event};
 use super::DecodeError;
-use crate::uuid_from_u16;
+use crate::{homie_property_id, uuid_from_u16, SensorDatatype};
 use std::fmt::{self, Display, Formatter};
 use uuid::Uuid;
 
@@ -146,7 +146,7 @@ generate_element![
     { 0x18, Cold, bool, read_bool, "cold", "" },
     { 0x19, Connected, bool, read_bool, "connected", "" },
     { 0x1a, DoorOpen, bool, read_bool, "door open", "" },
-    { 0x1b, GarageDoorOpen, bool, read_bool, "garade door open", "" },
+    { 0x1b, GarageDoorOpen, bool, read_bool, "garage door open", "" },
     { 0x1c, GasDetected, bool, read_bool, "gas detected", "" },
     { 0x0f, GenericBoolean, bool, read_bool, "generic boolean", "" },
     { 0x1d, Hot, bool, read_bool, "hot", "" },
@@ -173,6 +173,28 @@ generate_element![
 ];
 
 impl Element {
+    /// Returns the type of value of the property.
+    ///
+    /// Properties with no scaling factor are `Integer`, even though `value_float` also returns a
+    /// value for some of them.
+    pub fn datatype(&self) -> SensorDatatype {
+        if self.event().is_some() {
+            SensorDatatype::Event
+        } else if self.value_bool().is_some() {
+            SensorDatatype::Boolean
+        } else if self.value_int().is_some() {
+            SensorDatatype::Integer
+        } else {
+            SensorDatatype::Float
+        }
+    }
+
+    /// Returns a suitable Homie property ID for the property, derived from its name. Properties
+    /// with the same name, such as `Temperature` and `TemperatureSmall`, have the same ID.
+    pub fn homie_property_id(&self) -> String {
+        homie_property_id(self.name())
+    }
+
     /// Returns the boolean value of the reading, if it is a boolean property.
     ///
     /// Returns `None` if it is an event, floating-point or integer property.
@@ -522,4 +544,24 @@ mod tests {
             "(unencrypted) packet ID: 168, battery: 100%, temperature: 25.07°C, humidity: 43.9%"
         );
     }
+
+    #[test]
+    fn metadata() {
+        let element = Element::TemperatureSmall(2507);
+        assert_eq!(element.unit(), "°C");
+        assert_eq!(element.datatype(), SensorDatatype::Float);
+        assert_eq!(element.homie_property_id(), "temperature");
+
+        assert_eq!(Element::Battery(100).datatype(), SensorDatatype::Integer);
+        assert_eq!(Element::Pm2_5(12).homie_property_id(), "pm2-5");
+        assert_eq!(
+            Element::GarageDoorOpen(true).homie_property_id(),
+            "garage-door-open"
+        );
+        assert_eq!(
+            Element::GarageDoorOpen(true).datatype(),
+            SensorDatatype::Boolean
+        );
+        assert_eq!(Element::ButtonEvent(None).datatype(), SensorDatatype::Event);
+    }
 }
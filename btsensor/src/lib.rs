@@ -6,6 +6,8 @@
 
 pub mod atc;
 pub mod bthome;
+#[cfg(feature = "bluez")]
+pub mod stream;
 
 use crate::{atc::SensorReading, bthome::v1::Element};
 use bthome::v2::BtHomeV2;
@@ -24,6 +26,36 @@ const fn uuid_from_u16(short: u16) -> Uuid {
     Uuid::from_u128(BLUETOOTH_BASE_UUID | ((short as u128) << 96))
 }
 
+/// The type of value of a sensor property, to decide how to represent it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SensorDatatype {
+    /// A binary sensor, such as whether a door is open.
+    Boolean,
+    /// An integer value, with no scaling factor.
+    Integer,
+    /// A floating-point value.
+    Float,
+    /// An event such as a button press, rather than a state.
+    Event,
+}
+
+/// Converts the name of a property to a suitable Homie property ID, by lowercasing it and replacing
+/// any runs of other characters than letters and digits with hyphens.
+fn homie_property_id(name: &str) -> String {
+    let mut id = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            id.push(c.to_ascii_lowercase());
+        } else if !id.is_empty() && !id.ends_with('-') {
+            id.push('-');
+        }
+    }
+    if id.ends_with('-') {
+        id.pop();
+    }
+    id
+}
+
 /// A reading from some BLE sensor advertisement.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Reading {
@@ -63,6 +95,27 @@ impl Reading {
         }
         None
     }
+
+    /// Returns the packet ID or counter of the advertisement, if it has one.
+    ///
+    /// Sensors increment this for each new reading, so it can be used to detect duplicate
+    /// advertisements.
+    pub fn packet_id(&self) -> Option<u8> {
+        match self {
+            Self::Atc(SensorReading::Atc { packet_counter, .. }) => Some(*packet_counter),
+            Self::Atc(SensorReading::Pvvx { counter, .. }) => Some(*counter),
+            Self::BtHomeV1(elements) => elements.iter().find_map(|element| match element {
+                Element::Sensor(sensor) if sensor.property == bthome::v1::Property::PacketId => {
+                    sensor.value_int().and_then(|value| value.try_into().ok())
+                }
+                _ => None,
+            }),
+            Self::BtHomeV2(bthome) => bthome.elements.iter().find_map(|element| match element {
+                bthome::v2::Element::PacketId(packet_id) => Some(*packet_id),
+                _ => None,
+            }),
+        }
+    }
 }
 
 impl Display for Reading {
@@ -156,4 +209,29 @@ mod tests {
             ]))
         );
     }
+
+    #[test]
+    fn packet_id() {
+        assert_eq!(
+            Reading::BtHomeV1(vec![
+                Element::new_signed(Property::Temperature, 2500),
+                Element::new_unsigned(Property::PacketId, 42),
+            ])
+            .packet_id(),
+            Some(42)
+        );
+        assert_eq!(
+            Reading::BtHomeV1(vec![Element::new_signed(Property::Temperature, 2500)]).packet_id(),
+            None
+        );
+        assert_eq!(
+            Reading::BtHomeV2(BtHomeV2 {
+                encrypted: false,
+                trigger_based: false,
+                elements: vec![bthome::v2::Element::PacketId(7)],
+            })
+            .packet_id(),
+            Some(7)
+        );
+    }
 }
@@ -0,0 +1,173 @@
+//! An adapter to decode readings from a stream of [`bluez_async`] events.
+//!
+//! This requires the `bluez` feature.
+
+use crate::Reading;
+use bluez_async::{BluetoothEvent, DeviceEvent, DeviceId};
+use futures::future;
+use futures::stream::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Decodes readings from the `ServiceData` events in the given stream of Bluetooth events,
+/// ignoring all other events and any service data which can't be decoded.
+///
+/// Sensors may repeat the same advertisement several times, and it may be received via more than
+/// one adapter, so readings with the same packet ID as the previous reading from the same device
+/// are skipped. Readings without a packet ID are always passed through.
+pub fn readings(
+    events: impl Stream<Item = BluetoothEvent>,
+) -> impl Stream<Item = (DeviceId, Reading)> {
+    let mut deduplicator = Deduplicator::default();
+    events.filter_map(move |event| {
+        future::ready(match event {
+            BluetoothEvent::Device {
+                id,
+                event: DeviceEvent::ServiceData { service_data },
+            } => Reading::decode(&service_data)
+                .filter(|reading| deduplicator.is_new(id.clone(), reading))
+                .map(|reading| (id, reading)),
+            _ => None,
+        })
+    })
+}
+
+/// Keeps track of the last packet ID seen from each device.
+#[derive(Debug)]
+struct Deduplicator<K> {
+    last_packet_ids: HashMap<K, u8>,
+}
+
+impl<K> Default for Deduplicator<K> {
+    fn default() -> Self {
+        Self {
+            last_packet_ids: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash> Deduplicator<K> {
+    /// Returns false if the reading has the same packet ID as the previous one from the same
+    /// device, or true otherwise.
+    fn is_new(&mut self, device: K, reading: &Reading) -> bool {
+        match reading.packet_id() {
+            Some(packet_id) => self.last_packet_ids.insert(device, packet_id) != Some(packet_id),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atc::{self, SensorReading};
+    use crate::bthome::v1::{Element, Property};
+    use futures::executor::block_on;
+    use futures::stream;
+    use serde::de::value::{Error as ValueError, MapDeserializer};
+    use serde::Deserialize;
+    use uuid::Uuid;
+
+    fn device_id(object_path: &str) -> DeviceId {
+        // `DeviceId` can't be constructed directly outside of `bluez_async`, but it can be
+        // deserialized.
+        DeviceId::deserialize(MapDeserializer::<_, ValueError>::new(std::iter::once((
+            "object_path",
+            object_path,
+        ))))
+        .unwrap()
+    }
+
+    fn service_data_event(id: &DeviceId, uuid: Uuid, data: Vec<u8>) -> BluetoothEvent {
+        BluetoothEvent::Device {
+            id: id.clone(),
+            event: DeviceEvent::ServiceData {
+                service_data: HashMap::from([(uuid, data)]),
+            },
+        }
+    }
+
+    fn atc_data(packet_counter: u8) -> Vec<u8> {
+        vec![
+            0xff,
+            0xee,
+            0xdd,
+            0xcc,
+            0xbb,
+            0xaa,
+            0x37,
+            0x08,
+            42,
+            89,
+            0xf6,
+            0x05,
+            packet_counter,
+        ]
+    }
+
+    fn atc_reading(packet_counter: u8) -> Reading {
+        Reading::Atc(SensorReading::Atc {
+            mac: [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff],
+            temperature: 2103,
+            humidity: 42,
+            battery_percent: 89,
+            battery_mv: 1526,
+            packet_counter,
+        })
+    }
+
+    #[test]
+    fn deduplicate_by_packet_id() {
+        let mut deduplicator = Deduplicator::default();
+        assert!(deduplicator.is_new("a", &atc_reading(1)));
+        assert!(!deduplicator.is_new("a", &atc_reading(1)));
+        // The same packet from a different device is new.
+        assert!(deduplicator.is_new("b", &atc_reading(1)));
+        assert!(deduplicator.is_new("a", &atc_reading(2)));
+        assert!(!deduplicator.is_new("a", &atc_reading(2)));
+        assert!(deduplicator.is_new("a", &atc_reading(1)));
+    }
+
+    #[test]
+    fn readings_from_events() {
+        let a = device_id("/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF");
+        let b = device_id("/org/bluez/hci1/dev_AA_BB_CC_DD_EE_FF");
+        let events = vec![
+            BluetoothEvent::Device {
+                id: a.clone(),
+                event: DeviceEvent::Rssi { rssi: -60 },
+            },
+            service_data_event(&a, atc::UUID, atc_data(1)),
+            // Repeated advertisement.
+            service_data_event(&a, atc::UUID, atc_data(1)),
+            // The same advertisement received via another adapter is a different device.
+            service_data_event(&b, atc::UUID, atc_data(1)),
+            // Service data which can't be decoded.
+            service_data_event(&a, atc::UUID, vec![1, 2, 3]),
+            service_data_event(&a, Uuid::nil(), atc_data(2)),
+            BluetoothEvent::Device {
+                id: a.clone(),
+                event: DeviceEvent::Connected { connected: true },
+            },
+            service_data_event(&a, atc::UUID, atc_data(2)),
+        ];
+
+        let readings: Vec<_> = block_on(readings(stream::iter(events)).collect());
+        assert_eq!(
+            readings,
+            vec![
+                (a.clone(), atc_reading(1)),
+                (b, atc_reading(1)),
+                (a, atc_reading(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_packet_id_always_new() {
+        let mut deduplicator = Deduplicator::default();
+        let reading = Reading::BtHomeV1(vec![Element::new_signed(Property::Temperature, 2500)]);
+        assert!(deduplicator.is_new("a", &reading));
+        assert!(deduplicator.is_new("a", &reading));
+    }
+}
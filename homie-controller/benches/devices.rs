@@ -0,0 +1,150 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use homie_controller::replay::{RecordedPublish, Replayer};
+use homie_controller::Device;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+const DEVICE_COUNT: usize = 1000;
+/// The number of devices to discover for the large installation benchmark, each of which publishes
+/// 10 retained messages.
+const LARGE_DEVICE_COUNT: usize = 10_000;
+
+fn publish(topic: &str, payload: &str) -> RecordedPublish {
+    RecordedPublish {
+        time: Duration::ZERO,
+        topic: format!("homie/{}", topic),
+        payload: payload.as_bytes().to_vec(),
+        retain: true,
+    }
+}
+
+/// Make a recording of the given number of devices being discovered, each with a single node with a
+/// single integer property, followed by an update to the value of each property.
+fn make_recording(device_count: usize) -> Vec<RecordedPublish> {
+    let mut recording = make_discovery(device_count);
+    for i in 0..device_count {
+        recording.push(publish(&format!("device{}/node/value", i), "1"));
+    }
+    recording
+}
+
+/// Make a recording of the retained messages of the given number of devices, each with a single
+/// node with a single integer property, as received when first connecting to the broker.
+fn make_discovery(device_count: usize) -> Vec<RecordedPublish> {
+    let mut recording = vec![];
+    for i in 0..device_count {
+        let device_id = format!("device{}", i);
+        recording.extend([
+            publish(&format!("{}/$homie", device_id), "4.0"),
+            publish(&format!("{}/$name", device_id), "Device"),
+            publish(&format!("{}/$state", device_id), "ready"),
+            publish(&format!("{}/$nodes", device_id), "node"),
+            publish(&format!("{}/node/$name", device_id), "Node"),
+            publish(&format!("{}/node/$type", device_id), "Type"),
+            publish(&format!("{}/node/$properties", device_id), "value"),
+            publish(&format!("{}/node/value/$name", device_id), "Value"),
+            publish(&format!("{}/node/value/$datatype", device_id), "integer"),
+            publish(&format!("{}/node/value", device_id), "0"),
+        ]);
+    }
+    recording
+}
+
+fn devices(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let recording = make_recording(DEVICE_COUNT);
+
+    c.bench_function("replay 1000 devices", |b| {
+        let replayer = Replayer::new("homie");
+        b.iter(|| runtime.block_on(replayer.replay(&recording, None)).unwrap())
+    });
+
+    c.bench_function("replay 1000 devices with concurrent reader", |b| {
+        let replayer = Replayer::new("homie");
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            // Keep taking snapshots of the devices, holding on to each until the next is taken, as
+            // an application displaying them might.
+            scope.spawn(|| {
+                let mut snapshot = replayer.controller().devices();
+                while !stop.load(Ordering::Relaxed) {
+                    snapshot = black_box(replayer.controller().devices());
+                }
+                drop(snapshot);
+            });
+            b.iter(|| runtime.block_on(replayer.replay(&recording, None)).unwrap());
+            stop.store(true, Ordering::Relaxed);
+        });
+    });
+
+    c.bench_function("snapshot of 1000 devices during replay", |b| {
+        let replayer = Replayer::new("homie");
+        runtime.block_on(replayer.replay(&recording, None)).unwrap();
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            // Keep replaying the recording on another thread while snapshots are taken.
+            scope.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    runtime.block_on(replayer.replay(&recording, None)).unwrap();
+                }
+            });
+            // Hold on to the latest snapshot, so that the controller can't update it in place.
+            let mut snapshot = replayer.controller().devices();
+            b.iter(|| snapshot = replayer.controller().devices());
+            drop(snapshot);
+            stop.store(true, Ordering::Relaxed);
+        });
+    });
+
+    c.bench_function("snapshot of 1000 devices", |b| {
+        let replayer = Replayer::new("homie");
+        runtime.block_on(replayer.replay(&recording, None)).unwrap();
+        assert_eq!(replayer.controller().devices().len(), DEVICE_COUNT);
+        b.iter(|| replayer.controller().devices())
+    });
+
+    // Compare updating one device while a snapshot is held elsewhere with the deep copy of every
+    // device which was needed before devices were stored in a persistent map.
+    let mut group = c.benchmark_group("update 1 of 1000 devices with snapshot held");
+    let replayer = Replayer::new("homie");
+    runtime.block_on(replayer.replay(&recording, None)).unwrap();
+    let snapshot = replayer.controller().devices();
+    group.bench_function("persistent map", |b| {
+        b.iter(|| {
+            let mut devices = (*snapshot).clone();
+            let device = Arc::make_mut(devices.get_mut("device0").unwrap());
+            device.name = Some("Renamed".to_owned());
+            devices
+        })
+    });
+    group.bench_function("baseline: copy of every device", |b| {
+        b.iter(|| {
+            let mut devices: HashMap<String, Device> = snapshot
+                .iter()
+                .map(|(device_id, device)| (device_id.clone(), Device::clone(device)))
+                .collect();
+            devices.get_mut("device0").unwrap().name = Some("Renamed".to_owned());
+            devices
+        })
+    });
+    group.finish();
+
+    let discovery = make_discovery(LARGE_DEVICE_COUNT);
+    let mut group = c.benchmark_group("large installation");
+    group.sample_size(10);
+    group.bench_function("replay 100k retained publishes", |b| {
+        b.iter(|| {
+            // Start from scratch each time, as when first connecting to the broker.
+            let replayer = Replayer::new("homie");
+            runtime.block_on(replayer.replay(&discovery, None)).unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, devices);
+criterion_main!(benches);
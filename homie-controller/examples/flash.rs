@@ -13,27 +13,24 @@ fn spawn_poll_loop(
     mut event_loop: HomieEventLoop,
     controller: Arc<HomieController>,
 ) -> JoinHandle<Result<(), PollError>> {
-    task::spawn(async move {
-        loop {
-            for event in controller.poll(&mut event_loop).await? {
-                if let Event::PropertyValueChanged {
-                    device_id,
-                    node_id,
-                    property_id,
-                    value,
-                    fresh,
-                } = event
-                {
-                    println!(
-                        "{}/{}/{} = {} ({})",
-                        device_id, node_id, property_id, value, fresh
-                    );
-                } else {
-                    log::info!("Event: {:?}", event);
-                }
-            }
+    controller.on_event(|event| async move {
+        if let Event::PropertyValueChanged {
+            device_id,
+            node_id,
+            property_id,
+            value,
+            fresh,
+        } = event
+        {
+            println!(
+                "{}/{}/{} = {} ({})",
+                device_id, node_id, property_id, value, fresh
+            );
+        } else {
+            log::info!("Event: {:?}", event);
         }
-    })
+    });
+    task::spawn(async move { controller.run(&mut event_loop).await })
 }
 
 #[tokio::main]
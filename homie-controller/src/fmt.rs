@@ -0,0 +1,344 @@
+//! Helpers for displaying the devices known to a controller, for building command-line tools and
+//! dashboards.
+
+use crate::types::{Device, Node, Property, State};
+use crate::Devices;
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter, Write};
+
+/// Render the given devices as an indented text tree, sorted by ID, including the state of each
+/// device and the datatype, unit and current value of each property.
+///
+/// This is intended to be used with [`HomieController::devices`](crate::HomieController::devices).
+pub fn format_tree(devices: &Devices) -> String {
+    let mut output = String::new();
+    for device in sorted_values(devices) {
+        output.push_str(&format_device(device));
+    }
+    output
+}
+
+/// Render a single device and its nodes and properties as an indented text tree.
+pub fn format_device(device: &Device) -> String {
+    let mut output = String::new();
+    write_device(&mut output, device).expect("Writing to a String can't fail");
+    output
+}
+
+fn write_device(output: &mut String, device: &Device) -> fmt::Result {
+    write!(output, "{}", device.id)?;
+    if let Some(name) = &device.name {
+        write!(output, " \"{}\"", name)?;
+    }
+    writeln!(output, " ({})", device.state)?;
+    for node in sorted_values(&device.nodes) {
+        write_node(output, node)?;
+    }
+    Ok(())
+}
+
+fn write_node(output: &mut String, node: &Node) -> fmt::Result {
+    write!(output, "  {}", node.id)?;
+    if let Some(name) = &node.name {
+        write!(output, " \"{}\"", name)?;
+    }
+    if let Some(node_type) = &node.node_type {
+        write!(output, " [{}]", node_type)?;
+    }
+    writeln!(output)?;
+    for property in sorted_values(&node.properties) {
+        write_property(output, property)?;
+    }
+    Ok(())
+}
+
+fn write_property(output: &mut String, property: &Property) -> fmt::Result {
+    write!(output, "    {}", property.id)?;
+    if let Some(name) = &property.name {
+        write!(output, " \"{}\"", name)?;
+    }
+    write!(output, ": ")?;
+    match &property.value {
+        Some(value) => write!(output, "{}", value)?,
+        None => write!(output, "?")?,
+    }
+    if let Some(unit) = &property.unit {
+        write!(output, " {}", unit)?;
+    }
+    match property.datatype {
+        Some(datatype) => write!(output, " ({}", datatype)?,
+        None => write!(output, " (?")?,
+    }
+    if property.settable {
+        write!(output, ", settable")?;
+    }
+    if !property.retained {
+        write!(output, ", not retained")?;
+    }
+    writeln!(output, ")")
+}
+
+fn sorted_values<'a, T: 'a>(
+    map: impl IntoIterator<Item = (&'a String, &'a T)>,
+) -> impl Iterator<Item = &'a T> {
+    let mut entries: Vec<_> = map.into_iter().collect();
+    entries.sort_by_key(|(id, _)| *id);
+    entries.into_iter().map(|(_, value)| value)
+}
+
+fn sorted_keys<'a>(
+    old: impl Iterator<Item = &'a String>,
+    new: impl Iterator<Item = &'a String>,
+) -> BTreeSet<&'a String> {
+    old.chain(new).collect()
+}
+
+/// A difference between two snapshots of the devices known to a controller.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Change {
+    /// A device was added.
+    DeviceAdded { device_id: String },
+    /// A device was removed.
+    DeviceRemoved { device_id: String },
+    /// The state of a device changed.
+    StateChanged {
+        device_id: String,
+        old: State,
+        new: State,
+    },
+    /// A node was added to a device.
+    NodeAdded { device_id: String, node_id: String },
+    /// A node was removed from a device.
+    NodeRemoved { device_id: String, node_id: String },
+    /// A property was added to a node.
+    PropertyAdded {
+        device_id: String,
+        node_id: String,
+        property_id: String,
+    },
+    /// A property was removed from a node.
+    PropertyRemoved {
+        device_id: String,
+        node_id: String,
+        property_id: String,
+    },
+    /// The value of a property changed.
+    ValueChanged {
+        device_id: String,
+        node_id: String,
+        property_id: String,
+        old: Option<String>,
+        new: Option<String>,
+    },
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::DeviceAdded { device_id } => write!(f, "+ {}", device_id),
+            Self::DeviceRemoved { device_id } => write!(f, "- {}", device_id),
+            Self::StateChanged {
+                device_id,
+                old,
+                new,
+            } => write!(f, "~ {} state: {} -> {}", device_id, old, new),
+            Self::NodeAdded { device_id, node_id } => write!(f, "+ {}/{}", device_id, node_id),
+            Self::NodeRemoved { device_id, node_id } => write!(f, "- {}/{}", device_id, node_id),
+            Self::PropertyAdded {
+                device_id,
+                node_id,
+                property_id,
+            } => write!(f, "+ {}/{}/{}", device_id, node_id, property_id),
+            Self::PropertyRemoved {
+                device_id,
+                node_id,
+                property_id,
+            } => write!(f, "- {}/{}/{}", device_id, node_id, property_id),
+            Self::ValueChanged {
+                device_id,
+                node_id,
+                property_id,
+                old,
+                new,
+            } => write!(
+                f,
+                "~ {}/{}/{}: {} -> {}",
+                device_id,
+                node_id,
+                property_id,
+                old.as_deref().unwrap_or("?"),
+                new.as_deref().unwrap_or("?")
+            ),
+        }
+    }
+}
+
+/// Compute the differences between two snapshots of the devices known to a controller, sorted by
+/// device, node and property ID.
+///
+/// Changes within an added or removed device or node are not listed separately.
+pub fn diff(old: &Devices, new: &Devices) -> Vec<Change> {
+    let mut changes = vec![];
+    for device_id in sorted_keys(old.keys(), new.keys()) {
+        match (old.get(device_id), new.get(device_id)) {
+            (Some(old_device), Some(new_device)) => {
+                diff_device(&mut changes, old_device, new_device)
+            }
+            (None, Some(_)) => changes.push(Change::DeviceAdded {
+                device_id: device_id.to_owned(),
+            }),
+            (Some(_), None) => changes.push(Change::DeviceRemoved {
+                device_id: device_id.to_owned(),
+            }),
+            (None, None) => unreachable!(),
+        }
+    }
+    changes
+}
+
+fn diff_device(changes: &mut Vec<Change>, old: &Device, new: &Device) {
+    let device_id = &new.id;
+    if old.state != new.state {
+        changes.push(Change::StateChanged {
+            device_id: device_id.to_owned(),
+            old: old.state,
+            new: new.state,
+        });
+    }
+    for node_id in sorted_keys(old.nodes.keys(), new.nodes.keys()) {
+        match (old.nodes.get(node_id), new.nodes.get(node_id)) {
+            (Some(old_node), Some(new_node)) => diff_node(changes, device_id, old_node, new_node),
+            (None, Some(_)) => changes.push(Change::NodeAdded {
+                device_id: device_id.to_owned(),
+                node_id: node_id.to_owned(),
+            }),
+            (Some(_), None) => changes.push(Change::NodeRemoved {
+                device_id: device_id.to_owned(),
+                node_id: node_id.to_owned(),
+            }),
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+fn diff_node(changes: &mut Vec<Change>, device_id: &str, old: &Node, new: &Node) {
+    let node_id = &new.id;
+    for property_id in sorted_keys(old.properties.keys(), new.properties.keys()) {
+        match (
+            old.properties.get(property_id),
+            new.properties.get(property_id),
+        ) {
+            (Some(old_property), Some(new_property)) => {
+                if old_property.value != new_property.value {
+                    changes.push(Change::ValueChanged {
+                        device_id: device_id.to_owned(),
+                        node_id: node_id.to_owned(),
+                        property_id: property_id.to_owned(),
+                        old: old_property.value.clone(),
+                        new: new_property.value.clone(),
+                    });
+                }
+            }
+            (None, Some(_)) => changes.push(Change::PropertyAdded {
+                device_id: device_id.to_owned(),
+                node_id: node_id.to_owned(),
+                property_id: property_id.to_owned(),
+            }),
+            (Some(_), None) => changes.push(Change::PropertyRemoved {
+                device_id: device_id.to_owned(),
+                node_id: node_id.to_owned(),
+                property_id: property_id.to_owned(),
+            }),
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+/// Format a list of changes as returned by [`diff`], one per line.
+pub fn format_diff(changes: &[Change]) -> String {
+    changes
+        .iter()
+        .map(|change| format!("{}\n", change))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Datatype;
+    use std::sync::Arc;
+
+    fn make_test_devices() -> Devices {
+        let mut temperature = Property::new("temperature");
+        temperature.name = Some("Temperature".to_owned());
+        temperature.datatype = Some(Datatype::Float);
+        temperature.unit = Some("°C".to_owned());
+        temperature.value = Some("21.5".to_owned());
+
+        let mut target = Property::new("target");
+        target.name = Some("Target".to_owned());
+        target.datatype = Some(Datatype::Integer);
+        target.settable = true;
+
+        let mut node = Node::new("sensor");
+        node.name = Some("Sensor".to_owned());
+        node.node_type = Some("Thermostat".to_owned());
+        node.add_property(temperature);
+        node.add_property(target);
+
+        let mut device = Device::new("thermostat", "4.0");
+        device.name = Some("Living room".to_owned());
+        device.state = State::Ready;
+        device.add_node(node);
+
+        let other = Device::new("other", "4.0");
+
+        let mut devices = Devices::new();
+        devices.insert(device.id.clone(), Arc::new(device));
+        devices.insert(other.id.clone(), Arc::new(other));
+        devices
+    }
+
+    #[test]
+    fn tree() {
+        assert_eq!(
+            format_tree(&make_test_devices()),
+            "other (unknown)\n\
+             thermostat \"Living room\" (ready)\n  \
+               sensor \"Sensor\" [Thermostat]\n    \
+                 target \"Target\": ? (integer, settable)\n    \
+                 temperature \"Temperature\": 21.5 °C (float)\n"
+        );
+    }
+
+    #[test]
+    fn diff_unchanged() {
+        let devices = make_test_devices();
+        assert_eq!(diff(&devices, &devices), vec![]);
+    }
+
+    #[test]
+    fn diff_changes() {
+        let old = make_test_devices();
+        let mut new = old.clone();
+        new.remove("other");
+        new.insert("new".to_owned(), Arc::new(Device::new("new", "4.0")));
+        let thermostat = Arc::make_mut(new.get_mut("thermostat").unwrap());
+        thermostat.state = State::Lost;
+        let node = thermostat.nodes.get_mut("sensor").unwrap();
+        node.properties.remove("target");
+        node.properties.get_mut("temperature").unwrap().value = Some("22".to_owned());
+        thermostat.add_node(Node::new("extra"));
+
+        let changes = diff(&old, &new);
+        assert_eq!(
+            format_diff(&changes),
+            "+ new\n\
+             - other\n\
+             ~ thermostat state: ready -> lost\n\
+             + thermostat/extra\n\
+             - thermostat/sensor/target\n\
+             ~ thermostat/sensor/temperature: 21.5 -> 22\n"
+        );
+    }
+}
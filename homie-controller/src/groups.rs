@@ -0,0 +1,251 @@
+use crate::{Datatype, Device, Devices, Property};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+/// A rule for which devices belong to a group, as configured with
+/// [`HomieController::set_groups`](crate::HomieController::set_groups).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DeviceMatcher {
+    /// The device with exactly the given ID.
+    Id(String),
+    /// Any device whose ID starts with the given prefix.
+    IdPrefix(String),
+    /// Any device with a tag from the [meta extension](https://github.com/homieiot/convention/blob/develop/extensions/documents/homie_meta_extension.md)
+    /// with the given key and value.
+    Meta { key: String, value: String },
+}
+
+impl DeviceMatcher {
+    /// Returns whether the given device matches this rule.
+    pub fn matches(&self, device: &Device) -> bool {
+        match self {
+            Self::Id(id) => device.id == *id,
+            Self::IdPrefix(prefix) => device.id.starts_with(prefix.as_str()),
+            Self::Meta { key, value } => meta_values(device, key).any(|v| v == value),
+        }
+    }
+}
+
+/// Returns the values of the device-level meta extension tags of the given device with the given
+/// key. These are published as `$meta/<id>/$key` and `$meta/<id>/$value`.
+pub(crate) fn meta_values<'a>(device: &'a Device, key: &'a str) -> impl Iterator<Item = &'a str> {
+    device
+        .attributes
+        .iter()
+        .filter_map(move |(attribute, attribute_value)| {
+            let id = attribute.strip_prefix("$meta/")?.strip_suffix("/$key")?;
+            if attribute_value != key || id.contains('/') {
+                return None;
+            }
+            device
+                .attributes
+                .get(&format!("$meta/{}/$value", id))
+                .map(String::as_str)
+        })
+}
+
+/// The configured groups of devices.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Groups {
+    /// The matchers for each configured group, keyed by group name.
+    pub matchers: HashMap<String, Vec<DeviceMatcher>>,
+    /// The meta extension tag key from which further groups are derived, if any.
+    pub meta_key: Option<String>,
+}
+
+impl Groups {
+    /// Returns whether the given device is in the group with the given name.
+    fn contains(&self, name: &str, device: &Device) -> bool {
+        self.matchers
+            .get(name)
+            .is_some_and(|matchers| matchers.iter().any(|matcher| matcher.matches(device)))
+            || self
+                .meta_key
+                .as_ref()
+                .is_some_and(|key| meta_values(device, key).any(|value| value == name))
+    }
+
+    /// Returns the names of all configured groups, and all groups derived from meta tags of the
+    /// given devices, in order.
+    pub fn names(&self, devices: &Devices) -> Vec<String> {
+        let mut names: BTreeSet<String> = self.matchers.keys().cloned().collect();
+        if let Some(key) = &self.meta_key {
+            for device in devices.values() {
+                names.extend(meta_values(device, key).map(ToOwned::to_owned));
+            }
+        }
+        names.into_iter().collect()
+    }
+
+    /// Returns the group with the given name, with those of the given devices which are in it.
+    pub fn group(&self, name: &str, devices: &Devices) -> DeviceGroup {
+        let mut group_devices: Vec<Arc<Device>> = devices
+            .values()
+            .filter(|device| self.contains(name, device))
+            .cloned()
+            .collect();
+        group_devices.sort_by(|a, b| a.id.cmp(&b.id));
+        DeviceGroup {
+            name: name.to_owned(),
+            devices: group_devices,
+        }
+    }
+}
+
+/// A snapshot of the devices in a group, such as all the devices in a room, with helpers to
+/// aggregate their property values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceGroup {
+    /// The name of the group.
+    pub name: String,
+    /// The devices in the group, in order of their IDs.
+    pub devices: Vec<Arc<Device>>,
+}
+
+impl DeviceGroup {
+    /// Returns whether the group has no devices.
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Returns all properties with the given ID on any node of any device in the group.
+    pub fn properties<'a>(&'a self, property_id: &'a str) -> impl Iterator<Item = &'a Property> {
+        self.devices.iter().flat_map(move |device| {
+            device
+                .nodes
+                .values()
+                .filter_map(move |node| node.properties.get(property_id))
+        })
+    }
+
+    /// Returns whether all boolean properties with the given ID in the group are true, such as
+    /// whether all the lights in a room are on.
+    ///
+    /// Returns `None` if there are no such properties with a valid value.
+    pub fn all(&self, property_id: &str) -> Option<bool> {
+        let values = self.boolean_values(property_id);
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.into_iter().all(|value| value))
+        }
+    }
+
+    /// Returns whether any boolean property with the given ID in the group is true.
+    ///
+    /// Returns `None` if there are no such properties with a valid value.
+    pub fn any(&self, property_id: &str) -> Option<bool> {
+        let values = self.boolean_values(property_id);
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.into_iter().any(|value| value))
+        }
+    }
+
+    /// Returns the mean of the values of all integer and float properties with the given ID in the
+    /// group, such as the average temperature of a room. Properties without a valid value are
+    /// ignored.
+    ///
+    /// Returns `None` if there are no such properties with a valid value.
+    pub fn average(&self, property_id: &str) -> Option<f64> {
+        let values: Vec<f64> = self
+            .properties(property_id)
+            .filter_map(|property| match property.datatype {
+                Some(Datatype::Integer) => property.value::<i64>().ok().map(|value| value as f64),
+                Some(Datatype::Float) => property.value::<f64>().ok(),
+                _ => None,
+            })
+            .collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.iter().sum::<f64>() / values.len() as f64)
+        }
+    }
+
+    fn boolean_values(&self, property_id: &str) -> Vec<bool> {
+        self.properties(property_id)
+            .filter(|property| property.datatype == Some(Datatype::Boolean))
+            .filter_map(|property| property.value().ok())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Node;
+
+    fn device_with(id: &str, properties: Vec<(&str, Datatype, &str)>) -> Device {
+        let mut device = Device::new(id, "4.0");
+        let mut node = Node::new("node");
+        for (property_id, datatype, value) in properties {
+            let mut property = Property::new(property_id);
+            property.datatype = Some(datatype);
+            property.value = Some(value.to_owned());
+            node.add_property(property);
+        }
+        device.add_node(node);
+        device
+    }
+
+    #[test]
+    fn matchers() {
+        let mut device = device_with("kitchen-light", vec![]);
+        device
+            .attributes
+            .insert("$meta/1/$key".to_owned(), "room".to_owned());
+        device
+            .attributes
+            .insert("$meta/1/$value".to_owned(), "kitchen".to_owned());
+
+        assert!(DeviceMatcher::Id("kitchen-light".to_owned()).matches(&device));
+        assert!(!DeviceMatcher::Id("kitchen".to_owned()).matches(&device));
+        assert!(DeviceMatcher::IdPrefix("kitchen-".to_owned()).matches(&device));
+        assert!(!DeviceMatcher::IdPrefix("hall-".to_owned()).matches(&device));
+        assert!(DeviceMatcher::Meta {
+            key: "room".to_owned(),
+            value: "kitchen".to_owned()
+        }
+        .matches(&device));
+        assert!(!DeviceMatcher::Meta {
+            key: "room".to_owned(),
+            value: "hall".to_owned()
+        }
+        .matches(&device));
+    }
+
+    #[test]
+    fn aggregates() {
+        let group = DeviceGroup {
+            name: "kitchen".to_owned(),
+            devices: vec![
+                Arc::new(device_with(
+                    "a",
+                    vec![
+                        ("on", Datatype::Boolean, "true"),
+                        ("temperature", Datatype::Float, "20.5"),
+                    ],
+                )),
+                Arc::new(device_with(
+                    "b",
+                    vec![
+                        ("on", Datatype::Boolean, "false"),
+                        ("temperature", Datatype::Integer, "22"),
+                    ],
+                )),
+                Arc::new(device_with(
+                    "c",
+                    vec![("temperature", Datatype::Float, "invalid")],
+                )),
+            ],
+        };
+
+        assert_eq!(group.all("on"), Some(false));
+        assert_eq!(group.any("on"), Some(true));
+        assert_eq!(group.average("temperature"), Some(21.25));
+        assert_eq!(group.all("missing"), None);
+        assert_eq!(group.average("on"), None);
+    }
+}
@@ -0,0 +1,324 @@
+//! Conversion between devices discovered using Homie 4.0 and the
+//! [Homie 5 description document](https://homieiot.github.io/specification/#description).
+//!
+//! This requires the `json` feature.
+
+use crate::types::{Datatype, Device, Node, Property};
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+const HOMIE5_VERSION: &str = "5.0";
+
+/// An error converting a Homie 5 description document to a `Device`.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub enum DescriptionError {
+    /// A required field was missing or had the wrong type.
+    #[error("Missing or invalid field '{0}'")]
+    InvalidField(String),
+    /// A property had a datatype which isn't supported.
+    #[error("Unsupported datatype '{0}'")]
+    UnsupportedDatatype(String),
+}
+
+impl Device {
+    /// Converts the device to the Homie 5 description document equivalent to its Homie 4.0
+    /// attributes, including its nodes and properties along with their formats.
+    ///
+    /// Properties whose datatype is not yet known are left out, as Homie 5 requires it. The
+    /// `version` field is a hash of the rest of the description, so it stays the same as long as
+    /// the description doesn't change.
+    pub fn to_homie5_description(&self) -> Value {
+        let mut description = Map::new();
+        description.insert("homie".to_owned(), HOMIE5_VERSION.into());
+        insert_optional(&mut description, "name", &self.name);
+        let nodes: Map<String, Value> = self
+            .nodes
+            .values()
+            .map(|node| (node.id.clone(), node_description(node)))
+            .collect();
+        description.insert("nodes".to_owned(), nodes.into());
+
+        let version = description_version(&Value::Object(description.clone()).to_string());
+        description.insert("version".to_owned(), version.into());
+        description.into()
+    }
+
+    /// Converts a Homie 5 description document to a device with the given ID.
+    ///
+    /// The state and property values are not part of the description, so they are left unknown.
+    pub fn from_homie5_description(
+        id: &str,
+        description: &Value,
+    ) -> Result<Device, DescriptionError> {
+        let homie_version = description
+            .get("homie")
+            .and_then(Value::as_str)
+            .ok_or_else(|| DescriptionError::InvalidField("homie".to_owned()))?;
+        let mut device = Device::new(id, homie_version);
+        device.name = optional_string(description, "name")?;
+        for (node_id, node) in optional_object(description, "nodes")? {
+            device.add_node(node_from_description(node_id, node)?);
+        }
+        Ok(device)
+    }
+}
+
+fn node_description(node: &Node) -> Value {
+    let mut description = Map::new();
+    insert_optional(&mut description, "name", &node.name);
+    insert_optional(&mut description, "type", &node.node_type);
+    let properties: Map<String, Value> = node
+        .properties
+        .values()
+        .filter_map(|property| Some((property.id.clone(), property_description(property)?)))
+        .collect();
+    description.insert("properties".to_owned(), properties.into());
+    description.into()
+}
+
+fn property_description(property: &Property) -> Option<Value> {
+    let mut description = Map::new();
+    description.insert("datatype".to_owned(), property.datatype?.to_string().into());
+    insert_optional(&mut description, "name", &property.name);
+    insert_optional(&mut description, "format", &property.format);
+    insert_optional(&mut description, "unit", &property.unit);
+    // Only include settable and retained if they differ from the defaults.
+    if property.settable {
+        description.insert("settable".to_owned(), json!(true));
+    }
+    if !property.retained {
+        description.insert("retained".to_owned(), json!(false));
+    }
+    Some(description.into())
+}
+
+fn node_from_description(id: &str, description: &Value) -> Result<Node, DescriptionError> {
+    let mut node = Node::new(id);
+    node.name = optional_string(description, "name")?;
+    node.node_type = optional_string(description, "type")?;
+    for (property_id, property) in optional_object(description, "properties")? {
+        node.add_property(property_from_description(property_id, property)?);
+    }
+    Ok(node)
+}
+
+fn property_from_description(id: &str, description: &Value) -> Result<Property, DescriptionError> {
+    let mut property = Property::new(id);
+    let datatype = description
+        .get("datatype")
+        .and_then(Value::as_str)
+        .ok_or_else(|| DescriptionError::InvalidField(format!("{}.datatype", id)))?;
+    property.datatype = Some(
+        datatype
+            .parse::<Datatype>()
+            .map_err(|_| DescriptionError::UnsupportedDatatype(datatype.to_owned()))?,
+    );
+    property.name = optional_string(description, "name")?;
+    property.format = optional_string(description, "format")?;
+    property.unit = optional_string(description, "unit")?;
+    property.settable = optional_bool(description, "settable")?.unwrap_or(false);
+    property.retained = optional_bool(description, "retained")?.unwrap_or(true);
+    Ok(property)
+}
+
+fn insert_optional(description: &mut Map<String, Value>, key: &str, value: &Option<String>) {
+    if let Some(value) = value {
+        description.insert(key.to_owned(), value.as_str().into());
+    }
+}
+
+fn optional_string(description: &Value, key: &str) -> Result<Option<String>, DescriptionError> {
+    match description.get(key) {
+        None => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.to_owned())),
+        Some(_) => Err(DescriptionError::InvalidField(key.to_owned())),
+    }
+}
+
+fn optional_bool(description: &Value, key: &str) -> Result<Option<bool>, DescriptionError> {
+    match description.get(key) {
+        None => Ok(None),
+        Some(Value::Bool(value)) => Ok(Some(*value)),
+        Some(_) => Err(DescriptionError::InvalidField(key.to_owned())),
+    }
+}
+
+fn optional_object<'a>(
+    description: &'a Value,
+    key: &str,
+) -> Result<impl Iterator<Item = (&'a String, &'a Value)>, DescriptionError> {
+    match description.get(key) {
+        None => Ok(None.into_iter().flatten()),
+        Some(Value::Object(object)) => Ok(Some(object.iter()).into_iter().flatten()),
+        Some(_) => Err(DescriptionError::InvalidField(key.to_owned())),
+    }
+}
+
+/// Computes a version number for the given serialised description, using the 32-bit FNV-1a hash so
+/// that it is stable across builds.
+fn description_version(description: &str) -> u32 {
+    description.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::State;
+
+    fn make_test_device() -> Device {
+        let mut temperature = Property::new("temperature");
+        temperature.name = Some("Temperature".to_owned());
+        temperature.datatype = Some(Datatype::Float);
+        temperature.unit = Some("°C".to_owned());
+        temperature.format = Some("-40:80".to_owned());
+
+        let mut mode = Property::new("mode");
+        mode.name = Some("Mode".to_owned());
+        mode.datatype = Some(Datatype::Enum);
+        mode.format = Some("heat,cool,off".to_owned());
+        mode.settable = true;
+
+        let mut button = Property::new("button");
+        button.datatype = Some(Datatype::Boolean);
+        button.retained = false;
+
+        let mut colour = Property::new("colour");
+        colour.name = Some("Colour".to_owned());
+        colour.datatype = Some(Datatype::Color);
+        colour.format = Some("rgb".to_owned());
+        colour.settable = true;
+
+        let mut node = Node::new("thermostat");
+        node.name = Some("Thermostat".to_owned());
+        node.node_type = Some("Heater".to_owned());
+        node.add_property(temperature);
+        node.add_property(mode);
+        node.add_property(button);
+        node.add_property(colour);
+
+        let mut device = Device::new("device", HOMIE5_VERSION);
+        device.name = Some("Living room".to_owned());
+        device.add_node(node);
+        device.add_node(Node::new("empty"));
+        device
+    }
+
+    #[test]
+    fn to_description() {
+        let mut device = make_test_device();
+        let mut incomplete = Property::new("incomplete");
+        incomplete.name = Some("No datatype yet".to_owned());
+        device
+            .nodes
+            .get_mut("empty")
+            .unwrap()
+            .add_property(incomplete);
+
+        let mut description = device.to_homie5_description();
+        assert!(description["version"].is_u64());
+        description.as_object_mut().unwrap().remove("version");
+        assert_eq!(
+            description,
+            json!({
+                "homie": "5.0",
+                "name": "Living room",
+                "nodes": {
+                    "thermostat": {
+                        "name": "Thermostat",
+                        "type": "Heater",
+                        "properties": {
+                            "temperature": {
+                                "name": "Temperature",
+                                "datatype": "float",
+                                "format": "-40:80",
+                                "unit": "°C"
+                            },
+                            "mode": {
+                                "name": "Mode",
+                                "datatype": "enum",
+                                "format": "heat,cool,off",
+                                "settable": true
+                            },
+                            "button": {
+                                "datatype": "boolean",
+                                "retained": false
+                            },
+                            "colour": {
+                                "name": "Colour",
+                                "datatype": "color",
+                                "format": "rgb",
+                                "settable": true
+                            }
+                        }
+                    },
+                    "empty": {
+                        "properties": {}
+                    }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn version_changes_with_description() {
+        let device = make_test_device();
+        let version = device.to_homie5_description()["version"].clone();
+        assert_eq!(device.to_homie5_description()["version"], version);
+
+        let mut renamed = device;
+        renamed.name = Some("Kitchen".to_owned());
+        assert_ne!(renamed.to_homie5_description()["version"], version);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut device = make_test_device();
+        // The state and values aren't part of the description.
+        device.state = State::Ready;
+        device
+            .nodes
+            .get_mut("thermostat")
+            .unwrap()
+            .properties
+            .get_mut("temperature")
+            .unwrap()
+            .value = Some("21.5".to_owned());
+
+        let parsed =
+            Device::from_homie5_description("device", &device.to_homie5_description()).unwrap();
+
+        let mut expected = make_test_device();
+        expected.state = State::Unknown;
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn from_description_invalid() {
+        assert_eq!(
+            Device::from_homie5_description("device", &json!({ "name": "No version" })),
+            Err(DescriptionError::InvalidField("homie".to_owned()))
+        );
+        assert_eq!(
+            Device::from_homie5_description(
+                "device",
+                &json!({
+                    "homie": "5.0",
+                    "nodes": { "node": { "properties": { "p": { "datatype": "json" } } } }
+                })
+            ),
+            Err(DescriptionError::UnsupportedDatatype("json".to_owned()))
+        );
+        assert_eq!(
+            Device::from_homie5_description(
+                "device",
+                &json!({
+                    "homie": "5.0",
+                    "nodes": { "node": { "properties": { "p": { "datatype": "integer", "settable": "yes" } } } }
+                })
+            ),
+            Err(DescriptionError::InvalidField("settable".to_owned()))
+        );
+    }
+}
@@ -1,27 +1,79 @@
 //! `homie-controller` is a library for creating controllers to interact via an MQTT broker with IoT
 //! devices implementing the [Homie convention](https://homieiot.github.io/).
 
+use arc_swap::ArcSwap;
+use chrono::DateTime;
+use futures::channel::oneshot;
+use futures::future::{join_all, select, BoxFuture, Either};
+use futures::{pin_mut, FutureExt};
 use rumqttc::{
-    AsyncClient, ClientError, ConnectionError, EventLoop, Incoming, MqttOptions, Publish, QoS,
+    AsyncClient, ClientError, ConnectionError, EventLoop, Incoming, MqttOptions, Outgoing, Publish,
+    QoS,
 };
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
 use std::num::{ParseFloatError, ParseIntError};
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
 use std::str;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
 
+pub mod fmt;
+
+mod groups;
+use groups::Groups;
+pub use groups::{DeviceGroup, DeviceMatcher};
+
+#[cfg(feature = "json")]
+mod homie5;
+#[cfg(feature = "json")]
+pub use homie5::DescriptionError;
+
+mod metrics;
+use metrics::Metrics;
+pub use metrics::{MessageRate, MetricsConfig};
+
+mod multi;
+pub use multi::{MultiHomieController, TaggedEvent};
+
+mod rate;
+pub use rate::{RateOfChangeConfig, RecentValues};
+
+pub mod replay;
+use replay::Recorder;
+
 mod types;
-pub use types::{Datatype, Device, Extension, Node, Property, State};
-use types::{ParseDatatypeError, ParseExtensionError, ParseStateError};
+use types::{is_valid_id, ParseDatatypeError, ParsePropertyCategoryError, ParseStateError};
+pub use types::{Datatype, Device, Extension, Node, Property, PropertyCategory, State};
+
+mod units;
+pub use units::Unit;
 
 mod values;
 pub use values::{
-    ColorFormat, ColorHsv, ColorRgb, EnumValue, ParseColorError, ParseEnumError, Value, ValueError,
+    Color, ColorConversion, ColorFormat, ColorHsv, ColorRgb, DateTimeValue, DurationValue,
+    EnumValue, ParseColorError, ParseDurationError, ParseEnumError, Value, ValueError,
 };
 
 const REQUESTS_CAP: usize = 1000;
 
+/// The largest payload which is stored for a device attribute which isn't otherwise modelled, such
+/// as those under `$implementation/`. Some firmwares use these topics for larger data such as OTA
+/// firmware images, which shouldn't be kept in memory.
+const MAX_OTHER_ATTRIBUTE_SIZE: usize = 4096;
+
+/// The set of Homie devices known to a controller, keyed by their IDs.
+///
+/// This is a persistent map, so cloning it is cheap and the clone shares structure with the
+/// original until either is modified. Each device is behind an `Arc` so that only the device being
+/// modified needs to be copied.
+pub type Devices = imbl::HashMap<String, Arc<Device>>;
+
 /// An error encountered while polling a `HomieController`.
 #[derive(Error, Debug)]
 pub enum PollError {
@@ -31,22 +83,117 @@ pub enum PollError {
     /// Error connecting to or communicating with the MQTT broker.
     #[error("{0}")]
     Connection(#[from] ConnectionError),
+    /// The controller has been shut down with [`HomieController::shutdown`], so it can't be polled
+    /// any more.
+    #[error("Controller has been shut down")]
+    Shutdown,
+}
+
+/// An error encountered while trying to set the value of a property.
+#[derive(Error, Debug)]
+pub enum SetError {
+    /// The device is not known to the controller.
+    #[error("Unknown device '{device_id}'")]
+    UnknownDevice { device_id: String },
+    /// The node is not known on the device.
+    #[error("Unknown node '{device_id}/{node_id}'")]
+    UnknownNode { device_id: String, node_id: String },
+    /// The property is not known on the node.
+    #[error("Unknown property '{device_id}/{node_id}/{property_id}'")]
+    UnknownProperty {
+        device_id: String,
+        node_id: String,
+        property_id: String,
+    },
+    /// The property is not settable.
+    #[error("Property '{device_id}/{node_id}/{property_id}' is not settable")]
+    NotSettable {
+        device_id: String,
+        node_id: String,
+        property_id: String,
+    },
+    /// The value is not valid for the property.
+    #[error("{0}")]
+    InvalidValue(#[from] ValueError),
+    /// Error sending to the MQTT broker.
+    #[error("{0}")]
+    Client(#[from] ClientError),
+}
+
+/// An error encountered while sending a command with [`HomieController::command`].
+#[derive(Error, Debug)]
+pub enum CommandError {
+    /// Error sending to the MQTT broker.
+    #[error("{0}")]
+    Client(#[from] ClientError),
+    /// No fresh value was received for the result property within the timeout.
+    #[error("Timed out waiting for result from '{device_id}/{node_id}/{property_id}'")]
+    Timeout {
+        device_id: String,
+        node_id: String,
+        property_id: String,
+    },
+}
+
+/// An error returned when a device doesn't reach the state or value waited for with
+/// [`HomieController::wait_for_state`] or [`HomieController::wait_for_value`] within the timeout.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub enum WaitError {
+    /// The device didn't reach the given state.
+    #[error("Timed out waiting for device '{device_id}' to be {state}")]
+    State { device_id: String, state: State },
+    /// The property didn't have a value matching the predicate.
+    #[error("Timed out waiting for value of '{device_id}/{node_id}/{property_id}'")]
+    Value {
+        device_id: String,
+        node_id: String,
+        property_id: String,
+    },
+}
+
+/// Options for publishing to the set topic of a property.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SetOptions {
+    /// The MQTT quality of service with which to publish. Defaults to `AtLeastOnce`.
+    pub qos: QoS,
+    /// Whether the MQTT broker should retain the value. Defaults to false, as the device is
+    /// expected to publish the new value of the property itself.
+    pub retain: bool,
+}
+
+impl Default for SetOptions {
+    fn default() -> Self {
+        Self {
+            qos: QoS::AtLeastOnce,
+            retain: false,
+        }
+    }
 }
 
 /// An event from a Homie device, either because of a property change or because something new has
 /// been discovered.
+///
+/// # Freshness
+///
+/// An attribute update is fresh if it has just been sent by the device, as opposed to being a
+/// retained attribute received because the controller just connected to the MQTT broker. This lets
+/// applications avoid reacting to the initial flood of updates.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Event {
     /// A new device has been discovered, or an attribute of the device has been updated.
     DeviceUpdated {
         device_id: String,
         has_required_attributes: bool,
+        /// Whether the update is [fresh](Event#freshness).
+        fresh: bool,
     },
     /// An attribute of a node on a device has been updated.
     NodeUpdated {
         device_id: String,
         node_id: String,
         has_required_attributes: bool,
+        /// Whether the update is [fresh](Event#freshness).
+        fresh: bool,
     },
     /// An attribute of a property on a node has been updated.
     PropertyUpdated {
@@ -54,6 +201,8 @@ pub enum Event {
         node_id: String,
         property_id: String,
         has_required_attributes: bool,
+        /// Whether the update is [fresh](Event#freshness).
+        fresh: bool,
     },
     /// The value of a property has changed.
     PropertyValueChanged {
@@ -66,33 +215,89 @@ pub enum Event {
         /// being the initial value because the controller just connected to the MQTT broker.
         fresh: bool,
     },
+    /// A value was received for a property but it was not valid UTF-8. This is only sent if the
+    /// controller is configured with `NonUtf8Payloads::Binary`. The value of the property is not
+    /// updated.
+    BinaryPropertyValue {
+        device_id: String,
+        node_id: String,
+        property_id: String,
+        /// The raw bytes of the value.
+        value: Vec<u8>,
+        /// Whether the value is fresh, i.e. it has just been sent by the device, as opposed to
+        /// being the initial value because the controller just connected to the MQTT broker.
+        fresh: bool,
+    },
+    /// A device published something which doesn't conform to the Homie convention, such as a
+    /// malformed attribute or an invalid ID. This is only sent if the controller is in strict mode.
+    /// The message is also added to the `validation_errors` of the device, if it is known.
+    ValidationError {
+        device_id: String,
+        /// The full MQTT topic of the offending message.
+        topic: String,
+        /// A description of the problem.
+        message: String,
+    },
     /// Connected to the MQTT broker. This could be either the initial connection or a reconnection
     /// after the connection was dropped for some reason.
     Connected,
 }
 
+/// How a `HomieController` should handle property values which are not valid UTF-8.
+///
+/// This only applies to property values; attributes which are not valid UTF-8 are always ignored
+/// with a warning.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum NonUtf8Payloads {
+    /// Log a warning and ignore the value.
+    #[default]
+    Ignore,
+    /// Replace any invalid UTF-8 sequences with U+FFFD REPLACEMENT CHARACTER, and then handle the
+    /// value as normal.
+    Lossy,
+    /// Send an `Event::BinaryPropertyValue` with the raw bytes of the value.
+    Binary,
+}
+
 impl Event {
-    fn device_updated(device: &Device) -> Self {
+    fn device_updated(device: &Device, fresh: bool) -> Self {
         Event::DeviceUpdated {
             device_id: device.id.to_owned(),
             has_required_attributes: device.has_required_attributes(),
+            fresh,
         }
     }
 
-    fn node_updated(device_id: &str, node: &Node) -> Self {
+    fn node_updated(device_id: &str, node: &Node, fresh: bool) -> Self {
         Event::NodeUpdated {
             device_id: device_id.to_owned(),
             node_id: node.id.to_owned(),
             has_required_attributes: node.has_required_attributes(),
+            fresh,
         }
     }
 
-    fn property_updated(device_id: &str, node_id: &str, property: &Property) -> Self {
+    fn property_updated(device_id: &str, node_id: &str, property: &Property, fresh: bool) -> Self {
         Event::PropertyUpdated {
             device_id: device_id.to_owned(),
             node_id: node_id.to_owned(),
             property_id: property.id.to_owned(),
             has_required_attributes: property.has_required_attributes(),
+            fresh,
+        }
+    }
+
+    /// If this is a `PropertyValueChanged` event, deserialize the new value from JSON as the given
+    /// type. This doesn't check the datatype of the property; use
+    /// [`Property::value_json`](crate::Property::value_json) for that.
+    ///
+    /// Returns `None` for other kinds of event, or `InvalidJson` if the value can't be deserialized
+    /// as the given type.
+    #[cfg(feature = "json")]
+    pub fn value_json<T: serde::de::DeserializeOwned>(&self) -> Option<Result<T, ValueError>> {
+        match self {
+            Event::PropertyValueChanged { value, .. } => Some(values::from_json(value)),
+            _ => None,
         }
     }
 
@@ -107,17 +312,96 @@ impl Event {
     }
 }
 
+type EventHandler = Arc<dyn Fn(Event) -> BoxFuture<'static, ()> + Send + Sync>;
+
 /// A Homie controller, which connects to an MQTT broker and interacts with Homie devices.
-#[derive(Debug)]
 pub struct HomieController {
     mqtt_client: AsyncClient,
     base_topic: String,
-    /// The set of Homie devices which have been discovered so far, keyed by their IDs.
-    // TODO: Consider using Mutex<im::HashMap<...>> instead.
-    devices: Mutex<Arc<HashMap<String, Device>>>,
+    /// A snapshot of the set of Homie devices which have been discovered so far, keyed by their
+    /// IDs. This can be loaded without waiting for any lock.
+    devices: ArcSwap<Devices>,
+    /// The set of Homie devices, for updating. Holding this lock serialises updates, each of which
+    /// is then stored as the new snapshot in `devices`.
+    devices_writer: Mutex<Devices>,
     /// temporarily holds retained property payloads that were received before their nodes'
     /// $properties. The stored payloads are consumed when $properties is received.
     early_property_values: Mutex<HashMap<String, String>>,
+    /// Properties whose `$timestamp` has been received since their last value, so that it applies
+    /// to the next value rather than being cleared by it.
+    pending_timestamps: Mutex<HashSet<String>>,
+    /// How to handle property values which are not valid UTF-8.
+    non_utf8_payloads: NonUtf8Payloads,
+    /// Whether to report non-conformant messages as `Event::ValidationError` rather than just
+    /// logging them.
+    strict: bool,
+    /// Handlers registered with `on_event`, to which `run` dispatches events.
+    handlers: Mutex<Vec<EventHandler>>,
+    /// Whether `run` should call the handlers for each event concurrently rather than one after
+    /// another.
+    parallel_handlers: bool,
+    /// Counts of messages received from each device, if enabled.
+    metrics: Option<Mutex<Metrics>>,
+    /// Where to record received messages, if anywhere.
+    recorder: Option<Mutex<Recorder>>,
+    /// How to keep recent values of numeric properties, if enabled.
+    rate_of_change: Option<RateOfChangeConfig>,
+    /// After how many missed stats intervals a device should be presumed lost, if at all.
+    presume_lost_after: Option<u32>,
+    /// The state which each device that has been presumed lost was in before, to restore if it
+    /// starts sending stats again.
+    presumed_lost: Mutex<HashMap<String, State>>,
+    /// Named groups of devices, such as rooms.
+    groups: Groups,
+    /// Commands waiting for the next fresh value of a property.
+    value_waiters: Mutex<Vec<ValueWaiter>>,
+    /// Callers waiting for the devices to reach some condition.
+    condition_waiters: Mutex<Vec<ConditionWaiter>>,
+    /// Changes to subscriptions which have yet to be sent to the MQTT client. Each is only removed
+    /// once it has been sent, so that none are lost if the future sending them is cancelled.
+    pending_subscriptions: Mutex<VecDeque<SubscriptionChange>>,
+    /// Held while sending `pending_subscriptions`, so that each is sent once and in order.
+    subscriptions_lock: futures::lock::Mutex<()>,
+    /// Events from a message which has been handled but not yet returned, because handling it was
+    /// cancelled while updating subscriptions. These are returned by the next poll.
+    pending_events: Mutex<Vec<Event>>,
+    /// Whether `shutdown` has been called.
+    shut_down: AtomicBool,
+    /// Whether `pause_values` has been called without a matching `resume_values`, so that the
+    /// controller subscribes to node attributes individually rather than with a wildcard which
+    /// would also match property values.
+    values_paused: AtomicBool,
+}
+
+impl Debug for HomieController {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("HomieController")
+            .field("mqtt_client", &self.mqtt_client)
+            .field("base_topic", &self.base_topic)
+            .field("devices", &self.devices.load())
+            .field("early_property_values", &self.early_property_values)
+            .field("pending_timestamps", &self.pending_timestamps)
+            .field("non_utf8_payloads", &self.non_utf8_payloads)
+            .field("strict", &self.strict)
+            .field("handlers", &self.handlers.lock().unwrap().len())
+            .field("parallel_handlers", &self.parallel_handlers)
+            .field("metrics", &self.metrics)
+            .field("recorder", &self.recorder)
+            .field("rate_of_change", &self.rate_of_change)
+            .field("presume_lost_after", &self.presume_lost_after)
+            .field("presumed_lost", &self.presumed_lost)
+            .field("groups", &self.groups)
+            .field("value_waiters", &self.value_waiters.lock().unwrap().len())
+            .field(
+                "condition_waiters",
+                &self.condition_waiters.lock().unwrap().len(),
+            )
+            .field("pending_subscriptions", &self.pending_subscriptions)
+            .field("pending_events", &self.pending_events)
+            .field("shut_down", &self.shut_down)
+            .field("values_paused", &self.values_paused)
+            .finish()
+    }
 }
 
 pub struct HomieEventLoop {
@@ -130,6 +414,28 @@ impl HomieEventLoop {
     }
 }
 
+/// A command waiting for the next fresh value of a property.
+struct ValueWaiter {
+    device_id: String,
+    node_id: String,
+    property_id: String,
+    sender: oneshot::Sender<String>,
+}
+
+/// A caller waiting for the devices to reach some condition, such as a device being ready.
+struct ConditionWaiter {
+    condition: Box<dyn Fn(&Devices) -> bool + Send>,
+    /// Sent the first snapshot of the devices which meets the condition.
+    sender: oneshot::Sender<Arc<Devices>>,
+}
+
+/// A subscription or unsubscription waiting to be sent to the MQTT client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum SubscriptionChange {
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
 /// Internal struct for the return value of HomieController::handle_publish_sync()
 struct PublishResponse {
     events: Vec<Event>,
@@ -146,19 +452,254 @@ impl HomieController {
     /// * `mqtt_options`: Options for the MQTT connection, including which broker to connect to.
     pub fn new(mqtt_options: MqttOptions, base_topic: &str) -> (HomieController, HomieEventLoop) {
         let (mqtt_client, event_loop) = AsyncClient::new(mqtt_options, REQUESTS_CAP);
-        let controller = HomieController {
+        let controller = HomieController::with_client(mqtt_client, base_topic);
+        (controller, HomieEventLoop::new(event_loop))
+    }
+
+    /// Create a set of controllers for several Homie base topics, which share a single connection
+    /// to an MQTT broker.
+    ///
+    /// # Arguments
+    /// * `base_topics`: The Homie [base topics](https://homieiot.github.io/specification/#base-topic)
+    ///   under which to look for Homie devices.
+    /// * `mqtt_options`: Options for the MQTT connection, including which broker to connect to.
+    pub fn new_multi(
+        mqtt_options: MqttOptions,
+        base_topics: &[&str],
+    ) -> (MultiHomieController, HomieEventLoop) {
+        let (mqtt_client, event_loop) = AsyncClient::new(mqtt_options, REQUESTS_CAP);
+        let controller = MultiHomieController::with_client(mqtt_client, base_topics);
+        (controller, HomieEventLoop::new(event_loop))
+    }
+
+    /// Create a new `HomieController` which uses the given MQTT client.
+    fn with_client(mqtt_client: AsyncClient, base_topic: &str) -> HomieController {
+        HomieController {
             mqtt_client,
             base_topic: base_topic.to_string(),
-            devices: Mutex::new(Arc::new(HashMap::new())),
+            devices: ArcSwap::from_pointee(Devices::new()),
+            devices_writer: Mutex::new(Devices::new()),
             early_property_values: Mutex::new(HashMap::new()),
-        };
-        (controller, HomieEventLoop::new(event_loop))
+            pending_timestamps: Mutex::new(HashSet::new()),
+            non_utf8_payloads: NonUtf8Payloads::default(),
+            strict: false,
+            handlers: Mutex::new(vec![]),
+            parallel_handlers: false,
+            metrics: None,
+            recorder: None,
+            rate_of_change: None,
+            presume_lost_after: None,
+            presumed_lost: Mutex::new(HashMap::new()),
+            groups: Groups::default(),
+            value_waiters: Mutex::new(vec![]),
+            condition_waiters: Mutex::new(vec![]),
+            pending_subscriptions: Mutex::new(VecDeque::new()),
+            subscriptions_lock: futures::lock::Mutex::new(()),
+            pending_events: Mutex::new(vec![]),
+            shut_down: AtomicBool::new(false),
+            values_paused: AtomicBool::new(false),
+        }
+    }
+
+    /// Configure how property values which are not valid UTF-8 should be handled. By default they
+    /// are ignored with a warning.
+    pub fn set_non_utf8_payloads(&mut self, non_utf8_payloads: NonUtf8Payloads) {
+        self.non_utf8_payloads = non_utf8_payloads;
+    }
+
+    /// Configure whether the controller is in strict mode. By default it is lenient, and messages
+    /// which don't conform to the Homie convention are logged and ignored where possible.
+    ///
+    /// In strict mode, malformed attributes, invalid IDs and unexpected topics are also reported as
+    /// `Event::ValidationError`, and recorded in the `validation_errors` of the offending device.
+    /// This is useful for testing whether device firmware conforms to the convention.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Register a handler to be called with each event when the controller is driven by
+    /// [`run`](Self::run). Any number of handlers may be registered, and each is called with every
+    /// event in the order they were registered.
+    ///
+    /// If a handler panics, the panic is logged and the other handlers and later events are not
+    /// affected.
+    pub fn on_event<F, Fut>(&self, handler: F)
+    where
+        F: Fn(Event) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.handlers
+            .lock()
+            .unwrap()
+            .push(Arc::new(move |event| handler(event).boxed()));
+    }
+
+    /// Configure whether [`run`](Self::run) calls the handlers for each event concurrently. By
+    /// default each handler must finish before the next is called. Either way, all handlers finish
+    /// with one event before any is called with the next.
+    pub fn set_parallel_handlers(&mut self, parallel_handlers: bool) {
+        self.parallel_handlers = parallel_handlers;
+    }
+
+    /// Configure whether to count the messages and bytes received from each device, to help
+    /// identify devices which are flooding the MQTT broker. This is disabled by default. Enabling
+    /// or reconfiguring it resets the counts.
+    pub fn set_metrics(&mut self, config: Option<MetricsConfig>) {
+        self.metrics = config.map(|config| Mutex::new(Metrics::new(config, Instant::now())));
+    }
+
+    /// Record every message received from the MQTT broker with the given recorder, so that it can be
+    /// replayed later with a [`Replayer`](replay::Replayer). Pass `None` to stop recording.
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.recorder = recorder.map(Mutex::new);
+    }
+
+    /// Configure whether to keep recent values of integer and float properties, so that their rate
+    /// of change can be computed with [`Property::rate_of_change`]. This is disabled by default.
+    /// Changing it doesn't affect values which have already been kept.
+    pub fn set_rate_of_change(&mut self, config: Option<RateOfChangeConfig>) {
+        self.rate_of_change = config;
+    }
+
+    /// Configure whether to presume that devices have been lost if they stop sending
+    /// `$stats/uptime` for the given number of their `$stats/interval`s, even if their last will
+    /// was never published. This is disabled by default.
+    ///
+    /// Such devices have their state changed to [`State::PresumedLost`], with an
+    /// `Event::DeviceUpdated`. Their previous state is restored when they send stats again, or
+    /// replaced if they publish a new `$state`. Devices which are sleeping or haven't sent both
+    /// stats attributes are never presumed lost.
+    ///
+    /// This is checked whenever the controller handles anything from the MQTT broker, which
+    /// includes the keep-alive pings, so devices may be presumed lost up to a keep-alive interval
+    /// late.
+    pub fn set_presume_lost_after(&mut self, missed_intervals: Option<u32>) {
+        self.presume_lost_after = missed_intervals;
+    }
+
+    /// Configure named groups of devices, such as the rooms they are in, so that they can be
+    /// addressed together with [`group`](Self::group). Each group contains the devices which match
+    /// any of its matchers. This replaces any groups configured before.
+    pub fn set_groups(&mut self, groups: HashMap<String, Vec<DeviceMatcher>>) {
+        self.groups.matchers = groups;
+    }
+
+    /// Configure a key of [meta extension](https://github.com/homieiot/convention/blob/develop/extensions/documents/homie_meta_extension.md)
+    /// tags from which to derive further groups, such as `room`. Each device with a tag with this
+    /// key is then also in the group named by its value. Pass `None` to stop deriving groups.
+    pub fn set_group_meta_key(&mut self, key: Option<&str>) {
+        self.groups.meta_key = key.map(ToOwned::to_owned);
+    }
+
+    /// Get the names of all configured groups, and of groups derived from meta tags of the devices
+    /// which have been discovered so far, in order.
+    pub fn group_names(&self) -> Vec<String> {
+        self.groups.names(&self.devices.load())
+    }
+
+    /// Get a snapshot of the devices currently in the group with the given name, with helpers to
+    /// aggregate their property values. The group is empty if there is no such group.
+    pub fn group(&self, name: &str) -> DeviceGroup {
+        self.groups.group(name, &self.devices.load())
+    }
+
+    /// Get a snapshot of the devices currently in the group with the given name, in order of their
+    /// IDs.
+    pub fn devices_in_group(&self, name: &str) -> Vec<Arc<Device>> {
+        self.group(name).devices
+    }
+
+    /// Get the number of messages received from each device within the metrics window, keyed by
+    /// device ID. Devices which haven't sent any messages within the window are not included.
+    ///
+    /// This is empty unless metrics are enabled with [`set_metrics`](Self::set_metrics).
+    pub fn device_message_rates(&self) -> HashMap<String, MessageRate> {
+        match &self.metrics {
+            Some(metrics) => metrics.lock().unwrap().device_rates(Instant::now()),
+            None => HashMap::new(),
+        }
+    }
+
+    /// Get the number of values received for each property within the metrics window, keyed by
+    /// device ID, node ID and property ID. Properties which haven't had any values within the
+    /// window are not included.
+    ///
+    /// This is empty unless metrics are enabled with [`set_metrics`](Self::set_metrics) with
+    /// `per_property` set.
+    pub fn property_message_rates(&self) -> HashMap<(String, String, String), MessageRate> {
+        match &self.metrics {
+            Some(metrics) => metrics.lock().unwrap().property_rates(Instant::now()),
+            None => HashMap::new(),
+        }
     }
 
     /// Get a snapshot of the set of Homie devices which have been discovered so far, keyed by their
     /// IDs.
-    pub fn devices(&self) -> Arc<HashMap<String, Device>> {
-        self.devices.lock().unwrap().clone()
+    ///
+    /// This doesn't wait for any lock, so it is cheap to call even while messages are being
+    /// handled.
+    pub fn devices(&self) -> Arc<Devices> {
+        self.devices.load_full()
+    }
+
+    /// Call the given function with the current snapshot of the devices, and return its result.
+    ///
+    /// Unlike [`devices`](Self::devices) this doesn't touch the snapshot's reference count, so it
+    /// is cheaper in hot paths such as handling every value update. The function should be quick,
+    /// as the snapshot can't be freed while it runs.
+    pub fn with_devices<R>(&self, f: impl FnOnce(&Devices) -> R) -> R {
+        f(&self.devices.load())
+    }
+
+    /// Call the given function with the device with the given ID, if it is known, and return its
+    /// result.
+    pub fn with_device<R>(&self, device_id: &str, f: impl FnOnce(&Device) -> R) -> Option<R> {
+        self.with_devices(|devices| devices.get(device_id).map(|device| f(device)))
+    }
+
+    /// Call the given function with the given node, if it is known, and return its result.
+    pub fn with_node<R>(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        f: impl FnOnce(&Node) -> R,
+    ) -> Option<R> {
+        self.with_device(device_id, |device| device.nodes.get(node_id).map(f))
+            .flatten()
+    }
+
+    /// Call the given function with the given property, if it is known, and return its result.
+    ///
+    /// For example, to get the current value of a property without cloning any devices:
+    ///
+    /// ```
+    /// # use homie_controller::HomieController;
+    /// # fn example(controller: &HomieController) -> Option<f64> {
+    /// controller
+    ///     .with_property("device", "node", "temperature", |property| property.value().ok())
+    ///     .flatten()
+    /// # }
+    /// ```
+    pub fn with_property<R>(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        property_id: &str,
+        f: impl FnOnce(&Property) -> R,
+    ) -> Option<R> {
+        self.with_node(device_id, node_id, |node| {
+            node.properties.get(property_id).map(f)
+        })
+        .flatten()
+    }
+
+    /// Lock the devices for updating. The updated devices are stored as the new snapshot when the
+    /// returned guard is dropped.
+    fn write_devices(&self) -> DevicesWriteGuard<'_> {
+        DevicesWriteGuard {
+            devices: self.devices_writer.lock().unwrap(),
+            snapshot: &self.devices,
+        }
     }
 
     /// Get the Homie base topic which the controller was configured to use.
@@ -167,7 +708,20 @@ impl HomieController {
     }
 
     /// Poll the `EventLoop`, and maybe return a Homie event.
+    ///
+    /// This is cancellation safe: if the returned future is dropped before it completes, such as
+    /// when it is used in a `select!`, then no events are lost and the controller's subscriptions
+    /// stay consistent with its devices. Handling of any message which was interrupted is finished
+    /// by the next call.
+    ///
+    /// Once the controller has been [shut down](Self::shutdown) this returns
+    /// [`PollError::Shutdown`].
     pub async fn poll(&self, event_loop: &mut HomieEventLoop) -> Result<Vec<Event>, PollError> {
+        let interrupted = self.resume_interrupted().await?;
+        if !interrupted.is_empty() {
+            return Ok(interrupted);
+        }
+
         let notification = event_loop.event_loop.poll().await?;
         log::trace!("Notification = {:?}", notification);
 
@@ -178,24 +732,219 @@ impl HomieController {
         }
     }
 
+    /// Repeatedly poll the `EventLoop`, and call the handlers registered with
+    /// [`on_event`](Self::on_event) with each event.
+    ///
+    /// This only returns if there is an error polling. It can then be called again to reconnect.
+    pub async fn run(&self, event_loop: &mut HomieEventLoop) -> Result<(), PollError> {
+        loop {
+            for event in self.poll(event_loop).await? {
+                self.dispatch(event).await;
+            }
+        }
+    }
+
+    /// Call all registered handlers with the given event, catching any panics.
+    async fn dispatch(&self, event: Event) {
+        let handlers = self.handlers.lock().unwrap().clone();
+        let calls = handlers.into_iter().map(|handler| {
+            let event = event.clone();
+            async move {
+                let result = match panic::catch_unwind(AssertUnwindSafe(|| handler(event))) {
+                    Ok(future) => AssertUnwindSafe(future).catch_unwind().await,
+                    Err(panic) => Err(panic),
+                };
+                if result.is_err() {
+                    log::error!("Event handler panicked");
+                }
+            }
+        });
+        if self.parallel_handlers {
+            join_all(calls).await;
+        } else {
+            for call in calls {
+                call.await;
+            }
+        }
+    }
+
     async fn handle_event(&self, incoming: Incoming) -> Result<Vec<Event>, PollError> {
+        let mut events = self.handle_incoming(incoming).await?;
+        events.extend(self.presume_lost_devices(SystemTime::now()));
+        self.notify_value_waiters(&events);
+        self.notify_condition_waiters();
+        Ok(events)
+    }
+
+    /// Finish handling a message whose handling was interrupted by a previous poll being
+    /// cancelled, returning its events if there are any.
+    ///
+    /// Returns [`PollError::Shutdown`] if the controller has been shut down.
+    pub(crate) async fn resume_interrupted(&self) -> Result<Vec<Event>, PollError> {
+        if self.shut_down.load(Ordering::SeqCst) {
+            return Err(PollError::Shutdown);
+        }
+        self.flush_subscriptions().await?;
+        let events = std::mem::take(&mut *self.pending_events.lock().unwrap());
+        if !events.is_empty() {
+            self.notify_value_waiters(&events);
+            self.notify_condition_waiters();
+        }
+        Ok(events)
+    }
+
+    /// Keep the given events until subscriptions have been updated, so that they are returned by
+    /// the next poll if the current one is cancelled.
+    fn stash_events(&self, events: Vec<Event>) {
+        self.pending_events.lock().unwrap().extend(events);
+    }
+
+    /// Take the events kept by `stash_events`, now that subscriptions have been updated.
+    fn take_stashed_events(&self) -> Vec<Event> {
+        std::mem::take(&mut *self.pending_events.lock().unwrap())
+    }
+
+    /// Send any fresh property values among the given events to the commands waiting for them, and
+    /// drop any waiters whose command has been cancelled.
+    fn notify_value_waiters(&self, events: &[Event]) {
+        let mut waiters = self.value_waiters.lock().unwrap();
+        if waiters.is_empty() {
+            return;
+        }
+        for event in events {
+            if let Event::PropertyValueChanged {
+                device_id,
+                node_id,
+                property_id,
+                value,
+                fresh: true,
+            } = event
+            {
+                for waiter in std::mem::take(&mut *waiters) {
+                    if waiter.device_id == *device_id
+                        && waiter.node_id == *node_id
+                        && waiter.property_id == *property_id
+                    {
+                        // The command may have been cancelled meanwhile, in which case there is
+                        // nobody to send to.
+                        let _ = waiter.sender.send(value.to_owned());
+                    } else {
+                        waiters.push(waiter);
+                    }
+                }
+            }
+        }
+        waiters.retain(|waiter| !waiter.sender.is_canceled());
+    }
+
+    /// Send the current snapshot of the devices to any waiters whose condition it meets, and drop
+    /// any waiters which have been cancelled.
+    fn notify_condition_waiters(&self) {
+        let mut waiters = self.condition_waiters.lock().unwrap();
+        if waiters.is_empty() {
+            return;
+        }
+        let devices = self.devices.load_full();
+        for waiter in std::mem::take(&mut *waiters) {
+            if waiter.sender.is_canceled() {
+                continue;
+            }
+            if (waiter.condition)(&devices) {
+                // The waiter may have timed out meanwhile, in which case there is nobody to send
+                // to.
+                let _ = waiter.sender.send(devices.clone());
+            } else {
+                waiters.push(waiter);
+            }
+        }
+    }
+
+    /// Change the state of any devices which have missed too many stats intervals to
+    /// `State::PresumedLost`, if enabled.
+    fn presume_lost_devices(&self, now: SystemTime) -> Vec<Event> {
+        let missed_intervals = match self.presume_lost_after {
+            Some(missed_intervals) => missed_intervals,
+            None => return vec![],
+        };
+        let is_lost = |device: &Device| {
+            matches!(device.state, State::Init | State::Ready | State::Alert)
+                && match (device.stats_interval, device.stats_received) {
+                    // A device may send an interval so large that it would overflow, in which case
+                    // it can never be presumed lost.
+                    (Some(interval), Some(received)) => {
+                        match (
+                            interval.checked_mul(missed_intervals),
+                            now.duration_since(received),
+                        ) {
+                            (Some(timeout), Ok(elapsed)) => elapsed > timeout,
+                            _ => false,
+                        }
+                    }
+                    _ => false,
+                }
+        };
+        // Check the snapshot first, to avoid storing a new one when nothing has changed.
+        if !self.devices.load().values().any(|device| is_lost(device)) {
+            return vec![];
+        }
+
+        let mut devices = self.write_devices();
+        let mut presumed_lost = self.presumed_lost.lock().unwrap();
+        let mut events = vec![];
+        for (device_id, device) in devices.iter_mut() {
+            if is_lost(device) {
+                log::warn!("Presuming device {} has been lost", device_id);
+                let device = Arc::make_mut(device);
+                presumed_lost.insert(device_id.to_owned(), device.state);
+                device.state = State::PresumedLost;
+                events.push(Event::device_updated(device, true));
+            }
+        }
+        events
+    }
+
+    async fn handle_incoming(&self, incoming: Incoming) -> Result<Vec<Event>, PollError> {
         match incoming {
-            Incoming::Publish(publish) => match self.handle_publish(publish).await {
-                Err(HandleError::Warning(err)) => {
-                    // These error strings indicate some issue with parsing the publish
-                    // event from the network, perhaps due to a malfunctioning device,
-                    // so should just be logged and ignored.
-                    log::warn!("{}", err);
-                    Ok(vec![])
+            Incoming::Publish(publish) => {
+                if let Some(recorder) = &self.recorder {
+                    if let Err(e) = recorder.lock().unwrap().record(&publish) {
+                        log::error!("Failed to record {:?}: {}", publish, e);
+                    }
                 }
-                Err(HandleError::Fatal(e)) => Err(e.into()),
-                Ok(events) => Ok(events),
-            },
+                self.record_metrics(&publish);
+                let topic = self.strict.then(|| publish.topic.clone());
+                match self.handle_publish(publish).await {
+                    Err(HandleError::Warning(err)) => {
+                        // These error strings indicate some issue with parsing the publish
+                        // event from the network, perhaps due to a malfunctioning device,
+                        // so should just be logged and ignored, unless we are in strict mode.
+                        log::warn!("{}", err);
+                        if let Some(topic) = topic {
+                            let device_id = topic
+                                .strip_prefix(&self.base_topic)
+                                .and_then(|subtopic| subtopic.strip_prefix('/'))
+                                .and_then(|subtopic| subtopic.split('/').next())
+                                .unwrap_or_default();
+                            Ok(vec![validation_error(
+                                &mut self.write_devices(),
+                                device_id,
+                                &topic,
+                                err,
+                            )])
+                        } else {
+                            Ok(vec![])
+                        }
+                    }
+                    Err(HandleError::Fatal(e)) => Err(e.into()),
+                    Ok(events) => Ok(events),
+                }
+            }
             Incoming::ConnAck(_) => {
                 // We have connected or reconnected, so make our initial subscription to start
                 // discovering Homie devices.
+                self.stash_events(vec![Event::Connected]);
                 self.start().await?;
-                Ok(vec![Event::Connected])
+                Ok(self.take_stashed_events())
             }
             _ => Ok(vec![]),
         }
@@ -211,57 +960,169 @@ impl HomieController {
             topics_to_unsubscribe,
         } = self.handle_publish_sync(publish)?;
 
-        for topic in topics_to_subscribe {
-            log::trace!("Subscribe to {}", topic);
-            self.mqtt_client.subscribe(topic, QoS::AtLeastOnce).await?;
+        self.stash_events(events);
+        self.update_subscriptions(topics_to_subscribe, topics_to_unsubscribe)
+            .await?;
+
+        Ok(self.take_stashed_events())
+    }
+
+    /// Count the given message towards the metrics for the device which sent it, if enabled.
+    fn record_metrics(&self, publish: &Publish) {
+        if let Some(metrics) = &self.metrics {
+            if let Some(subtopic) = publish
+                .topic
+                .strip_prefix(&self.base_topic)
+                .and_then(|subtopic| subtopic.strip_prefix('/'))
+            {
+                let device_id = subtopic.split('/').next().unwrap_or_default();
+                if !device_id.starts_with('$') {
+                    metrics.lock().unwrap().record(
+                        Instant::now(),
+                        device_id,
+                        property_value_topic(subtopic),
+                        publish.payload.len(),
+                    );
+                }
+            }
         }
-        for topic in topics_to_unsubscribe {
-            log::trace!("Unsubscribe from {}", topic);
-            self.mqtt_client.unsubscribe(topic).await?;
+    }
+
+    /// Subscribe to and unsubscribe from the given topics.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(subscribe = topics_to_subscribe.len(), unsubscribe = topics_to_unsubscribe.len())
+        )
+    )]
+    async fn update_subscriptions(
+        &self,
+        topics_to_subscribe: Vec<String>,
+        topics_to_unsubscribe: Vec<String>,
+    ) -> Result<(), ClientError> {
+        {
+            let mut pending = self.pending_subscriptions.lock().unwrap();
+            pending.extend(
+                topics_to_subscribe
+                    .into_iter()
+                    .map(SubscriptionChange::Subscribe),
+            );
+            pending.extend(
+                topics_to_unsubscribe
+                    .into_iter()
+                    .map(SubscriptionChange::Unsubscribe),
+            );
         }
+        self.flush_subscriptions().await
+    }
 
-        Ok(events)
+    /// Send all pending subscription changes to the MQTT client, in order.
+    async fn flush_subscriptions(&self) -> Result<(), ClientError> {
+        let _guard = self.subscriptions_lock.lock().await;
+        loop {
+            let change = match self.pending_subscriptions.lock().unwrap().front() {
+                Some(change) => change.clone(),
+                None => return Ok(()),
+            };
+            match change {
+                SubscriptionChange::Subscribe(topic) => {
+                    log::trace!("Subscribe to {}", topic);
+                    self.mqtt_client.subscribe(topic, QoS::AtLeastOnce).await?;
+                }
+                SubscriptionChange::Unsubscribe(topic) => {
+                    log::trace!("Unsubscribe from {}", topic);
+                    self.mqtt_client.unsubscribe(topic).await?;
+                }
+            }
+            // Only remove the change now that it has been sent, in case this is cancelled.
+            self.pending_subscriptions.lock().unwrap().pop_front();
+        }
     }
 
     /// Handle a publish event, update the devices, and return any event and any new topics which
     /// should be subscribed to or unsubscribed from.
     ///
-    /// This is separate from `handle_publish` because it takes the `devices` lock, to ensure that
-    /// no async operations are awaited while the lock is held.
+    /// This is separate from `handle_publish` because it takes the `devices_writer` lock, to ensure
+    /// that no async operations are awaited while the lock is held.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(topic = %publish.topic, device_id, node_id, property_id)
+        )
+    )]
     fn handle_publish_sync(&self, publish: Publish) -> Result<PublishResponse, HandleError> {
-        let base_topic = format!("{}/", self.base_topic);
-        let payload = str::from_utf8(&publish.payload)
-            .map_err(|e| format!("Payload not valid UTF-8: {}", e))?;
         let subtopic = publish
             .topic
-            .strip_prefix(&base_topic)
+            .strip_prefix(self.base_topic.as_str())
+            .and_then(|subtopic| subtopic.strip_prefix('/'))
             .ok_or_else(|| format!("Publish with unexpected topic: {:?}", publish))?;
+        let payload = match str::from_utf8(&publish.payload) {
+            Ok(payload) => Cow::Borrowed(payload),
+            Err(e) => match (self.non_utf8_payloads, property_value_topic(subtopic)) {
+                (NonUtf8Payloads::Lossy, Some(_)) => String::from_utf8_lossy(&publish.payload),
+                (NonUtf8Payloads::Binary, Some([device_id, node_id, property_id])) => {
+                    return self.handle_binary_property_value(
+                        device_id,
+                        node_id,
+                        property_id,
+                        &publish,
+                    );
+                }
+                _ => return Err(format!("Payload not valid UTF-8: {}", e).into()),
+            },
+        };
+        let payload = payload.as_ref();
+        let fresh = !publish.retain;
 
-        // If there are no other references to the devices this will give us a mutable reference
-        // directly. If there are other references it will clone the underlying HashMap and update
-        // our Arc to point to that, so that it is now a unique reference.
-        let devices = &mut *self.devices.lock().unwrap();
-        let devices = Arc::make_mut(devices);
+        // Any snapshots returned by `devices()` share structure with this, so only the parts which
+        // are modified are copied.
+        let devices = &mut *self.write_devices();
 
         let early_property_values = &mut *self.early_property_values.lock().unwrap();
+        let pending_timestamps = &mut *self.pending_timestamps.lock().unwrap();
 
         // Collect MQTT topics to which we need to subscribe or unsubscribe here, so that the
         // subscription can happen after the devices lock has been released.
         let mut topics_to_subscribe: Vec<String> = vec![];
         let mut topics_to_unsubscribe: Vec<String> = vec![];
+        // Problems with IDs to report in strict mode.
+        let mut invalid_ids: Vec<String> = vec![];
+        // IDs which have been dropped from lists, which are always logged.
+        let mut ignored_ids: Vec<String> = vec![];
 
-        let parts = subtopic.split('/').collect::<Vec<&str>>();
-        let events = match parts.as_slice() {
+        let mut parts_buffer = [""; MAX_TOPIC_PARTS];
+        let parts = split_subtopic(subtopic, &mut parts_buffer);
+        #[cfg(feature = "tracing")]
+        record_ids(parts);
+        let mut events = match parts {
             [device_id, "$homie"] => {
-                if !devices.contains_key(*device_id) {
+                // An empty payload is the device being deleted, such as by `purge_device`, not a
+                // new device.
+                if !payload.is_empty() && !devices.contains_key(*device_id) {
+                    if !is_valid_id(device_id) {
+                        invalid_ids.push(format!("Invalid device ID '{}'", device_id));
+                    }
                     log::trace!("Homie device '{}' version '{}'", device_id, payload);
-                    devices.insert((*device_id).to_owned(), Device::new(device_id, payload));
+                    devices.insert(
+                        (*device_id).to_owned(),
+                        Arc::new(Device::new(device_id, payload)),
+                    );
                     topics_to_subscribe.push(format!("{}/{}/+", self.base_topic, device_id));
                     topics_to_subscribe.push(format!("{}/{}/$fw/+", self.base_topic, device_id));
                     topics_to_subscribe.push(format!("{}/{}/$stats/+", self.base_topic, device_id));
+                    topics_to_subscribe.push(format!(
+                        "{}/{}/$implementation/#",
+                        self.base_topic, device_id
+                    ));
+                    topics_to_subscribe.push(format!("{}/{}/$meta/#", self.base_topic, device_id));
                     vec![Event::DeviceUpdated {
                         device_id: (*device_id).to_owned(),
                         has_required_attributes: false,
+                        fresh,
                     }]
                 } else {
                     vec![]
@@ -269,47 +1130,45 @@ impl HomieController {
             }
             [device_id, "$name"] => {
                 let device = get_mut_device_for(devices, "Got name for", device_id)?;
-                device.name = Some(payload.to_owned());
-                vec![Event::device_updated(device)]
+                set_string(&mut device.name, payload);
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$state"] => {
                 let state = payload.parse()?;
                 let device = get_mut_device_for(devices, "Got state for", device_id)?;
                 device.state = state;
-                vec![Event::device_updated(device)]
+                self.presumed_lost.lock().unwrap().remove(*device_id);
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$implementation"] => {
                 let device = get_mut_device_for(devices, "Got implementation for", device_id)?;
-                device.implementation = Some(payload.to_owned());
-                vec![Event::device_updated(device)]
+                set_string(&mut device.implementation, payload);
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$extensions"] => {
                 let device = get_mut_device_for(devices, "Got extensions for", device_id)?;
-                device.extensions = payload
-                    .split(',')
-                    .map(|part| part.parse())
-                    .collect::<Result<Vec<_>, _>>()?;
-                vec![Event::device_updated(device)]
+                device.extensions = Extension::parse_list(payload);
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$localip"] => {
                 let device = get_mut_device_for(devices, "Got localip for", device_id)?;
-                device.local_ip = Some(payload.to_owned());
-                vec![Event::device_updated(device)]
+                set_string(&mut device.local_ip, payload);
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$mac"] => {
                 let device = get_mut_device_for(devices, "Got mac for", device_id)?;
-                device.mac = Some(payload.to_owned());
-                vec![Event::device_updated(device)]
+                set_string(&mut device.mac, payload);
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$fw", "name"] => {
                 let device = get_mut_device_for(devices, "Got fw/name for", device_id)?;
-                device.firmware_name = Some(payload.to_owned());
-                vec![Event::device_updated(device)]
+                set_string(&mut device.firmware_name, payload);
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$fw", "version"] => {
                 let device = get_mut_device_for(devices, "Got fw/version for", device_id)?;
-                device.firmware_version = Some(payload.to_owned());
-                vec![Event::device_updated(device)]
+                set_string(&mut device.firmware_version, payload);
+                vec![Event::device_updated(device, fresh)]
             }
             [_device_id, "$stats"] => {
                 // Homie 3.0 list of available stats. We don't need this, so ignore it without
@@ -320,52 +1179,56 @@ impl HomieController {
                 let interval = payload.parse()?;
                 let device = get_mut_device_for(devices, "Got stats/interval for", device_id)?;
                 device.stats_interval = Some(Duration::from_secs(interval));
-                vec![Event::device_updated(device)]
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$stats", "uptime"] => {
                 let uptime = payload.parse()?;
                 let device = get_mut_device_for(devices, "Got stats/uptime for", device_id)?;
                 device.stats_uptime = Some(Duration::from_secs(uptime));
-                vec![Event::device_updated(device)]
+                device.stats_received = Some(SystemTime::now());
+                if let Some(state) = self.presumed_lost.lock().unwrap().remove(*device_id) {
+                    device.state = state;
+                }
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$stats", "signal"] => {
                 let signal = payload.parse()?;
                 let device = get_mut_device_for(devices, "Got stats/signal for", device_id)?;
                 device.stats_signal = Some(signal);
-                vec![Event::device_updated(device)]
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$stats", "cputemp"] => {
                 let cputemp = payload.parse()?;
                 let device = get_mut_device_for(devices, "Got stats/cputemp for", device_id)?;
                 device.stats_cputemp = Some(cputemp);
-                vec![Event::device_updated(device)]
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$stats", "cpuload"] => {
                 let cpuload = payload.parse()?;
                 let device = get_mut_device_for(devices, "Got stats/cpuload for", device_id)?;
                 device.stats_cpuload = Some(cpuload);
-                vec![Event::device_updated(device)]
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$stats", "battery"] => {
                 let battery = payload.parse()?;
                 let device = get_mut_device_for(devices, "Got stats/battery for", device_id)?;
                 device.stats_battery = Some(battery);
-                vec![Event::device_updated(device)]
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$stats", "freeheap"] => {
                 let freeheap = payload.parse()?;
                 let device = get_mut_device_for(devices, "Got stats/freeheap for", device_id)?;
                 device.stats_freeheap = Some(freeheap);
-                vec![Event::device_updated(device)]
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$stats", "supply"] => {
                 let supply = payload.parse()?;
                 let device = get_mut_device_for(devices, "Got stats/supply for", device_id)?;
                 device.stats_supply = Some(supply);
-                vec![Event::device_updated(device)]
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, "$nodes"] => {
-                let nodes: Vec<_> = payload.split(',').collect();
+                let nodes = split_id_list(payload, "node", None, &mut ignored_ids);
                 let device = get_mut_device_for(devices, "Got nodes for", device_id)?;
 
                 // Remove nodes which aren't in the new list.
@@ -373,8 +1236,7 @@ impl HomieController {
                     let kept = nodes.contains(&node_id.as_ref());
                     if !kept {
                         // The node has been removed, so unsubscribe from its topics and those of its properties
-                        let node_topic = format!("{}/{}/{}/+", self.base_topic, device_id, node_id);
-                        topics_to_unsubscribe.push(node_topic);
+                        topics_to_unsubscribe.extend(self.node_subscriptions(device_id, node_id));
                         for property_id in node.properties.keys() {
                             let topic = format!(
                                 "{}/{}/{}/{}/+",
@@ -389,26 +1251,29 @@ impl HomieController {
                 // Add new nodes.
                 for node_id in nodes {
                     if !device.nodes.contains_key(node_id) {
+                        if !is_valid_id(node_id) {
+                            invalid_ids.push(format!("Invalid node ID '{}'", node_id));
+                        }
                         device.add_node(Node::new(node_id));
-                        let topic = format!("{}/{}/{}/+", self.base_topic, device_id, node_id);
-                        topics_to_subscribe.push(topic);
+                        topics_to_subscribe.extend(self.node_subscriptions(device_id, node_id));
                     }
                 }
 
-                vec![Event::device_updated(device)]
+                vec![Event::device_updated(device, fresh)]
             }
             [device_id, node_id, "$name"] => {
                 let node = get_mut_node_for(devices, "Got node name for", device_id, node_id)?;
-                node.name = Some(payload.to_owned());
-                vec![Event::node_updated(device_id, node)]
+                set_string(&mut node.name, payload);
+                vec![Event::node_updated(device_id, node, fresh)]
             }
             [device_id, node_id, "$type"] => {
                 let node = get_mut_node_for(devices, "Got node type for", device_id, node_id)?;
-                node.node_type = Some(payload.to_owned());
-                vec![Event::node_updated(device_id, node)]
+                set_string(&mut node.node_type, payload);
+                vec![Event::node_updated(device_id, node, fresh)]
             }
             [device_id, node_id, "$properties"] => {
-                let properties: Vec<_> = payload.split(',').collect();
+                let properties =
+                    split_id_list(payload, "property", Some(node_id), &mut ignored_ids);
                 let node = get_mut_node_for(devices, "Got properties for", device_id, node_id)?;
 
                 // Remove properties which aren't in the new list.
@@ -425,15 +1290,26 @@ impl HomieController {
                     kept
                 });
 
-                let mut events = vec![Event::node_updated(device_id, node)];
+                let mut events = vec![Event::node_updated(device_id, node, fresh)];
 
                 // Add new properties.
                 for property_id in properties {
                     if !node.properties.contains_key(property_id) {
+                        if !is_valid_id(property_id) {
+                            invalid_ids.push(format!(
+                                "Invalid property ID '{}' for node '{}'",
+                                property_id, node_id
+                            ));
+                        }
                         let mut new_prop = Property::new(property_id);
 
-                        let key = format!("{}/{}/{}", device_id, node_id, property_id);
-                        new_prop.value = early_property_values.remove(&key);
+                        if !early_property_values.is_empty() {
+                            let key = format!("{}/{}/{}", device_id, node_id, property_id);
+                            new_prop.value = early_property_values.remove(&key);
+                        }
+                        if new_prop.value.is_some() {
+                            new_prop.last_updated = Some(SystemTime::now());
+                        }
 
                         if let Some(value) = new_prop.value.clone() {
                             events.push(Event::PropertyValueChanged {
@@ -464,8 +1340,8 @@ impl HomieController {
                     node_id,
                     property_id,
                 )?;
-                property.name = Some(payload.to_owned());
-                vec![Event::property_updated(device_id, node_id, property)]
+                set_string(&mut property.name, payload);
+                vec![Event::property_updated(device_id, node_id, property, fresh)]
             }
             [device_id, node_id, property_id, "$datatype"] => {
                 let datatype = payload.parse()?;
@@ -477,7 +1353,7 @@ impl HomieController {
                     property_id,
                 )?;
                 property.datatype = Some(datatype);
-                vec![Event::property_updated(device_id, node_id, property)]
+                vec![Event::property_updated(device_id, node_id, property, fresh)]
             }
             [device_id, node_id, property_id, "$unit"] => {
                 let property = get_mut_property_for(
@@ -487,8 +1363,8 @@ impl HomieController {
                     node_id,
                     property_id,
                 )?;
-                property.unit = Some(payload.to_owned());
-                vec![Event::property_updated(device_id, node_id, property)]
+                set_string(&mut property.unit, payload);
+                vec![Event::property_updated(device_id, node_id, property, fresh)]
             }
             [device_id, node_id, property_id, "$format"] => {
                 let property = get_mut_property_for(
@@ -498,38 +1374,64 @@ impl HomieController {
                     node_id,
                     property_id,
                 )?;
-                property.format = Some(payload.to_owned());
-                vec![Event::property_updated(device_id, node_id, property)]
+                set_string(&mut property.format, payload);
+                vec![Event::property_updated(device_id, node_id, property, fresh)]
             }
-            [device_id, node_id, property_id, "$settable"] => {
-                let settable = payload
-                    .parse()
-                    .map_err(|_| format!("Invalid boolean '{}' for $settable.", payload))?;
+            [device_id, node_id, property_id, "$category"] => {
+                let category = payload.parse()?;
                 let property = get_mut_property_for(
                     devices,
-                    "Got property settable for",
+                    "Got property category for",
                     device_id,
                     node_id,
                     property_id,
                 )?;
-                property.settable = settable;
-                vec![Event::property_updated(device_id, node_id, property)]
+                property.category = Some(category);
+                vec![Event::property_updated(device_id, node_id, property, fresh)]
             }
-            [device_id, node_id, property_id, "$retained"] => {
-                let retained = payload
-                    .parse()
-                    .map_err(|_| format!("Invalid boolean '{}' for $retained.", payload))?;
+            [device_id, node_id, property_id, "$timestamp"] => {
+                let timestamp = DateTime::parse_from_rfc3339(payload)
+                    .map_err(|e| format!("Invalid $timestamp '{}': {}", payload, e))?;
                 let property = get_mut_property_for(
                     devices,
-                    "Got property retained for",
+                    "Got property timestamp for",
                     device_id,
                     node_id,
                     property_id,
                 )?;
-                property.retained = retained;
-                vec![Event::property_updated(device_id, node_id, property)]
+                property.timestamp = Some(timestamp);
+                pending_timestamps.insert(format!("{}/{}/{}", device_id, node_id, property_id));
+                vec![Event::property_updated(device_id, node_id, property, fresh)]
             }
-            [device_id, node_id, property_id]
+            [device_id, node_id, property_id, "$settable"] => {
+                let settable = payload
+                    .parse()
+                    .map_err(|_| format!("Invalid boolean '{}' for $settable.", payload))?;
+                let property = get_mut_property_for(
+                    devices,
+                    "Got property settable for",
+                    device_id,
+                    node_id,
+                    property_id,
+                )?;
+                property.settable = settable;
+                vec![Event::property_updated(device_id, node_id, property, fresh)]
+            }
+            [device_id, node_id, property_id, "$retained"] => {
+                let retained = payload
+                    .parse()
+                    .map_err(|_| format!("Invalid boolean '{}' for $retained.", payload))?;
+                let property = get_mut_property_for(
+                    devices,
+                    "Got property retained for",
+                    device_id,
+                    node_id,
+                    property_id,
+                )?;
+                property.retained = retained;
+                vec![Event::property_updated(device_id, node_id, property, fresh)]
+            }
+            [device_id, node_id, property_id]
                 if !device_id.starts_with('$')
                     && !node_id.starts_with('$')
                     && !property_id.starts_with('$') =>
@@ -542,7 +1444,15 @@ impl HomieController {
                     property_id,
                 ) {
                     Ok(property) => {
-                        property.value = Some(payload.to_owned());
+                        set_string(&mut property.value, payload);
+                        property.last_updated = Some(SystemTime::now());
+                        // A timestamp only applies to the value which follows it.
+                        if !pending_timestamps.remove(subtopic) {
+                            property.timestamp = None;
+                        }
+                        if let Some(config) = &self.rate_of_change {
+                            record_recent_value(property, config);
+                        }
                         vec![Event::property_value(
                             device_id,
                             node_id,
@@ -563,17 +1473,57 @@ impl HomieController {
                     Err(e) => return Err(e.into()),
                 }
             }
+            [device_id, attribute, ..]
+                if attribute.starts_with('$') && !device_id.starts_with('$') =>
+            {
+                let device = get_mut_device_for(devices, "Got attribute for", device_id)?;
+                // The attribute may have more levels than were split into `parts`.
+                let attribute = &subtopic[device_id.len() + 1..];
+                if payload.len() > MAX_OTHER_ATTRIBUTE_SIZE {
+                    return Err(format!(
+                        "Ignoring {} byte payload for {}/{}",
+                        payload.len(),
+                        device_id,
+                        attribute
+                    )
+                    .into());
+                }
+                if payload.is_empty() {
+                    // The retained attribute has been cleared.
+                    device.attributes.remove(attribute);
+                } else if let Some(value) = device.attributes.get_mut(attribute) {
+                    if value != payload {
+                        payload.clone_into(value);
+                    }
+                } else {
+                    device
+                        .attributes
+                        .insert(attribute.to_owned(), payload.to_owned());
+                }
+                vec![Event::device_updated(device, fresh)]
+            }
             [_device_id, _node_id, _property_id, "set"] => {
                 // Value set message may have been sent by us or another controller. Either way,
                 // ignore it, it is only for the device.
                 vec![]
             }
             _ => {
-                log::warn!("Unexpected subtopic {} = {}", subtopic, payload);
-                vec![]
+                return Err(format!("Unexpected subtopic {} = {}", subtopic, payload).into());
             }
         };
 
+        let messages = if self.strict {
+            invalid_ids.into_iter().chain(ignored_ids).collect()
+        } else {
+            ignored_ids
+        };
+        for message in messages {
+            log::warn!("{}", message);
+            if self.strict {
+                events.push(validation_error(devices, parts[0], &publish.topic, message));
+            }
+        }
+
         Ok(PublishResponse {
             events,
             topics_to_subscribe,
@@ -581,14 +1531,48 @@ impl HomieController {
         })
     }
 
+    /// Handle a property value which is not valid UTF-8, by sending it as an
+    /// `Event::BinaryPropertyValue`.
+    fn handle_binary_property_value(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        property_id: &str,
+        publish: &Publish,
+    ) -> Result<PublishResponse, HandleError> {
+        let devices = self.devices();
+        let is_known = devices
+            .get(device_id)
+            .and_then(|device| device.nodes.get(node_id))
+            .is_some_and(|node| node.properties.contains_key(property_id));
+        if !is_known {
+            return Err(format!(
+                "Got binary property value for unknown property '{}/{}/{}'",
+                device_id, node_id, property_id
+            )
+            .into());
+        }
+        Ok(PublishResponse {
+            events: vec![Event::BinaryPropertyValue {
+                device_id: device_id.to_owned(),
+                node_id: node_id.to_owned(),
+                property_id: property_id.to_owned(),
+                value: publish.payload.to_vec(),
+                fresh: !publish.retain,
+            }],
+            topics_to_subscribe: vec![],
+            topics_to_unsubscribe: vec![],
+        })
+    }
+
     /// Start discovering Homie devices.
     async fn start(&self) -> Result<(), ClientError> {
         // Clear set of known devices so that we correctly subscribe to their topics again.
-        *self.devices.lock().unwrap() = Arc::new(HashMap::new());
+        *self.write_devices() = Devices::new();
+        self.presumed_lost.lock().unwrap().clear();
 
         let topic = format!("{}/+/$homie", self.base_topic);
-        log::trace!("Subscribe to {}", topic);
-        self.mqtt_client.subscribe(topic, QoS::AtLeastOnce).await
+        self.update_subscriptions(vec![topic], vec![]).await
     }
 
     /// Attempt to set the state of a settable property of a device. If this succeeds the device
@@ -599,34 +1583,726 @@ impl HomieController {
         node_id: &str,
         property_id: &str,
         value: impl Value,
+    ) -> Result<(), ClientError> {
+        self.set_with_options(
+            device_id,
+            node_id,
+            property_id,
+            value,
+            SetOptions::default(),
+        )
+        .await
+    }
+
+    /// Attempt to set the state of a settable property of a device, as with [`set`](Self::set), but
+    /// first check that the property has been discovered, is settable, and that the value is valid
+    /// for its datatype and format. Nothing is published if any of these checks fail.
+    ///
+    /// The checks are made against the controller's current view of the device, so the device
+    /// should be discovered fully first. Use `set` to skip them.
+    pub async fn set_checked<V: Value>(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        property_id: &str,
+        value: V,
+    ) -> Result<(), SetError> {
+        let property = self.get_property(device_id, node_id, property_id)?;
+        if !property.settable {
+            return Err(SetError::NotSettable {
+                device_id: device_id.to_owned(),
+                node_id: node_id.to_owned(),
+                property_id: property_id.to_owned(),
+            });
+        }
+        property.check_value(&value)?;
+        self.set(device_id, node_id, property_id, value).await?;
+        Ok(())
+    }
+
+    /// Send a command to a device which implements a request/response pattern over two properties,
+    /// by setting the command property to the given value and then waiting for the next fresh value
+    /// of the result property.
+    ///
+    /// The controller must be polled concurrently, e.g. by [`run`](Self::run) on another task, for
+    /// the result to be received. Values of the result property which were published before the
+    /// command was sent are ignored. If the returned future is dropped before the result arrives,
+    /// the command stops waiting for it.
+    pub async fn command(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        command_property_id: &str,
+        result_property_id: &str,
+        value: impl Value,
+        timeout: Duration,
+    ) -> Result<String, CommandError> {
+        let (sender, receiver) = oneshot::channel();
+        // Start waiting before sending the command, so that the result can't be missed.
+        self.value_waiters.lock().unwrap().push(ValueWaiter {
+            device_id: device_id.to_owned(),
+            node_id: node_id.to_owned(),
+            property_id: result_property_id.to_owned(),
+            sender,
+        });
+        self.set(device_id, node_id, command_property_id, value)
+            .await?;
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(result)) => Ok(result),
+            // The sender is only dropped without sending if the waiter is removed, which can't
+            // happen while it is still waiting.
+            Ok(Err(oneshot::Canceled)) | Err(_) => Err(CommandError::Timeout {
+                device_id: device_id.to_owned(),
+                node_id: node_id.to_owned(),
+                property_id: result_property_id.to_owned(),
+            }),
+        }
+    }
+
+    /// Wait until the device with the given ID is in the given state, or the timeout elapses.
+    ///
+    /// This returns immediately if the device is already in the state. Otherwise the controller
+    /// must be polled concurrently, e.g. by [`run`](Self::run) on another task, for the change to
+    /// be noticed.
+    pub async fn wait_for_state(
+        &self,
+        device_id: &str,
+        state: State,
+        timeout: Duration,
+    ) -> Result<(), WaitError> {
+        let id = device_id.to_owned();
+        self.wait_for(
+            move |devices| devices.get(&id).is_some_and(|device| device.state == state),
+            timeout,
+        )
+        .await
+        .map(|_| ())
+        .ok_or_else(|| WaitError::State {
+            device_id: device_id.to_owned(),
+            state,
+        })
+    }
+
+    /// Wait until the given property has a value for which the given predicate returns true, or the
+    /// timeout elapses, and return the value.
+    ///
+    /// This returns immediately if the current value of the property already matches, so to wait
+    /// for a device to confirm a value after setting it, the predicate should check for the new
+    /// value. Otherwise the controller must be polled concurrently, e.g. by [`run`](Self::run) on
+    /// another task, for the change to be noticed.
+    pub async fn wait_for_value(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        property_id: &str,
+        predicate: impl Fn(&str) -> bool + Send + 'static,
+        timeout: Duration,
+    ) -> Result<String, WaitError> {
+        let get_value = {
+            let device_id = device_id.to_owned();
+            let node_id = node_id.to_owned();
+            let property_id = property_id.to_owned();
+            move |devices: &Devices| {
+                devices
+                    .get(&device_id)?
+                    .nodes
+                    .get(&node_id)?
+                    .properties
+                    .get(&property_id)?
+                    .value
+                    .clone()
+            }
+        };
+        let condition = {
+            let get_value = get_value.clone();
+            move |devices: &Devices| get_value(devices).is_some_and(|value| predicate(&value))
+        };
+        self.wait_for(condition, timeout)
+            .await
+            .and_then(|devices| get_value(&devices))
+            .ok_or_else(|| WaitError::Value {
+                device_id: device_id.to_owned(),
+                node_id: node_id.to_owned(),
+                property_id: property_id.to_owned(),
+            })
+    }
+
+    /// Wait until the devices meet the given condition, and return the first snapshot which does,
+    /// or `None` if the timeout elapses first.
+    async fn wait_for(
+        &self,
+        condition: impl Fn(&Devices) -> bool + Send + 'static,
+        timeout: Duration,
+    ) -> Option<Arc<Devices>> {
+        let receiver = {
+            // Hold the lock while checking the current snapshot, so that a new snapshot can't be
+            // stored and notified in between without the waiter seeing it.
+            let mut waiters = self.condition_waiters.lock().unwrap();
+            let devices = self.devices.load_full();
+            if condition(&devices) {
+                return Some(devices);
+            }
+            let (sender, receiver) = oneshot::channel();
+            waiters.push(ConditionWaiter {
+                condition: Box::new(condition),
+                sender,
+            });
+            receiver
+        };
+        tokio::time::timeout(timeout, receiver).await.ok()?.ok()
+    }
+
+    /// Attempt to set the state of a settable property of a device, as with [`set`](Self::set),
+    /// but with the given QoS and retain flag rather than the defaults.
+    pub async fn set_with_options(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        property_id: &str,
+        value: impl Value,
+        options: SetOptions,
+    ) -> Result<(), ClientError> {
+        self.set_raw(
+            device_id,
+            node_id,
+            property_id,
+            value.to_string().into_bytes(),
+            options,
+        )
+        .await
+    }
+
+    /// Publish the given payload as-is to the set topic of a property of a device, for devices
+    /// which expect binary or otherwise non-standard payloads.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, payload), fields(len = payload.len()))
+    )]
+    pub async fn set_raw(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        property_id: &str,
+        payload: Vec<u8>,
+        options: SetOptions,
     ) -> Result<(), ClientError> {
         let topic = format!(
             "{}/{}/{}/{}/set",
             self.base_topic, device_id, node_id, property_id
         );
         self.mqtt_client
-            .publish(topic, QoS::AtLeastOnce, false, value.to_string())
+            .publish(topic, options.qos, options.retain, payload)
             .await
     }
 
+    /// Attempt to set the colour of a settable colour property of a device, converting it to the
+    /// format declared by the property.
+    ///
+    /// Returns `InvalidValue` if the property is not a colour property or its format is not known.
+    pub async fn set_color(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        property_id: &str,
+        color: impl ColorConversion,
+    ) -> Result<(), SetError> {
+        let format = self
+            .get_property(device_id, node_id, property_id)?
+            .color_format()?;
+        match format {
+            ColorFormat::Rgb => {
+                self.set(device_id, node_id, property_id, color.to_rgb())
+                    .await?
+            }
+            ColorFormat::Hsv => {
+                self.set(device_id, node_id, property_id, color.to_hsv())
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    /// Get a copy of the given property, if it has been discovered.
+    fn get_property(
+        &self,
+        device_id: &str,
+        node_id: &str,
+        property_id: &str,
+    ) -> Result<Property, SetError> {
+        let devices = self.devices();
+        let device = devices
+            .get(device_id)
+            .ok_or_else(|| SetError::UnknownDevice {
+                device_id: device_id.to_owned(),
+            })?;
+        let node = device
+            .nodes
+            .get(node_id)
+            .ok_or_else(|| SetError::UnknownNode {
+                device_id: device_id.to_owned(),
+                node_id: node_id.to_owned(),
+            })?;
+        node.properties
+            .get(property_id)
+            .cloned()
+            .ok_or_else(|| SetError::UnknownProperty {
+                device_id: device_id.to_owned(),
+                node_id: node_id.to_owned(),
+                property_id: property_id.to_owned(),
+            })
+    }
+
+    /// Delete the device with the given ID from the MQTT broker, such as a device which has been
+    /// retired but whose retained messages remain, by publishing an empty retained message to each
+    /// of its topics. Returns the topics which were cleared.
+    ///
+    /// The topics are worked out from the attributes, nodes and properties which the controller
+    /// has received for the device, so it should be given a chance to discover the device fully
+    /// first. `$settable` and `$retained` are always included, as the controller can't tell whether
+    /// they were published, and clearing a topic with no retained message has no effect. `$homie`
+    /// is cleared last, so that if purging fails part way the device can still be discovered and
+    /// purged again.
+    ///
+    /// If `dry_run` is true then nothing is published, but the topics which would be cleared are
+    /// still returned. Otherwise, once all the topics have been cleared, the controller also forgets
+    /// the device and unsubscribes from its topics, so if publishing fails the device is still known
+    /// and can be purged again. If the device is not known to the controller then nothing is done
+    /// and no topics are returned.
+    pub async fn purge_device(
+        &self,
+        device_id: &str,
+        dry_run: bool,
+    ) -> Result<Vec<String>, ClientError> {
+        let topics: Vec<String> = match self.devices.load().get(device_id) {
+            Some(device) => device_subtopics(device)
+                .into_iter()
+                .map(|subtopic| format!("{}/{}", self.base_topic, subtopic))
+                .collect(),
+            None => return Ok(vec![]),
+        };
+        if dry_run {
+            return Ok(topics);
+        }
+
+        for topic in &topics {
+            self.mqtt_client
+                .publish(topic, QoS::AtLeastOnce, true, "")
+                .await?;
+        }
+
+        let topics_to_unsubscribe = match self.write_devices().remove(device_id) {
+            Some(device) => self.device_subscriptions(&device),
+            None => vec![],
+        };
+        self.presumed_lost.lock().unwrap().remove(device_id);
+        let prefix = format!("{}/", device_id);
+        self.early_property_values
+            .lock()
+            .unwrap()
+            .retain(|subtopic, _| !subtopic.starts_with(&prefix));
+        self.pending_timestamps
+            .lock()
+            .unwrap()
+            .retain(|subtopic| !subtopic.starts_with(&prefix));
+        self.update_subscriptions(vec![], topics_to_unsubscribe)
+            .await?;
+        Ok(topics)
+    }
+
     /// Disconnect from the MQTT broker.
+    ///
+    /// This only queues the disconnection, which happens when the `EventLoop` is next polled. Use
+    /// [`shutdown`](Self::shutdown) to stop the controller cleanly.
     pub async fn disconnect(&self) -> Result<(), ClientError> {
         self.mqtt_client.disconnect().await
     }
+
+    /// Shut down the controller cleanly: unsubscribe from all the topics it has subscribed to,
+    /// disconnect from the MQTT broker, and poll the `EventLoop` until the disconnection has been
+    /// sent. Any messages received meanwhile are ignored.
+    ///
+    /// After this has been called, [`poll`](Self::poll) and [`run`](Self::run) return
+    /// [`PollError::Shutdown`], even if shutting down fails.
+    pub async fn shutdown(&self, event_loop: &mut HomieEventLoop) -> Result<(), PollError> {
+        self.shut_down.store(true, Ordering::SeqCst);
+
+        let mut topics_to_unsubscribe = vec![format!("{}/+/$homie", self.base_topic)];
+        for device in self.devices.load().values() {
+            topics_to_unsubscribe.extend(self.device_subscriptions(device));
+        }
+        // Requests are only sent while the event loop is being polled, so the requests must be
+        // queued concurrently with polling it, in case there are more than fit in the queue.
+        let send = async {
+            for topic in topics_to_unsubscribe {
+                log::trace!("Unsubscribe from {}", topic);
+                self.mqtt_client.unsubscribe(topic).await?;
+            }
+            self.mqtt_client.disconnect().await?;
+            Ok::<_, PollError>(())
+        };
+        let drain = async {
+            loop {
+                let notification = event_loop.event_loop.poll().await?;
+                log::trace!("Notification = {:?}", notification);
+                if let rumqttc::Event::Outgoing(Outgoing::Disconnect) = notification {
+                    return Ok::<_, PollError>(());
+                }
+            }
+        };
+        pin_mut!(send, drain);
+        match select(send, drain).await {
+            Either::Left((sent, drain)) => {
+                sent?;
+                drain.await
+            }
+            Either::Right((drained, _)) => drained,
+        }
+    }
+
+    /// Stop receiving property values, such as during broker maintenance, while still keeping
+    /// track of devices, nodes and properties and their attributes.
+    ///
+    /// Property values are published on the same level as node attributes, so the wildcard
+    /// subscription for each node is replaced by subscriptions to its individual attributes. Values
+    /// which were already on their way may still be received. Call
+    /// [`resume_values`](Self::resume_values) to start receiving values again.
+    pub async fn pause_values(&self) -> Result<(), ClientError> {
+        if self.values_paused.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        // Subscribe to the attributes before unsubscribing from the wildcard, so that no attribute
+        // changes are missed in between.
+        let (topics_to_subscribe, topics_to_unsubscribe) = self.node_subscription_changes(true);
+        self.update_subscriptions(topics_to_subscribe, topics_to_unsubscribe)
+            .await
+    }
+
+    /// Start receiving property values again after [`pause_values`](Self::pause_values).
+    ///
+    /// The broker sends the retained value of each property on resubscribing, so the values of
+    /// properties which changed while paused are resynchronised, and returned from
+    /// [`poll`](Self::poll) as [`Event::PropertyValueChanged`] events with `fresh` false.
+    pub async fn resume_values(&self) -> Result<(), ClientError> {
+        if !self.values_paused.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let (topics_to_subscribe, topics_to_unsubscribe) = self.node_subscription_changes(false);
+        self.update_subscriptions(topics_to_subscribe, topics_to_unsubscribe)
+            .await
+    }
+
+    /// Whether receiving property values is currently paused by
+    /// [`pause_values`](Self::pause_values).
+    pub fn values_paused(&self) -> bool {
+        self.values_paused.load(Ordering::SeqCst)
+    }
+
+    /// Get the topics to subscribe to and unsubscribe from for all known nodes, to switch between
+    /// node subscriptions with values paused or not.
+    fn node_subscription_changes(&self, paused: bool) -> (Vec<String>, Vec<String>) {
+        let mut topics_to_subscribe = vec![];
+        let mut topics_to_unsubscribe = vec![];
+        for device in self.devices.load().values() {
+            for node_id in device.nodes.keys() {
+                let wildcard = node_subscriptions(&self.base_topic, &device.id, node_id, false);
+                let attributes = node_subscriptions(&self.base_topic, &device.id, node_id, true);
+                if paused {
+                    topics_to_subscribe.extend(attributes);
+                    topics_to_unsubscribe.extend(wildcard);
+                } else {
+                    topics_to_subscribe.extend(wildcard);
+                    topics_to_unsubscribe.extend(attributes);
+                }
+            }
+        }
+        (topics_to_subscribe, topics_to_unsubscribe)
+    }
+
+    /// Get the topics which the controller currently subscribes to for the given node, not
+    /// including those of its properties.
+    fn node_subscriptions(&self, device_id: &str, node_id: &str) -> Vec<String> {
+        node_subscriptions(&self.base_topic, device_id, node_id, self.values_paused())
+    }
+
+    /// Get the topics which the controller subscribes to for the given device.
+    fn device_subscriptions(&self, device: &Device) -> Vec<String> {
+        let device_topic = format!("{}/{}", self.base_topic, device.id);
+        let mut topics: Vec<String> = ["+", "$fw/+", "$stats/+", "$implementation/#", "$meta/#"]
+            .iter()
+            .map(|subtopic| format!("{}/{}", device_topic, subtopic))
+            .collect();
+        for (node_id, node) in &device.nodes {
+            topics.extend(self.node_subscriptions(&device.id, node_id));
+            for property_id in node.properties.keys() {
+                topics.push(format!("{}/{}/{}/+", device_topic, node_id, property_id));
+            }
+        }
+        topics
+    }
+}
+
+/// Get the topics to subscribe to for a node, not including those of its properties. If property
+/// values are paused then these are the individual node attributes, otherwise a wildcard which also
+/// matches property values.
+fn node_subscriptions(
+    base_topic: &str,
+    device_id: &str,
+    node_id: &str,
+    values_paused: bool,
+) -> Vec<String> {
+    if values_paused {
+        ["$name", "$type", "$properties"]
+            .iter()
+            .map(|attribute| format!("{}/{}/{}/{}", base_topic, device_id, node_id, attribute))
+            .collect()
+    } else {
+        vec![format!("{}/{}/{}/+", base_topic, device_id, node_id)]
+    }
+}
+
+/// Records the device ID, and node and property IDs if any, from the given parts of a subtopic on
+/// the current span.
+#[cfg(feature = "tracing")]
+fn record_ids(parts: &[&str]) {
+    let span = tracing::Span::current();
+    let ids = parts
+        .iter()
+        .take_while(|part| !part.starts_with('$'))
+        .take(3);
+    for (field, id) in ["device_id", "node_id", "property_id"].iter().zip(ids) {
+        span.record(*field, *id);
+    }
+}
+
+/// Splits a comma-separated `$nodes` or `$properties` list, dropping duplicates and any IDs which
+/// can't be used as part of a topic, and adding a message to `ignored` for each one dropped.
+///
+/// An empty payload is an empty list.
+fn split_id_list<'a>(
+    payload: &'a str,
+    kind: &str,
+    node_id: Option<&str>,
+    ignored: &mut Vec<String>,
+) -> Vec<&'a str> {
+    let mut ids = vec![];
+    if payload.is_empty() {
+        return ids;
+    }
+    let context = || match node_id {
+        Some(node_id) => format!(" for node '{}'", node_id),
+        None => String::new(),
+    };
+    for id in payload.split(',') {
+        if id.is_empty() || id.starts_with('$') || id.contains(['/', '+', '#']) {
+            ignored.push(format!(
+                "Ignoring unusable {} ID '{}'{}",
+                kind,
+                id,
+                context()
+            ));
+        } else if ids.contains(&id) {
+            ignored.push(format!(
+                "Ignoring duplicate {} ID '{}'{}",
+                kind,
+                id,
+                context()
+            ));
+        } else {
+            ids.push(id);
+        }
+    }
+    ids
+}
+
+/// The maximum number of levels of a subtopic which are matched on. Any further levels are only
+/// needed for device attributes, which use the rest of the subtopic as is.
+const MAX_TOPIC_PARTS: usize = 5;
+
+/// Splits the given subtopic into its levels, using the given buffer rather than allocating.
+///
+/// If there are more than `MAX_TOPIC_PARTS` levels then only that many are returned, the last of
+/// which is the next level rather than the rest of the subtopic. This can't match any pattern
+/// expecting fewer levels.
+fn split_subtopic<'a, 'b>(
+    subtopic: &'a str,
+    buffer: &'b mut [&'a str; MAX_TOPIC_PARTS],
+) -> &'b [&'a str] {
+    let mut count = 0;
+    for (slot, part) in buffer.iter_mut().zip(subtopic.split('/')) {
+        *slot = part;
+        count += 1;
+    }
+    &buffer[..count]
+}
+
+/// Sets the given string attribute to the given value, reusing its allocation if it is already set.
+fn set_string(attribute: &mut Option<String>, value: &str) {
+    match attribute {
+        Some(current) => {
+            if current != value {
+                value.clone_into(current);
+            }
+        }
+        None => *attribute = Some(value.to_owned()),
+    }
+}
+
+/// If the given subtopic is for the value of a property, returns the device, node and property IDs.
+fn property_value_topic(subtopic: &str) -> Option<[&str; 3]> {
+    let mut parts_buffer = [""; MAX_TOPIC_PARTS];
+    match split_subtopic(subtopic, &mut parts_buffer) {
+        [device_id, node_id, property_id]
+            if !device_id.starts_with('$')
+                && !node_id.starts_with('$')
+                && !property_id.starts_with('$') =>
+        {
+            Some([device_id, node_id, property_id])
+        }
+        _ => None,
+    }
+}
+
+/// Exclusive access to update the devices known to a controller, which stores the result as the new
+/// snapshot when dropped.
+struct DevicesWriteGuard<'a> {
+    devices: MutexGuard<'a, Devices>,
+    snapshot: &'a ArcSwap<Devices>,
+}
+
+impl Deref for DevicesWriteGuard<'_> {
+    type Target = Devices;
+
+    fn deref(&self) -> &Devices {
+        &self.devices
+    }
+}
+
+impl DerefMut for DevicesWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Devices {
+        &mut self.devices
+    }
+}
+
+impl Drop for DevicesWriteGuard<'_> {
+    fn drop(&mut self) {
+        self.snapshot.store(Arc::new(self.devices.clone()));
+    }
+}
+
+/// Get the subtopics under the base topic of all the attributes, nodes and properties known for the
+/// given device, ending with `$homie`.
+fn device_subtopics(device: &Device) -> Vec<String> {
+    let mut attributes = vec![];
+    if device.name.is_some() {
+        attributes.push("$name");
+    }
+    if device.state != State::Unknown {
+        attributes.push("$state");
+    }
+    if device.implementation.is_some() {
+        attributes.push("$implementation");
+    }
+    if !device.extensions.is_empty() {
+        attributes.push("$extensions");
+    }
+    if device.local_ip.is_some() {
+        attributes.push("$localip");
+    }
+    if device.mac.is_some() {
+        attributes.push("$mac");
+    }
+    if device.firmware_name.is_some() {
+        attributes.push("$fw/name");
+    }
+    if device.firmware_version.is_some() {
+        attributes.push("$fw/version");
+    }
+    let stats = [
+        ("$stats/interval", device.stats_interval.is_some()),
+        ("$stats/uptime", device.stats_uptime.is_some()),
+        ("$stats/signal", device.stats_signal.is_some()),
+        ("$stats/cputemp", device.stats_cputemp.is_some()),
+        ("$stats/cpuload", device.stats_cpuload.is_some()),
+        ("$stats/battery", device.stats_battery.is_some()),
+        ("$stats/freeheap", device.stats_freeheap.is_some()),
+        ("$stats/supply", device.stats_supply.is_some()),
+    ];
+    attributes.extend(
+        stats
+            .iter()
+            .filter(|(_, known)| *known)
+            .map(|(topic, _)| topic),
+    );
+    if !device.nodes.is_empty() {
+        attributes.push("$nodes");
+    }
+    let mut subtopics: Vec<String> = attributes
+        .into_iter()
+        .map(|attribute| format!("{}/{}", device.id, attribute))
+        .collect();
+    let mut custom_attributes: Vec<_> = device.attributes.keys().collect();
+    custom_attributes.sort();
+    subtopics.extend(
+        custom_attributes
+            .into_iter()
+            .map(|attribute| format!("{}/{}", device.id, attribute)),
+    );
+
+    let mut nodes: Vec<_> = device.nodes.values().collect();
+    nodes.sort_by(|a, b| a.id.cmp(&b.id));
+    for node in nodes {
+        let node_topic = format!("{}/{}", device.id, node.id);
+        if node.name.is_some() {
+            subtopics.push(format!("{}/$name", node_topic));
+        }
+        if node.node_type.is_some() {
+            subtopics.push(format!("{}/$type", node_topic));
+        }
+        subtopics.push(format!("{}/$properties", node_topic));
+
+        let mut properties: Vec<_> = node.properties.values().collect();
+        properties.sort_by(|a, b| a.id.cmp(&b.id));
+        for property in properties {
+            let property_topic = format!("{}/{}", node_topic, property.id);
+            if property.value.is_some() {
+                subtopics.push(property_topic.clone());
+            }
+            let attributes = [
+                ("$name", property.name.is_some()),
+                ("$datatype", property.datatype.is_some()),
+                ("$unit", property.unit.is_some()),
+                ("$format", property.format.is_some()),
+                ("$category", property.category.is_some()),
+                ("$timestamp", property.timestamp.is_some()),
+                ("$settable", true),
+                ("$retained", true),
+            ];
+            subtopics.extend(
+                attributes
+                    .iter()
+                    .filter(|(_, known)| *known)
+                    .map(|(attribute, _)| format!("{}/{}", property_topic, attribute)),
+            );
+        }
+    }
+
+    subtopics.push(format!("{}/$homie", device.id));
+    subtopics
 }
 
 fn get_mut_device_for<'a>(
-    devices: &'a mut HashMap<String, Device>,
+    devices: &'a mut Devices,
     err_prefix: &str,
     device_id: &str,
 ) -> Result<&'a mut Device, String> {
     devices
         .get_mut(device_id)
+        .map(Arc::make_mut)
         .ok_or_else(|| format!("{} unknown device '{}'", err_prefix, device_id))
 }
 
 fn get_mut_node_for<'a>(
-    devices: &'a mut HashMap<String, Device>,
+    devices: &'a mut Devices,
     err_prefix: &str,
     device_id: &str,
     node_id: &str,
@@ -639,7 +2315,7 @@ fn get_mut_node_for<'a>(
 }
 
 fn get_mut_property_for<'a>(
-    devices: &'a mut HashMap<String, Device>,
+    devices: &'a mut Devices,
     err_prefix: &str,
     device_id: &str,
     node_id: &str,
@@ -654,6 +2330,33 @@ fn get_mut_property_for<'a>(
     })
 }
 
+/// Record a validation error against the given device, if it is known, and construct the
+/// corresponding event.
+fn validation_error(devices: &mut Devices, device_id: &str, topic: &str, message: String) -> Event {
+    if let Some(device) = devices.get_mut(device_id).map(Arc::make_mut) {
+        if !device.validation_errors.contains(&message) {
+            device.validation_errors.push(message.clone());
+        }
+    }
+    Event::ValidationError {
+        device_id: device_id.to_owned(),
+        topic: topic.to_owned(),
+        message,
+    }
+}
+
+/// Adds the current value of the given property to its recent values, if it is numeric.
+fn record_recent_value(property: &mut Property, config: &RateOfChangeConfig) {
+    let value = match property.datatype {
+        Some(Datatype::Integer) => property.value::<i64>().map(|value| value as f64),
+        Some(Datatype::Float) => property.value::<f64>(),
+        _ => return,
+    };
+    if let (Ok(value), Some(time)) = (value, property.value_time()) {
+        property.recent_values.add(time, value, config);
+    }
+}
+
 #[derive(Error, Debug)]
 enum HandleError {
     #[error("{0}")]
@@ -680,8 +2383,8 @@ impl From<ParseDatatypeError> for HandleError {
     }
 }
 
-impl From<ParseExtensionError> for HandleError {
-    fn from(e: ParseExtensionError) -> Self {
+impl From<ParsePropertyCategoryError> for HandleError {
+    fn from(e: ParsePropertyCategoryError) -> Self {
         HandleError::Warning(e.to_string())
     }
 }
@@ -702,7 +2405,7 @@ impl From<ParseFloatError> for HandleError {
 mod tests {
     use super::*;
     use flume::Receiver;
-    use rumqttc::{ConnAck, Packet, Request, Subscribe};
+    use rumqttc::{ConnAck, Disconnect, Packet, Request, Subscribe, Unsubscribe};
 
     fn make_test_controller() -> (HomieController, Receiver<Request>) {
         let (requests_tx, requests_rx) = flume::unbounded();
@@ -710,8 +2413,27 @@ mod tests {
         let controller = HomieController {
             base_topic: "base_topic".to_owned(),
             mqtt_client,
-            devices: Mutex::new(Arc::new(HashMap::new())),
+            devices: ArcSwap::from_pointee(Devices::new()),
+            devices_writer: Mutex::new(Devices::new()),
             early_property_values: Mutex::new(HashMap::new()),
+            pending_timestamps: Mutex::new(HashSet::new()),
+            non_utf8_payloads: NonUtf8Payloads::default(),
+            strict: false,
+            handlers: Mutex::new(vec![]),
+            parallel_handlers: false,
+            metrics: None,
+            recorder: None,
+            rate_of_change: None,
+            presume_lost_after: None,
+            presumed_lost: Mutex::new(HashMap::new()),
+            groups: Groups::default(),
+            value_waiters: Mutex::new(vec![]),
+            condition_waiters: Mutex::new(vec![]),
+            pending_subscriptions: Mutex::new(VecDeque::new()),
+            subscriptions_lock: futures::lock::Mutex::new(()),
+            pending_events: Mutex::new(vec![]),
+            shut_down: AtomicBool::new(false),
+            values_paused: AtomicBool::new(false),
         };
         (controller, requests_rx)
     }
@@ -793,6 +2515,8 @@ mod tests {
                 "base_topic/device_id/+",
                 "base_topic/device_id/$fw/+",
                 "base_topic/device_id/$stats/+",
+                "base_topic/device_id/$implementation/#",
+                "base_topic/device_id/$meta/#",
             ],
         );
 
@@ -819,14 +2543,38 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn retained_payloads_before_properties() -> Result<(), Box<dyn std::error::Error>> {
+    async fn snapshot_unaffected_by_updates() -> Result<(), Box<dyn std::error::Error>> {
         let (controller, _requests_rx) = make_test_controller();
-
-        // Connecting should start discovering.
         connect(&controller).await?;
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
 
-        // Discover a new device.
-        publish_retained(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        let snapshot = controller.devices();
+        publish(&controller, "base_topic/device_id/$name", "Device name").await?;
+        publish(&controller, "base_topic/other_device/$homie", "4.0").await?;
+
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot["device_id"].name, None);
+        let devices = controller.devices();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices["device_id"].name, Some("Device name".to_owned()));
+
+        // Reconnecting clears the devices.
+        connect(&controller).await?;
+        assert!(controller.devices().is_empty());
+        assert_eq!(devices.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retained_payloads_before_properties() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+
+        // Connecting should start discovering.
+        connect(&controller).await?;
+
+        // Discover a new device.
+        publish_retained(&controller, "base_topic/device_id/$homie", "4.0").await?;
 
         // Discover a node on the device.
         publish_retained(
@@ -914,37 +2662,38 @@ mod tests {
             publish(&controller, "base_topic/device_id/$homie", "4.0").await?,
             vec![Event::DeviceUpdated {
                 device_id: "device_id".to_owned(),
-                has_required_attributes: false
+                has_required_attributes: false,
+                fresh: true,
             }]
         );
         assert_eq!(
             publish(&controller, "base_topic/device_id/$name", "Device name").await?,
             vec![Event::DeviceUpdated {
                 device_id: "device_id".to_owned(),
-                has_required_attributes: false
+                has_required_attributes: false,
+                fresh: true,
             }]
         );
         assert_eq!(
             publish(&controller, "base_topic/device_id/$state", "ready").await?,
             vec![Event::DeviceUpdated {
                 device_id: "device_id".to_owned(),
-                has_required_attributes: true
+                has_required_attributes: true,
+                fresh: true,
             }]
         );
         let mut expected_device = Device::new("device_id", "4.0");
         expected_device.state = State::Ready;
         expected_device.name = Some("Device name".to_owned());
-        assert_eq!(
-            controller.devices().get("device_id").unwrap().to_owned(),
-            expected_device
-        );
+        assert_eq!(controller.devices()["device_id"], Arc::new(expected_device));
 
         // A node on the device.
         assert_eq!(
             publish(&controller, "base_topic/device_id/$nodes", "node_id").await?,
             vec![Event::DeviceUpdated {
                 device_id: "device_id".to_owned(),
-                has_required_attributes: false
+                has_required_attributes: false,
+                fresh: true,
             }]
         );
         assert_eq!(
@@ -957,7 +2706,8 @@ mod tests {
             vec![Event::NodeUpdated {
                 device_id: "device_id".to_owned(),
                 node_id: "node_id".to_owned(),
-                has_required_attributes: false
+                has_required_attributes: false,
+                fresh: true,
             }]
         );
         assert_eq!(
@@ -970,7 +2720,8 @@ mod tests {
             vec![Event::NodeUpdated {
                 device_id: "device_id".to_owned(),
                 node_id: "node_id".to_owned(),
-                has_required_attributes: false
+                has_required_attributes: false,
+                fresh: true,
             }]
         );
 
@@ -985,7 +2736,8 @@ mod tests {
             vec![Event::NodeUpdated {
                 device_id: "device_id".to_owned(),
                 node_id: "node_id".to_owned(),
-                has_required_attributes: false
+                has_required_attributes: false,
+                fresh: true,
             }]
         );
         assert_eq!(
@@ -999,7 +2751,8 @@ mod tests {
                 device_id: "device_id".to_owned(),
                 node_id: "node_id".to_owned(),
                 property_id: "property_id".to_owned(),
-                has_required_attributes: false
+                has_required_attributes: false,
+                fresh: true,
             }]
         );
         assert_eq!(
@@ -1013,7 +2766,8 @@ mod tests {
                 device_id: "device_id".to_owned(),
                 node_id: "node_id".to_owned(),
                 property_id: "property_id".to_owned(),
-                has_required_attributes: true
+                has_required_attributes: true,
+                fresh: true,
             }]
         );
 
@@ -1063,10 +2817,17 @@ mod tests {
             "integer",
         )
         .await?;
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/property_id/$category",
+            "diagnostic",
+        )
+        .await?;
 
         let expected_property = Property {
             name: Some("Property name".to_owned()),
             datatype: Some(Datatype::Integer),
+            category: Some(PropertyCategory::Diagnostic),
             ..Property::new("property_id")
         };
         let expected_node = Node {
@@ -1082,11 +2843,1437 @@ mod tests {
             ..Device::new("device_id", "4.0")
         };
 
+        assert_eq!(controller.devices()["device_id"], Arc::new(expected_device));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn records_value_times() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+        add_color_property(&controller, None);
+
+        let before = SystemTime::now();
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/property_id",
+            "1,2,3",
+        )
+        .await?;
+        let property =
+            controller.devices()["device_id"].nodes["node_id"].properties["property_id"].clone();
+        let last_updated = property.last_updated.unwrap();
+        assert!(last_updated >= before && last_updated <= SystemTime::now());
+        assert_eq!(property.timestamp, None);
+        assert_eq!(property.value_time(), Some(last_updated));
+
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/property_id/$timestamp",
+            "2024-01-02T03:04:05+01:00",
+        )
+        .await?;
+        let property =
+            controller.devices()["device_id"].nodes["node_id"].properties["property_id"].clone();
+        let timestamp = DateTime::parse_from_rfc3339("2024-01-02T03:04:05+01:00")?;
+        assert_eq!(property.timestamp, Some(timestamp));
+        assert_eq!(property.last_updated, Some(last_updated));
+        assert_eq!(property.value_time(), Some(timestamp.into()));
+
+        // A timestamp published before a value applies to it.
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/property_id/$timestamp",
+            "2024-01-02T04:00:00+01:00",
+        )
+        .await?;
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/property_id",
+            "4,5,6",
+        )
+        .await?;
+        let property =
+            controller.devices()["device_id"].nodes["node_id"].properties["property_id"].clone();
+        let timestamp = DateTime::parse_from_rfc3339("2024-01-02T04:00:00+01:00")?;
+        assert_eq!(property.value.as_deref(), Some("4,5,6"));
+        assert_eq!(property.timestamp, Some(timestamp));
+
+        // A new value without a new timestamp clears the old one.
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/property_id",
+            "7,8,9",
+        )
+        .await?;
+        let property =
+            controller.devices()["device_id"].nodes["node_id"].properties["property_id"].clone();
+        assert_eq!(property.timestamp, None);
+        assert_eq!(property.value_time(), property.last_updated);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn computes_rate_of_change() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut controller, _requests_rx) = make_test_controller();
+        controller.set_rate_of_change(Some(RateOfChangeConfig::default()));
+        let property = Property {
+            datatype: Some(Datatype::Float),
+            ..Property::new("property_id")
+        };
+        let node = Node {
+            properties: property_set(vec![property]),
+            ..Node::new("node_id")
+        };
+        let device = Device {
+            nodes: node_set(vec![node]),
+            ..Device::new("device_id", "4.0")
+        };
+        controller
+            .write_devices()
+            .insert(device.id.clone(), Arc::new(device));
+
+        for (timestamp, value) in [
+            ("2024-01-02T03:00:00Z", "20.0"),
+            ("2024-01-02T03:30:00Z", "20.5"),
+        ] {
+            publish(
+                &controller,
+                "base_topic/device_id/node_id/property_id/$timestamp",
+                timestamp,
+            )
+            .await?;
+            publish(
+                &controller,
+                "base_topic/device_id/node_id/property_id",
+                value,
+            )
+            .await?;
+        }
+
+        let property =
+            controller.devices()["device_id"].nodes["node_id"].properties["property_id"].clone();
+        assert_eq!(property.recent_values.len(), 2);
+        assert_eq!(
+            property.rate_of_change(Duration::from_secs(3600)),
+            Some(1.0)
+        );
+
+        Ok(())
+    }
+
+    fn add_color_property(controller: &HomieController, format: Option<&str>) {
+        let property = Property {
+            datatype: Some(Datatype::Color),
+            settable: true,
+            format: format.map(ToOwned::to_owned),
+            ..Property::new("property_id")
+        };
+        let node = Node {
+            properties: property_set(vec![property]),
+            ..Node::new("node_id")
+        };
+        let device = Device {
+            nodes: node_set(vec![node]),
+            ..Device::new("device_id", "4.0")
+        };
+        controller
+            .write_devices()
+            .insert(device.id.clone(), Arc::new(device));
+    }
+
+    fn expect_set(requests_rx: &Receiver<Request>, payload: &str) {
+        match requests_rx.try_recv().unwrap() {
+            Request::Publish(publish) => {
+                assert_eq!(
+                    publish.topic,
+                    "base_topic/device_id/node_id/property_id/set"
+                );
+                assert_eq!(publish.payload, payload.as_bytes());
+                assert!(!publish.retain);
+            }
+            request => panic!("Unexpected request {:?}", request),
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatch_to_handlers() {
+        for parallel in [false, true] {
+            let (mut controller, _requests_rx) = make_test_controller();
+            controller.set_parallel_handlers(parallel);
+            let received = Arc::new(Mutex::new(vec![]));
+
+            let received_first = received.clone();
+            controller.on_event(move |event| {
+                received_first.lock().unwrap().push((1, event));
+                async {}
+            });
+            controller.on_event(|_| async { panic!("Handler failed") });
+            controller.on_event(|_| -> futures::future::Ready<()> { panic!("Handler failed") });
+            let received_second = received.clone();
+            controller.on_event(move |event| {
+                let received_second = received_second.clone();
+                async move {
+                    received_second.lock().unwrap().push((2, event));
+                }
+            });
+
+            controller.dispatch(Event::Connected).await;
+            controller.dispatch(Event::Connected).await;
+
+            assert_eq!(
+                *received.lock().unwrap(),
+                vec![
+                    (1, Event::Connected),
+                    (2, Event::Connected),
+                    (1, Event::Connected),
+                    (2, Event::Connected),
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn set_with_options_and_raw() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, requests_rx) = make_test_controller();
+
+        controller
+            .set_with_options(
+                "device_id",
+                "node_id",
+                "property_id",
+                42,
+                SetOptions {
+                    qos: QoS::ExactlyOnce,
+                    retain: true,
+                },
+            )
+            .await?;
+        match requests_rx.try_recv()? {
+            Request::Publish(publish) => {
+                assert_eq!(
+                    publish.topic,
+                    "base_topic/device_id/node_id/property_id/set"
+                );
+                assert_eq!(publish.payload, "42".as_bytes());
+                assert_eq!(publish.qos, QoS::ExactlyOnce);
+                assert!(publish.retain);
+            }
+            request => panic!("Unexpected request {:?}", request),
+        }
+
+        controller
+            .set_raw(
+                "device_id",
+                "node_id",
+                "property_id",
+                vec![0xff, 0x00],
+                SetOptions::default(),
+            )
+            .await?;
+        match requests_rx.try_recv()? {
+            Request::Publish(publish) => {
+                assert_eq!(publish.payload, vec![0xff, 0x00]);
+                assert_eq!(publish.qos, QoS::AtLeastOnce);
+                assert!(!publish.retain);
+            }
+            request => panic!("Unexpected request {:?}", request),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn command_returns_next_fresh_result() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, requests_rx) = make_test_controller();
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        publish(&controller, "base_topic/device_id/$nodes", "node_id").await?;
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/$properties",
+            "command,result",
+        )
+        .await?;
+        publish_retained(&controller, "base_topic/device_id/node_id/result", "old").await?;
+        requests_rx.drain();
+
+        let command = controller.command(
+            "device_id",
+            "node_id",
+            "command",
+            "result",
+            "ping".to_owned(),
+            Duration::from_secs(10),
+        );
+        let respond = async {
+            // Wait for the command to be sent before responding.
+            while requests_rx.is_empty() {
+                tokio::task::yield_now().await;
+            }
+            publish_retained(&controller, "base_topic/device_id/node_id/result", "stale").await?;
+            publish(&controller, "base_topic/device_id/node_id/other", "other").await?;
+            publish(&controller, "base_topic/device_id/node_id/result", "pong").await
+        };
+        let (result, _) = futures::future::try_join(
+            async { command.await.map_err(Box::<dyn std::error::Error>::from) },
+            async { respond.await.map_err(Box::<dyn std::error::Error>::from) },
+        )
+        .await?;
+        assert_eq!(result, "pong");
+        match requests_rx.try_recv()? {
+            Request::Publish(publish) => {
+                assert_eq!(publish.topic, "base_topic/device_id/node_id/command/set");
+                assert_eq!(publish.payload, "ping".as_bytes());
+            }
+            request => panic!("Unexpected request {:?}", request),
+        }
+        assert!(controller.value_waiters.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn command_times_out() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+
+        let result = controller
+            .command(
+                "device_id",
+                "node_id",
+                "command",
+                "result",
+                1,
+                Duration::from_millis(10),
+            )
+            .await;
+        assert!(matches!(result, Err(CommandError::Timeout { .. })));
+
+        // The cancelled waiter is cleaned up when the next event is handled.
+        connect(&controller).await?;
+        assert!(controller.value_waiters.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancelled_poll_resumed() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut controller, _requests_rx) = make_test_controller();
+        connect(&controller).await?;
+
+        // Use a client whose queue is already full, so that sending subscriptions blocks.
+        let (full_tx, _full_rx) = flume::bounded(1);
+        full_tx.send(Request::Disconnect(Disconnect))?;
+        controller.mqtt_client = AsyncClient::from_senders(full_tx);
+        assert!(publish(&controller, "base_topic/device_id/$homie", "4.0")
+            .now_or_never()
+            .is_none());
+
+        // The subscriptions and event should be resumed once there is space.
+        let (requests_tx, requests_rx) = flume::unbounded();
+        controller.mqtt_client = AsyncClient::from_senders(requests_tx);
+        assert_eq!(
+            controller.resume_interrupted().await?,
+            vec![Event::DeviceUpdated {
+                device_id: "device_id".to_owned(),
+                has_required_attributes: false,
+                fresh: true,
+            }]
+        );
+        expect_subscriptions(
+            &requests_rx,
+            &[
+                "base_topic/device_id/+",
+                "base_topic/device_id/$fw/+",
+                "base_topic/device_id/$stats/+",
+                "base_topic/device_id/$implementation/#",
+                "base_topic/device_id/$meta/#",
+            ],
+        );
+        assert!(requests_rx.is_empty());
+        assert_eq!(controller.resume_interrupted().await?, vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn poll_after_shutdown() {
+        let (controller, mut event_loop) =
+            HomieController::new(MqttOptions::new("client_id", "localhost", 1), "base_topic");
+        controller.shut_down.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            controller.poll(&mut event_loop).await,
+            Err(PollError::Shutdown)
+        ));
+        assert!(matches!(
+            controller.run(&mut event_loop).await,
+            Err(PollError::Shutdown)
+        ));
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume_values() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, requests_rx) = make_test_controller();
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        publish(&controller, "base_topic/device_id/$nodes", "node_id").await?;
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/$properties",
+            "property_id",
+        )
+        .await?;
+        requests_rx.drain();
+
+        // Pausing replaces the node wildcard with its attributes, but keeps the property
+        // attributes.
+        controller.pause_values().await?;
+        assert!(controller.values_paused());
+        let requests: Vec<_> = requests_rx.drain().collect();
+        assert_eq!(
+            requests,
+            vec![
+                Request::Subscribe(Subscribe::new(
+                    "base_topic/device_id/node_id/$name",
+                    QoS::AtLeastOnce
+                )),
+                Request::Subscribe(Subscribe::new(
+                    "base_topic/device_id/node_id/$type",
+                    QoS::AtLeastOnce
+                )),
+                Request::Subscribe(Subscribe::new(
+                    "base_topic/device_id/node_id/$properties",
+                    QoS::AtLeastOnce
+                )),
+                Request::Unsubscribe(Unsubscribe::new("base_topic/device_id/node_id/+")),
+            ]
+        );
+
+        // Pausing again does nothing.
+        controller.pause_values().await?;
+        assert!(requests_rx.is_empty());
+
+        // Nodes discovered while paused are subscribed to in the same way.
+        publish(&controller, "base_topic/device_id/$nodes", "node_id,other").await?;
+        expect_subscriptions(
+            &requests_rx,
+            &[
+                "base_topic/device_id/other/$name",
+                "base_topic/device_id/other/$type",
+                "base_topic/device_id/other/$properties",
+            ],
+        );
+        assert!(requests_rx.is_empty());
+
+        // Resuming subscribes to the wildcards again.
+        controller.resume_values().await?;
+        assert!(!controller.values_paused());
+        let mut subscribed = vec![];
+        let mut unsubscribed = vec![];
+        for request in requests_rx.drain() {
+            match request {
+                Request::Subscribe(subscribe) => {
+                    subscribed.extend(subscribe.filters.into_iter().map(|filter| filter.path))
+                }
+                Request::Unsubscribe(unsubscribe) => unsubscribed.extend(unsubscribe.topics),
+                request => panic!("Unexpected request {:?}", request),
+            }
+        }
+        subscribed.sort();
+        assert_eq!(
+            subscribed,
+            vec![
+                "base_topic/device_id/node_id/+",
+                "base_topic/device_id/other/+"
+            ]
+        );
+        assert_eq!(unsubscribed.len(), 6);
+        assert!(unsubscribed.contains(&"base_topic/device_id/other/$properties".to_owned()));
+
+        // Resuming again does nothing.
+        controller.resume_values().await?;
+        assert!(requests_rx.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn purge_device_clears_known_topics() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, requests_rx) = make_test_controller();
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        publish(&controller, "base_topic/device_id/$name", "Device name").await?;
+        publish(&controller, "base_topic/device_id/$state", "lost").await?;
+        publish(&controller, "base_topic/device_id/$stats/uptime", "42").await?;
+        publish(&controller, "base_topic/device_id/$custom", "value").await?;
+        publish(&controller, "base_topic/device_id/$nodes", "node_id").await?;
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/$properties",
+            "property_id",
+        )
+        .await?;
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/property_id/$datatype",
+            "integer",
+        )
+        .await?;
+        publish(&controller, "base_topic/device_id/node_id/property_id", "7").await?;
+        requests_rx.drain();
+
+        let expected_topics = vec![
+            "base_topic/device_id/$name",
+            "base_topic/device_id/$state",
+            "base_topic/device_id/$stats/uptime",
+            "base_topic/device_id/$nodes",
+            "base_topic/device_id/$custom",
+            "base_topic/device_id/node_id/$properties",
+            "base_topic/device_id/node_id/property_id",
+            "base_topic/device_id/node_id/property_id/$datatype",
+            "base_topic/device_id/node_id/property_id/$settable",
+            "base_topic/device_id/node_id/property_id/$retained",
+            "base_topic/device_id/$homie",
+        ];
+        assert_eq!(
+            controller.purge_device("device_id", true).await?,
+            expected_topics
+        );
+        assert!(requests_rx.is_empty());
+        assert!(controller.devices().contains_key("device_id"));
+
+        assert_eq!(
+            controller.purge_device("device_id", false).await?,
+            expected_topics
+        );
+        assert!(!controller.devices().contains_key("device_id"));
+        let mut unsubscribed = vec![];
+        let mut published = vec![];
+        for request in requests_rx.drain() {
+            match request {
+                Request::Unsubscribe(unsubscribe) => unsubscribed.extend(unsubscribe.topics),
+                Request::Publish(publish) => {
+                    assert!(publish.retain);
+                    assert!(publish.payload.is_empty());
+                    published.push(publish.topic);
+                }
+                request => panic!("Unexpected request {:?}", request),
+            }
+        }
+        assert!(unsubscribed.contains(&"base_topic/device_id/+".to_owned()));
+        assert!(unsubscribed.contains(&"base_topic/device_id/node_id/property_id/+".to_owned()));
+        assert_eq!(published, expected_topics);
+
+        // The empty $homie message echoed back by the broker doesn't rediscover the device.
+        publish_retained(&controller, "base_topic/device_id/$homie", "").await?;
+        assert!(!controller.devices().contains_key("device_id"));
+        assert_eq!(
+            controller.purge_device("device_id", false).await?,
+            Vec::<String>::new()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn purge_device_keeps_device_if_publishing_fails(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, requests_rx) = make_test_controller();
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        publish(&controller, "base_topic/device_id/$name", "Device name").await?;
+
+        // Closing the request channel makes every publish fail.
+        drop(requests_rx);
+        assert!(controller.purge_device("device_id", false).await.is_err());
+        assert!(controller.devices().contains_key("device_id"));
         assert_eq!(
-            controller.devices().get("device_id").unwrap().to_owned(),
-            expected_device
+            controller.purge_device("device_id", true).await?,
+            vec!["base_topic/device_id/$name", "base_topic/device_id/$homie"]
         );
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn wait_for_state_until_ready() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        publish(&controller, "base_topic/device_id/$state", "init").await?;
+
+        let wait = controller.wait_for_state("device_id", State::Ready, Duration::from_secs(10));
+        let update = async {
+            publish(&controller, "base_topic/device_id/$state", "alert").await?;
+            publish(&controller, "base_topic/device_id/$state", "ready").await
+        };
+        let (result, _) = futures::future::join(wait, update).await;
+        assert_eq!(result, Ok(()));
+        assert!(controller.condition_waiters.lock().unwrap().is_empty());
+
+        // The device is already ready, so this doesn't need to wait.
+        controller
+            .wait_for_state("device_id", State::Ready, Duration::ZERO)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wait_for_state_times_out() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+
+        let result = controller
+            .wait_for_state("device_id", State::Ready, Duration::from_millis(10))
+            .await;
+        assert_eq!(
+            result,
+            Err(WaitError::State {
+                device_id: "device_id".to_owned(),
+                state: State::Ready,
+            })
+        );
+
+        // The cancelled waiter is cleaned up when the next event is handled.
+        connect(&controller).await?;
+        assert!(controller.condition_waiters.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wait_for_value_matching() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        publish(&controller, "base_topic/device_id/$nodes", "node_id").await?;
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/$properties",
+            "level",
+        )
+        .await?;
+        publish(&controller, "base_topic/device_id/node_id/level", "10").await?;
+
+        let wait = controller.wait_for_value(
+            "device_id",
+            "node_id",
+            "level",
+            |value| value.parse::<i64>().is_ok_and(|level| level >= 50),
+            Duration::from_secs(10),
+        );
+        let update = async {
+            publish(&controller, "base_topic/device_id/node_id/level", "30").await?;
+            publish(&controller, "base_topic/device_id/node_id/level", "60").await
+        };
+        let (result, _) = futures::future::join(wait, update).await;
+        assert_eq!(result, Ok("60".to_owned()));
+
+        let result = controller
+            .wait_for_value(
+                "device_id",
+                "node_id",
+                "level",
+                |value| value == "100",
+                Duration::from_millis(10),
+            )
+            .await;
+        assert_eq!(
+            result,
+            Err(WaitError::Value {
+                device_id: "device_id".to_owned(),
+                node_id: "node_id".to_owned(),
+                property_id: "level".to_owned(),
+            })
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_checked_validates() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, requests_rx) = make_test_controller();
+
+        assert!(matches!(
+            controller
+                .set_checked("device_id", "node_id", "property_id", 5)
+                .await,
+            Err(SetError::UnknownDevice { .. })
+        ));
+
+        let property = Property {
+            datatype: Some(Datatype::Integer),
+            format: Some("0:10".to_owned()),
+            ..Property::new("property_id")
+        };
+        let node = Node {
+            properties: property_set(vec![property.clone()]),
+            ..Node::new("node_id")
+        };
+        let device = Device {
+            nodes: node_set(vec![node.clone()]),
+            ..Device::new("device_id", "4.0")
+        };
+        controller
+            .write_devices()
+            .insert(device.id.clone(), Arc::new(device.clone()));
+        assert!(matches!(
+            controller
+                .set_checked("device_id", "node_id", "property_id", 5)
+                .await,
+            Err(SetError::NotSettable { .. })
+        ));
+
+        let property = Property {
+            settable: true,
+            ..property
+        };
+        let node = Node {
+            properties: property_set(vec![property]),
+            ..node
+        };
+        let device = Device {
+            nodes: node_set(vec![node]),
+            ..device
+        };
+        controller
+            .write_devices()
+            .insert(device.id.clone(), Arc::new(device));
+        assert!(matches!(
+            controller
+                .set_checked("device_id", "node_id", "property_id", 11)
+                .await,
+            Err(SetError::InvalidValue(ValueError::NotInFormat { .. }))
+        ));
+        assert!(matches!(
+            controller
+                .set_checked("device_id", "node_id", "property_id", true)
+                .await,
+            Err(SetError::InvalidValue(ValueError::WrongDatatype { .. }))
+        ));
+        assert!(requests_rx.is_empty());
+
+        controller
+            .set_checked("device_id", "node_id", "property_id", 10)
+            .await?;
+        expect_set(&requests_rx, "10");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_color_converts_format() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, requests_rx) = make_test_controller();
+
+        add_color_property(&controller, Some("hsv"));
+        controller
+            .set_color(
+                "device_id",
+                "node_id",
+                "property_id",
+                ColorRgb::new(255, 0, 0),
+            )
+            .await?;
+        expect_set(&requests_rx, "0,100,100");
+        controller
+            .set_color(
+                "device_id",
+                "node_id",
+                "property_id",
+                ColorHsv::new(120, 50, 50),
+            )
+            .await?;
+        expect_set(&requests_rx, "120,50,50");
+
+        add_color_property(&controller, Some("rgb"));
+        controller
+            .set_color(
+                "device_id",
+                "node_id",
+                "property_id",
+                ColorHsv::new(240, 100, 100),
+            )
+            .await?;
+        expect_set(&requests_rx, "0,0,255");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_color_fails_for_invalid_property() {
+        let (controller, requests_rx) = make_test_controller();
+
+        assert!(matches!(
+            controller
+                .set_color(
+                    "device_id",
+                    "node_id",
+                    "property_id",
+                    ColorRgb::new(1, 2, 3)
+                )
+                .await,
+            Err(SetError::UnknownDevice { .. })
+        ));
+
+        add_color_property(&controller, None);
+        assert!(matches!(
+            controller
+                .set_color("device_id", "node_id", "other_id", ColorRgb::new(1, 2, 3))
+                .await,
+            Err(SetError::UnknownProperty { .. })
+        ));
+        assert!(matches!(
+            controller
+                .set_color(
+                    "device_id",
+                    "node_id",
+                    "property_id",
+                    ColorRgb::new(1, 2, 3)
+                )
+                .await,
+            Err(SetError::InvalidValue(ValueError::Unknown))
+        ));
+
+        add_color_property(&controller, Some("xyz"));
+        assert!(matches!(
+            controller
+                .set_color(
+                    "device_id",
+                    "node_id",
+                    "property_id",
+                    ColorRgb::new(1, 2, 3)
+                )
+                .await,
+            Err(SetError::InvalidValue(ValueError::WrongFormat { .. }))
+        ));
+
+        assert!(requests_rx.is_empty());
+    }
+
+    async fn publish_bytes(
+        controller: &HomieController,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<Vec<Event>, PollError> {
+        controller
+            .handle_event(Packet::Publish(Publish::new(
+                topic,
+                QoS::AtLeastOnce,
+                payload.to_vec(),
+            )))
+            .await
+    }
+
+    #[tokio::test]
+    async fn non_utf8_value_ignored_by_default() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+        add_color_property(&controller, None);
+
+        let events = publish_bytes(
+            &controller,
+            "base_topic/device_id/node_id/property_id",
+            &[0x41, 0xff],
+        )
+        .await?;
+        assert_eq!(events, vec![]);
+        assert_eq!(
+            controller.devices()["device_id"].nodes["node_id"].properties["property_id"].value,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn non_utf8_value_lossy() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut controller, _requests_rx) = make_test_controller();
+        controller.set_non_utf8_payloads(NonUtf8Payloads::Lossy);
+        add_color_property(&controller, None);
+
+        let events = publish_bytes(
+            &controller,
+            "base_topic/device_id/node_id/property_id",
+            &[0x41, 0xff],
+        )
+        .await?;
+        assert_eq!(
+            events,
+            vec![Event::PropertyValueChanged {
+                device_id: "device_id".to_owned(),
+                node_id: "node_id".to_owned(),
+                property_id: "property_id".to_owned(),
+                value: "A\u{fffd}".to_owned(),
+                fresh: true,
+            }]
+        );
+
+        // Attributes are still ignored.
+        let events =
+            publish_bytes(&controller, "base_topic/device_id/$name", &[0x41, 0xff]).await?;
+        assert_eq!(events, vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn non_utf8_value_binary() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut controller, _requests_rx) = make_test_controller();
+        controller.set_non_utf8_payloads(NonUtf8Payloads::Binary);
+        add_color_property(&controller, None);
+
+        let events = publish_bytes(
+            &controller,
+            "base_topic/device_id/node_id/property_id",
+            &[0x41, 0xff],
+        )
+        .await?;
+        assert_eq!(
+            events,
+            vec![Event::BinaryPropertyValue {
+                device_id: "device_id".to_owned(),
+                node_id: "node_id".to_owned(),
+                property_id: "property_id".to_owned(),
+                value: vec![0x41, 0xff],
+                fresh: true,
+            }]
+        );
+        assert_eq!(
+            controller.devices()["device_id"].nodes["node_id"].properties["property_id"].value,
+            None
+        );
+
+        // Values for unknown properties are ignored.
+        let events = publish_bytes(
+            &controller,
+            "base_topic/device_id/node_id/other_id",
+            &[0x41, 0xff],
+        )
+        .await?;
+        assert_eq!(events, vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retained_attributes_not_fresh() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+
+        assert_eq!(
+            publish_retained(&controller, "base_topic/device_id/$homie", "4.0").await?,
+            vec![Event::DeviceUpdated {
+                device_id: "device_id".to_owned(),
+                has_required_attributes: false,
+                fresh: false,
+            }]
+        );
+        publish_retained(&controller, "base_topic/device_id/$nodes", "node_id").await?;
+        assert_eq!(
+            publish_retained(&controller, "base_topic/device_id/node_id/$name", "Node").await?,
+            vec![Event::NodeUpdated {
+                device_id: "device_id".to_owned(),
+                node_id: "node_id".to_owned(),
+                has_required_attributes: false,
+                fresh: false,
+            }]
+        );
+        assert_eq!(
+            publish(&controller, "base_topic/device_id/$name", "Device").await?,
+            vec![Event::DeviceUpdated {
+                device_id: "device_id".to_owned(),
+                has_required_attributes: false,
+                fresh: true,
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn other_device_attributes() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        assert_eq!(
+            publish(
+                &controller,
+                "base_topic/device_id/$implementation/config",
+                "{}"
+            )
+            .await?,
+            vec![Event::DeviceUpdated {
+                device_id: "device_id".to_owned(),
+                has_required_attributes: false,
+                fresh: true,
+            }]
+        );
+        publish(&controller, "base_topic/device_id/$custom", "old").await?;
+        publish(&controller, "base_topic/device_id/$custom", "value").await?;
+        publish(&controller, "base_topic/device_id/$fw/checksum", "abc").await?;
+        publish(
+            &controller,
+            "base_topic/device_id/$implementation/ota/a/b/c",
+            "deep",
+        )
+        .await?;
+        publish(
+            &controller,
+            "base_topic/device_id/$implementation",
+            "esp8266",
+        )
+        .await?;
+        assert_eq!(
+            controller.devices()["device_id"].attributes,
+            HashMap::from([
+                ("$implementation/config".to_owned(), "{}".to_owned()),
+                ("$custom".to_owned(), "value".to_owned()),
+                ("$fw/checksum".to_owned(), "abc".to_owned()),
+                ("$implementation/ota/a/b/c".to_owned(), "deep".to_owned()),
+            ])
+        );
+        assert_eq!(
+            controller.devices()["device_id"].implementation,
+            Some("esp8266".to_owned())
+        );
+
+        // An empty payload removes the attribute.
+        publish(&controller, "base_topic/device_id/$custom", "").await?;
+        assert!(!controller.devices()["device_id"]
+            .attributes
+            .contains_key("$custom"));
+
+        // Large payloads such as firmware images aren't stored.
+        let firmware = "x".repeat(MAX_OTHER_ATTRIBUTE_SIZE + 1);
+        assert_eq!(
+            publish(
+                &controller,
+                "base_topic/device_id/$implementation/ota/firmware",
+                &firmware,
+            )
+            .await?,
+            vec![]
+        );
+        assert!(!controller.devices()["device_id"]
+            .attributes
+            .contains_key("$implementation/ota/firmware"));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn event_value_json() {
+        let event = Event::PropertyValueChanged {
+            device_id: "device_id".to_owned(),
+            node_id: "node_id".to_owned(),
+            property_id: "property_id".to_owned(),
+            value: "[1,2]".to_owned(),
+            fresh: true,
+        };
+        assert_eq!(event.value_json::<Vec<u8>>(), Some(Ok(vec![1, 2])));
+        assert!(matches!(
+            event.value_json::<String>(),
+            Some(Err(ValueError::InvalidJson { .. }))
+        ));
+
+        let event = Event::DeviceUpdated {
+            device_id: "device_id".to_owned(),
+            has_required_attributes: true,
+            fresh: true,
+        };
+        assert_eq!(event.value_json::<Vec<u8>>(), None);
+    }
+
+    #[tokio::test]
+    async fn device_extensions() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        assert_eq!(
+            publish(&controller, "base_topic/device_id/$extensions", "").await?,
+            vec![Event::DeviceUpdated {
+                device_id: "device_id".to_owned(),
+                has_required_attributes: false,
+                fresh: true,
+            }]
+        );
+        assert_eq!(controller.devices()["device_id"].extensions, vec![]);
+
+        publish(
+            &controller,
+            "base_topic/device_id/$extensions",
+            "org.homie.legacy-firmware:0.1.1:[4.x],esp-stats",
+        )
+        .await?;
+        assert_eq!(
+            controller.devices()["device_id"].extensions,
+            vec![
+                Extension::Standard {
+                    id: "org.homie.legacy-firmware".to_owned(),
+                    version: "0.1.1".to_owned(),
+                    homie_versions: vec!["4.x".to_owned()],
+                },
+                Extension::Custom("esp-stats".to_owned()),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lenient_mode_ignores_invalid_messages() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+
+        publish(&controller, "base_topic/Device_1/$homie", "4.0").await?;
+        assert_eq!(
+            publish(&controller, "base_topic/Device_1/$state", "broken").await?,
+            vec![]
+        );
+        assert!(controller.devices()["Device_1"]
+            .validation_errors
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn strict_mode_reports_invalid_messages() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut controller, _requests_rx) = make_test_controller();
+        controller.set_strict(true);
+
+        assert_eq!(
+            publish(&controller, "base_topic/Device_1/$homie", "4.0").await?,
+            vec![
+                Event::DeviceUpdated {
+                    device_id: "Device_1".to_owned(),
+                    has_required_attributes: false,
+                    fresh: true,
+                },
+                Event::ValidationError {
+                    device_id: "Device_1".to_owned(),
+                    topic: "base_topic/Device_1/$homie".to_owned(),
+                    message: "Invalid device ID 'Device_1'".to_owned(),
+                },
+            ]
+        );
+
+        assert_eq!(
+            publish(&controller, "base_topic/Device_1/$state", "broken").await?,
+            vec![Event::ValidationError {
+                device_id: "Device_1".to_owned(),
+                topic: "base_topic/Device_1/$state".to_owned(),
+                message: "Invalid state 'broken'".to_owned(),
+            }]
+        );
+
+        let events = publish(&controller, "base_topic/Device_1/$nodes", "node,Node_2").await?;
+        assert_eq!(
+            events[1..],
+            [Event::ValidationError {
+                device_id: "Device_1".to_owned(),
+                topic: "base_topic/Device_1/$nodes".to_owned(),
+                message: "Invalid node ID 'Node_2'".to_owned(),
+            }]
+        );
+
+        let events = publish(&controller, "base_topic/Device_1/node/$properties", "a_b").await?;
+        assert!(matches!(
+            &events[1],
+            Event::ValidationError { message, .. }
+                if message == "Invalid property ID 'a_b' for node 'node'"
+        ));
+
+        assert_eq!(
+            controller.devices()["Device_1"].validation_errors,
+            vec![
+                "Invalid device ID 'Device_1'".to_owned(),
+                "Invalid state 'broken'".to_owned(),
+                "Invalid node ID 'Node_2'".to_owned(),
+                "Invalid property ID 'a_b' for node 'node'".to_owned(),
+            ]
+        );
+
+        // Errors for unknown devices are still reported.
+        assert!(matches!(
+            &publish(&controller, "base_topic/other/$name", "Name").await?[..],
+            [Event::ValidationError { device_id, .. }] if device_id == "other"
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn presumes_lost_after_missed_stats() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut controller, _requests_rx) = make_test_controller();
+        controller.set_presume_lost_after(Some(3));
+
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        publish(&controller, "base_topic/device_id/$state", "ready").await?;
+        publish(&controller, "base_topic/device_id/$stats/interval", "60").await?;
+        publish(&controller, "base_topic/device_id/$stats/uptime", "10").await?;
+        let received = controller.devices()["device_id"].stats_received.unwrap();
+
+        assert_eq!(
+            controller.presume_lost_devices(received + Duration::from_secs(170)),
+            vec![]
+        );
+        assert_eq!(
+            controller.presume_lost_devices(received + Duration::from_secs(190)),
+            vec![Event::DeviceUpdated {
+                device_id: "device_id".to_owned(),
+                has_required_attributes: false,
+                fresh: true,
+            }]
+        );
+        assert_eq!(controller.devices()["device_id"].state, State::PresumedLost);
+        // It is only presumed lost once.
+        assert_eq!(
+            controller.presume_lost_devices(received + Duration::from_secs(250)),
+            vec![]
+        );
+
+        // The previous state is restored when stats arrive again.
+        publish(&controller, "base_topic/device_id/$stats/uptime", "250").await?;
+        assert_eq!(controller.devices()["device_id"].state, State::Ready);
+
+        // Reconnecting forgets which devices were presumed lost.
+        controller.presume_lost_devices(received + Duration::from_secs(1000));
+        connect(&controller).await?;
+        assert!(controller.presumed_lost.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn huge_stats_interval_never_lost() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut controller, _requests_rx) = make_test_controller();
+        controller.set_presume_lost_after(Some(3));
+
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        publish(&controller, "base_topic/device_id/$state", "ready").await?;
+        publish(
+            &controller,
+            "base_topic/device_id/$stats/interval",
+            &u64::MAX.to_string(),
+        )
+        .await?;
+        publish(&controller, "base_topic/device_id/$stats/uptime", "10").await?;
+        let received = controller.devices()["device_id"].stats_received.unwrap();
+
+        assert_eq!(
+            controller.presume_lost_devices(received + Duration::from_secs(1_000_000)),
+            vec![]
+        );
+        assert_eq!(controller.devices()["device_id"].state, State::Ready);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn groups_from_config_and_meta_tags() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut controller, _requests_rx) = make_test_controller();
+        let mut groups = HashMap::new();
+        groups.insert(
+            "hall".to_owned(),
+            vec![DeviceMatcher::IdPrefix("hall-".to_owned())],
+        );
+        controller.set_groups(groups);
+        controller.set_group_meta_key(Some("room"));
+
+        publish(&controller, "base_topic/hall-light/$homie", "4.0").await?;
+        publish(&controller, "base_topic/sensor/$homie", "4.0").await?;
+        publish(&controller, "base_topic/sensor/$meta/1/$key", "room").await?;
+        publish(&controller, "base_topic/sensor/$meta/1/$value", "kitchen").await?;
+
+        assert_eq!(controller.group_names(), vec!["hall", "kitchen"]);
+        assert_eq!(
+            controller
+                .devices_in_group("hall")
+                .iter()
+                .map(|device| device.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["hall-light"]
+        );
+        assert_eq!(
+            controller
+                .devices_in_group("kitchen")
+                .iter()
+                .map(|device| device.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["sensor"]
+        );
+        assert!(controller.group("bedroom").is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn borrowed_accessors() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        publish(&controller, "base_topic/device_id/$nodes", "node_id").await?;
+        publish(&controller, "base_topic/device_id/node_id/$type", "sensor").await?;
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/$properties",
+            "property_id",
+        )
+        .await?;
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/property_id",
+            "42",
+        )
+        .await?;
+
+        assert_eq!(controller.with_devices(|devices| devices.len()), 1);
+        assert_eq!(
+            controller.with_device("device_id", |device| device.homie_version.clone()),
+            Some("4.0".to_owned())
+        );
+        assert_eq!(
+            controller.with_node("device_id", "node_id", |node| node.node_type.clone()),
+            Some(Some("sensor".to_owned()))
+        );
+        assert_eq!(
+            controller.with_property("device_id", "node_id", "property_id", |property| {
+                property.value.clone()
+            }),
+            Some(Some("42".to_owned()))
+        );
+        assert_eq!(controller.with_device("other", |_| ()), None);
+        assert_eq!(controller.with_node("device_id", "other", |_| ()), None);
+        assert_eq!(
+            controller.with_property("device_id", "node_id", "other", |_| ()),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ignores_pathological_id_lists() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, requests_rx) = make_test_controller();
+
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        requests_rx.drain();
+
+        publish(
+            &controller,
+            "base_topic/device_id/$nodes",
+            "node,,node,$state,a/b,+,#,other",
+        )
+        .await?;
+        expect_subscriptions(
+            &requests_rx,
+            &[
+                "base_topic/device_id/node/+",
+                "base_topic/device_id/other/+",
+            ],
+        );
+        let mut node_ids: Vec<_> = controller.devices()["device_id"]
+            .nodes
+            .keys()
+            .cloned()
+            .collect();
+        node_ids.sort();
+        assert_eq!(node_ids, vec!["node", "other"]);
+
+        publish(
+            &controller,
+            "base_topic/device_id/node/$properties",
+            "a,a,,b/+,b",
+        )
+        .await?;
+        expect_subscriptions(
+            &requests_rx,
+            &[
+                "base_topic/device_id/node/a/+",
+                "base_topic/device_id/node/b/+",
+            ],
+        );
+        assert_eq!(
+            controller.devices()["device_id"].nodes["node"]
+                .properties
+                .len(),
+            2
+        );
+
+        // An empty list removes all nodes.
+        publish(&controller, "base_topic/device_id/$nodes", "").await?;
+        assert!(controller.devices()["device_id"].nodes.is_empty());
+
+        // Nothing is reported in lenient mode.
+        assert!(controller.devices()["device_id"]
+            .validation_errors
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn strict_mode_reports_ignored_ids() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut controller, _requests_rx) = make_test_controller();
+        controller.set_strict(true);
+
+        publish(&controller, "base_topic/device-id/$homie", "4.0").await?;
+        let events = publish(&controller, "base_topic/device-id/$nodes", "node,node,").await?;
+        assert_eq!(
+            events[1..],
+            [
+                Event::ValidationError {
+                    device_id: "device-id".to_owned(),
+                    topic: "base_topic/device-id/$nodes".to_owned(),
+                    message: "Ignoring duplicate node ID 'node'".to_owned(),
+                },
+                Event::ValidationError {
+                    device_id: "device-id".to_owned(),
+                    topic: "base_topic/device-id/$nodes".to_owned(),
+                    message: "Ignoring unusable node ID ''".to_owned(),
+                },
+            ]
+        );
+
+        let events = publish(&controller, "base_topic/device-id/node/$properties", "$a").await?;
+        assert!(matches!(
+            &events[1],
+            Event::ValidationError { message, .. }
+                if message == "Ignoring unusable property ID '$a' for node 'node'"
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metrics_count_messages_per_device() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut controller, _requests_rx) = make_test_controller();
+        assert!(controller.device_message_rates().is_empty());
+        controller.set_metrics(Some(MetricsConfig {
+            per_property: true,
+            ..Default::default()
+        }));
+
+        publish(&controller, "base_topic/device_id/$homie", "4.0").await?;
+        publish(
+            &controller,
+            "base_topic/device_id/node_id/property_id",
+            "42",
+        )
+        .await?;
+        publish(&controller, "base_topic/other/$name", "Name").await?;
+        publish(&controller, "base_topic/$broadcast/alert", "Hello").await?;
+
+        let device_rates = controller.device_message_rates();
+        assert_eq!(device_rates.len(), 2);
+        assert_eq!(device_rates["device_id"].messages, 2);
+        assert_eq!(device_rates["device_id"].bytes, 5);
+        assert_eq!(device_rates["other"].messages, 1);
+
+        let property_rates = controller.property_message_rates();
+        assert_eq!(property_rates.len(), 1);
+        let rate = property_rates[&(
+            "device_id".to_owned(),
+            "node_id".to_owned(),
+            "property_id".to_owned(),
+        )];
+        assert_eq!(rate.messages, 1);
+        assert_eq!(rate.bytes, 2);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The number of buckets into which the metrics window is divided. Messages are counted in the
+/// current bucket, and the oldest bucket is discarded as time moves on.
+const BUCKETS: usize = 10;
+
+/// Configuration for tracking how many messages each device publishes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MetricsConfig {
+    /// The length of the rolling window over which messages are counted. Defaults to 1 minute.
+    pub window: Duration,
+    /// Whether to also count messages for each property value, as well as for each device.
+    /// Defaults to false.
+    pub per_property: bool,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60),
+            per_property: false,
+        }
+    }
+}
+
+/// The number of messages received from a device or for a property within the metrics window.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MessageRate {
+    /// The number of messages received.
+    pub messages: u64,
+    /// The total size in bytes of the payloads of the messages received.
+    pub bytes: u64,
+    /// The length of the window over which the messages were counted.
+    pub window: Duration,
+}
+
+impl MessageRate {
+    /// The average number of messages received per second over the window.
+    pub fn messages_per_second(&self) -> f64 {
+        self.messages as f64 / self.window.as_secs_f64()
+    }
+
+    /// The average number of payload bytes received per second over the window.
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / self.window.as_secs_f64()
+    }
+}
+
+/// A count of messages and bytes over a rolling window.
+#[derive(Clone, Debug)]
+struct RollingCounter {
+    /// Message and byte counts for each bucket, indexed by period modulo `BUCKETS`.
+    buckets: [(u64, u64); BUCKETS],
+    /// The period which the latest bucket is for.
+    period: u64,
+}
+
+impl RollingCounter {
+    fn new(period: u64) -> Self {
+        Self {
+            buckets: [(0, 0); BUCKETS],
+            period,
+        }
+    }
+
+    /// Moves the window on to the given period, discarding any buckets which are now too old.
+    fn advance(&mut self, period: u64) {
+        if period <= self.period {
+            return;
+        }
+        let expired = (period - self.period).min(BUCKETS as u64);
+        for i in 1..=expired {
+            self.buckets[((self.period + i) % BUCKETS as u64) as usize] = (0, 0);
+        }
+        self.period = period;
+    }
+
+    fn record(&mut self, period: u64, bytes: usize) {
+        self.advance(period);
+        let bucket = &mut self.buckets[(period % BUCKETS as u64) as usize];
+        bucket.0 += 1;
+        bucket.1 += bytes as u64;
+    }
+
+    /// Returns the total message and byte counts.
+    fn total(&self) -> (u64, u64) {
+        self.buckets
+            .iter()
+            .fold((0, 0), |(messages, bytes), bucket| {
+                (messages + bucket.0, bytes + bucket.1)
+            })
+    }
+}
+
+/// Counts messages received by the controller per device and optionally per property.
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    config: MetricsConfig,
+    start: Instant,
+    devices: HashMap<String, RollingCounter>,
+    properties: HashMap<(String, String, String), RollingCounter>,
+}
+
+impl Metrics {
+    pub fn new(config: MetricsConfig, now: Instant) -> Self {
+        Self {
+            config,
+            start: now,
+            devices: HashMap::new(),
+            properties: HashMap::new(),
+        }
+    }
+
+    fn period(&self, now: Instant) -> u64 {
+        let bucket_length = self.config.window / BUCKETS as u32;
+        (now.duration_since(self.start).as_nanos() / bucket_length.as_nanos().max(1)) as u64
+    }
+
+    /// Records a message from the given device, and for the given property if it is a property
+    /// value and per-property metrics are enabled.
+    pub fn record(
+        &mut self,
+        now: Instant,
+        device_id: &str,
+        property: Option<[&str; 3]>,
+        bytes: usize,
+    ) {
+        let period = self.period(now);
+        self.devices
+            .entry(device_id.to_owned())
+            .or_insert_with(|| RollingCounter::new(period))
+            .record(period, bytes);
+        if let (true, Some([device_id, node_id, property_id])) =
+            (self.config.per_property, property)
+        {
+            self.properties
+                .entry((
+                    device_id.to_owned(),
+                    node_id.to_owned(),
+                    property_id.to_owned(),
+                ))
+                .or_insert_with(|| RollingCounter::new(period))
+                .record(period, bytes);
+        }
+    }
+
+    /// Gets the message rates of each device which has sent any messages within the window, and
+    /// forgets about the others.
+    pub fn device_rates(&mut self, now: Instant) -> HashMap<String, MessageRate> {
+        let period = self.period(now);
+        rates(&mut self.devices, period, self.config.window)
+    }
+
+    /// Gets the message rates of each property which has had any values within the window, and
+    /// forgets about the others.
+    pub fn property_rates(
+        &mut self,
+        now: Instant,
+    ) -> HashMap<(String, String, String), MessageRate> {
+        let period = self.period(now);
+        rates(&mut self.properties, period, self.config.window)
+    }
+}
+
+fn rates<K: Clone + Eq + std::hash::Hash>(
+    counters: &mut HashMap<K, RollingCounter>,
+    period: u64,
+    window: Duration,
+) -> HashMap<K, MessageRate> {
+    for counter in counters.values_mut() {
+        counter.advance(period);
+    }
+    counters.retain(|_, counter| counter.total().0 > 0);
+    counters
+        .iter()
+        .map(|(key, counter)| {
+            let (messages, bytes) = counter.total();
+            (
+                key.clone(),
+                MessageRate {
+                    messages,
+                    bytes,
+                    window,
+                },
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(messages: u64, bytes: u64) -> MessageRate {
+        MessageRate {
+            messages,
+            bytes,
+            window: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn rolling_window() {
+        let start = Instant::now();
+        let config = MetricsConfig {
+            window: Duration::from_secs(10),
+            per_property: false,
+        };
+        let mut metrics = Metrics::new(config, start);
+
+        metrics.record(start, "a", None, 5);
+        metrics.record(start + Duration::from_secs(3), "a", None, 10);
+        metrics.record(start + Duration::from_secs(4), "b", None, 1);
+        assert_eq!(
+            metrics.device_rates(start + Duration::from_secs(5)),
+            HashMap::from([("a".to_owned(), rate(2, 15)), ("b".to_owned(), rate(1, 1))])
+        );
+
+        // After the window has passed, the first message is no longer counted.
+        assert_eq!(
+            metrics.device_rates(start + Duration::from_secs(10)),
+            HashMap::from([("a".to_owned(), rate(1, 10)), ("b".to_owned(), rate(1, 1))])
+        );
+
+        // Devices which haven't sent anything recently are forgotten.
+        assert_eq!(
+            metrics.device_rates(start + Duration::from_secs(60)),
+            HashMap::new()
+        );
+        assert!(metrics.devices.is_empty());
+    }
+
+    #[test]
+    fn per_property() {
+        let start = Instant::now();
+        let config = MetricsConfig {
+            window: Duration::from_secs(10),
+            per_property: true,
+        };
+        let mut metrics = Metrics::new(config, start);
+
+        metrics.record(start, "device", Some(["device", "node", "property"]), 3);
+        metrics.record(start, "device", None, 7);
+        assert_eq!(
+            metrics.device_rates(start),
+            HashMap::from([("device".to_owned(), rate(2, 10))])
+        );
+        assert_eq!(
+            metrics.property_rates(start),
+            HashMap::from([(
+                (
+                    "device".to_owned(),
+                    "node".to_owned(),
+                    "property".to_owned()
+                ),
+                rate(1, 3)
+            )])
+        );
+        assert_eq!(rate(1, 3).messages_per_second(), 0.1);
+    }
+
+    #[test]
+    fn properties_not_counted_by_default() {
+        let start = Instant::now();
+        let mut metrics = Metrics::new(MetricsConfig::default(), start);
+        metrics.record(start, "device", Some(["device", "node", "property"]), 3);
+        assert_eq!(metrics.property_rates(start), HashMap::new());
+    }
+}
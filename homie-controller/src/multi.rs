@@ -0,0 +1,264 @@
+use crate::{Device, Event, HomieController, HomieEventLoop, PollError};
+use rumqttc::{AsyncClient, Incoming};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// An event from a Homie device under one of the base topics of a `MultiHomieController`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TaggedEvent {
+    /// The base topic of the controller which produced the event.
+    pub base_topic: String,
+    pub event: Event,
+}
+
+/// A set of Homie controllers for several base topics, sharing a single connection to the MQTT
+/// broker. Create one with [`HomieController::new_multi`].
+///
+/// Each base topic has its own [`HomieController`], which can be configured individually and used
+/// to set properties of devices under that base topic. Base topics shouldn't be nested within each
+/// other.
+#[derive(Debug)]
+pub struct MultiHomieController {
+    controllers: Vec<HomieController>,
+}
+
+impl MultiHomieController {
+    /// Create controllers for the given base topics which all use the given MQTT client.
+    pub(crate) fn with_client(mqtt_client: AsyncClient, base_topics: &[&str]) -> Self {
+        Self {
+            controllers: base_topics
+                .iter()
+                .map(|base_topic| HomieController::with_client(mqtt_client.clone(), base_topic))
+                .collect(),
+        }
+    }
+
+    /// Get the base topics under which the controllers look for Homie devices.
+    pub fn base_topics(&self) -> impl Iterator<Item = &str> {
+        self.controllers
+            .iter()
+            .map(|controller| controller.base_topic())
+    }
+
+    /// Get the controller for the given base topic, if there is one.
+    pub fn controller(&self, base_topic: &str) -> Option<&HomieController> {
+        self.controllers
+            .iter()
+            .find(|controller| controller.base_topic() == base_topic)
+    }
+
+    /// Get the controller for the given base topic mutably, to configure it.
+    pub fn controller_mut(&mut self, base_topic: &str) -> Option<&mut HomieController> {
+        self.controllers
+            .iter_mut()
+            .find(|controller| controller.base_topic() == base_topic)
+    }
+
+    /// Get a snapshot of the Homie devices which have been discovered so far under all base topics,
+    /// keyed by their base topic and ID.
+    pub fn devices(&self) -> HashMap<(String, String), Arc<Device>> {
+        self.controllers
+            .iter()
+            .flat_map(|controller| {
+                let base_topic = controller.base_topic();
+                controller
+                    .devices()
+                    .iter()
+                    .map(|(device_id, device)| {
+                        (
+                            (base_topic.to_owned(), device_id.to_owned()),
+                            device.to_owned(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Poll the `EventLoop`, and maybe return some Homie events tagged with their base topics.
+    ///
+    /// A connection to the MQTT broker results in an `Event::Connected` for each base topic.
+    ///
+    /// As for [`HomieController::poll`], this is cancellation safe.
+    pub async fn poll(
+        &self,
+        event_loop: &mut HomieEventLoop,
+    ) -> Result<Vec<TaggedEvent>, PollError> {
+        let mut interrupted = vec![];
+        for controller in &self.controllers {
+            let base_topic = controller.base_topic();
+            interrupted.extend(
+                controller
+                    .resume_interrupted()
+                    .await?
+                    .into_iter()
+                    .map(|event| TaggedEvent {
+                        base_topic: base_topic.to_owned(),
+                        event,
+                    }),
+            );
+        }
+        if !interrupted.is_empty() {
+            return Ok(interrupted);
+        }
+
+        let notification = event_loop.event_loop.poll().await?;
+        log::trace!("Notification = {:?}", notification);
+
+        if let rumqttc::Event::Incoming(incoming) = notification {
+            self.handle_event(incoming).await
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    /// Repeatedly poll the `EventLoop`, and call the handlers registered on the controller for each
+    /// base topic with the events from that base topic.
+    ///
+    /// This only returns if there is an error polling. It can then be called again to reconnect.
+    pub async fn run(&self, event_loop: &mut HomieEventLoop) -> Result<(), PollError> {
+        loop {
+            for tagged in self.poll(event_loop).await? {
+                if let Some(controller) = self.controller(&tagged.base_topic) {
+                    controller.dispatch(tagged.event).await;
+                }
+            }
+        }
+    }
+
+    async fn handle_event(&self, incoming: Incoming) -> Result<Vec<TaggedEvent>, PollError> {
+        let controllers: Vec<&HomieController> = match &incoming {
+            Incoming::Publish(publish) => match self.controller_for_topic(&publish.topic) {
+                Some(controller) => vec![controller],
+                None => {
+                    log::warn!("Publish with unexpected topic: {:?}", publish);
+                    vec![]
+                }
+            },
+            _ => self.controllers.iter().collect(),
+        };
+        let mut events = vec![];
+        for controller in controllers {
+            let base_topic = controller.base_topic();
+            events.extend(
+                controller
+                    .handle_event(incoming.clone())
+                    .await?
+                    .into_iter()
+                    .map(|event| TaggedEvent {
+                        base_topic: base_topic.to_owned(),
+                        event,
+                    }),
+            );
+        }
+        Ok(events)
+    }
+
+    /// Find the controller whose base topic the given topic is under, preferring the longest.
+    fn controller_for_topic(&self, topic: &str) -> Option<&HomieController> {
+        self.controllers
+            .iter()
+            .filter(|controller| {
+                topic
+                    .strip_prefix(controller.base_topic())
+                    .is_some_and(|subtopic| subtopic.starts_with('/'))
+            })
+            .max_by_key(|controller| controller.base_topic().len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::{ConnAck, ConnectReturnCode, Publish, QoS, Request, Subscribe};
+
+    fn make_test_controller() -> (MultiHomieController, flume::Receiver<Request>) {
+        let (requests_tx, requests_rx) = flume::unbounded();
+        let controller =
+            MultiHomieController::with_client(AsyncClient::from_senders(requests_tx), &["a", "b"]);
+        (controller, requests_rx)
+    }
+
+    async fn publish(
+        controller: &MultiHomieController,
+        topic: &str,
+        payload: &str,
+    ) -> Result<Vec<TaggedEvent>, PollError> {
+        controller
+            .handle_event(Incoming::Publish(Publish::new(
+                topic,
+                QoS::AtLeastOnce,
+                payload,
+            )))
+            .await
+    }
+
+    #[tokio::test]
+    async fn connect_subscribes_to_all_base_topics() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, requests_rx) = make_test_controller();
+
+        let events = controller
+            .handle_event(Incoming::ConnAck(ConnAck::new(
+                ConnectReturnCode::Success,
+                false,
+            )))
+            .await?;
+        assert_eq!(
+            events,
+            vec![
+                TaggedEvent {
+                    base_topic: "a".to_owned(),
+                    event: Event::Connected
+                },
+                TaggedEvent {
+                    base_topic: "b".to_owned(),
+                    event: Event::Connected
+                },
+            ]
+        );
+        let requests: Vec<_> = requests_rx.drain().collect();
+        assert_eq!(
+            requests,
+            vec![
+                Request::Subscribe(Subscribe::new("a/+/$homie", QoS::AtLeastOnce)),
+                Request::Subscribe(Subscribe::new("b/+/$homie", QoS::AtLeastOnce)),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn devices_keyed_by_base_topic() -> Result<(), Box<dyn std::error::Error>> {
+        let (controller, _requests_rx) = make_test_controller();
+
+        assert_eq!(
+            publish(&controller, "a/device_id/$homie", "4.0").await?,
+            vec![TaggedEvent {
+                base_topic: "a".to_owned(),
+                event: Event::DeviceUpdated {
+                    device_id: "device_id".to_owned(),
+                    has_required_attributes: false,
+                    fresh: true,
+                }
+            }]
+        );
+        publish(&controller, "b/device_id/$homie", "3.0").await?;
+        publish(&controller, "b/device_id/$name", "Device B").await?;
+        assert_eq!(
+            publish(&controller, "c/device_id/$homie", "4.0").await?,
+            vec![]
+        );
+
+        let devices = controller.devices();
+        assert_eq!(devices.len(), 2);
+        let device_a = &devices[&("a".to_owned(), "device_id".to_owned())];
+        assert_eq!(device_a.homie_version, "4.0");
+        assert_eq!(device_a.name, None);
+        let device_b = &devices[&("b".to_owned(), "device_id".to_owned())];
+        assert_eq!(device_b.homie_version, "3.0");
+        assert_eq!(device_b.name, Some("Device B".to_owned()));
+
+        Ok(())
+    }
+}
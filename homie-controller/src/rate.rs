@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+/// Configuration for keeping recent values of numeric properties, so that their rate of change can
+/// be computed with [`Property::rate_of_change`](crate::Property::rate_of_change).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RateOfChangeConfig {
+    /// How far back to keep values for. Defaults to 1 hour.
+    pub window: Duration,
+    /// The maximum number of values to keep for each property, to bound memory use for properties
+    /// which are updated frequently. The oldest are dropped first. Defaults to 100.
+    pub max_values: usize,
+}
+
+impl Default for RateOfChangeConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60 * 60),
+            max_values: 100,
+        }
+    }
+}
+
+/// Recent values of a numeric property along with the times at which they were measured, oldest
+/// first.
+///
+/// This is empty unless enabled with
+/// [`HomieController::set_rate_of_change`](crate::HomieController::set_rate_of_change).
+#[derive(Clone, Debug, Default)]
+pub struct RecentValues {
+    values: VecDeque<(SystemTime, f64)>,
+}
+
+impl PartialEq for RecentValues {
+    fn eq(&self, other: &Self) -> bool {
+        self.values.len() == other.values.len()
+            && self
+                .values
+                .iter()
+                .zip(&other.values)
+                .all(|((a_time, a), (b_time, b))| a_time == b_time && a.to_bits() == b.to_bits())
+    }
+}
+
+impl Eq for RecentValues {}
+
+impl RecentValues {
+    /// Returns an iterator over the values and the times at which they were measured, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = (SystemTime, f64)> + '_ {
+        self.values.iter().copied()
+    }
+
+    /// Returns the number of values kept.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns whether there are no values kept.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Adds a new value, and drops any which are older than the window or beyond the maximum number
+    /// of values.
+    pub(crate) fn add(&mut self, time: SystemTime, value: f64, config: &RateOfChangeConfig) {
+        self.values.push_back((time, value));
+        while self.values.len() > config.max_values {
+            self.values.pop_front();
+        }
+        while self.values.front().is_some_and(|(oldest, _)| {
+            time.duration_since(*oldest)
+                .is_ok_and(|age| age > config.window)
+        }) {
+            self.values.pop_front();
+        }
+    }
+
+    /// The rate of change of the values over the given period, e.g. per hour, estimated as the
+    /// gradient of a least-squares linear fit.
+    ///
+    /// Returns `None` if there are fewer than two values, or they were all measured at the same
+    /// time.
+    pub fn rate_of_change(&self, per: Duration) -> Option<f64> {
+        let (start, _) = *self.values.front()?;
+        let points: Vec<(f64, f64)> = self
+            .iter()
+            .map(|(time, value)| (seconds_between(start, time), value))
+            .collect();
+        let count = points.len() as f64;
+        let mean_time = points.iter().map(|(time, _)| time).sum::<f64>() / count;
+        let mean_value = points.iter().map(|(_, value)| value).sum::<f64>() / count;
+        let mut covariance = 0.0;
+        let mut variance = 0.0;
+        for (time, value) in points {
+            covariance += (time - mean_time) * (value - mean_value);
+            variance += (time - mean_time) * (time - mean_time);
+        }
+        if variance == 0.0 {
+            return None;
+        }
+        Some(covariance / variance * per.as_secs_f64())
+    }
+}
+
+/// Returns the number of seconds from `start` to `time`, which is negative if `time` is earlier.
+fn seconds_between(start: SystemTime, time: SystemTime) -> f64 {
+    match time.duration_since(start) {
+        Ok(duration) => duration.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(values: &mut RecentValues, seconds: u64, value: f64, config: &RateOfChangeConfig) {
+        values.add(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(seconds),
+            value,
+            config,
+        );
+    }
+
+    #[test]
+    fn rate_of_change() {
+        let config = RateOfChangeConfig::default();
+        let mut values = RecentValues::default();
+        assert_eq!(values.rate_of_change(Duration::from_secs(1)), None);
+
+        add(&mut values, 1000, 20.0, &config);
+        assert_eq!(values.rate_of_change(Duration::from_secs(1)), None);
+
+        add(&mut values, 1600, 20.5, &config);
+        add(&mut values, 2200, 21.0, &config);
+        let per_hour = values.rate_of_change(Duration::from_secs(3600)).unwrap();
+        assert!((per_hour - 3.0).abs() < 1e-9, "{}", per_hour);
+    }
+
+    #[test]
+    fn bounded_by_window_and_count() {
+        let config = RateOfChangeConfig {
+            window: Duration::from_secs(100),
+            max_values: 3,
+        };
+        let mut values = RecentValues::default();
+        add(&mut values, 0, 1.0, &config);
+        add(&mut values, 50, 2.0, &config);
+        add(&mut values, 100, 3.0, &config);
+        assert_eq!(values.len(), 3);
+
+        // The oldest value is dropped to keep within the maximum number.
+        add(&mut values, 120, 4.0, &config);
+        assert_eq!(
+            values.iter().map(|(_, value)| value).collect::<Vec<_>>(),
+            vec![2.0, 3.0, 4.0]
+        );
+
+        // Values older than the window are dropped.
+        add(&mut values, 210, 5.0, &config);
+        assert_eq!(
+            values.iter().map(|(_, value)| value).collect::<Vec<_>>(),
+            vec![4.0, 5.0]
+        );
+    }
+}
@@ -0,0 +1,422 @@
+//! Recording the MQTT messages received by a controller, and replaying them later into another
+//! controller without a broker or any live devices.
+//!
+//! Recordings are text files with one message per line, giving the time in milliseconds since
+//! recording started, whether the message was retained (`r`) or not (`-`), the topic and the
+//! payload, separated by tabs. Bytes in the topic and payload other than printable ASCII are
+//! escaped as `\xNN`, and backslashes as `\\`.
+
+use crate::{Event, HomieController, PollError};
+use rumqttc::{AsyncClient, ConnAck, ConnectReturnCode, Incoming, Publish, QoS, Request};
+use std::fmt::{self, Debug, Formatter};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::time::sleep;
+
+/// An error reading a recording.
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    /// Error reading the recording file.
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// A line of the recording couldn't be parsed.
+    #[error("Invalid recording line {line}: {reason}")]
+    InvalidLine { line: usize, reason: String },
+}
+
+/// A message received by a controller, as recorded by a `Recorder`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RecordedPublish {
+    /// The time at which the message was received, relative to when recording started.
+    pub time: Duration,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+impl RecordedPublish {
+    fn to_publish(&self) -> Publish {
+        let mut publish = Publish::new(&self.topic, QoS::AtLeastOnce, self.payload.clone());
+        publish.retain = self.retain;
+        publish
+    }
+}
+
+/// Writes the messages received by a controller to a recording, once it is passed to
+/// [`HomieController::set_recorder`].
+///
+/// Messages are written by a separate thread through a buffer, so that recording doesn't hold up
+/// the controller. The buffer is flushed whenever there are no more messages waiting to be written,
+/// and when the recorder is dropped.
+pub struct Recorder {
+    /// Formatted lines waiting to be written. This is only `None` while the recorder is dropped.
+    lines: Option<flume::Sender<String>>,
+    writer_thread: Option<JoinHandle<()>>,
+    start: Instant,
+}
+
+impl Debug for Recorder {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+impl Recorder {
+    /// Create a recorder which writes to the given writer. Times are recorded relative to now.
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        let (lines, lines_rx) = flume::unbounded();
+        let writer_thread = thread::spawn(move || write_lines(BufWriter::new(writer), lines_rx));
+        Self {
+            lines: Some(lines),
+            writer_thread: Some(writer_thread),
+            start: Instant::now(),
+        }
+    }
+
+    /// Create a recorder which writes to a new file at the given path, replacing it if it already
+    /// exists.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(File::create(path)?))
+    }
+
+    /// Queue the given message to be appended to the recording.
+    pub(crate) fn record(&mut self, publish: &Publish) -> io::Result<()> {
+        let line = format!(
+            "{}\t{}\t{}\t{}\n",
+            self.start.elapsed().as_millis(),
+            if publish.retain { "r" } else { "-" },
+            escape(publish.topic.as_bytes()),
+            escape(&publish.payload)
+        );
+        self.lines
+            .as_ref()
+            .and_then(|lines| lines.send(line).ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "Recording writer thread has stopped",
+                )
+            })
+    }
+}
+
+impl Drop for Recorder {
+    /// Wait for all the messages recorded so far to be written.
+    fn drop(&mut self) {
+        self.lines = None;
+        if let Some(writer_thread) = self.writer_thread.take() {
+            if writer_thread.join().is_err() {
+                log::error!("Recording writer thread panicked");
+            }
+        }
+    }
+}
+
+/// Write the lines received from the given channel to the given writer until the channel is closed,
+/// flushing whenever no more lines are waiting.
+fn write_lines(mut writer: impl Write, lines: flume::Receiver<String>) {
+    let mut result = Ok(());
+    for line in lines.iter() {
+        result = writer.write_all(line.as_bytes());
+        if result.is_ok() && lines.is_empty() {
+            result = writer.flush();
+        }
+        if let Err(e) = &result {
+            log::error!("Failed to write recording: {}", e);
+            break;
+        }
+    }
+    if result.is_ok() {
+        if let Err(e) = writer.flush() {
+            log::error!("Failed to write recording: {}", e);
+        }
+    }
+}
+
+/// Read all the messages from the recording at the given path.
+pub fn open_recording(path: impl AsRef<Path>) -> Result<Vec<RecordedPublish>, ReplayError> {
+    read_recording(BufReader::new(File::open(path)?))
+}
+
+/// Read all the messages from the given recording.
+pub fn read_recording(reader: impl BufRead) -> Result<Vec<RecordedPublish>, ReplayError> {
+    reader
+        .lines()
+        .enumerate()
+        .map(|(index, line)| {
+            parse_line(&line?).map_err(|reason| ReplayError::InvalidLine {
+                line: index + 1,
+                reason,
+            })
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Result<RecordedPublish, String> {
+    let parts: Vec<&str> = line.split('\t').collect();
+    let [time, retain, topic, payload] = parts.as_slice() else {
+        return Err(format!("Expected 4 fields but got {}", parts.len()));
+    };
+    let time = time
+        .parse()
+        .map_err(|e| format!("Invalid time '{}': {}", time, e))?;
+    let retain = match *retain {
+        "r" => true,
+        "-" => false,
+        _ => return Err(format!("Invalid retain flag '{}'", retain)),
+    };
+    let topic = String::from_utf8(unescape(topic)?).map_err(|e| e.to_string())?;
+    Ok(RecordedPublish {
+        time: Duration::from_millis(time),
+        topic,
+        payload: unescape(payload)?,
+        retain,
+    })
+}
+
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'\\' => escaped.push_str("\\\\"),
+            b' '..=b'~' => escaped.push(byte.into()),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+fn unescape(escaped: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+        } else if let Some(tail) = rest.strip_prefix(b"\\") {
+            bytes.push(b'\\');
+            rest = tail;
+        } else if let (Some(b'x'), Some(hex)) = (rest.first(), rest.get(1..3)) {
+            let hex = std::str::from_utf8(hex).map_err(|e| e.to_string())?;
+            bytes.push(
+                u8::from_str_radix(hex, 16).map_err(|_| format!("Invalid escape '\\x{}'", hex))?,
+            );
+            rest = &rest[3..];
+        } else {
+            return Err(format!("Invalid escape in '{}'", escaped));
+        }
+    }
+    Ok(bytes)
+}
+
+/// A controller which isn't connected to an MQTT broker, into which recordings can be replayed.
+///
+/// It produces the same events as the controller which made the recording, and calls any handlers
+/// registered on it with them.
+#[derive(Debug)]
+pub struct Replayer {
+    controller: HomieController,
+    /// Requests which the controller tries to send to the broker, such as subscriptions. These are
+    /// discarded.
+    requests: flume::Receiver<Request>,
+}
+
+impl Replayer {
+    /// Create a new replayer with a controller looking for Homie devices under the given base topic.
+    pub fn new(base_topic: &str) -> Self {
+        let (requests_tx, requests) = flume::unbounded();
+        let controller =
+            HomieController::with_client(AsyncClient::from_senders(requests_tx), base_topic);
+        Self {
+            controller,
+            requests,
+        }
+    }
+
+    /// Get the controller, to inspect its devices.
+    pub fn controller(&self) -> &HomieController {
+        &self.controller
+    }
+
+    /// Get the controller mutably, to configure it before replaying.
+    pub fn controller_mut(&mut self) -> &mut HomieController {
+        &mut self.controller
+    }
+
+    /// Replay the given messages into the controller, calling the handlers registered on it with
+    /// the resulting events, and returning all of them.
+    ///
+    /// The controller first behaves as if it had just connected to the MQTT broker. Then if `speed`
+    /// is given, each message is replayed at its original time relative to the start of the replay,
+    /// divided by `speed`; 2.0 is twice as fast as the original, for example. Otherwise they are
+    /// replayed as fast as possible.
+    pub async fn replay(
+        &self,
+        recording: &[RecordedPublish],
+        speed: Option<f64>,
+    ) -> Result<Vec<Event>, PollError> {
+        let start = Instant::now();
+        let mut events = self
+            .handle(Incoming::ConnAck(ConnAck::new(
+                ConnectReturnCode::Success,
+                false,
+            )))
+            .await?;
+        for recorded in recording {
+            if let Some(speed) = speed {
+                let due = start + recorded.time.div_f64(speed);
+                let now = Instant::now();
+                if due > now {
+                    sleep(due - now).await;
+                }
+            }
+            events.extend(
+                self.handle(Incoming::Publish(recorded.to_publish()))
+                    .await?,
+            );
+        }
+        Ok(events)
+    }
+
+    async fn handle(&self, incoming: Incoming) -> Result<Vec<Event>, PollError> {
+        let events = self.controller.handle_event(incoming).await?;
+        self.requests.drain();
+        for event in &events {
+            self.controller.dispatch(event.clone()).await;
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A writer which can be inspected after it has been given to a `Recorder`.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn publish(topic: &str, payload: &[u8], retain: bool) -> Publish {
+        let mut publish = Publish::new(topic, QoS::AtLeastOnce, payload.to_vec());
+        publish.retain = retain;
+        publish
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        for bytes in [
+            &b"plain text"[..],
+            b"tab\tnew\nline",
+            b"back\\slash\\x41",
+            b"\xff\x00",
+        ] {
+            assert_eq!(unescape(&escape(bytes)).unwrap(), bytes);
+        }
+        assert_eq!(escape(b"a\tb\\"), "a\\x09b\\\\");
+        assert!(unescape("\\x4").is_err());
+        assert!(unescape("\\n").is_err());
+    }
+
+    #[test]
+    fn record_and_read() {
+        let buffer = SharedBuffer::default();
+        let mut recorder = Recorder::new(buffer.clone());
+        recorder
+            .record(&publish("homie/device/$name", b"Device", true))
+            .unwrap();
+        recorder
+            .record(&publish("homie/device/node/value", b"1\n2", false))
+            .unwrap();
+        // Dropping the recorder waits for everything to be written.
+        drop(recorder);
+
+        let recording = read_recording(&buffer.0.lock().unwrap()[..]).unwrap();
+        assert_eq!(recording.len(), 2);
+        assert_eq!(recording[0].topic, "homie/device/$name");
+        assert_eq!(recording[0].payload, b"Device");
+        assert!(recording[0].retain);
+        assert_eq!(recording[1].payload, b"1\n2");
+        assert!(!recording[1].retain);
+        assert!(recording[0].time <= recording[1].time);
+    }
+
+    #[test]
+    fn read_invalid() {
+        assert!(matches!(
+            read_recording(&b"0\tr\thomie/device/$name\tDevice\n12\tx\ttopic\tpayload"[..]),
+            Err(ReplayError::InvalidLine { line: 2, .. })
+        ));
+        assert!(matches!(
+            read_recording(&b"0\tr\thomie/device/$name"[..]),
+            Err(ReplayError::InvalidLine { line: 1, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn replay_produces_events() {
+        let recording: Vec<RecordedPublish> = [
+            ("homie/device/$homie", "4.0", true),
+            ("homie/device/$name", "Device", true),
+            ("homie/device/$state", "ready", true),
+            ("homie/device/$nodes", "node", true),
+            ("homie/device/node/$name", "Node", true),
+            ("homie/device/node/$type", "Type", true),
+            ("homie/device/node/$properties", "property", true),
+            ("homie/device/node/property/$name", "Property", true),
+            ("homie/device/node/property/$datatype", "integer", true),
+            ("homie/device/node/property", "42", false),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(index, (topic, payload, retain))| RecordedPublish {
+            time: Duration::from_millis(index as u64),
+            topic: (*topic).to_owned(),
+            payload: payload.as_bytes().to_vec(),
+            retain: *retain,
+        })
+        .collect();
+
+        let replayer = Replayer::new("homie");
+        let handled = Arc::new(Mutex::new(0));
+        let handler_count = handled.clone();
+        replayer.controller().on_event(move |_| {
+            *handler_count.lock().unwrap() += 1;
+            async {}
+        });
+
+        let events = replayer.replay(&recording, Some(10.0)).await.unwrap();
+        assert_eq!(events[0], Event::Connected);
+        assert_eq!(
+            events.last(),
+            Some(&Event::PropertyValueChanged {
+                device_id: "device".to_owned(),
+                node_id: "node".to_owned(),
+                property_id: "property".to_owned(),
+                value: "42".to_owned(),
+                fresh: true,
+            })
+        );
+        assert_eq!(*handled.lock().unwrap(), events.len());
+        assert_eq!(
+            replayer.controller().devices()["device"].name,
+            Some("Device".to_owned())
+        );
+    }
+}
@@ -1,9 +1,12 @@
+use crate::rate::RecentValues;
+use crate::units::Unit;
 use crate::values::{ColorFormat, EnumValue, Value, ValueError};
+use chrono::{DateTime, FixedOffset};
 use std::collections::HashMap;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::ops::RangeInclusive;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
 /// The state of a Homie device according to the Homie
@@ -24,6 +27,11 @@ pub enum State {
     /// The device was uncleanly disconnected from the MQTT broker. This could happen due to a
     /// network issue, power failure or some other unexpected failure.
     Lost,
+    /// The device has stopped sending stats, so the controller presumes it has been lost even
+    /// though it hasn't said so. This is never published by a device, but only set by the
+    /// controller if enabled with
+    /// [`HomieController::set_presume_lost_after`](crate::HomieController::set_presume_lost_after).
+    PresumedLost,
     /// The device is connected to the MQTT broker but something is wrong and it may require human
     /// intervention.
     Alert,
@@ -38,6 +46,7 @@ impl State {
             Self::Disconnected => "disconnected",
             Self::Sleeping => "sleeping",
             Self::Lost => "lost",
+            Self::PresumedLost => "presumed-lost",
             Self::Alert => "alert",
         }
     }
@@ -90,6 +99,10 @@ pub enum Datatype {
     /// [color](https://homieiot.github.io/specification/#color), depending on the property
     /// [format](struct.Property.html#method.color_format).
     Color,
+    /// An [ISO 8601 date and time](https://homieiot.github.io/specification/#datetime).
+    DateTime,
+    /// An [ISO 8601 duration](https://homieiot.github.io/specification/#duration).
+    Duration,
 }
 
 impl Datatype {
@@ -101,6 +114,8 @@ impl Datatype {
             Self::String => "string",
             Self::Enum => "enum",
             Self::Color => "color",
+            Self::DateTime => "datetime",
+            Self::Duration => "duration",
         }
     }
 }
@@ -128,11 +143,63 @@ impl FromStr for Datatype {
             "string" => Ok(Self::String),
             "enum" => Ok(Self::Enum),
             "color" => Ok(Self::Color),
+            "datetime" => Ok(Self::DateTime),
+            "duration" => Ok(Self::Duration),
             _ => Err(ParseDatatypeError(s.to_owned())),
         }
     }
 }
 
+/// The category of a Homie property, to help user interfaces group properties appropriately.
+///
+/// This isn't part of the Homie specification, but is published by `homie-device` as the
+/// `$category` attribute of the property.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PropertyCategory {
+    /// A user-facing reading or state, such as the temperature measured by a sensor or whether a
+    /// light is on.
+    Telemetry,
+    /// Information about the health of the device, such as its battery level or signal strength.
+    Diagnostic,
+    /// A setting which changes how the device behaves, such as a reporting interval.
+    Config,
+}
+
+impl PropertyCategory {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Telemetry => "telemetry",
+            Self::Diagnostic => "diagnostic",
+            Self::Config => "config",
+        }
+    }
+}
+
+impl Display for PropertyCategory {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An error which can be returned when parsing a `PropertyCategory` from a string, if the string
+/// does not match a known category.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+#[error("Invalid property category '{0}'")]
+pub struct ParsePropertyCategoryError(String);
+
+impl FromStr for PropertyCategory {
+    type Err = ParsePropertyCategoryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "telemetry" => Ok(Self::Telemetry),
+            "diagnostic" => Ok(Self::Diagnostic),
+            "config" => Ok(Self::Config),
+            _ => Err(ParsePropertyCategoryError(s.to_owned())),
+        }
+    }
+}
+
 /// A [property](https://homieiot.github.io/specification/#properties) of a Homie node.
 ///
 /// The `id`, `name` and `datatype` are required, but might not be available immediately when the
@@ -172,11 +239,28 @@ pub struct Property {
     /// [range](#method.range) to parse it according to the datatype of the property.
     pub format: Option<String>,
 
+    /// The category of the property, if the device declares one with the non-standard `$category`
+    /// attribute.
+    pub category: Option<PropertyCategory>,
+
     /// The current value of the property, if known. This may change frequently.
     ///
     /// This field holds the raw string received from the device. Use [value](#method.value) to
     /// parse it according to the datatype of the property.
     pub value: Option<String>,
+
+    /// When the controller received the current value of the property, if known.
+    pub last_updated: Option<SystemTime>,
+
+    /// When the current value was measured, if the device declares it with the non-standard
+    /// `$timestamp` attribute. Devices which use this should publish it before each new value, so
+    /// that it applies to the value by the time the value is received.
+    pub timestamp: Option<DateTime<FixedOffset>>,
+
+    /// Recent values of the property, if it is numeric and the controller has been configured to
+    /// keep them with
+    /// [`HomieController::set_rate_of_change`](crate::HomieController::set_rate_of_change).
+    pub recent_values: RecentValues,
 }
 
 impl Property {
@@ -194,7 +278,11 @@ impl Property {
             retained: true,
             unit: None,
             format: None,
+            category: None,
             value: None,
+            last_updated: None,
+            timestamp: None,
+            recent_values: RecentValues::default(),
         }
     }
 
@@ -205,6 +293,21 @@ impl Property {
         self.name.is_some() && self.datatype.is_some()
     }
 
+    /// The time at which the current value of the property was measured: the `$timestamp` declared
+    /// by the device if there is one, otherwise when the controller received the value.
+    pub fn value_time(&self) -> Option<SystemTime> {
+        self.timestamp.map(SystemTime::from).or(self.last_updated)
+    }
+
+    /// The rate of change of the property's recent values over the given period, e.g. per hour.
+    ///
+    /// Returns `None` unless the controller has been configured to keep recent values with
+    /// [`HomieController::set_rate_of_change`](crate::HomieController::set_rate_of_change) and at
+    /// least two values have been received at different times within the window.
+    pub fn rate_of_change(&self, per: Duration) -> Option<f64> {
+        self.recent_values.rate_of_change(per)
+    }
+
     /// The value of the property, parsed as the appropriate Homie `Value` type. This will return
     /// `WrongDatatype` if you try to parse it as a type which doesn't match the datatype declared
     /// by the property.
@@ -220,6 +323,37 @@ impl Property {
         }
     }
 
+    /// The value of a string property, deserialized from JSON as the given type.
+    ///
+    /// Returns `WrongDatatype` if the datatype of the property is not `String`, `Unknown` if it has
+    /// no value, or `InvalidJson` if the value can't be deserialized as the given type.
+    #[cfg(feature = "json")]
+    pub fn value_json<T: serde::de::DeserializeOwned>(&self) -> Result<T, ValueError> {
+        String::valid_for(self.datatype, &self.format)?;
+        match self.value {
+            None => Err(ValueError::Unknown),
+            Some(ref value) => crate::values::from_json(value),
+        }
+    }
+
+    /// The numeric value of the property, converted from the unit declared by the property to the
+    /// given unit.
+    ///
+    /// Returns `WrongDatatype` if the datatype of the property is not `Integer` or `Float`,
+    /// `Unknown` if the property has no value or no unit, or `IncompatibleUnits` if the unit of the
+    /// property can't be converted to the given unit.
+    pub fn value_in(&self, unit: &Unit) -> Result<f64, ValueError> {
+        let value = match self.datatype {
+            Some(Datatype::Integer) => self.value::<i64>()? as f64,
+            _ => self.value::<f64>()?,
+        };
+        let from: Unit = match self.unit {
+            None => return Err(ValueError::Unknown),
+            Some(ref from) => from.parse().unwrap_or_else(|e| match e {}),
+        };
+        from.convert(value, unit)
+    }
+
     /// If the datatype of the property is `Color`, returns the color format.
     pub fn color_format(&self) -> Result<ColorFormat, ValueError> {
         // If the datatype is known and it isn't color, that's an error. If it's not known, maybe
@@ -257,6 +391,35 @@ impl Property {
         }
     }
 
+    /// Checks whether the given value could be set on the property: that its type matches the
+    /// datatype of the property, and it is within the range or one of the enum values declared by
+    /// the format, if any.
+    ///
+    /// Returns `WrongDatatype` or `WrongFormat` if the type doesn't match, or `NotInFormat` if the
+    /// value isn't allowed by the format.
+    pub fn check_value<T: Value>(&self, value: &T) -> Result<(), ValueError> {
+        T::valid_for(self.datatype, &self.format)?;
+
+        let Some(format) = &self.format else {
+            return Ok(());
+        };
+        let value = value.to_string();
+        let allowed = match self.datatype {
+            Some(Datatype::Integer) => self.range::<i64>()?.contains(&parse_number(&value)?),
+            Some(Datatype::Float) => self.range::<f64>()?.contains(&parse_number(&value)?),
+            Some(Datatype::Enum) => self.enum_values()?.contains(&value.as_str()),
+            _ => true,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(ValueError::NotInFormat {
+                value,
+                format: format.to_owned(),
+            })
+        }
+    }
+
     /// If the dataype of the property is `Integer` or `Float`, gets the allowed range of values (if
     /// any is declared by the device).
     pub fn range<T: Value + Copy>(&self) -> Result<RangeInclusive<T>, ValueError> {
@@ -282,6 +445,14 @@ impl Property {
     }
 }
 
+/// Parses the string form of a numeric value which is to be checked against a range.
+fn parse_number<T: Value>(value: &str) -> Result<T, ValueError> {
+    value.parse().map_err(|_| ValueError::ParseFailed {
+        value: value.to_owned(),
+        datatype: T::datatype(),
+    })
+}
+
 /// A [node](https://homieiot.github.io/specification/#nodes) of a Homie device.
 ///
 /// All attributes are required, but might not be available immediately when the node is first
@@ -340,14 +511,43 @@ impl Node {
 
 /// A Homie [extension](https://homieiot.github.io/extensions/) supported by a device.
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct Extension {
-    /// The identifier of the extension. This should be a reverse domain name followed by some
-    /// suffix.
-    pub id: String,
-    /// The version of the extension.
-    pub version: String,
-    /// The versions of the Homie spec which the extension supports.
-    pub homie_versions: Vec<String>,
+pub enum Extension {
+    /// An extension in the format given by the Homie spec, `id:version:[homie_versions]`.
+    Standard {
+        /// The identifier of the extension. This should be a reverse domain name followed by some
+        /// suffix.
+        id: String,
+        /// The version of the extension.
+        version: String,
+        /// The versions of the Homie spec which the extension supports.
+        homie_versions: Vec<String>,
+    },
+    /// An extension which doesn't follow the format given by the Homie spec, as published by some
+    /// firmwares. This contains the entry from the `$extensions` attribute as-is.
+    Custom(String),
+}
+
+impl Extension {
+    /// Get the identifier of the extension, or the whole entry for a custom extension.
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Standard { id, .. } => id,
+            Self::Custom(entry) => entry,
+        }
+    }
+
+    /// Parse the value of a device's `$extensions` attribute. Empty entries are skipped, so an empty
+    /// value means there are no extensions, and entries which don't follow the format given by the
+    /// Homie spec are kept as `Extension::Custom` rather than being rejected.
+    pub fn parse_list(s: &str) -> Vec<Self> {
+        s.split(',')
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                part.parse()
+                    .unwrap_or_else(|_| Extension::Custom(part.to_owned()))
+            })
+            .collect()
+    }
 }
 
 /// An error which can be returned when parsing an `Extension` from a string.
@@ -363,7 +563,7 @@ impl FromStr for Extension {
         if let [id, version, homie_versions] = parts.as_slice() {
             if let Some(homie_versions) = homie_versions.strip_prefix('[') {
                 if let Some(homie_versions) = homie_versions.strip_suffix(']') {
-                    return Ok(Extension {
+                    return Ok(Extension::Standard {
                         id: (*id).to_owned(),
                         version: (*version).to_owned(),
                         homie_versions: homie_versions.split(';').map(|p| p.to_owned()).collect(),
@@ -423,6 +623,9 @@ pub struct Device {
     /// The amount of time since the device booted.
     pub stats_uptime: Option<Duration>,
 
+    /// When `$stats/uptime` was last received from the device.
+    pub stats_received: Option<SystemTime>,
+
     /// The device's signal strength in %.
     pub stats_signal: Option<i64>,
 
@@ -440,6 +643,16 @@ pub struct Device {
 
     /// The device's power supply voltage in volts.
     pub stats_supply: Option<f64>,
+
+    /// Any other device attributes which aren't modelled by the fields above, such as
+    /// implementation-specific attributes under `$implementation/`. These are keyed by their
+    /// subtopic relative to the device, e.g. `$implementation/config`. Payloads larger than 4 KiB
+    /// are ignored.
+    pub attributes: HashMap<String, String>,
+
+    /// Problems found with the device's conformance to the Homie convention, such as malformed
+    /// attributes or invalid IDs. This is only populated if the controller is in strict mode.
+    pub validation_errors: Vec<String>,
 }
 
 impl Device {
@@ -464,12 +677,15 @@ impl Device {
             firmware_version: None,
             stats_interval: None,
             stats_uptime: None,
+            stats_received: None,
             stats_signal: None,
             stats_cputemp: None,
             stats_cpuload: None,
             stats_battery: None,
             stats_freeheap: None,
             stats_supply: None,
+            attributes: HashMap::new(),
+            validation_errors: Vec::default(),
         }
     }
 
@@ -491,27 +707,52 @@ impl Device {
     }
 }
 
+/// Returns whether the given ID follows the Homie
+/// [ID format](https://homieiot.github.io/specification/#topic-ids), i.e. it matches
+/// `^[a-z0-9][a-z0-9-]*$`.
+pub(crate) fn is_valid_id(id: &str) -> bool {
+    let mut chars = id.chars();
+    matches!(chars.next(), Some('a'..='z' | '0'..='9'))
+        && chars.all(|c| matches!(c, 'a'..='z' | '0'..='9' | '-'))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::values::{ColorHsv, ColorRgb, EnumValue};
+    use crate::values::{ColorHsv, ColorRgb, DateTimeValue, DurationValue, EnumValue};
 
     #[test]
     fn extension_parse_succeeds() {
         let legacy_stats: Extension = "org.homie.legacy-stats:0.1.1:[4.x]".parse().unwrap();
-        assert_eq!(legacy_stats.id, "org.homie.legacy-stats");
-        assert_eq!(legacy_stats.version, "0.1.1");
-        assert_eq!(legacy_stats.homie_versions, &["4.x"]);
+        assert_eq!(
+            legacy_stats,
+            Extension::Standard {
+                id: "org.homie.legacy-stats".to_owned(),
+                version: "0.1.1".to_owned(),
+                homie_versions: vec!["4.x".to_owned()],
+            }
+        );
 
         let meta: Extension = "eu.epnw.meta:1.1.0:[3.0.1;4.x]".parse().unwrap();
-        assert_eq!(meta.id, "eu.epnw.meta");
-        assert_eq!(meta.version, "1.1.0");
-        assert_eq!(meta.homie_versions, &["3.0.1", "4.x"]);
+        assert_eq!(
+            meta,
+            Extension::Standard {
+                id: "eu.epnw.meta".to_owned(),
+                version: "1.1.0".to_owned(),
+                homie_versions: vec!["3.0.1".to_owned(), "4.x".to_owned()],
+            }
+        );
 
         let minimal: Extension = "a:0:[]".parse().unwrap();
-        assert_eq!(minimal.id, "a");
-        assert_eq!(minimal.version, "0");
-        assert_eq!(minimal.homie_versions, &[""]);
+        assert_eq!(
+            minimal,
+            Extension::Standard {
+                id: "a".to_owned(),
+                version: "0".to_owned(),
+                homie_versions: vec!["".to_owned()],
+            }
+        );
+        assert_eq!(minimal.id(), "a");
     }
 
     #[test]
@@ -530,6 +771,71 @@ mod tests {
         );
     }
 
+    #[test]
+    fn extension_list_parse() {
+        assert_eq!(Extension::parse_list(""), vec![]);
+        assert_eq!(
+            Extension::parse_list("org.homie.legacy-stats:0.1.1:[4.x],stats,"),
+            vec![
+                Extension::Standard {
+                    id: "org.homie.legacy-stats".to_owned(),
+                    version: "0.1.1".to_owned(),
+                    homie_versions: vec!["4.x".to_owned()],
+                },
+                Extension::Custom("stats".to_owned()),
+            ]
+        );
+        assert_eq!(Extension::parse_list(","), vec![]);
+        assert_eq!(
+            Extension::parse_list("stats,,esp"),
+            vec![
+                Extension::Custom("stats".to_owned()),
+                Extension::Custom("esp".to_owned()),
+            ]
+        );
+        assert_eq!(Extension::Custom("stats".to_owned()).id(), "stats");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn property_json_parse() {
+        #[derive(Debug, serde::Deserialize, PartialEq)]
+        struct Status {
+            mode: String,
+            level: u8,
+        }
+
+        let mut property = Property::new("property_id");
+        assert_eq!(property.value_json::<Status>(), Err(ValueError::Unknown));
+
+        property.value = Some(r#"{"mode":"auto","level":3}"#.to_owned());
+        property.datatype = Some(Datatype::String);
+        assert_eq!(
+            property.value_json(),
+            Ok(Status {
+                mode: "auto".to_owned(),
+                level: 3
+            })
+        );
+
+        // A value of the wrong shape fails to parse.
+        property.value = Some(r#"{"mode":"auto"}"#.to_owned());
+        assert!(matches!(
+            property.value_json::<Status>(),
+            Err(ValueError::InvalidJson { value, .. }) if value == r#"{"mode":"auto"}"#
+        ));
+
+        // Properties of other datatypes aren't parsed.
+        property.datatype = Some(Datatype::Integer);
+        assert_eq!(
+            property.value_json::<Status>(),
+            Err(ValueError::WrongDatatype {
+                expected: Datatype::String,
+                actual: Datatype::Integer
+            })
+        );
+    }
+
     #[test]
     fn property_integer_parse() {
         let mut property = Property::new("property_id");
@@ -733,6 +1039,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn property_check_value() {
+        let mut property = Property::new("property_id");
+
+        // With no datatype or format, anything is allowed.
+        assert_eq!(property.check_value(&42), Ok(()));
+
+        property.datatype = Some(Datatype::Integer);
+        property.format = Some("1:10".to_owned());
+        assert_eq!(property.check_value(&10), Ok(()));
+        assert_eq!(
+            property.check_value(&11),
+            Err(ValueError::NotInFormat {
+                value: "11".to_owned(),
+                format: "1:10".to_owned(),
+            })
+        );
+        assert_eq!(
+            property.check_value(&1.0),
+            Err(ValueError::WrongDatatype {
+                expected: Datatype::Float,
+                actual: Datatype::Integer,
+            })
+        );
+
+        property.datatype = Some(Datatype::Enum);
+        property.format = Some("on,off".to_owned());
+        assert_eq!(property.check_value(&EnumValue::new("off")), Ok(()));
+        assert_eq!(
+            property.check_value(&EnumValue::new("dim")),
+            Err(ValueError::NotInFormat {
+                value: "dim".to_owned(),
+                format: "on,off".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn property_datetime_duration_parse() {
+        let mut property = Property::new("property_id");
+        property.datatype = Some("datetime".parse().unwrap());
+        property.value = Some("2021-03-04T05:06:07Z".to_owned());
+        assert_eq!(
+            property.value::<DateTimeValue>().unwrap().to_string(),
+            "2021-03-04T05:06:07Z"
+        );
+        assert_eq!(
+            property.value::<DurationValue>(),
+            Err(ValueError::WrongDatatype {
+                actual: Datatype::DateTime,
+                expected: Datatype::Duration,
+            })
+        );
+
+        property.datatype = Some("duration".parse().unwrap());
+        property.value = Some("PT1H".to_owned());
+        assert_eq!(
+            property.value(),
+            Ok(DurationValue::new(chrono::TimeDelta::hours(1)))
+        );
+    }
+
     #[test]
     fn property_color_format() {
         let mut property = Property::new("property_id");
@@ -862,6 +1230,47 @@ mod tests {
         );
     }
 
+    #[test]
+    fn property_value_in_unit() {
+        let mut property = Property::new("property_id");
+
+        // With no known value or unit, conversion fails.
+        assert_eq!(property.value_in(&Unit::Celsius), Err(ValueError::Unknown));
+        property.value = Some("21.5".to_owned());
+        assert_eq!(property.value_in(&Unit::Celsius), Err(ValueError::Unknown));
+
+        // A float value is converted.
+        property.datatype = Some(Datatype::Float);
+        property.unit = Some("°C".to_owned());
+        assert_eq!(property.value_in(&Unit::Celsius), Ok(21.5));
+        assert_eq!(property.value_in(&Unit::Fahrenheit), Ok(70.7));
+
+        // An integer value is converted.
+        property.datatype = Some(Datatype::Integer);
+        property.unit = Some("W".to_owned());
+        property.value = Some("1500".to_owned());
+        assert_eq!(property.value_in(&Unit::Kilowatt), Ok(1.5));
+
+        // Converting to an incompatible unit fails.
+        assert_eq!(
+            property.value_in(&Unit::Percent),
+            Err(ValueError::IncompatibleUnits {
+                from: Unit::Watt,
+                to: Unit::Percent
+            })
+        );
+
+        // Non-numeric datatypes can't be converted.
+        property.datatype = Some(Datatype::String);
+        assert_eq!(
+            property.value_in(&Unit::Watt),
+            Err(ValueError::WrongDatatype {
+                expected: Datatype::Float,
+                actual: Datatype::String
+            })
+        );
+    }
+
     #[test]
     fn property_has_required_attributes() {
         let mut property = Property::new("property_id");
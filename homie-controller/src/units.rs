@@ -0,0 +1,236 @@
+use crate::values::ValueError;
+use std::convert::Infallible;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::str::FromStr;
+
+/// The unit of a Homie property, as declared by its `$unit` attribute.
+///
+/// This covers the [recommended units](https://homieiot.github.io/specification/#property-attributes)
+/// and a few common multiples of them. Any other unit is kept as `Custom`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Unit {
+    /// Degrees Celsius.
+    Celsius,
+    /// Degrees Fahrenheit.
+    Fahrenheit,
+    /// Degrees of angle.
+    Degree,
+    /// Litres.
+    Litre,
+    /// US gallons.
+    Gallon,
+    /// Volts.
+    Volt,
+    /// Watts.
+    Watt,
+    /// Kilowatts.
+    Kilowatt,
+    /// Watt-hours.
+    WattHour,
+    /// Kilowatt-hours.
+    KilowattHour,
+    /// Amperes.
+    Ampere,
+    /// Percent.
+    Percent,
+    /// Metres.
+    Metre,
+    /// Feet.
+    Foot,
+    /// Pascals.
+    Pascal,
+    /// Pounds per square inch.
+    Psi,
+    /// A count or amount.
+    Count,
+    /// Some other unit not known to this library.
+    Custom(String),
+}
+
+/// The physical quantity which a unit measures. Values can only be converted between units of the
+/// same quantity.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Quantity {
+    Temperature,
+    Angle,
+    Volume,
+    Voltage,
+    Power,
+    Energy,
+    Current,
+    Ratio,
+    Length,
+    Pressure,
+    Count,
+}
+
+impl Unit {
+    fn as_str(&self) -> &str {
+        match self {
+            Self::Celsius => "°C",
+            Self::Fahrenheit => "°F",
+            Self::Degree => "°",
+            Self::Litre => "L",
+            Self::Gallon => "gal",
+            Self::Volt => "V",
+            Self::Watt => "W",
+            Self::Kilowatt => "kW",
+            Self::WattHour => "Wh",
+            Self::KilowattHour => "kWh",
+            Self::Ampere => "A",
+            Self::Percent => "%",
+            Self::Metre => "m",
+            Self::Foot => "ft",
+            Self::Pascal => "Pa",
+            Self::Psi => "psi",
+            Self::Count => "#",
+            Self::Custom(unit) => unit,
+        }
+    }
+
+    /// Returns the quantity measured by this unit, and the offset, numerator and denominator to
+    /// convert a value in this unit to the base unit for that quantity, as
+    /// `(value + offset) * numerator / denominator`.
+    fn to_base(&self) -> Option<(Quantity, f64, f64, f64)> {
+        Some(match self {
+            Self::Celsius => (Quantity::Temperature, 0.0, 1.0, 1.0),
+            Self::Fahrenheit => (Quantity::Temperature, -32.0, 5.0, 9.0),
+            Self::Degree => (Quantity::Angle, 0.0, 1.0, 1.0),
+            Self::Litre => (Quantity::Volume, 0.0, 1.0, 1.0),
+            Self::Gallon => (Quantity::Volume, 0.0, 3.785_411_784, 1.0),
+            Self::Volt => (Quantity::Voltage, 0.0, 1.0, 1.0),
+            Self::Watt => (Quantity::Power, 0.0, 1.0, 1.0),
+            Self::Kilowatt => (Quantity::Power, 0.0, 1000.0, 1.0),
+            Self::WattHour => (Quantity::Energy, 0.0, 1.0, 1.0),
+            Self::KilowattHour => (Quantity::Energy, 0.0, 1000.0, 1.0),
+            Self::Ampere => (Quantity::Current, 0.0, 1.0, 1.0),
+            Self::Percent => (Quantity::Ratio, 0.0, 1.0, 1.0),
+            Self::Metre => (Quantity::Length, 0.0, 1.0, 1.0),
+            Self::Foot => (Quantity::Length, 0.0, 0.3048, 1.0),
+            Self::Pascal => (Quantity::Pressure, 0.0, 1.0, 1.0),
+            Self::Psi => (Quantity::Pressure, 0.0, 6_894.757_293_168, 1.0),
+            Self::Count => (Quantity::Count, 0.0, 1.0, 1.0),
+            Self::Custom(_) => return None,
+        })
+    }
+
+    /// Convert the given value from this unit to the given unit.
+    ///
+    /// Returns `Err(IncompatibleUnits(...))` if the two units don't measure the same quantity, or
+    /// either of them is a custom unit which isn't the same as the other.
+    pub fn convert(&self, value: f64, to: &Unit) -> Result<f64, ValueError> {
+        if self == to {
+            return Ok(value);
+        }
+        let incompatible = || ValueError::IncompatibleUnits {
+            from: self.to_owned(),
+            to: to.to_owned(),
+        };
+        let (from_quantity, from_offset, from_numerator, from_denominator) =
+            self.to_base().ok_or_else(incompatible)?;
+        let (to_quantity, to_offset, to_numerator, to_denominator) =
+            to.to_base().ok_or_else(incompatible)?;
+        if from_quantity != to_quantity {
+            return Err(incompatible());
+        }
+        let base = (value + from_offset) * from_numerator / from_denominator;
+        Ok(base * to_denominator / to_numerator - to_offset)
+    }
+}
+
+impl FromStr for Unit {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            // Some devices use the masculine ordinal indicator rather than the degree sign.
+            "°C" | "ºC" => Self::Celsius,
+            "°F" | "ºF" => Self::Fahrenheit,
+            "°" | "º" => Self::Degree,
+            "L" => Self::Litre,
+            "gal" => Self::Gallon,
+            "V" => Self::Volt,
+            "W" => Self::Watt,
+            "kW" => Self::Kilowatt,
+            "Wh" => Self::WattHour,
+            "kWh" => Self::KilowattHour,
+            "A" => Self::Ampere,
+            "%" => Self::Percent,
+            "m" => Self::Metre,
+            "ft" => Self::Foot,
+            "Pa" => Self::Pascal,
+            "psi" => Self::Psi,
+            "#" => Self::Count,
+            _ => Self::Custom(s.to_owned()),
+        })
+    }
+}
+
+impl Display for Unit {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_to_from_string() {
+        for unit in &[
+            Unit::Celsius,
+            Unit::Fahrenheit,
+            Unit::Kilowatt,
+            Unit::KilowattHour,
+            Unit::Percent,
+            Unit::Count,
+            Unit::Custom("lux".to_owned()),
+        ] {
+            assert_eq!(&unit.to_string().parse::<Unit>().unwrap(), unit);
+        }
+    }
+
+    #[test]
+    fn unit_parse_ordinal_indicator() {
+        assert_eq!("ºC".parse(), Ok(Unit::Celsius));
+        assert_eq!("ºF".parse(), Ok(Unit::Fahrenheit));
+    }
+
+    #[test]
+    fn convert_temperature() {
+        assert_eq!(Unit::Celsius.convert(100.0, &Unit::Fahrenheit), Ok(212.0));
+        assert_eq!(Unit::Fahrenheit.convert(32.0, &Unit::Celsius), Ok(0.0));
+        assert_eq!(Unit::Celsius.convert(21.5, &Unit::Celsius), Ok(21.5));
+    }
+
+    #[test]
+    fn convert_power_and_energy() {
+        assert_eq!(Unit::Watt.convert(1500.0, &Unit::Kilowatt), Ok(1.5));
+        assert_eq!(Unit::Kilowatt.convert(2.0, &Unit::Watt), Ok(2000.0));
+        assert_eq!(Unit::KilowattHour.convert(0.25, &Unit::WattHour), Ok(250.0));
+    }
+
+    #[test]
+    fn convert_incompatible() {
+        assert_eq!(
+            Unit::Watt.convert(1.0, &Unit::KilowattHour),
+            Err(ValueError::IncompatibleUnits {
+                from: Unit::Watt,
+                to: Unit::KilowattHour
+            })
+        );
+        assert_eq!(
+            Unit::Custom("lux".to_owned()).convert(1.0, &Unit::Percent),
+            Err(ValueError::IncompatibleUnits {
+                from: Unit::Custom("lux".to_owned()),
+                to: Unit::Percent
+            })
+        );
+        // The same custom unit is trivially convertible to itself.
+        assert_eq!(
+            Unit::Custom("lux".to_owned()).convert(3.0, &Unit::Custom("lux".to_owned())),
+            Ok(3.0)
+        );
+    }
+}
@@ -1,4 +1,6 @@
 use crate::types::Datatype;
+use crate::units::Unit;
+use chrono::{DateTime, FixedOffset, SecondsFormat, TimeDelta};
 use std::fmt::{self, Debug, Display, Formatter};
 use std::num::ParseIntError;
 use std::str::FromStr;
@@ -34,6 +36,41 @@ pub enum ValueError {
         /// The datatype as which the value was attempted to be parsed.
         datatype: Datatype,
     },
+    /// The value of the property couldn't be converted to the requested unit, because it measures
+    /// a different quantity.
+    #[error("Can't convert from unit {from} to {to}.")]
+    IncompatibleUnits {
+        /// The unit of the property, as declared by the device.
+        from: Unit,
+        /// The unit to which conversion was requested.
+        to: Unit,
+    },
+    /// The value is of the right datatype for the property, but not allowed by its format, such as
+    /// an integer outside its range or a string which isn't one of its enum values.
+    #[error("Value {value} is not allowed by format {format}.")]
+    NotInFormat {
+        /// The value which was checked.
+        value: String,
+        /// The format string of the property.
+        format: String,
+    },
+    /// The value of the property couldn't be deserialized from JSON as the requested type.
+    #[error("Parsing {value} as JSON failed: {error}")]
+    InvalidJson {
+        /// The string value of the property.
+        value: String,
+        /// A description of why deserialization failed.
+        error: String,
+    },
+}
+
+/// Deserialize the given string value of a property from JSON.
+#[cfg(feature = "json")]
+pub(crate) fn from_json<T: serde::de::DeserializeOwned>(value: &str) -> Result<T, ValueError> {
+    serde_json::from_str(value).map_err(|e| ValueError::InvalidJson {
+        value: value.to_owned(),
+        error: e.to_string(),
+    })
 }
 
 /// The value of a Homie property. This has implementations corresponding to the possible property datatypes.
@@ -136,10 +173,22 @@ impl Display for ColorFormat {
     }
 }
 
+/// A [colour](https://homieiot.github.io/specification/#color) value, in either RGB or HSV format.
 pub trait Color: Value {
+    /// The format of this colour type.
     fn format() -> ColorFormat;
 }
 
+/// Conversions between the colour formats, as used by
+/// [`HomieController::set_color`](crate::HomieController::set_color).
+pub trait ColorConversion: Color {
+    /// Convert the colour to RGB format.
+    fn to_rgb(&self) -> ColorRgb;
+
+    /// Convert the colour to HSV format.
+    fn to_hsv(&self) -> ColorHsv;
+}
+
 impl<T: Color> Value for T {
     fn datatype() -> Datatype {
         Datatype::Color
@@ -214,6 +263,38 @@ impl Color for ColorRgb {
     }
 }
 
+impl ColorConversion for ColorRgb {
+    fn to_rgb(&self) -> ColorRgb {
+        self.clone()
+    }
+
+    fn to_hsv(&self) -> ColorHsv {
+        let r = f64::from(self.r) / 255.0;
+        let g = f64::from(self.g) / 255.0;
+        let b = f64::from(self.b) / 255.0;
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+
+        let h = if delta == 0.0 {
+            0.0
+        } else if max == r {
+            60.0 * ((g - b) / delta).rem_euclid(6.0)
+        } else if max == g {
+            60.0 * ((b - r) / delta + 2.0)
+        } else {
+            60.0 * ((r - g) / delta + 4.0)
+        };
+        let s = if max == 0.0 { 0.0 } else { delta / max };
+
+        ColorHsv {
+            h: h.round() as u16 % 360,
+            s: (s * 100.0).round() as u8,
+            v: (max * 100.0).round() as u8,
+        }
+    }
+}
+
 /// A [colour](https://homieiot.github.io/specification/#color) in hue-saturation-value format.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ColorHsv {
@@ -233,6 +314,16 @@ impl ColorHsv {
         assert!(v <= 100);
         ColorHsv { h, s, v }
     }
+
+    /// Returns a copy of the colour with the hue, saturation and value clamped to their valid
+    /// ranges.
+    pub fn clamped(&self) -> Self {
+        ColorHsv {
+            h: self.h.min(360),
+            s: self.s.min(100),
+            v: self.v.min(100),
+        }
+    }
 }
 
 impl Display for ColorHsv {
@@ -264,6 +355,38 @@ impl Color for ColorHsv {
     }
 }
 
+impl ColorConversion for ColorHsv {
+    fn to_rgb(&self) -> ColorRgb {
+        let ColorHsv { h, s, v } = self.clamped();
+        let s = f64::from(s) / 100.0;
+        let v = f64::from(v) / 100.0;
+        let h = f64::from(h % 360) / 60.0;
+
+        let c = v * s;
+        let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+        let (r, g, b) = match h as u8 {
+            0 => (c, x, 0.0),
+            1 => (x, c, 0.0),
+            2 => (0.0, c, x),
+            3 => (0.0, x, c),
+            4 => (x, 0.0, c),
+            _ => (c, 0.0, x),
+        };
+        let m = v - c;
+        let to_channel = |value: f64| ((value + m) * 255.0).round() as u8;
+
+        ColorRgb {
+            r: to_channel(r),
+            g: to_channel(g),
+            b: to_channel(b),
+        }
+    }
+
+    fn to_hsv(&self) -> ColorHsv {
+        self.clamped()
+    }
+}
+
 /// The value of a Homie [enum](https://homieiot.github.io/specification/#enum) property.
 ///
 /// This must be a non-empty string.
@@ -306,6 +429,186 @@ impl Value for EnumValue {
     }
 }
 
+/// The value of a Homie [datetime](https://homieiot.github.io/specification/#datetime) property,
+/// in ISO 8601 format.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct DateTimeValue(pub DateTime<FixedOffset>);
+
+impl DateTimeValue {
+    /// Construct a new datetime value.
+    pub fn new(datetime: impl Into<DateTime<FixedOffset>>) -> Self {
+        Self(datetime.into())
+    }
+}
+
+impl FromStr for DateTimeValue {
+    type Err = chrono::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+impl Display for DateTimeValue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(&self.0.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+    }
+}
+
+impl Value for DateTimeValue {
+    fn datatype() -> Datatype {
+        Datatype::DateTime
+    }
+}
+
+impl From<DateTimeValue> for DateTime<FixedOffset> {
+    fn from(value: DateTimeValue) -> Self {
+        value.0
+    }
+}
+
+/// An error while attempting to parse a `DurationValue` from a string.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+#[error("Invalid ISO 8601 duration '{0}'.")]
+pub struct ParseDurationError(String);
+
+/// The value of a Homie [duration](https://homieiot.github.io/specification/#duration) property,
+/// in ISO 8601 format.
+///
+/// Only weeks, days, hours, minutes and seconds are supported when parsing, as years and months
+/// don't have a fixed length. Durations are formatted using only hours, minutes and seconds.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct DurationValue(pub TimeDelta);
+
+impl DurationValue {
+    /// Construct a new duration value.
+    pub fn new(duration: TimeDelta) -> Self {
+        Self(duration)
+    }
+}
+
+impl FromStr for DurationValue {
+    type Err = ParseDurationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseDurationError(s.to_owned());
+
+        let (negative, rest) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let rest = rest.strip_prefix('P').ok_or_else(error)?;
+        let (date, time) = match rest.split_once('T') {
+            Some((_, "")) => return Err(error()),
+            Some((date, time)) => (date, Some(time)),
+            None => (rest, None),
+        };
+        if date.is_empty() && time.is_none() {
+            return Err(error());
+        }
+
+        let mut duration = TimeDelta::zero();
+        for (component, designators) in [(date, "WD"), (time.unwrap_or(""), "HMS")] {
+            // Designators must appear in order, each at most once.
+            let mut designators = designators.chars();
+            let mut component = component;
+            while !component.is_empty() {
+                let end = component
+                    .find(|c: char| c.is_ascii_alphabetic())
+                    .ok_or_else(error)?;
+                let (number, designator) = (&component[..end], component.as_bytes()[end] as char);
+                component = &component[end + 1..];
+                if !designators.any(|d| d == designator) {
+                    return Err(error());
+                }
+                let seconds_per_unit = match designator {
+                    'W' => 7 * 86400,
+                    'D' => 86400,
+                    'H' => 3600,
+                    'M' => 60,
+                    _ => 1,
+                };
+                duration = parse_duration_component(number, seconds_per_unit, designator == 'S')
+                    .and_then(|component| duration.checked_add(&component))
+                    .ok_or_else(error)?;
+            }
+        }
+
+        Ok(Self(if negative { -duration } else { duration }))
+    }
+}
+
+/// Parse a number of some unit from an ISO 8601 duration. Only seconds may have a fractional part.
+fn parse_duration_component(
+    number: &str,
+    seconds_per_unit: i64,
+    allow_fraction: bool,
+) -> Option<TimeDelta> {
+    let (whole, fraction) = match number.split_once(['.', ',']) {
+        Some((whole, fraction)) if allow_fraction => (whole, Some(fraction)),
+        Some(_) => return None,
+        None => (number, None),
+    };
+    if whole.is_empty() || !whole.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let mut duration =
+        TimeDelta::try_seconds(whole.parse::<i64>().ok()?.checked_mul(seconds_per_unit)?)?;
+    if let Some(fraction) = fraction {
+        if fraction.is_empty()
+            || fraction.len() > 9
+            || !fraction.bytes().all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+        let nanos = fraction.parse::<i64>().ok()? * 10_i64.pow(9 - fraction.len() as u32);
+        duration = duration.checked_add(&TimeDelta::nanoseconds(nanos))?;
+    }
+    Some(duration)
+}
+
+impl Display for DurationValue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let (sign, duration) = if self.0 < TimeDelta::zero() {
+            ("-", -self.0)
+        } else {
+            ("", self.0)
+        };
+        let seconds = duration.num_seconds();
+        let nanos = duration.subsec_nanos();
+        let hours = seconds / 3600;
+        let minutes = seconds / 60 % 60;
+        let seconds = seconds % 60;
+
+        write!(f, "{}PT", sign)?;
+        if hours != 0 {
+            write!(f, "{}H", hours)?;
+        }
+        if minutes != 0 {
+            write!(f, "{}M", minutes)?;
+        }
+        if nanos != 0 {
+            let fraction = format!("{:09}", nanos);
+            write!(f, "{}.{}S", seconds, fraction.trim_end_matches('0'))?;
+        } else if seconds != 0 || (hours == 0 && minutes == 0) {
+            write!(f, "{}S", seconds)?;
+        }
+        Ok(())
+    }
+}
+
+impl Value for DurationValue {
+    fn datatype() -> Datatype {
+        Datatype::Duration
+    }
+}
+
+impl From<DurationValue> for TimeDelta {
+    fn from(value: DurationValue) -> Self {
+        value.0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -322,6 +625,86 @@ mod tests {
         assert_eq!(color.to_string().parse(), Ok(color));
     }
 
+    #[test]
+    fn datetime_to_from_string() {
+        let datetime: DateTimeValue = "2021-03-04T05:06:07Z".parse().unwrap();
+        assert_eq!(datetime.to_string(), "2021-03-04T05:06:07Z");
+        assert_eq!(datetime.to_string().parse(), Ok(datetime));
+
+        let datetime: DateTimeValue = "2021-03-04T05:06:07.5+01:00".parse().unwrap();
+        assert_eq!(datetime.to_string(), "2021-03-04T05:06:07.500+01:00");
+        assert_eq!(datetime.to_string().parse(), Ok(datetime));
+    }
+
+    #[test]
+    fn datetime_parse_invalid() {
+        assert!("".parse::<DateTimeValue>().is_err());
+        assert!("2021-03-04".parse::<DateTimeValue>().is_err());
+        assert!("2021-13-04T05:06:07Z".parse::<DateTimeValue>().is_err());
+    }
+
+    #[test]
+    fn duration_parse() {
+        assert_eq!(
+            "PT12H5M46S".parse(),
+            Ok(DurationValue(TimeDelta::seconds(43546)))
+        );
+        assert_eq!("PT0S".parse(), Ok(DurationValue(TimeDelta::zero())));
+        assert_eq!(
+            "P1W2DT3M".parse(),
+            Ok(DurationValue(TimeDelta::seconds(9 * 86400 + 180)))
+        );
+        assert_eq!(
+            "PT1.25S".parse(),
+            Ok(DurationValue(TimeDelta::milliseconds(1250)))
+        );
+        assert_eq!("-PT1M".parse(), Ok(DurationValue(TimeDelta::seconds(-60))));
+    }
+
+    #[test]
+    fn duration_parse_invalid() {
+        for invalid in [
+            "", "P", "PT", "12H", "PT12", "P1Y", "P1M", "PT1S2M", "PT1H1H", "PT1.5M", "PT-1S",
+            "PTS", "P1DT",
+        ] {
+            assert_eq!(
+                invalid.parse::<DurationValue>(),
+                Err(ParseDurationError(invalid.to_owned())),
+            );
+        }
+    }
+
+    #[test]
+    fn duration_parse_overflow() {
+        for overflowing in [
+            "P9999999999999D",
+            "P15250284452WT100000H",
+            "PT9223372036854775.999S",
+        ] {
+            assert_eq!(
+                overflowing.parse::<DurationValue>(),
+                Err(ParseDurationError(overflowing.to_owned())),
+            );
+        }
+    }
+
+    #[test]
+    fn duration_to_from_string() {
+        for (seconds, millis, formatted) in [
+            (0, 0, "PT0S"),
+            (43546, 0, "PT12H5M46S"),
+            (3600, 0, "PT1H"),
+            (86400 + 60, 0, "PT24H1M"),
+            (1, 500, "PT1.5S"),
+            (-90, 0, "-PT1M30S"),
+        ] {
+            let duration =
+                DurationValue(TimeDelta::seconds(seconds) + TimeDelta::milliseconds(millis));
+            assert_eq!(duration.to_string(), formatted);
+            assert_eq!(duration.to_string().parse(), Ok(duration));
+        }
+    }
+
     #[test]
     fn color_rgb_parse_invalid() {
         assert_eq!("".parse::<ColorRgb>(), Err(ParseColorError()));
@@ -343,4 +726,74 @@ mod tests {
         assert_eq!("361,2,3".parse::<ColorHsv>(), Err(ParseColorError()));
         assert_eq!("1,-2,3".parse::<ColorHsv>(), Err(ParseColorError()));
     }
+
+    #[test]
+    fn color_rgb_to_hsv() {
+        assert_eq!(ColorRgb::new(0, 0, 0).to_hsv(), ColorHsv::new(0, 0, 0));
+        assert_eq!(
+            ColorRgb::new(255, 255, 255).to_hsv(),
+            ColorHsv::new(0, 0, 100)
+        );
+        assert_eq!(
+            ColorRgb::new(255, 0, 0).to_hsv(),
+            ColorHsv::new(0, 100, 100)
+        );
+        assert_eq!(
+            ColorRgb::new(0, 255, 0).to_hsv(),
+            ColorHsv::new(120, 100, 100)
+        );
+        assert_eq!(
+            ColorRgb::new(0, 0, 255).to_hsv(),
+            ColorHsv::new(240, 100, 100)
+        );
+        assert_eq!(
+            ColorRgb::new(255, 0, 128).to_hsv(),
+            ColorHsv::new(330, 100, 100)
+        );
+        assert_eq!(
+            ColorRgb::new(64, 128, 128).to_hsv(),
+            ColorHsv::new(180, 50, 50)
+        );
+    }
+
+    #[test]
+    fn color_hsv_to_rgb() {
+        assert_eq!(ColorHsv::new(0, 0, 0).to_rgb(), ColorRgb::new(0, 0, 0));
+        assert_eq!(
+            ColorHsv::new(0, 0, 100).to_rgb(),
+            ColorRgb::new(255, 255, 255)
+        );
+        assert_eq!(
+            ColorHsv::new(0, 100, 100).to_rgb(),
+            ColorRgb::new(255, 0, 0)
+        );
+        assert_eq!(
+            ColorHsv::new(360, 100, 100).to_rgb(),
+            ColorRgb::new(255, 0, 0)
+        );
+        assert_eq!(
+            ColorHsv::new(120, 100, 100).to_rgb(),
+            ColorRgb::new(0, 255, 0)
+        );
+        assert_eq!(
+            ColorHsv::new(240, 100, 100).to_rgb(),
+            ColorRgb::new(0, 0, 255)
+        );
+        assert_eq!(
+            ColorHsv::new(180, 50, 50).to_rgb(),
+            ColorRgb::new(64, 128, 128)
+        );
+    }
+
+    #[test]
+    fn color_hsv_clamped() {
+        let color = ColorHsv {
+            h: 400,
+            s: 150,
+            v: 200,
+        };
+        assert_eq!(color.clamped(), ColorHsv::new(360, 100, 100));
+        assert_eq!(color.to_hsv(), ColorHsv::new(360, 100, 100));
+        assert_eq!(color.to_rgb(), ColorRgb::new(255, 0, 0));
+    }
 }
@@ -5,17 +5,11 @@
 use futures::future::ready;
 use homie_controller::{Event, HomieController, State};
 use homie_device::{HomieDevice, Node, Property, SpawnError};
+use mqtt_test_broker::spawn_mqtt_broker;
 use rumqttc::{ConnectionError, MqttOptions, StateError};
-use rumqttd::{Broker, Config, ConnectionSettings, RouterConfig, ServerSettings};
-use std::collections::HashMap;
 use std::env;
 use std::io::ErrorKind;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::mpsc;
-use std::thread;
-
-// A high port number which is hopefully not in use, to use for the MQTT broker.
-const PORT: u16 = 10883;
 
 #[tokio::test]
 async fn test_device() {
@@ -26,16 +20,17 @@ async fn test_device() {
     let _ = log_builder.is_test(true).try_init();
 
     // Start MQTT broker.
-    spawn_mqtt_broker(PORT);
+    let port = spawn_mqtt_broker();
 
     // Start controller.
-    let controller_options = MqttOptions::new("homie_controller", "localhost", PORT);
+    let controller_options = MqttOptions::new("homie_controller", "localhost", port);
     let (controller, mut event_loop) = HomieController::new(controller_options, "homie");
 
     // Start device
     let (updates_tx, updates_rx) = mpsc::sync_channel(10);
-    let device_options = MqttOptions::new("homie_device", "localhost", PORT);
-    let mut device_builder = HomieDevice::builder("homie/device_id", "Device name", device_options);
+    let device_options = MqttOptions::new("homie_device", "localhost", port);
+    let mut device_builder =
+        HomieDevice::builder_for("homie", "device-id", "Device name", device_options).unwrap();
     device_builder.set_update_callback(move |node_id, property_id, value| {
         assert_eq!(property_id, "property_id");
         assert_eq!(node_id, "node_id");
@@ -64,7 +59,7 @@ async fn test_device() {
         for event in controller.poll(&mut event_loop).await.unwrap() {
             log::trace!("Event: {:?}", event);
             let devices = controller.devices();
-            if let Some(device) = devices.get("device_id") {
+            if let Some(device) = devices.get("device-id") {
                 // For some reason we get the ready state before all the attributes of the property
                 // have been filled in, so we need to explicitly check for the unit and settable
                 // being set.
@@ -90,7 +85,7 @@ async fn test_device() {
     // Check that the device looks how we expect.
     {
         let devices = controller.devices();
-        let device = devices.get("device_id").unwrap();
+        let device = devices.get("device-id").unwrap();
         log::info!("Device: {:?}", device);
         assert_eq!(device.name, Some("Device name".to_string()));
         assert_eq!(device.homie_version, "4.0");
@@ -125,7 +120,7 @@ async fn test_device() {
                 fresh,
             } = event
             {
-                assert_eq!(device_id, "device_id");
+                assert_eq!(device_id, "device-id");
                 assert_eq!(node_id, "node_id");
                 assert_eq!(property_id, "property_id");
                 assert_eq!(value, "42");
@@ -138,7 +133,7 @@ async fn test_device() {
     // Check that the device looks how we expect.
     {
         let devices = controller.devices();
-        let device = devices.get("device_id").unwrap();
+        let device = devices.get("device-id").unwrap();
         let node = device.nodes.get("node_id").unwrap();
         let property = node.properties.get("property_id").unwrap();
         log::info!("Property: {:?}", property);
@@ -147,7 +142,7 @@ async fn test_device() {
 
     // Send a value from the controller to the device.
     controller
-        .set("device_id", "node_id", "property_id", 13)
+        .set("device-id", "node_id", "property_id", 13)
         .await
         .unwrap();
 
@@ -163,7 +158,7 @@ async fn test_device() {
                 fresh,
             } = event
             {
-                assert_eq!(device_id, "device_id");
+                assert_eq!(device_id, "device-id");
                 assert_eq!(node_id, "node_id");
                 assert_eq!(property_id, "property_id");
                 assert_eq!(value, "13");
@@ -177,7 +172,7 @@ async fn test_device() {
     // Check that the value sent back is reflected on the controller's view of the device.
     {
         let devices = controller.devices();
-        let device = devices.get("device_id").unwrap();
+        let device = devices.get("device-id").unwrap();
         let node = device.nodes.get("node_id").unwrap();
         let property = node.properties.get("property_id").unwrap();
         log::info!("Property: {:?}", property);
@@ -203,46 +198,3 @@ async fn test_device() {
         }
     }
 }
-
-/// Spawn an MQTT broker listening on the given port on localhost.
-fn spawn_mqtt_broker(port: u16) {
-    let mut v4 = HashMap::new();
-    v4.insert(
-        "1".to_string(),
-        ServerSettings {
-            name: "Test broker".to_string(),
-            listen: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
-            tls: None,
-            next_connection_delay_ms: 1,
-            connections: ConnectionSettings {
-                connection_timeout_ms: 100,
-                max_payload_size: 2048,
-                max_inflight_count: 500,
-                auth: None,
-                dynamic_filters: false,
-                external_auth: None,
-            },
-        },
-    );
-    let broker_config = Config {
-        id: 0,
-        router: RouterConfig {
-            max_segment_size: 2000,
-            max_segment_count: 10,
-            max_connections: 20,
-            initialized_filters: None,
-            max_outgoing_packet_count: 100,
-            ..Default::default()
-        },
-        v4: Some(v4),
-        console: None,
-        ..Config::default()
-    };
-    let mut broker = Broker::new(broker_config);
-    thread::spawn(move || {
-        broker.start().expect(&format!(
-            "Failed to start MQTT broker. This may be because port {} is already in use",
-            port,
-        ));
-    });
-}
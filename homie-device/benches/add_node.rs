@@ -0,0 +1,57 @@
+//! Benchmark of publishing a node with many properties to a local MQTT broker.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use homie_device::{HomieDevice, Node, Property};
+use mqtt_test_broker::spawn_mqtt_broker;
+use rumqttc::MqttOptions;
+use tokio::runtime::Runtime;
+
+const PROPERTY_COUNT: usize = 100;
+
+fn make_node(property_count: usize) -> Node {
+    let properties = (0..property_count)
+        .map(|i| {
+            Property::float(
+                &format!("property{}", i),
+                "Property",
+                i % 2 == 0,
+                true,
+                Some("°C"),
+                None,
+            )
+        })
+        .collect();
+    Node::new("node", "Node", "type", properties)
+}
+
+fn add_node(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let port = spawn_mqtt_broker();
+
+    let mqtt_options = MqttOptions::new("homie_device_bench", "localhost", port);
+    let (mut homie, homie_handle) = runtime.block_on(async {
+        let (mut homie, homie_handle) =
+            HomieDevice::builder_for("homie", "bench", "Bench", mqtt_options)
+                .unwrap()
+                .spawn()
+                .await
+                .unwrap();
+        homie.ready().await.unwrap();
+        (homie, tokio::spawn(homie_handle))
+    });
+
+    let node = make_node(PROPERTY_COUNT);
+    c.bench_function("add_node with 100 properties", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                homie.add_node(node.clone()).await.unwrap();
+                homie.remove_node("node").await.unwrap();
+            })
+        })
+    });
+
+    homie_handle.abort();
+}
+
+criterion_group!(benches, add_node);
+criterion_main!(benches);
@@ -12,11 +12,12 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     let mqttoptions = MqttOptions::new("homie_example", "test.mosquitto.org", 1883);
 
-    let (mut homie, homie_handle) = HomieDevice::builder(
-        "homie/example_lifecycle",
+    let (mut homie, homie_handle) = HomieDevice::builder_for(
+        "homie",
+        "example-lifecycle",
         "Homie lifecycle example",
         mqttoptions,
-    )
+    )?
     .spawn()
     .await?;
 
@@ -1,14 +1,15 @@
-use homie_device::{ColorFormat, ColorRgb, HomieDevice, Node, Property, SpawnError};
+use homie_device::{ColorFormat, ColorRgb, HomieDevice, Node, Property};
 use rumqttc::MqttOptions;
+use std::error::Error;
 
 #[tokio::main]
-async fn main() -> Result<(), SpawnError> {
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     pretty_env_logger::init();
 
     let mqttoptions = MqttOptions::new("homie_example", "test.mosquitto.org", 1883);
 
     let mut builder =
-        HomieDevice::builder("homie/example_light", "Homie light example", mqttoptions);
+        HomieDevice::builder_for("homie", "example-light", "Homie light example", mqttoptions)?;
     builder.set_update_callback(update_callback);
     let (mut homie, homie_handle) = builder.spawn().await?;
 
@@ -27,7 +28,8 @@ async fn main() -> Result<(), SpawnError> {
     println!("Ready");
 
     // This will only resolve (with an error) if we lose connection to the MQTT broker.
-    homie_handle.await
+    homie_handle.await?;
+    Ok(())
 }
 
 async fn update_callback(node_id: String, property_id: String, value: String) -> Option<String> {
@@ -13,10 +13,14 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     let mqttoptions = MqttOptions::new("homie_example", "test.mosquitto.org", 1883);
 
-    let (mut homie, homie_handle) =
-        HomieDevice::builder("homie/example_sensor", "Homie sensor example", mqttoptions)
-            .spawn()
-            .await?;
+    let (mut homie, homie_handle) = HomieDevice::builder_for(
+        "homie",
+        "example-sensor",
+        "Homie sensor example",
+        mqttoptions,
+    )?
+    .spawn()
+    .await?;
 
     homie
         .add_node(Node::new(
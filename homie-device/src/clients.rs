@@ -0,0 +1,291 @@
+use crate::Executor;
+use rumqttc::{AsyncClient, ClientError, Event, EventLoop, Incoming, QoS};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long to wait before trying to reconnect to an additional broker after the connection fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long after a message is received from one broker that the same message from another broker
+/// is treated as a duplicate.
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// The MQTT clients for all the brokers which a device is published to.
+///
+/// Requests are sent to the primary broker as usual, waiting if its request channel is full, and
+/// any errors are returned. Requests to additional brokers never wait: if one of them is
+/// unreachable for long enough that its request channel fills up then they are dropped, and
+/// instead all retained values and subscriptions are restored once it reconnects.
+#[derive(Clone, Debug)]
+pub(crate) struct MqttClients {
+    primary: AsyncClient,
+    additional: Vec<AsyncClient>,
+    /// The retained values and subscriptions to restore on additional brokers when they reconnect.
+    /// This is only kept up to date if there are any additional brokers.
+    restore: Arc<Mutex<RestoreState>>,
+}
+
+impl From<AsyncClient> for MqttClients {
+    fn from(client: AsyncClient) -> Self {
+        Self::new(client, vec![])
+    }
+}
+
+#[derive(Debug, Default)]
+struct RestoreState {
+    retained: BTreeMap<String, Vec<u8>>,
+    subscriptions: BTreeSet<String>,
+}
+
+impl MqttClients {
+    pub(crate) fn new(primary: AsyncClient, additional: Vec<AsyncClient>) -> Self {
+        Self {
+            primary,
+            additional,
+            restore: Default::default(),
+        }
+    }
+
+    /// Get the client for the primary broker.
+    pub(crate) fn primary(&self) -> &AsyncClient {
+        &self.primary
+    }
+
+    /// Get the number of brokers, including the primary one.
+    pub(crate) fn len(&self) -> usize {
+        self.additional.len() + 1
+    }
+
+    pub(crate) async fn publish(
+        &self,
+        topic: String,
+        retain: bool,
+        value: Vec<u8>,
+    ) -> Result<(), ClientError> {
+        self.publish_additional(&topic, retain, &value);
+        self.primary
+            .publish(topic, QoS::AtLeastOnce, retain, value)
+            .await
+    }
+
+    pub(crate) async fn subscribe(&self, topic: String) -> Result<(), ClientError> {
+        if !self.additional.is_empty() {
+            self.restore
+                .lock()
+                .unwrap()
+                .subscriptions
+                .insert(topic.clone());
+            for client in &self.additional {
+                if let Err(e) = client.try_subscribe(topic.clone(), QoS::AtLeastOnce) {
+                    log::warn!(
+                        "Failed to subscribe to {} on additional broker: {}",
+                        topic,
+                        e
+                    );
+                }
+            }
+        }
+        self.primary.subscribe(topic, QoS::AtLeastOnce).await
+    }
+
+    pub(crate) async fn unsubscribe(&self, topic: String) -> Result<(), ClientError> {
+        if !self.additional.is_empty() {
+            self.restore.lock().unwrap().subscriptions.remove(&topic);
+            for client in &self.additional {
+                if let Err(e) = client.try_unsubscribe(topic.clone()) {
+                    log::warn!(
+                        "Failed to unsubscribe from {} on additional broker: {}",
+                        topic,
+                        e
+                    );
+                }
+            }
+        }
+        self.primary.unsubscribe(topic).await
+    }
+
+    pub(crate) async fn disconnect(&self) -> Result<(), ClientError> {
+        for client in &self.additional {
+            if let Err(e) = client.try_disconnect() {
+                log::warn!("Failed to disconnect from additional broker: {}", e);
+            }
+        }
+        self.primary.disconnect().await
+    }
+
+    /// Queue the given publish for all additional brokers, and remember it to restore later if it
+    /// is retained.
+    fn publish_additional(&self, topic: &str, retain: bool, value: &[u8]) {
+        if self.additional.is_empty() {
+            return;
+        }
+        if retain {
+            let retained = &mut self.restore.lock().unwrap().retained;
+            // An empty retained message removes the retained value from the broker.
+            if value.is_empty() {
+                retained.remove(topic);
+            } else {
+                retained.insert(topic.to_owned(), value.to_owned());
+            }
+        }
+        for client in &self.additional {
+            if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, retain, value) {
+                log::debug!("Dropping publish to {} for additional broker: {}", topic, e);
+            }
+        }
+    }
+
+    /// Poll the event loop for the additional broker with the given index (starting from 1, as 0 is
+    /// the primary broker), forwarding incoming messages to the given channel.
+    ///
+    /// Unlike for the primary broker, connection errors are logged and the connection retried rather
+    /// than returned, so this never completes unless the channel is closed. Whenever a new session is
+    /// established, the given subscriptions are made along with those of the device, and all
+    /// retained values are republished.
+    pub(crate) async fn run_additional(
+        &self,
+        index: usize,
+        mut event_loop: EventLoop,
+        extra_subscriptions: Vec<String>,
+        incoming_tx: flume::Sender<(usize, Incoming)>,
+        executor: Arc<dyn Executor>,
+    ) {
+        let client = self.additional[index - 1].clone();
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(incoming)) => {
+                    if let Incoming::ConnAck(connack) = &incoming {
+                        if !connack.session_present {
+                            self.restore_to(&client, &extra_subscriptions, &*executor);
+                        }
+                    }
+                    if incoming_tx.send_async((index, incoming)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::warn!(
+                        "Connection to additional MQTT broker {} failed, retrying: {}",
+                        index,
+                        e
+                    );
+                    executor.sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Spawn a task to restore all subscriptions and retained values to the given client. This
+    /// can't be done on the task polling its event loop, as it may need to wait for the request
+    /// channel to be drained.
+    fn restore_to(
+        &self,
+        client: &AsyncClient,
+        extra_subscriptions: &[String],
+        executor: &dyn Executor,
+    ) {
+        let (subscriptions, retained) = {
+            let restore = self.restore.lock().unwrap();
+            let mut subscriptions: Vec<String> = extra_subscriptions.to_vec();
+            subscriptions.extend(restore.subscriptions.iter().cloned());
+            (subscriptions, restore.retained.clone())
+        };
+        let client = client.clone();
+        executor.spawn(Box::pin(async move {
+            for topic in subscriptions {
+                if let Err(e) = client.subscribe(&topic, QoS::AtLeastOnce).await {
+                    log::error!("Failed to restore subscription to {}: {}", topic, e);
+                    return;
+                }
+            }
+            for (topic, value) in retained {
+                if let Err(e) = client.publish(&topic, QoS::AtLeastOnce, true, value).await {
+                    log::error!("Failed to restore retained value of {}: {}", topic, e);
+                    return;
+                }
+            }
+        }));
+    }
+}
+
+/// Filters out messages which are received from more than one broker, such as a set command sent by
+/// a controller connected to several of them.
+#[derive(Debug, Default)]
+pub(crate) struct Deduplicator {
+    /// The indices of the brokers from which each recent message has been received, keyed by topic
+    /// and payload, and the time at which it was first received.
+    recent: HashMap<(String, Vec<u8>), (BTreeSet<usize>, Instant)>,
+}
+
+impl Deduplicator {
+    /// Check whether the given message received from the broker with the given index is a
+    /// duplicate of one received recently from a different broker.
+    pub(crate) fn is_duplicate(&mut self, index: usize, topic: &str, payload: &[u8]) -> bool {
+        let now = Instant::now();
+        self.recent
+            .retain(|_, (_, received)| now.duration_since(*received) < DUPLICATE_WINDOW);
+        let key = (topic.to_owned(), payload.to_owned());
+        if let Some((indices, _)) = self.recent.get_mut(&key) {
+            // Only drop one copy from each other broker.
+            if indices.insert(index) {
+                return true;
+            }
+        }
+        // This is either the first copy, or another copy from the same broker which is a new
+        // message.
+        self.recent.insert(key, ([index].into(), now));
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_retained_values_to_restore() {
+        let (primary_tx, _primary_rx) = flume::unbounded();
+        let (additional_tx, _additional_rx) = flume::unbounded();
+        let clients = MqttClients::new(
+            AsyncClient::from_senders(primary_tx),
+            vec![AsyncClient::from_senders(additional_tx)],
+        );
+
+        clients.publish_additional("homie/device/$state", true, b"ready");
+        clients.publish_additional("homie/device/node/$name", true, b"Node");
+        clients.publish_additional("homie/device/node/$name", true, b"");
+        clients.publish_additional("homie/device/node/event", false, b"pressed");
+
+        let retained = &clients.restore.lock().unwrap().retained;
+        assert_eq!(
+            retained.iter().collect::<Vec<_>>(),
+            vec![(&"homie/device/$state".to_string(), &b"ready".to_vec())]
+        );
+    }
+
+    #[test]
+    fn duplicates_from_other_brokers() {
+        let mut deduplicator = Deduplicator::default();
+        assert!(!deduplicator.is_duplicate(0, "homie/device/node/property/set", b"on"));
+        assert!(deduplicator.is_duplicate(1, "homie/device/node/property/set", b"on"));
+        // The same payload again from the same broker is a new command.
+        assert!(!deduplicator.is_duplicate(1, "homie/device/node/property/set", b"on"));
+        assert!(!deduplicator.is_duplicate(1, "homie/device/node/property/set", b"on"));
+        // A different payload from another broker is not a duplicate.
+        assert!(!deduplicator.is_duplicate(0, "homie/device/node/property/set", b"off"));
+        assert!(!deduplicator.is_duplicate(1, "homie/device/node/other/set", b"off"));
+    }
+
+    #[test]
+    fn duplicates_from_three_brokers() {
+        let mut deduplicator = Deduplicator::default();
+        assert!(!deduplicator.is_duplicate(0, "homie/device/node/property/set", b"on"));
+        assert!(deduplicator.is_duplicate(2, "homie/device/node/property/set", b"on"));
+        assert!(deduplicator.is_duplicate(1, "homie/device/node/property/set", b"on"));
+        // A fourth copy must be a new command, as each broker has already sent one.
+        assert!(!deduplicator.is_duplicate(1, "homie/device/node/property/set", b"on"));
+        assert!(deduplicator.is_duplicate(0, "homie/device/node/property/set", b"on"));
+        assert!(deduplicator.is_duplicate(2, "homie/device/node/property/set", b"on"));
+    }
+}
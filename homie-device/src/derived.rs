@@ -0,0 +1,126 @@
+use crate::Node;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::{Arc, Mutex};
+
+/// A function to compute the value of a derived property from the value of its source property,
+/// or `None` if there should be no derived value.
+type TransformFn = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+/// A property whose value is computed from that of another property.
+#[derive(Clone)]
+struct Derivation {
+    source_node_id: String,
+    source_property_id: String,
+    node_id: String,
+    property_id: String,
+    transform: Arc<TransformFn>,
+}
+
+impl Debug for Derivation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Derivation")
+            .field("source_node_id", &self.source_node_id)
+            .field("source_property_id", &self.source_property_id)
+            .field("node_id", &self.node_id)
+            .field("property_id", &self.property_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The derived properties of the device, shared between the device and anything publishing values
+/// for it.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Derivations {
+    derivations: Arc<Mutex<Vec<Derivation>>>,
+}
+
+impl Derivations {
+    pub(crate) fn add(
+        &self,
+        source_node_id: &str,
+        source_property_id: &str,
+        node_id: &str,
+        property_id: &str,
+        transform: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) {
+        self.derivations.lock().unwrap().push(Derivation {
+            source_node_id: source_node_id.to_owned(),
+            source_property_id: source_property_id.to_owned(),
+            node_id: node_id.to_owned(),
+            property_id: property_id.to_owned(),
+            transform: Arc::new(transform),
+        });
+    }
+
+    /// Removes all derivations from or to properties of the given node.
+    pub(crate) fn remove_node(&self, node: &Node) {
+        self.derivations.lock().unwrap().retain(|derivation| {
+            derivation.source_node_id != node.id && derivation.node_id != node.id
+        });
+    }
+
+    /// Computes the values of the properties derived from the given property, given its new value.
+    /// Returns the node ID, property ID and value of each derived property which has a value.
+    pub(crate) fn derive(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        value: &str,
+    ) -> Vec<(String, String, String)> {
+        // Clone the matching derivations so that the lock isn't held while calling the transforms.
+        let derivations: Vec<Derivation> = self
+            .derivations
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|derivation| {
+                derivation.source_node_id == node_id && derivation.source_property_id == property_id
+            })
+            .cloned()
+            .collect();
+        derivations
+            .into_iter()
+            .filter_map(|derivation| {
+                let value = (derivation.transform)(value)?;
+                Some((derivation.node_id, derivation.property_id, value))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_matching_only() {
+        let derivations = Derivations::default();
+        derivations.add("node", "celsius", "node", "fahrenheit", |value| {
+            let celsius: f64 = value.parse().ok()?;
+            Some(format!("{}", celsius * 9.0 / 5.0 + 32.0))
+        });
+        derivations.add("node", "celsius", "other", "copy", |value| {
+            Some(value.to_owned())
+        });
+
+        assert_eq!(
+            derivations.derive("node", "celsius", "100"),
+            vec![
+                ("node".to_owned(), "fahrenheit".to_owned(), "212".to_owned()),
+                ("other".to_owned(), "copy".to_owned(), "100".to_owned()),
+            ]
+        );
+        // A transform returning `None` is skipped.
+        assert_eq!(
+            derivations.derive("node", "celsius", "hot"),
+            vec![("other".to_owned(), "copy".to_owned(), "hot".to_owned())]
+        );
+        assert_eq!(derivations.derive("node", "fahrenheit", "100"), vec![]);
+
+        derivations.remove_node(&Node::new("other", "Other", "type", vec![]));
+        assert_eq!(
+            derivations.derive("node", "celsius", "0"),
+            vec![("node".to_owned(), "fahrenheit".to_owned(), "32".to_owned())]
+        );
+    }
+}
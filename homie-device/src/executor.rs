@@ -0,0 +1,54 @@
+use futures::future::BoxFuture;
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// The async runtime on which a Homie device spawns background work and waits for timers, such as
+/// publishing stats periodically or publishing rate-limited values.
+///
+/// By default [`TokioExecutor`] is used. To run the protocol logic on another executor, implement
+/// this trait for it, pass it to
+/// [`HomieDeviceBuilder::set_executor`](crate::HomieDeviceBuilder::set_executor), and poll the
+/// futures returned by [`HomieDeviceBuilder::run`](crate::HomieDeviceBuilder::run) on it rather
+/// than calling `spawn`, which always uses Tokio.
+///
+/// The MQTT connection itself is handled by `rumqttc`, which uses Tokio's networking, so a Tokio
+/// reactor must still be available when the event loop future is polled, e.g. by wrapping it with
+/// the `async-compat` crate.
+///
+/// For example, for `async-std`:
+///
+/// ```ignore
+/// #[derive(Debug)]
+/// struct AsyncStdExecutor;
+///
+/// impl Executor for AsyncStdExecutor {
+///     fn spawn(&self, future: BoxFuture<'static, ()>) {
+///         async_std::task::spawn(future);
+///     }
+///
+///     fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+///         Box::pin(async_std::task::sleep(duration))
+///     }
+/// }
+/// ```
+pub trait Executor: Debug + Send + Sync {
+    /// Spawn the given future to run to completion in the background.
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+
+    /// Returns a future which completes once the given duration has elapsed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// An executor which uses the current Tokio runtime.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioExecutor;
+
+impl Executor for TokioExecutor {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        tokio::task::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
@@ -0,0 +1,132 @@
+use thiserror::Error;
+
+/// An error returned when an ID doesn't follow the Homie
+/// [ID format](https://homieiot.github.io/specification/#topic-ids).
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+#[error("Invalid Homie ID '{id}'")]
+pub struct InvalidIdError {
+    /// The invalid ID.
+    pub id: String,
+}
+
+/// Check that the given ID follows the Homie [ID format](https://homieiot.github.io/specification/#topic-ids),
+/// i.e. it matches `^[a-z0-9][a-z0-9-]*$`.
+pub fn validate_id(id: &str) -> Result<(), InvalidIdError> {
+    if is_valid_id(id) {
+        Ok(())
+    } else {
+        Err(InvalidIdError { id: id.to_owned() })
+    }
+}
+
+/// Check whether the given ID follows the Homie [ID format](https://homieiot.github.io/specification/#topic-ids).
+///
+/// This is the same check as [`validate_id`], but can be evaluated at compile time.
+pub const fn is_valid_id(id: &str) -> bool {
+    let bytes = id.as_bytes();
+    if bytes.is_empty() || bytes[0] == b'-' {
+        return false;
+    }
+    let mut i = 0;
+    while i < bytes.len() {
+        if !matches!(bytes[i], b'a'..=b'z' | b'0'..=b'9' | b'-') {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// An error returned when the base topic or ID with which a device is constructed is not valid.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub enum DeviceTopicError {
+    /// The base topic is empty, has an empty level, or contains an MQTT wildcard.
+    #[error("Invalid Homie base topic '{0}'")]
+    InvalidBaseTopic(String),
+    /// The device ID doesn't follow the Homie ID format.
+    #[error("{0}")]
+    InvalidDeviceId(#[from] InvalidIdError),
+}
+
+/// Check that the given Homie [base topic](https://homieiot.github.io/specification/#base-topic) is
+/// valid. It may have several levels, such as `"building/floor-1/homie"`, but none of them may be
+/// empty or contain an MQTT wildcard.
+pub fn validate_base_topic(base_topic: &str) -> Result<(), DeviceTopicError> {
+    if base_topic
+        .split('/')
+        .all(|level| !level.is_empty() && !level.contains(['+', '#']))
+    {
+        Ok(())
+    } else {
+        Err(DeviceTopicError::InvalidBaseTopic(base_topic.to_owned()))
+    }
+}
+
+/// Convert a human-readable name to a valid Homie ID, by converting it to lowercase and replacing
+/// each run of other characters with a single hyphen. For example, `"Living Room (East)"` becomes
+/// `"living-room-east"`.
+///
+/// Returns an empty string, which is not a valid ID, if the name contains no ASCII letters or
+/// digits.
+pub fn slugify(name: &str) -> String {
+    let mut id = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            id.push(c.to_ascii_lowercase());
+        } else if !id.is_empty() && !id.ends_with('-') {
+            id.push('-');
+        }
+    }
+    if id.ends_with('-') {
+        id.pop();
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_ids() {
+        for id in ["a", "0", "node", "node-2", "3-way", "a--b", "trailing-"] {
+            assert_eq!(validate_id(id), Ok(()), "{}", id);
+        }
+    }
+
+    #[test]
+    fn invalid_ids() {
+        for id in ["", "-node", "Node", "node_id", "node id", "$state", "nöde"] {
+            assert_eq!(
+                validate_id(id),
+                Err(InvalidIdError { id: id.to_owned() }),
+                "{}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn base_topics() {
+        for base_topic in ["homie", "building/floor-1/homie", "devices"] {
+            assert_eq!(validate_base_topic(base_topic), Ok(()), "{}", base_topic);
+        }
+        for base_topic in ["", "/homie", "homie/", "a//b", "homie/+", "homie/#"] {
+            assert_eq!(
+                validate_base_topic(base_topic),
+                Err(DeviceTopicError::InvalidBaseTopic(base_topic.to_owned())),
+                "{}",
+                base_topic
+            );
+        }
+    }
+
+    #[test]
+    fn slugify_names() {
+        assert_eq!(slugify("Living Room (East)"), "living-room-east");
+        assert_eq!(slugify("  temperature_sensor_2 "), "temperature-sensor-2");
+        assert_eq!(slugify("CO₂ level"), "co-level");
+        assert_eq!(slugify("already-valid"), "already-valid");
+        assert_eq!(slugify("!!!"), "");
+    }
+}
@@ -0,0 +1,268 @@
+use crate::{HomieDevice, Property};
+use rumqttc::ClientError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::watch;
+
+type Validator<T> = dyn Fn(&T) -> Result<(), String> + Send + Sync;
+
+/// An error while attempting to update or publish a `JsonConfig`.
+#[derive(Debug, Error)]
+pub enum JsonConfigError {
+    /// The value couldn't be parsed as JSON of the expected structure.
+    #[error("Invalid JSON: {0}")]
+    Parse(#[from] serde_json::Error),
+    /// The value was rejected by the validator.
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+    /// The config couldn't be serialized as JSON, such as because it contains a map with non-string
+    /// keys.
+    #[error("Failed to serialize config: {0}")]
+    Serialize(serde_json::Error),
+    /// The config couldn't be published to the MQTT broker.
+    #[error("Failed to publish config: {0}")]
+    Publish(#[from] ClientError),
+}
+
+/// A settable string property holding configuration for the device as JSON.
+///
+/// Register it with [`HomieDeviceBuilder::add_json_config`](crate::HomieDeviceBuilder::add_json_config)
+/// and include [`property`](Self::property) in one of the device's nodes. When a controller sets
+/// the property, the new value is parsed as `T` and checked by the validator. If it is valid then
+/// it replaces the current config, and the effective config is republished. If not, the current
+/// config is left unchanged.
+///
+/// `JsonConfig` is cheap to clone, and all clones refer to the same config.
+pub struct JsonConfig<T> {
+    node_id: String,
+    property_id: String,
+    sender: Arc<watch::Sender<T>>,
+    validator: Arc<Validator<T>>,
+}
+
+impl<T> Clone for JsonConfig<T> {
+    fn clone(&self) -> Self {
+        Self {
+            node_id: self.node_id.clone(),
+            property_id: self.property_id.clone(),
+            sender: self.sender.clone(),
+            validator: self.validator.clone(),
+        }
+    }
+}
+
+impl<T: Debug> Debug for JsonConfig<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("JsonConfig")
+            .field("node_id", &self.node_id)
+            .field("property_id", &self.property_id)
+            .field("config", &*self.sender.borrow())
+            .field("validator", &"...")
+            .finish()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Send + Sync + 'static> JsonConfig<T> {
+    /// Create a new JSON config property with the given initial config.
+    ///
+    /// # Arguments
+    /// * `node_id`: The ID of the node to which the property will be added.
+    /// * `property_id`: The subtopic ID for the property. This must be unique per node, and follow
+    ///   the Homie [ID format](https://homieiot.github.io/specification/#topic-ids).
+    /// * `initial`: The initial config.
+    pub fn new(node_id: &str, property_id: &str, initial: T) -> Self {
+        Self {
+            node_id: node_id.to_owned(),
+            property_id: property_id.to_owned(),
+            sender: Arc::new(watch::channel(initial).0),
+            validator: Arc::new(|_| Ok(())),
+        }
+    }
+
+    /// Set a function to check new configs before they are applied. It should return an error
+    /// message if the config is invalid.
+    pub fn with_validator(
+        mut self,
+        validator: impl Fn(&T) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.validator = Arc::new(validator);
+        self
+    }
+
+    /// The ID of the node to which the property belongs.
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// The ID of the property.
+    pub fn property_id(&self) -> &str {
+        &self.property_id
+    }
+
+    /// Create the settable, retained string property for the config, to be added to the node.
+    pub fn property(&self, name: &str) -> Property {
+        Property::string(&self.property_id, name, true, true, None)
+    }
+
+    /// Get a copy of the current config.
+    pub fn get(&self) -> T
+    where
+        T: Clone,
+    {
+        self.sender.borrow().clone()
+    }
+
+    /// Subscribe to changes to the config.
+    pub fn subscribe(&self) -> watch::Receiver<T> {
+        self.sender.subscribe()
+    }
+
+    /// Serialize the current config to JSON.
+    pub fn to_json(&self) -> Result<String, JsonConfigError> {
+        serde_json::to_string(&*self.sender.borrow()).map_err(JsonConfigError::Serialize)
+    }
+
+    /// Parse and validate the given JSON, and if it is valid then replace the current config with
+    /// it.
+    ///
+    /// Returns the effective config serialized as JSON, or an error if it was invalid.
+    pub fn update(&self, json: &str) -> Result<String, JsonConfigError> {
+        let config: T = serde_json::from_str(json)?;
+        (self.validator)(&config).map_err(JsonConfigError::Invalid)?;
+        let effective = serde_json::to_string(&config).map_err(JsonConfigError::Serialize)?;
+        self.sender.send_replace(config);
+        Ok(effective)
+    }
+
+    /// Publish the current config as the value of the property. This should be called after adding
+    /// the node containing the property to the device.
+    pub async fn publish(&self, homie: &HomieDevice) -> Result<(), JsonConfigError> {
+        homie
+            .publish_value(&self.node_id, &self.property_id, self.to_json()?)
+            .await?;
+        Ok(())
+    }
+
+    /// Handle a value set by a controller, returning the value to publish if it was applied.
+    pub(crate) fn handle_set(&self, value: &str) -> Option<String> {
+        match self.update(value) {
+            Ok(effective) => Some(effective),
+            Err(e) => {
+                log::warn!(
+                    "Rejected config for {}/{}: {}",
+                    self.node_id,
+                    self.property_id,
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    struct TestConfig {
+        interval: u32,
+        #[serde(default)]
+        enabled: bool,
+    }
+
+    fn make_test_config() -> JsonConfig<TestConfig> {
+        JsonConfig::new(
+            "node",
+            "config",
+            TestConfig {
+                interval: 10,
+                enabled: false,
+            },
+        )
+        .with_validator(|config| {
+            if config.interval == 0 {
+                Err("interval must be positive".to_owned())
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    #[test]
+    fn update_valid() {
+        let config = make_test_config();
+        let receiver = config.subscribe();
+
+        assert_eq!(
+            config.update(r#"{"interval": 5}"#).unwrap(),
+            r#"{"interval":5,"enabled":false}"#
+        );
+        assert_eq!(
+            config.get(),
+            TestConfig {
+                interval: 5,
+                enabled: false
+            }
+        );
+        assert!(receiver.has_changed().unwrap());
+    }
+
+    #[test]
+    fn update_invalid_json() {
+        let config = make_test_config();
+
+        assert!(matches!(
+            config.update(r#"{"enabled": true}"#),
+            Err(JsonConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            config.update("not json"),
+            Err(JsonConfigError::Parse(_))
+        ));
+        assert_eq!(
+            config.to_json().unwrap(),
+            r#"{"interval":10,"enabled":false}"#
+        );
+    }
+
+    #[test]
+    fn update_rejected_by_validator() {
+        let config = make_test_config();
+        let receiver = config.subscribe();
+
+        assert!(matches!(
+            config.update(r#"{"interval": 0, "enabled": true}"#),
+            Err(JsonConfigError::Invalid(_))
+        ));
+        assert_eq!(
+            config.to_json().unwrap(),
+            r#"{"interval":10,"enabled":false}"#
+        );
+        assert!(!receiver.has_changed().unwrap());
+    }
+
+    #[test]
+    fn serialize_error() {
+        // serde_json can't serialize maps with non-string keys.
+        let config = JsonConfig::new("node", "config", BTreeMap::from([((1, 2), 3)]));
+
+        assert!(matches!(
+            config.to_json(),
+            Err(JsonConfigError::Serialize(_))
+        ));
+        assert!(matches!(
+            config.update(r#"{}"#),
+            Ok(json) if json == "{}"
+        ));
+        assert!(matches!(
+            config.to_json(),
+            Ok(json) if json == "{}"
+        ));
+    }
+}
@@ -3,7 +3,7 @@
 //!
 //! See the examples directory for examples of how to use it.
 
-use futures::future::try_join;
+use futures::future::{join_all, try_join, try_join3};
 use futures::FutureExt;
 
 use mac_address::get_mac_address;
@@ -11,24 +11,52 @@ use rumqttc::{
     self, AsyncClient, ClientError, ConnectionError, Event, EventLoop, Incoming, LastWill,
     MqttOptions, QoS,
 };
+use std::collections::HashMap;
 use std::fmt::{self, Debug, Display, Formatter};
 use std::future::Future;
 use std::pin::Pin;
 use std::str;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::task::{self, JoinError, JoinHandle};
-use tokio::time::sleep;
 
+mod clients;
+use crate::clients::{Deduplicator, MqttClients};
+mod derived;
+use crate::derived::Derivations;
+mod executor;
+pub use crate::executor::{Executor, TokioExecutor};
+mod id;
+pub use crate::id::{
+    is_valid_id, slugify, validate_base_topic, validate_id, DeviceTopicError, InvalidIdError,
+};
+#[cfg(feature = "json")]
+mod json_config;
+#[cfg(feature = "json")]
+pub use crate::json_config::{JsonConfig, JsonConfigError};
+mod logger;
+mod macros;
+pub use crate::logger::HomieLogger;
+mod rate_limit;
+use crate::rate_limit::RateLimiter;
+mod set_value;
+pub use crate::set_value::{InvalidSetPayloads, InvalidValueError, SetValue, UpdateOutcome};
 mod types;
-pub use crate::types::{Datatype, Node, Property};
+mod updater;
+pub use crate::types::{Datatype, Node, NodeBuilder, Property, PropertyBuilder, PropertyCategory};
+pub use crate::updater::{PropertyUpdateError, PropertyUpdater};
+mod validate;
+pub use crate::validate::{Violation, ViolationKind};
+mod value_publisher;
+pub use crate::value_publisher::ValuePublisher;
 mod values;
-pub use crate::values::{Color, ColorFormat, ColorHsv, ColorRgb};
+pub use crate::values::{format_datetime, format_duration, Color, ColorFormat, ColorHsv, ColorRgb};
 
 const HOMIE_VERSION: &str = "4.0";
 const HOMIE_IMPLEMENTATION: &str = "homie-rs";
 const STATS_INTERVAL: Duration = Duration::from_secs(60);
-const REQUESTS_CAP: usize = 10;
+const REQUESTS_CAP: usize = 1000;
 
 /// Error type for futures representing tasks spawned by this crate.
 #[derive(Error, Debug)]
@@ -43,6 +71,44 @@ pub enum SpawnError {
     Internal(&'static str),
 }
 
+/// The futures which handle the MQTT connection of a Homie device, returned by
+/// [`HomieDeviceBuilder::run`] for the caller to spawn or poll as it chooses.
+///
+/// They run until there is an error. All of them must be polled for the device to work.
+pub struct DeviceFutures {
+    /// Polls the MQTT event loop, which sends all requests and receives incoming messages.
+    pub event_loop: DeviceFuture,
+    /// Handles incoming messages, calling the update and broadcast callbacks.
+    pub incoming: DeviceFuture,
+    /// Periodically publishes stats.
+    pub stats: DeviceFuture,
+    /// Publishes log lines, if a logger was added to the builder.
+    pub logger: Option<DeviceFuture>,
+}
+
+impl DeviceFutures {
+    /// Poll all the futures together on the current task, until the first one fails.
+    pub async fn join(self) -> Result<(), SpawnError> {
+        let logger = self.logger;
+        let logger = async move {
+            match logger {
+                Some(logger) => logger.await,
+                None => Ok(()),
+            }
+        };
+        try_join3(try_join(self.event_loop, self.incoming), self.stats, logger).await?;
+        Ok(())
+    }
+}
+
+impl Debug for DeviceFutures {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceFutures")
+            .field("logger", &self.logger.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum State {
     /// The device is connected to the MQTT broker but is not yet ready to operate.
@@ -87,38 +153,114 @@ impl From<State> for Vec<u8> {
 }
 
 type UpdateCallback = Box<
-    dyn FnMut(String, String, String) -> Pin<Box<dyn Future<Output = Option<String>> + Send>>
+    dyn FnMut(String, String, String) -> Pin<Box<dyn Future<Output = UpdateOutcome> + Send>>
         + Send
         + Sync,
 >;
 
+type TypedUpdateCallback = Box<
+    dyn FnMut(String, String, SetValue) -> Pin<Box<dyn Future<Output = Option<String>> + Send>>
+        + Send
+        + Sync,
+>;
+
+type BroadcastCallback =
+    Box<dyn FnMut(String, String) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+type SetHandlerFn = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+type DeviceFuture = Pin<Box<dyn Future<Output = Result<(), SpawnError>> + Send>>;
+
+/// A handler for values set on a particular property, which takes precedence over the update
+/// callback. It returns the value to publish for the property, if any.
+struct SetHandler {
+    node_id: String,
+    property_id: String,
+    handle: Box<SetHandlerFn>,
+}
+
 /// Builder for `HomieDevice` and associated objects.
 pub struct HomieDeviceBuilder {
-    device_base: String,
+    base_topic: String,
+    device_id: String,
     device_name: String,
     firmware_name: Option<String>,
     firmware_version: Option<String>,
     mqtt_options: MqttOptions,
+    additional_mqtt_options: Vec<MqttOptions>,
     update_callback: Option<UpdateCallback>,
+    typed_update_callback: Option<TypedUpdateCallback>,
+    broadcast_callback: Option<BroadcastCallback>,
+    set_handlers: Vec<SetHandler>,
+    min_publish_interval: Option<Duration>,
+    invalid_set_payloads: InvalidSetPayloads,
+    publish_set_errors: bool,
+    logger: Option<HomieLogger>,
+    property_updaters: Vec<PropertyUpdater>,
+    executor: Arc<dyn Executor>,
 }
 
 impl Debug for HomieDeviceBuilder {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("HomieDeviceBuilder")
-            .field("device_base", &self.device_base)
+            .field("base_topic", &self.base_topic)
+            .field("device_id", &self.device_id)
             .field("device_name", &self.device_name)
             .field("firmware_name", &self.firmware_name)
             .field("firmware_version", &self.firmware_version)
             .field("mqtt_options", &self.mqtt_options)
+            .field("additional_mqtt_options", &self.additional_mqtt_options)
             .field(
                 "update_callback",
                 &self.update_callback.as_ref().map(|_| "..."),
             )
+            .field(
+                "typed_update_callback",
+                &self.typed_update_callback.as_ref().map(|_| "..."),
+            )
+            .field(
+                "broadcast_callback",
+                &self.broadcast_callback.as_ref().map(|_| "..."),
+            )
+            .field(
+                "set_handlers",
+                &self
+                    .set_handlers
+                    .iter()
+                    .map(|handler| format!("{}/{}", handler.node_id, handler.property_id))
+                    .collect::<Vec<_>>(),
+            )
+            .field("min_publish_interval", &self.min_publish_interval)
+            .field("invalid_set_payloads", &self.invalid_set_payloads)
+            .field("publish_set_errors", &self.publish_set_errors)
+            .field("logger", &self.logger)
+            .field("property_updaters", &self.property_updaters)
+            .field("executor", &self.executor)
             .finish()
     }
 }
 
 impl HomieDeviceBuilder {
+    /// Get the Homie base topic under which the device will be published.
+    pub fn base_topic(&self) -> &str {
+        &self.base_topic
+    }
+
+    /// Get the ID of the device.
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// Get the topic under which all the device's attributes are published, i.e. the base topic
+    /// followed by the device ID.
+    fn device_base(&self) -> String {
+        if self.base_topic.is_empty() {
+            self.device_id.clone()
+        } else {
+            format!("{}/{}", self.base_topic, self.device_id)
+        }
+    }
+
     /// Set the firmware name and version to be advertised for the Homie device.
     ///
     /// If this is not set, it will default to the cargo package name and version.
@@ -127,67 +269,317 @@ impl HomieDeviceBuilder {
         self.firmware_version = Some(firmware_version.to_string());
     }
 
+    /// Also publish the device to the MQTT broker with the given options, in addition to the one
+    /// passed when creating the builder. This may be called several times to add more brokers.
+    ///
+    /// Everything the device publishes is sent to all brokers, and set commands and broadcasts are
+    /// accepted from any of them. If the same message is received from more than one broker within
+    /// ten seconds, such as when a controller is connected to several of them, only the first copy is
+    /// handled.
+    ///
+    /// Each additional broker has its own connection, which is retried independently if it fails,
+    /// and restored by republishing all retained values once it reconnects. Errors from additional
+    /// brokers are only logged; only errors from the original broker are returned.
+    pub fn add_additional_broker(&mut self, mqtt_options: MqttOptions) {
+        self.additional_mqtt_options.push(mqtt_options);
+    }
+
+    /// Set the executor used to spawn background work and wait for timers. By default this is
+    /// [`TokioExecutor`].
+    ///
+    /// To run the device without Tokio's executor, use [`run`](Self::run) rather than
+    /// [`spawn`](Self::spawn), and poll the returned futures on the same executor.
+    pub fn set_executor(&mut self, executor: impl Executor + 'static) {
+        self.executor = Arc::new(executor);
+    }
+
+    /// Limit how often property values are published by `HomieDevice::publish_value`.
+    ///
+    /// Values published for a property within `min_interval` of the previous value for the same
+    /// property are held back until the interval has elapsed, and only the latest of them is then
+    /// published. This is useful for properties which may change very rapidly, such as a dimmer
+    /// being dragged, to avoid flooding the MQTT broker.
+    ///
+    /// Held back values are published by a task spawned with the [executor](Self::set_executor).
+    /// This task isn't tied to the `HomieDevice`, so if the device is dropped before the interval
+    /// has elapsed it will still try to publish the latest value, and log an error if the MQTT
+    /// client has since been disconnected.
+    ///
+    /// By default there is no limit.
+    pub fn set_min_publish_interval(&mut self, min_interval: Duration) {
+        self.min_publish_interval = Some(min_interval);
+    }
+
+    /// Set how values set by a controller which are not valid for the datatype and format of the
+    /// property are handled.
+    ///
+    /// By default they are passed to the update callback like any other value. If they are
+    /// rejected, a warning is logged and the current value of the property is republished so that
+    /// the controller sees that it hasn't changed.
+    pub fn set_invalid_set_payloads(&mut self, invalid_set_payloads: InvalidSetPayloads) {
+        self.invalid_set_payloads = invalid_set_payloads;
+    }
+
+    /// Set whether to publish the reason why a value set by a controller was rejected, either
+    /// because it was invalid or by the update callback, to the `$error` subtopic of the property.
+    ///
+    /// This isn't part of the Homie convention, so it is disabled by default. When enabled, the
+    /// reason is published as a non-retained message, after the current value of the property is
+    /// republished.
+    pub fn set_publish_set_errors(&mut self, publish_set_errors: bool) {
+        self.publish_set_errors = publish_set_errors;
+    }
+
+    /// Set a callback to be called when a controller sets the value of a property, with the node
+    /// ID, property ID and new value as a string. This replaces any callback set by
+    /// `set_typed_update_callback`.
+    ///
+    /// The callback returns the value to publish for the property, if any. To change the attributes
+    /// of properties from the callback, use a [`PropertyUpdater`].
     pub fn set_update_callback<F, Fut>(&mut self, mut update_callback: F)
     where
         F: (FnMut(String, String, String) -> Fut) + Send + Sync + 'static,
         Fut: Future<Output = Option<String>> + Send + 'static,
     {
+        self.typed_update_callback = None;
         self.update_callback = Some(Box::new(
             move |node_id: String, property_id: String, value: String| {
+                update_callback(node_id, property_id, value)
+                    .map(UpdateOutcome::from)
+                    .boxed()
+            },
+        ));
+    }
+
+    /// Set a callback to be called when a controller sets the value of a property, as for
+    /// `set_update_callback`, but which returns whether the value was accepted, rejected or will be
+    /// dealt with later. This replaces any callback set by `set_update_callback` or
+    /// `set_typed_update_callback`.
+    ///
+    /// Rejected values cause the current value of the property to be republished, so that the
+    /// controller sees that it hasn't changed, and the reason to be published too if enabled with
+    /// `set_publish_set_errors`.
+    pub fn set_acknowledged_update_callback<F, Fut>(&mut self, mut update_callback: F)
+    where
+        F: (FnMut(String, String, String) -> Fut) + Send + Sync + 'static,
+        Fut: Future<Output = UpdateOutcome> + Send + 'static,
+    {
+        self.typed_update_callback = None;
+        self.update_callback = Some(Box::new(
+            move |node_id: String, property_id: String, value: String| {
+                update_callback(node_id, property_id, value).boxed()
+            },
+        ));
+    }
+
+    /// Set a callback to be called when a controller sets the value of a property, with the value
+    /// parsed according to the datatype of the property. This replaces any callback set by
+    /// `set_update_callback` or `set_acknowledged_update_callback`.
+    ///
+    /// Invalid values are never passed to this callback: they are rejected as described for
+    /// `set_invalid_set_payloads`, unless that is set to `InvalidSetPayloads::Clamp`.
+    pub fn set_typed_update_callback<F, Fut>(&mut self, mut update_callback: F)
+    where
+        F: (FnMut(String, String, SetValue) -> Fut) + Send + Sync + 'static,
+        Fut: Future<Output = Option<String>> + Send + 'static,
+    {
+        self.update_callback = None;
+        self.typed_update_callback = Some(Box::new(
+            move |node_id: String, property_id: String, value: SetValue| {
                 update_callback(node_id, property_id, value).boxed()
             },
         ));
     }
 
+    /// Set a callback to be called when a controller sends a
+    /// [broadcast message](https://homieiot.github.io/specification/#broadcast-channel), with the
+    /// broadcast level (e.g. `alert`) and the payload.
+    ///
+    /// If this is set, the device subscribes to all broadcasts under its base topic, and
+    /// resubscribes whenever it reconnects to the MQTT broker without a persistent session.
+    pub fn set_broadcast_callback<F, Fut>(&mut self, mut broadcast_callback: F)
+    where
+        F: (FnMut(String, String) -> Fut) + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.broadcast_callback = Some(Box::new(move |level: String, payload: String| {
+            broadcast_callback(level, payload).boxed()
+        }));
+    }
+
+    /// Handle values set on the given JSON config property, rather than passing them to the update
+    /// callback.
+    ///
+    /// Valid configs will be applied and republished automatically. The property must still be
+    /// added to a node of the device, with `config.property(...)`, and its initial value published
+    /// with `config.publish(...)`.
+    #[cfg(feature = "json")]
+    pub fn add_json_config<T>(&mut self, config: &JsonConfig<T>)
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned + Send + Sync + 'static,
+    {
+        let config = config.clone();
+        self.set_handlers.push(SetHandler {
+            node_id: config.node_id().to_owned(),
+            property_id: config.property_id().to_owned(),
+            handle: Box::new(move |value| config.handle_set(value)),
+        });
+    }
+
+    /// Publish log lines captured by the given logger, and handle values set on its level property
+    /// rather than passing them to the update callback.
+    ///
+    /// The logger must still be installed with `logger.install()`, and its level property added to
+    /// a node of the device with `logger.property(...)` and its initial value published with
+    /// `logger.publish(...)`.
+    pub fn add_logger(&mut self, logger: &HomieLogger) {
+        let handler_logger = logger.clone();
+        self.set_handlers.push(SetHandler {
+            node_id: logger.node_id().to_owned(),
+            property_id: logger.property_id().to_owned(),
+            handle: Box::new(move |value| handler_logger.handle_set(value)),
+        });
+        self.logger = Some(logger.clone());
+    }
+
+    /// Allow the given updater to change attributes of the device's properties once it has been
+    /// spawned, such as from within the update callback.
+    ///
+    /// Returns an error if the updater has already been added to this or another builder.
+    pub fn add_property_updater(
+        &mut self,
+        updater: &PropertyUpdater,
+    ) -> Result<(), PropertyUpdateError> {
+        updater.register()?;
+        self.property_updaters.push(updater.clone());
+        Ok(())
+    }
+
     /// Create a new Homie device, connect to the MQTT broker, and start a task to handle the MQTT
     /// connection.
     ///
     /// # Return value
     /// A pair of the `HomieDevice` itself, and a `Future` for the tasks which handle the MQTT
     /// connection. You should join on this future to handle any errors it returns.
+    #[allow(clippy::result_large_err)]
     pub async fn spawn(
         self,
     ) -> Result<(HomieDevice, impl Future<Output = Result<(), SpawnError>>), ClientError> {
-        let (event_loop, mut homie, stats, firmware, update_callback) = self.build();
+        let (homie, futures) = self.run().await?;
+
+        let event_loop_task = task::spawn(futures.event_loop);
+        let incoming_task = task::spawn(futures.incoming);
+        let stats_task = task::spawn(futures.stats);
+        let logger_task = futures.logger.map(task::spawn);
+        let logger_task = async move {
+            match logger_task {
+                Some(logger_task) => logger_task.await?,
+                None => Ok(()),
+            }
+        };
+        let join_handle = try_join3(
+            try_join_unit_handles(event_loop_task, incoming_task),
+            stats_task.map(|res| res?),
+            logger_task,
+        )
+        .map(simplify_unit_triple);
 
-        // This needs to be spawned before we wait for anything to be sent, as the start() calls below do.
-        let event_task = homie.spawn(event_loop, update_callback);
+        Ok((homie, join_handle))
+    }
+
+    /// Create a new Homie device and queue its initial attributes to be published, but rather than
+    /// spawning tasks to handle the MQTT connection, return futures for them.
+    ///
+    /// This is for applications which want to control where the tasks run, such as on a
+    /// current-thread runtime, or to instrument them. Nothing is sent to the MQTT broker until the
+    /// futures are polled, and dropping them stops the device.
+    ///
+    /// # Return value
+    /// A pair of the `HomieDevice` itself, and the futures which must all be polled to completion,
+    /// e.g. with [`DeviceFutures::join`].
+    pub async fn run(mut self) -> Result<(HomieDevice, DeviceFutures), ClientError> {
+        let logger = self.logger.take();
+        let executor = self.executor.clone();
+        let (
+            event_loop,
+            additional_event_loops,
+            mut homie,
+            stats,
+            firmware,
+            update_callback,
+            broadcast_callback,
+        ) = self.build();
+        let (event_loop, incoming) = homie.run_event_loop(
+            event_loop,
+            additional_event_loops,
+            executor.clone(),
+            update_callback,
+            broadcast_callback,
+        );
 
+        // These are queued in the request channel, which has plenty of room for them, until the
+        // event loop is polled.
         stats.start().await?;
         if let Some(firmware) = firmware {
             firmware.start().await?;
         }
         homie.start().await?;
 
-        let stats_task = stats.spawn();
-        let join_handle = try_join(event_task, stats_task).map(simplify_unit_pair);
+        let logger = logger.map(|logger| logger.run(homie.publisher.clone(), executor).boxed());
+        let futures = DeviceFutures {
+            event_loop: event_loop.boxed(),
+            incoming: incoming.boxed(),
+            stats: stats.run().boxed(),
+            logger,
+        };
 
-        Ok((homie, join_handle))
+        Ok((homie, futures))
     }
 
+    #[allow(clippy::type_complexity)]
     fn build(
         self,
     ) -> (
         EventLoop,
+        Vec<EventLoop>,
         HomieDevice,
         HomieStats,
         Option<HomieFirmware>,
         Option<UpdateCallback>,
+        Option<BroadcastCallback>,
     ) {
-        let mut mqtt_options = self.mqtt_options;
+        let device_base = self.device_base();
         let last_will = LastWill::new(
-            format!("{}/$state", self.device_base),
+            format!("{}/$state", device_base),
             State::Lost,
             QoS::AtLeastOnce,
             true,
         );
-        mqtt_options.set_last_will(last_will);
+        let mut mqtt_options = self.mqtt_options;
+        mqtt_options.set_last_will(last_will.clone());
         let (client, event_loop) = AsyncClient::new(mqtt_options, REQUESTS_CAP);
-
-        let publisher = DevicePublisher::new(client, self.device_base);
+        let (additional_clients, additional_event_loops) = self
+            .additional_mqtt_options
+            .into_iter()
+            .map(|mut mqtt_options| {
+                mqtt_options.set_last_will(last_will.clone());
+                AsyncClient::new(mqtt_options, REQUESTS_CAP)
+            })
+            .unzip();
+
+        let executor = self.executor;
+        let mut publisher =
+            DevicePublisher::new(MqttClients::new(client, additional_clients), device_base);
+        publisher.rate_limiter = self
+            .min_publish_interval
+            .map(|min_interval| Arc::new(RateLimiter::new(min_interval, executor.clone())));
+
+        for updater in &self.property_updaters {
+            updater.bind(publisher.clone());
+        }
 
         let mut extension_ids = vec![HomieStats::EXTENSION_ID];
-        let stats = HomieStats::new(publisher.clone());
+        let stats = HomieStats::new(publisher.clone(), executor);
         let firmware = if let (Some(firmware_name), Some(firmware_version)) =
             (self.firmware_name, self.firmware_version)
         {
@@ -201,10 +593,92 @@ impl HomieDeviceBuilder {
             None
         };
 
-        let homie = HomieDevice::new(publisher, self.device_name, &extension_ids);
+        let mut invalid_set_payloads = self.invalid_set_payloads;
+        let update_callback = match self.typed_update_callback {
+            Some(typed_update_callback) => {
+                if invalid_set_payloads == InvalidSetPayloads::Allow {
+                    invalid_set_payloads = InvalidSetPayloads::Reject;
+                }
+                Some(with_typed_values(
+                    publisher.properties.clone(),
+                    typed_update_callback,
+                ))
+            }
+            None => self.update_callback,
+        };
+        let update_callback = with_set_handlers(self.set_handlers, update_callback);
+
+        let mut homie = HomieDevice::new(publisher, self.device_name, &extension_ids);
+        homie.invalid_set_payloads = invalid_set_payloads;
+        homie.publish_set_errors = self.publish_set_errors;
+
+        (
+            event_loop,
+            additional_event_loops,
+            homie,
+            stats,
+            firmware,
+            update_callback,
+            self.broadcast_callback,
+        )
+    }
+}
+
+/// Wrap the given typed update callback as an untyped one, which parses values according to the
+/// datatype of the property before passing them on.
+///
+/// Values have already been validated by the time the callback is called, so any which fail to
+/// parse here are for properties which have since been removed, and are ignored.
+fn with_typed_values(
+    properties: Properties,
+    mut typed_update_callback: TypedUpdateCallback,
+) -> UpdateCallback {
+    Box::new(move |node_id: String, property_id: String, value: String| {
+        match properties
+            .get(&node_id, &property_id)
+            .map(|property| SetValue::parse(&property, &value, true))
+        {
+            Some(Ok(value)) => typed_update_callback(node_id, property_id, value)
+                .map(UpdateOutcome::from)
+                .boxed(),
+            _ => {
+                log::warn!(
+                    "Ignoring value {:?} for unknown property {}/{}",
+                    value,
+                    node_id,
+                    property_id
+                );
+                async { UpdateOutcome::Deferred }.boxed()
+            }
+        }
+    })
+}
 
-        (event_loop, homie, stats, firmware, self.update_callback)
+/// Combine the given set handlers with the update callback, so that values set on properties with
+/// a handler are passed to it rather than the callback.
+fn with_set_handlers(
+    set_handlers: Vec<SetHandler>,
+    update_callback: Option<UpdateCallback>,
+) -> Option<UpdateCallback> {
+    if set_handlers.is_empty() {
+        return update_callback;
     }
+    let mut update_callback = update_callback;
+    Some(Box::new(
+        move |node_id: String, property_id: String, value: String| {
+            if let Some(handler) = set_handlers
+                .iter()
+                .find(|handler| handler.node_id == node_id && handler.property_id == property_id)
+            {
+                let result = (handler.handle)(&value).into();
+                async move { result }.boxed()
+            } else if let Some(callback) = update_callback.as_mut() {
+                callback(node_id, property_id, value)
+            } else {
+                async { UpdateOutcome::Deferred }.boxed()
+            }
+        },
+    ))
 }
 
 /// A Homie [device](https://homieiot.github.io/specification/#devices). This corresponds to a
@@ -213,33 +687,80 @@ impl HomieDeviceBuilder {
 pub struct HomieDevice {
     publisher: DevicePublisher,
     device_name: String,
-    nodes: Vec<Node>,
     state: State,
     extension_ids: String,
+    invalid_set_payloads: InvalidSetPayloads,
+    publish_set_errors: bool,
 }
 
 impl HomieDevice {
     /// Create a builder to construct a new Homie device.
     ///
     /// # Arguments
-    /// * `device_base`: The base topic ID for the device, including the Homie base topic. This
-    ///   might be something like "homie/my-device-id" if you are using the default Homie
-    ///   [base topic](https://homieiot.github.io/specification/#base-topic). This must be
-    ///   unique per MQTT broker.
+    /// * `base_topic`: The Homie [base topic](https://homieiot.github.io/specification/#base-topic)
+    ///   under which to publish the device. This is usually "homie", but may have several levels.
+    /// * `device_id`: The ID of the device, which must be unique per base topic and follow the
+    ///   Homie [ID format](https://homieiot.github.io/specification/#topic-ids).
     /// * `device_name`: The human-readable name of the device.
     /// * `mqtt_options`: Options for the MQTT connection, including which server to connect to.
+    ///
+    /// Returns an error if the base topic or device ID is not valid.
+    pub fn builder_for(
+        base_topic: &str,
+        device_id: &str,
+        device_name: &str,
+        mqtt_options: MqttOptions,
+    ) -> Result<HomieDeviceBuilder, DeviceTopicError> {
+        validate_base_topic(base_topic)?;
+        validate_id(device_id)?;
+        Ok(Self::new_builder(
+            base_topic,
+            device_id,
+            device_name,
+            mqtt_options,
+        ))
+    }
+
+    /// Create a builder to construct a new Homie device, from a single topic combining the Homie
+    /// base topic and device ID, such as "homie/my-device-id". This is split at the last `/`, and
+    /// not validated.
+    #[deprecated(
+        since = "0.10.0",
+        note = "Use `builder_for`, which takes the base topic and device ID separately and validates them"
+    )]
     pub fn builder(
         device_base: &str,
         device_name: &str,
         mqtt_options: MqttOptions,
+    ) -> HomieDeviceBuilder {
+        let (base_topic, device_id) = device_base.rsplit_once('/').unwrap_or(("", device_base));
+        Self::new_builder(base_topic, device_id, device_name, mqtt_options)
+    }
+
+    fn new_builder(
+        base_topic: &str,
+        device_id: &str,
+        device_name: &str,
+        mqtt_options: MqttOptions,
     ) -> HomieDeviceBuilder {
         HomieDeviceBuilder {
-            device_base: device_base.to_string(),
+            base_topic: base_topic.to_string(),
+            device_id: device_id.to_string(),
             device_name: device_name.to_string(),
             firmware_name: None,
             firmware_version: None,
             mqtt_options,
+            additional_mqtt_options: vec![],
             update_callback: None,
+            typed_update_callback: None,
+            broadcast_callback: None,
+            set_handlers: vec![],
+            min_publish_interval: None,
+            invalid_set_payloads: InvalidSetPayloads::default(),
+            publish_set_errors: false,
+            logger: None,
+            property_updaters: vec![],
+            executor: Arc::new(TokioExecutor),
         }
     }
 
@@ -247,9 +768,10 @@ impl HomieDevice {
         HomieDevice {
             publisher,
             device_name,
-            nodes: vec![],
             state: State::Disconnected,
             extension_ids: extension_ids.join(","),
+            invalid_set_payloads: InvalidSetPayloads::default(),
+            publish_set_errors: false,
         }
     }
 
@@ -271,36 +793,106 @@ impl HomieDevice {
         Ok(())
     }
 
-    /// Spawn a task to handle the EventLoop.
-    fn spawn(
+    /// Return futures to poll the EventLoops, and to handle the incoming messages which they receive.
+    fn run_event_loop(
         &self,
         mut event_loop: EventLoop,
+        additional_event_loops: Vec<EventLoop>,
+        executor: Arc<dyn Executor>,
         mut update_callback: Option<UpdateCallback>,
-    ) -> impl Future<Output = Result<(), SpawnError>> {
+        mut broadcast_callback: Option<BroadcastCallback>,
+    ) -> (
+        impl Future<Output = Result<(), SpawnError>>,
+        impl Future<Output = Result<(), SpawnError>>,
+    ) {
         let device_base = format!("{}/", self.publisher.device_base);
+        let broadcast_prefix = broadcast_callback
+            .as_ref()
+            .map(|_| broadcast_prefix(&self.publisher.device_base));
         let (incoming_tx, incoming_rx) = flume::unbounded();
 
-        let mqtt_task = task::spawn(async move {
+        let client = self.publisher.clients.primary().clone();
+        let broadcast_topic = broadcast_prefix
+            .as_ref()
+            .map(|prefix| format!("{}#", prefix));
+        let additional_futures = additional_event_loops
+            .into_iter()
+            .enumerate()
+            .map(|(i, event_loop)| {
+                let clients = self.publisher.clients.clone();
+                let extra_subscriptions = broadcast_topic.iter().cloned().collect();
+                let incoming_tx = incoming_tx.clone();
+                let executor = executor.clone();
+                async move {
+                    clients
+                        .run_additional(
+                            i + 1,
+                            event_loop,
+                            extra_subscriptions,
+                            incoming_tx,
+                            executor,
+                        )
+                        .await
+                }
+            })
+            .collect::<Vec<_>>();
+        let primary_future = async move {
             loop {
                 let notification = event_loop.poll().await?;
                 log::trace!("Notification = {:?}", notification);
 
                 if let Event::Incoming(incoming) = notification {
-                    incoming_tx.send_async(incoming).await.map_err(|_| {
+                    if let (Incoming::ConnAck(connack), Some(broadcast_topic)) =
+                        (&incoming, &broadcast_topic)
+                    {
+                        if !connack.session_present {
+                            // This can't wait for the request to be sent on this task, as that only
+                            // happens when the event loop is polled.
+                            let client = client.clone();
+                            let broadcast_topic = broadcast_topic.clone();
+                            executor.spawn(Box::pin(async move {
+                                if let Err(e) =
+                                    client.subscribe(&broadcast_topic, QoS::AtLeastOnce).await
+                                {
+                                    log::error!(
+                                        "Failed to subscribe to {}: {}",
+                                        broadcast_topic,
+                                        e
+                                    );
+                                }
+                            }));
+                        }
+                    }
+                    incoming_tx.send_async((0, incoming)).await.map_err(|_| {
                         SpawnError::Internal("Incoming event channel receiver closed.")
                     })?;
                 }
             }
-        });
+        };
+        let mqtt_future = async move {
+            // The additional brokers never fail, so this only completes if the primary one does.
+            let additional_future = join_all(additional_futures).map(Ok::<_, SpawnError>);
+            let ((), _) = try_join(primary_future, additional_future).await?;
+            Ok(())
+        };
 
         let publisher = self.publisher.clone();
-        let incoming_task: JoinHandle<Result<(), SpawnError>> = task::spawn(async move {
+        let invalid_set_payloads = self.invalid_set_payloads;
+        let publish_set_errors = self.publish_set_errors;
+        let mut deduplicator = (self.publisher.clients.len() > 1).then(Deduplicator::default);
+        let incoming_future = async move {
             loop {
-                if let Incoming::Publish(publish) = incoming_rx
+                let (index, incoming) = incoming_rx
                     .recv_async()
                     .await
-                    .map_err(|_| SpawnError::Internal("Incoming event channel sender closed."))?
-                {
+                    .map_err(|_| SpawnError::Internal("Incoming event channel sender closed."))?;
+                if let Incoming::Publish(publish) = incoming {
+                    if let Some(deduplicator) = deduplicator.as_mut() {
+                        if deduplicator.is_duplicate(index, &publish.topic, &publish.payload) {
+                            log::trace!("Ignoring duplicate from broker {}: {:?}", index, publish);
+                            continue;
+                        }
+                    }
                     if let Some(rest) = publish.topic.strip_prefix(&device_base) {
                         if let ([node_id, property_id, "set"], Ok(payload)) = (
                             rest.split('/').collect::<Vec<&str>>().as_slice(),
@@ -312,35 +904,76 @@ impl HomieDevice {
                                 property_id,
                                 payload
                             );
-                            if let Some(callback) = update_callback.as_mut() {
-                                if let Some(value) = callback(
-                                    node_id.to_string(),
-                                    property_id.to_string(),
-                                    payload.to_string(),
-                                )
-                                .await
-                                {
+                            let property_id = publisher.aliases.resolve(node_id, property_id);
+                            let payload = match publisher.properties.validate(
+                                node_id,
+                                &property_id,
+                                payload,
+                                invalid_set_payloads,
+                            ) {
+                                Ok(payload) => payload,
+                                Err(e) => {
                                     publisher
-                                        .publish_retained(
-                                            &format!("{}/{}", node_id, property_id),
-                                            value,
+                                        .reject_set(
+                                            node_id,
+                                            &property_id,
+                                            &e.to_string(),
+                                            publish_set_errors,
                                         )
                                         .await?;
+                                    continue;
+                                }
+                            };
+                            if let Some(callback) = update_callback.as_mut() {
+                                match callback(node_id.to_string(), property_id.clone(), payload)
+                                    .await
+                                {
+                                    UpdateOutcome::Accepted(value) => {
+                                        publisher
+                                            .publish_property(node_id, &property_id, &value)
+                                            .await?;
+                                    }
+                                    UpdateOutcome::Rejected(reason) => {
+                                        publisher
+                                            .reject_set(
+                                                node_id,
+                                                &property_id,
+                                                &reason,
+                                                publish_set_errors,
+                                            )
+                                            .await?;
+                                    }
+                                    UpdateOutcome::Deferred => {}
                                 }
                             }
                         }
+                    } else if let (Some(level), Some(callback)) = (
+                        broadcast_prefix
+                            .as_ref()
+                            .and_then(|prefix| publish.topic.strip_prefix(prefix)),
+                        broadcast_callback.as_mut(),
+                    ) {
+                        match str::from_utf8(&publish.payload) {
+                            Ok(payload) => callback(level.to_owned(), payload.to_owned()).await,
+                            Err(_) => log::warn!("Non-UTF8 broadcast payload: {:?}", publish),
+                        }
                     } else {
                         log::warn!("Unexpected publish: {:?}", publish);
                     }
                 }
             }
-        });
-        try_join_unit_handles(mqtt_task, incoming_task)
+        };
+        (mqtt_future, incoming_future)
     }
 
     /// Check whether a node with the given ID currently exists on the device.
     pub fn has_node(&self, node_id: &str) -> bool {
-        self.nodes.iter().any(|n| n.id == node_id)
+        self.publisher
+            .nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|n| n.id == node_id)
     }
 
     /// Add a node to the Homie device. It will immediately be published.
@@ -352,24 +985,101 @@ impl HomieDevice {
         if self.has_node(&node.id) {
             panic!("Tried to add node with duplicate ID: {:?}", node);
         }
-        self.nodes.push(node);
-        // `node` was moved into the `nodes` vector, but we can safely get a reference to it because
-        // nothing else can modify `nodes` in the meantime.
-        let node = &self.nodes[self.nodes.len() - 1];
+        self.publisher.aliases.add_node(&node);
+        self.publisher.properties.add_node(&node);
+        self.publisher.nodes.lock().unwrap().push(node.clone());
 
-        self.publish_node(node).await?;
+        self.publish_node(&node).await?;
         self.publish_nodes().await
     }
 
     /// Remove the node with the given ID.
     pub async fn remove_node(&mut self, node_id: &str) -> Result<(), ClientError> {
         // Panic on attempt to remove a node which was never added.
-        let index = self.nodes.iter().position(|n| n.id == node_id).unwrap();
-        self.unpublish_node(&self.nodes[index]).await?;
-        self.nodes.remove(index);
+        let node = self
+            .publisher
+            .nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|n| n.id == node_id)
+            .cloned()
+            .unwrap();
+        self.unpublish_node(&node).await?;
+        self.publisher
+            .nodes
+            .lock()
+            .unwrap()
+            .retain(|n| n.id != node_id);
+        self.publisher.aliases.remove_node(&node);
+        self.publisher.properties.remove_node(&node);
+        self.publisher.derivations.remove_node(&node);
         self.publish_nodes().await
     }
 
+    /// Keep the given property published with a value derived from that of the given source
+    /// property, such as a temperature in Fahrenheit mirroring one in Celsius. Whenever a value is
+    /// published for the source property, including when it is set by a controller, `transform` is
+    /// called with it and the result is published for the derived property, unless it is `None`. If
+    /// the source property already has a retained value, the derived value is published
+    /// immediately.
+    ///
+    /// Derived values are not themselves used to derive further values. The derivation is removed
+    /// if the node of either property is removed.
+    ///
+    /// This will panic if the derived property isn't on a node which has been added to the device.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # async fn example(device: &mut homie_device::HomieDevice) -> Result<(), rumqttc::ClientError> {
+    /// device
+    ///     .add_derived_property("room", "temperature", "room", "temperature-f", |celsius| {
+    ///         let celsius: f64 = celsius.parse().ok()?;
+    ///         Some(format!("{:.1}", celsius * 9.0 / 5.0 + 32.0))
+    ///     })
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn add_derived_property(
+        &mut self,
+        source_node_id: &str,
+        source_property_id: &str,
+        node_id: &str,
+        property_id: &str,
+        transform: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> Result<(), ClientError> {
+        if self
+            .publisher
+            .properties
+            .get(node_id, property_id)
+            .is_none()
+        {
+            panic!(
+                "Tried to derive a value for unknown property {}/{}",
+                node_id, property_id
+            );
+        }
+        self.publisher.derivations.add(
+            source_node_id,
+            source_property_id,
+            node_id,
+            property_id,
+            transform,
+        );
+        if let Some(value) = self
+            .publisher
+            .properties
+            .value(source_node_id, source_property_id)
+        {
+            self.publisher
+                .publish_derived(source_node_id, source_property_id, &value)
+                .await?;
+        }
+        Ok(())
+    }
+
     async fn publish_node(&self, node: &Node) -> Result<(), ClientError> {
         self.publisher
             .publish_retained(&format!("{}/$name", node.id), node.name.as_str())
@@ -378,48 +1088,53 @@ impl HomieDevice {
             .publish_retained(&format!("{}/$type", node.id), node.node_type.as_str())
             .await?;
         let mut property_ids: Vec<&str> = vec![];
-        for property in &node.properties {
-            property_ids.push(&property.id);
+        for (property, property_id) in property_ids_with_aliases(node) {
+            property_ids.push(property_id);
             self.publisher
                 .publish_retained(
-                    &format!("{}/{}/$name", node.id, property.id),
+                    &format!("{}/{}/$name", node.id, property_id),
                     property.name.as_str(),
                 )
                 .await?;
             self.publisher
                 .publish_retained(
-                    &format!("{}/{}/$datatype", node.id, property.id),
+                    &format!("{}/{}/$datatype", node.id, property_id),
                     property.datatype,
                 )
                 .await?;
             self.publisher
                 .publish_retained(
-                    &format!("{}/{}/$settable", node.id, property.id),
+                    &format!("{}/{}/$settable", node.id, property_id),
                     if property.settable { "true" } else { "false" },
                 )
                 .await?;
             self.publisher
                 .publish_retained(
-                    &format!("{}/{}/$retained", node.id, property.id),
+                    &format!("{}/{}/$retained", node.id, property_id),
                     if property.retained { "true" } else { "false" },
                 )
                 .await?;
             if let Some(unit) = &property.unit {
                 self.publisher
-                    .publish_retained(&format!("{}/{}/$unit", node.id, property.id), unit.as_str())
+                    .publish_retained(&format!("{}/{}/$unit", node.id, property_id), unit.as_str())
                     .await?;
             }
             if let Some(format) = &property.format {
                 self.publisher
                     .publish_retained(
-                        &format!("{}/{}/$format", node.id, property.id),
+                        &format!("{}/{}/$format", node.id, property_id),
                         format.as_str(),
                     )
                     .await?;
             }
+            if let Some(category) = property.category {
+                self.publisher
+                    .publish_retained(&format!("{}/{}/$category", node.id, property_id), category)
+                    .await?;
+            }
             if property.settable {
                 self.publisher
-                    .subscribe(&format!("{}/{}/set", node.id, property.id))
+                    .subscribe(&format!("{}/{}/set", node.id, property_id))
                     .await?;
             }
         }
@@ -430,10 +1145,10 @@ impl HomieDevice {
     }
 
     async fn unpublish_node(&self, node: &Node) -> Result<(), ClientError> {
-        for property in &node.properties {
+        for (property, property_id) in property_ids_with_aliases(node) {
             if property.settable {
                 self.publisher
-                    .unsubscribe(&format!("{}/{}/set", node.id, property.id))
+                    .unsubscribe(&format!("{}/{}/set", node.id, property_id))
                     .await?;
             }
         }
@@ -442,7 +1157,10 @@ impl HomieDevice {
 
     async fn publish_nodes(&mut self) -> Result<(), ClientError> {
         let node_ids = self
+            .publisher
             .nodes
+            .lock()
+            .unwrap()
             .iter()
             .map(|node| node.id.as_str())
             .collect::<Vec<&str>>()
@@ -482,47 +1200,377 @@ impl HomieDevice {
     // 'disconnected'.
     pub async fn disconnect(mut self) -> Result<(), ClientError> {
         self.set_state(State::Disconnected).await?;
-        self.publisher.client.disconnect().await
+        self.publisher.clients.disconnect().await
     }
 
     /// Publish a new value for the given retained property of the given node of this device. The
     /// caller is responsible for ensuring that the value is of the correct type.
+    ///
+    /// If a minimum publish interval was set on the builder, the value may be held back and
+    /// published later, or replaced by a newer value before being published.
+    ///
+    /// If the property has any aliases, the value is published to them too, and the values of any
+    /// [derived properties](Self::add_derived_property) are published.
     pub async fn publish_value(
         &self,
         node_id: &str,
         property_id: &str,
         value: impl ToString,
     ) -> Result<(), ClientError> {
-        self.publisher
-            .publish_retained(&format!("{}/{}", node_id, property_id), value.to_string())
+        self.value_publisher()
+            .publish_value(node_id, property_id, value)
             .await
     }
 
     /// Publish a new value for the given non-retained property of the given node of this device. The
     /// caller is responsible for ensuring that the value is of the correct type.
+    ///
+    /// If the property has any aliases, the value is published to them too, and the values of any
+    /// [derived properties](Self::add_derived_property) are published.
     pub async fn publish_nonretained_value(
         &self,
         node_id: &str,
         property_id: &str,
         value: impl ToString,
     ) -> Result<(), ClientError> {
-        self.publisher
-            .publish_nonretained(&format!("{}/{}", node_id, property_id), value.to_string())
+        self.value_publisher()
+            .publish_nonretained_value(node_id, property_id, value)
             .await
     }
+
+    /// Get a handle which can be cloned and sent to other tasks to publish property values
+    /// concurrently, without sharing the device itself.
+    pub fn value_publisher(&self) -> ValuePublisher {
+        ValuePublisher::new(self.publisher.clone())
+    }
+
+    /// Get the Homie base topic under which the device is published.
+    pub fn base_topic(&self) -> &str {
+        self.publisher
+            .device_base
+            .rsplit_once('/')
+            .map_or("", |(base_topic, _)| base_topic)
+    }
+
+    /// Get the ID of the device.
+    pub fn device_id(&self) -> &str {
+        self.publisher
+            .device_base
+            .rsplit_once('/')
+            .map_or(self.publisher.device_base.as_str(), |(_, device_id)| {
+                device_id
+            })
+    }
+
+    /// Check that the device and its nodes conform to the Homie specification, returning all the
+    /// problems found, or an empty list if there are none. See [`Node::validate`] for the checks
+    /// made on each node, which can be used to check a node before adding it.
+    ///
+    /// Nothing stops a non-conforming device from being published, but controllers may ignore
+    /// parts of it or reject it entirely, so this is useful in tests.
+    pub fn validate(&self) -> Vec<Violation> {
+        let nodes = self.publisher.nodes.lock().unwrap();
+        validate::validate_device(self.device_id(), &self.device_name, &nodes)
+    }
+}
+
+/// Gets the prefix of broadcast topics for the device with the given base topic, i.e. the Homie base
+/// topic followed by `$broadcast/`.
+fn broadcast_prefix(device_base: &str) -> String {
+    match device_base.rsplit_once('/') {
+        Some((base_topic, _)) => format!("{}/$broadcast/", base_topic),
+        None => "$broadcast/".to_owned(),
+    }
+}
+
+/// Iterates over the properties of the given node along with the IDs under which to publish them,
+/// including any aliases.
+fn property_ids_with_aliases(node: &Node) -> impl Iterator<Item = (&Property, &str)> {
+    node.properties.iter().flat_map(|property| {
+        std::iter::once(property.id.as_str())
+            .chain(property.aliases.iter().map(String::as_str))
+            .map(move |property_id| (property, property_id))
+    })
+}
+
+/// Map from `(node_id, property_id)` to the aliases of the property.
+type AliasMap = HashMap<(String, String), Vec<String>>;
+
+/// The aliases of the properties of the nodes currently on the device, shared between the device
+/// and the task handling set messages.
+#[derive(Clone, Debug, Default)]
+struct Aliases {
+    /// The aliases of each property which has any.
+    aliases: Arc<Mutex<AliasMap>>,
+}
+
+impl Aliases {
+    fn add_node(&self, node: &Node) {
+        let mut aliases = self.aliases.lock().unwrap();
+        for property in &node.properties {
+            if !property.aliases.is_empty() {
+                aliases.insert(
+                    (node.id.clone(), property.id.clone()),
+                    property.aliases.clone(),
+                );
+            }
+        }
+    }
+
+    fn remove_node(&self, node: &Node) {
+        self.aliases
+            .lock()
+            .unwrap()
+            .retain(|(node_id, _), _| *node_id != node.id);
+    }
+
+    /// Gets the main ID of the property with the given ID or alias.
+    fn resolve(&self, node_id: &str, property_id: &str) -> String {
+        self.aliases
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|((alias_node_id, _), aliases)| {
+                alias_node_id == node_id && aliases.iter().any(|alias| alias == property_id)
+            })
+            .map_or(property_id, |((_, main_id), _)| main_id)
+            .to_owned()
+    }
+
+    /// Gets the subtopics to which values of the given property should be published: its own, and
+    /// one for each alias.
+    fn subtopics(&self, node_id: &str, property_id: &str) -> Vec<String> {
+        let mut subtopics = vec![format!("{}/{}", node_id, property_id)];
+        if let Some(aliases) = self
+            .aliases
+            .lock()
+            .unwrap()
+            .get(&(node_id.to_owned(), property_id.to_owned()))
+        {
+            subtopics.extend(aliases.iter().map(|alias| format!("{}/{}", node_id, alias)));
+        }
+        subtopics
+    }
+}
+
+/// A property of a node currently on the device, along with the last retained value published for
+/// it.
+#[derive(Clone, Debug)]
+struct PropertyState {
+    property: Property,
+    value: Option<String>,
+}
+
+/// The properties of the nodes currently on the device, shared between the device and the task
+/// handling set messages so that set values can be validated.
+#[derive(Clone, Debug, Default)]
+struct Properties {
+    /// Map from `(node_id, property_id)` to the property.
+    properties: Arc<Mutex<HashMap<(String, String), PropertyState>>>,
+}
+
+impl Properties {
+    fn add_node(&self, node: &Node) {
+        let mut properties = self.properties.lock().unwrap();
+        for property in &node.properties {
+            properties.insert(
+                (node.id.clone(), property.id.clone()),
+                PropertyState {
+                    property: property.clone(),
+                    value: None,
+                },
+            );
+        }
+    }
+
+    fn remove_node(&self, node: &Node) {
+        self.properties
+            .lock()
+            .unwrap()
+            .retain(|(node_id, _), _| *node_id != node.id);
+    }
+
+    fn get(&self, node_id: &str, property_id: &str) -> Option<Property> {
+        self.properties
+            .lock()
+            .unwrap()
+            .get(&(node_id.to_owned(), property_id.to_owned()))
+            .map(|state| state.property.clone())
+    }
+
+    /// Gets the last retained value published for the given property, if any.
+    fn value(&self, node_id: &str, property_id: &str) -> Option<String> {
+        self.properties
+            .lock()
+            .unwrap()
+            .get(&(node_id.to_owned(), property_id.to_owned()))
+            .and_then(|state| state.value.clone())
+    }
+
+    /// Applies the given change to the given property, returning false if there is no such
+    /// property.
+    fn update(&self, node_id: &str, property_id: &str, change: impl FnOnce(&mut Property)) -> bool {
+        match self
+            .properties
+            .lock()
+            .unwrap()
+            .get_mut(&(node_id.to_owned(), property_id.to_owned()))
+        {
+            Some(state) => {
+                change(&mut state.property);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_value(&self, node_id: &str, property_id: &str, value: &str) {
+        if let Some(state) = self
+            .properties
+            .lock()
+            .unwrap()
+            .get_mut(&(node_id.to_owned(), property_id.to_owned()))
+        {
+            state.value = Some(value.to_owned());
+        }
+    }
+
+    /// Checks the given value set by a controller against the datatype and format of the property,
+    /// returning the value to pass to the update callback.
+    ///
+    /// Values for properties which aren't known are always passed through.
+    fn validate(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        payload: &str,
+        invalid_set_payloads: InvalidSetPayloads,
+    ) -> Result<String, InvalidValueError> {
+        let property = match (invalid_set_payloads, self.get(node_id, property_id)) {
+            (InvalidSetPayloads::Allow, _) | (_, None) => return Ok(payload.to_owned()),
+            (_, Some(property)) => property,
+        };
+        match SetValue::parse(&property, payload, false) {
+            Ok(_) => Ok(payload.to_owned()),
+            Err(InvalidValueError::WrongFormat { .. })
+                if invalid_set_payloads == InvalidSetPayloads::Clamp =>
+            {
+                Ok(SetValue::parse(&property, payload, true)?.to_string())
+            }
+            Err(e) => Err(e),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 struct DevicePublisher {
-    pub client: AsyncClient,
+    clients: MqttClients,
     device_base: String,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// The nodes currently on the device, shared so that a [`PropertyUpdater`] can change them.
+    nodes: Arc<Mutex<Vec<Node>>>,
+    aliases: Aliases,
+    properties: Properties,
+    derivations: Derivations,
 }
 
 impl DevicePublisher {
-    fn new(client: AsyncClient, device_base: String) -> Self {
+    fn new(clients: impl Into<MqttClients>, device_base: String) -> Self {
         Self {
-            client,
+            clients: clients.into(),
             device_base,
+            rate_limiter: None,
+            nodes: Arc::default(),
+            aliases: Aliases::default(),
+            properties: Properties::default(),
+            derivations: Derivations::default(),
+        }
+    }
+
+    /// Publish a retained value for the given property and any aliases, and remember it as the
+    /// current value.
+    async fn publish_property(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        value: &str,
+    ) -> Result<(), ClientError> {
+        self.properties.set_value(node_id, property_id, value);
+        for subtopic in self.aliases.subtopics(node_id, property_id) {
+            self.publish_retained(&subtopic, value).await?;
+        }
+        self.publish_derived(node_id, property_id, value).await
+    }
+
+    /// Publish the values of any properties derived from the given property, now that it has the
+    /// given value. Each is published retained or not according to the derived property, and to any
+    /// aliases it has. Derived values are not themselves used to derive further values.
+    async fn publish_derived(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        value: &str,
+    ) -> Result<(), ClientError> {
+        for (node_id, property_id, value) in self.derivations.derive(node_id, property_id, value) {
+            let retained = self
+                .properties
+                .get(&node_id, &property_id)
+                .is_none_or(|property| property.retained);
+            if retained {
+                self.properties.set_value(&node_id, &property_id, &value);
+            }
+            for subtopic in self.aliases.subtopics(&node_id, &property_id) {
+                if retained {
+                    self.publish_value(&subtopic, value.as_str()).await?;
+                } else {
+                    self.publish_nonretained(&subtopic, value.as_str()).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Log that a value set on the given property was rejected for the given reason, and republish
+    /// the current value of the property, if any, so that the controller sees that it hasn't
+    /// changed. The reason is also published to the `$error` subtopic of the property if
+    /// `publish_error` is set.
+    async fn reject_set(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        reason: &str,
+        publish_error: bool,
+    ) -> Result<(), ClientError> {
+        log::warn!(
+            "Rejecting value for {}/{}: {}",
+            node_id,
+            property_id,
+            reason
+        );
+        if let Some(value) = self.properties.value(node_id, property_id) {
+            self.publish_property(node_id, property_id, &value).await?;
+        }
+        if publish_error {
+            self.publish_nonretained(&format!("{}/{}/$error", node_id, property_id), reason)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Publish a retained property value, subject to the rate limiter if there is one.
+    async fn publish_value(
+        &self,
+        subtopic: &str,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<(), ClientError> {
+        match &self.rate_limiter {
+            Some(rate_limiter) => {
+                let topic = format!("{}/{}", self.device_base, subtopic);
+                rate_limiter
+                    .publish_retained(&self.clients, topic, value.into())
+                    .await
+            }
+            None => self.publish_retained(subtopic, value).await,
         }
     }
 
@@ -532,9 +1580,7 @@ impl DevicePublisher {
         value: impl Into<Vec<u8>>,
     ) -> Result<(), ClientError> {
         let topic = format!("{}/{}", self.device_base, subtopic);
-        self.client
-            .publish(topic, QoS::AtLeastOnce, true, value)
-            .await
+        self.clients.publish(topic, true, value.into()).await
     }
 
     async fn publish_nonretained(
@@ -543,19 +1589,17 @@ impl DevicePublisher {
         value: impl Into<Vec<u8>>,
     ) -> Result<(), ClientError> {
         let topic = format!("{}/{}", self.device_base, subtopic);
-        self.client
-            .publish(topic, QoS::AtLeastOnce, false, value)
-            .await
+        self.clients.publish(topic, false, value.into()).await
     }
 
     async fn subscribe(&self, subtopic: &str) -> Result<(), ClientError> {
         let topic = format!("{}/{}", self.device_base, subtopic);
-        self.client.subscribe(topic, QoS::AtLeastOnce).await
+        self.clients.subscribe(topic).await
     }
 
     async fn unsubscribe(&self, subtopic: &str) -> Result<(), ClientError> {
         let topic = format!("{}/{}", self.device_base, subtopic);
-        self.client.unsubscribe(topic).await
+        self.clients.unsubscribe(topic).await
     }
 }
 
@@ -563,16 +1607,18 @@ impl DevicePublisher {
 #[derive(Debug)]
 struct HomieStats {
     publisher: DevicePublisher,
+    executor: Arc<dyn Executor>,
     start_time: Instant,
 }
 
 impl HomieStats {
     const EXTENSION_ID: &'static str = "org.homie.legacy-stats:0.1.1:[4.x]";
 
-    fn new(publisher: DevicePublisher) -> Self {
+    fn new(publisher: DevicePublisher, executor: Arc<dyn Executor>) -> Self {
         let now = Instant::now();
         Self {
             publisher,
+            executor,
             start_time: now,
         }
     }
@@ -585,17 +1631,14 @@ impl HomieStats {
     }
 
     /// Periodically send stats.
-    fn spawn(self) -> impl Future<Output = Result<(), SpawnError>> {
-        let task: JoinHandle<Result<(), SpawnError>> = task::spawn(async move {
-            loop {
-                let uptime = Instant::now() - self.start_time;
-                self.publisher
-                    .publish_retained("$stats/uptime", uptime.as_secs().to_string())
-                    .await?;
-                sleep(STATS_INTERVAL).await;
-            }
-        });
-        task.map(|res| res?)
+    async fn run(self) -> Result<(), SpawnError> {
+        loop {
+            let uptime = Instant::now() - self.start_time;
+            self.publisher
+                .publish_retained("$stats/uptime", uptime.as_secs().to_string())
+                .await?;
+            self.executor.sleep(STATS_INTERVAL).await;
+        }
     }
 }
 
@@ -661,11 +1704,17 @@ fn simplify_unit_pair<E>(m: Result<((), ()), E>) -> Result<(), E> {
     m.map(|((), ())| ())
 }
 
+fn simplify_unit_triple<E>(m: Result<((), (), ()), E>) -> Result<(), E> {
+    m.map(|((), (), ())| ())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use flume::Receiver;
+    use futures::future::BoxFuture;
     use rumqttc::Request;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     fn make_test_device() -> (HomieDevice, Receiver<Request>) {
         let (requests_tx, requests_rx) = flume::unbounded();
@@ -716,6 +1765,26 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn publishes_to_additional_brokers() -> Result<(), ClientError> {
+        let (primary_tx, primary_rx) = flume::unbounded();
+        let (additional_tx, additional_rx) = flume::unbounded();
+        let clients = MqttClients::new(
+            AsyncClient::from_senders(primary_tx),
+            vec![AsyncClient::from_senders(additional_tx)],
+        );
+        let publisher = DevicePublisher::new(clients, "homie/test-device".to_string());
+        let mut device = HomieDevice::new(publisher, "Test device".to_string(), &[]);
+
+        device.start().await?;
+        device.ready().await?;
+
+        let primary_requests = primary_rx.drain().collect::<Vec<_>>();
+        assert_eq!(primary_requests.len(), 6);
+        assert_eq!(additional_rx.drain().collect::<Vec<_>>(), primary_requests);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn sleep_then_ready_again_succeeds() -> Result<(), ClientError> {
         let (mut device, rx) = make_test_device();
@@ -771,13 +1840,23 @@ mod tests {
 
     #[tokio::test]
     async fn minimal_build_succeeds() -> Result<(), ClientError> {
-        let builder = HomieDevice::builder(
-            "homie/test-device",
+        let builder = HomieDevice::builder_for(
+            "homie",
+            "test-device",
             "Test device",
             MqttOptions::new("client_id", "hostname", 1234),
-        );
-
-        let (_event_loop, homie, _stats, firmware, _callback) = builder.build();
+        )
+        .unwrap();
+
+        let (
+            _event_loop,
+            _additional_event_loops,
+            homie,
+            _stats,
+            firmware,
+            _callback,
+            _broadcast_callback,
+        ) = builder.build();
 
         assert_eq!(homie.device_name, "Test device");
         assert_eq!(homie.publisher.device_base, "homie/test-device");
@@ -788,15 +1867,25 @@ mod tests {
 
     #[tokio::test]
     async fn set_firmware_build_succeeds() -> Result<(), ClientError> {
-        let mut builder = HomieDevice::builder(
-            "homie/test-device",
+        let mut builder = HomieDevice::builder_for(
+            "homie",
+            "test-device",
             "Test device",
             MqttOptions::new("client_id", "hostname", 1234),
-        );
+        )
+        .unwrap();
 
         builder.set_firmware("firmware_name", "firmware_version");
 
-        let (_event_loop, homie, _stats, firmware, _callback) = builder.build();
+        let (
+            _event_loop,
+            _additional_event_loops,
+            homie,
+            _stats,
+            firmware,
+            _callback,
+            _broadcast_callback,
+        ) = builder.build();
 
         assert_eq!(homie.device_name, "Test device");
         assert_eq!(homie.publisher.device_base, "homie/test-device");
@@ -807,6 +1896,66 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn multi_level_base_topic() {
+        let builder = HomieDevice::builder_for(
+            "building/floor-1/homie",
+            "test-device",
+            "Test device",
+            MqttOptions::new("client_id", "hostname", 1234),
+        )
+        .unwrap();
+        assert_eq!(builder.base_topic(), "building/floor-1/homie");
+        assert_eq!(builder.device_id(), "test-device");
+
+        let (
+            _event_loop,
+            _additional_event_loops,
+            homie,
+            _stats,
+            _firmware,
+            _callback,
+            _broadcast_callback,
+        ) = builder.build();
+
+        assert_eq!(
+            homie.publisher.device_base,
+            "building/floor-1/homie/test-device"
+        );
+        assert_eq!(homie.base_topic(), "building/floor-1/homie");
+        assert_eq!(homie.device_id(), "test-device");
+    }
+
+    #[test]
+    fn invalid_base_topic_or_device_id() {
+        let mqtt_options = MqttOptions::new("client_id", "hostname", 1234);
+        assert_eq!(
+            HomieDevice::builder_for("homie/", "test-device", "Test", mqtt_options.clone())
+                .unwrap_err(),
+            DeviceTopicError::InvalidBaseTopic("homie/".to_owned())
+        );
+        assert_eq!(
+            HomieDevice::builder_for("homie", "test/device", "Test", mqtt_options).unwrap_err(),
+            DeviceTopicError::InvalidDeviceId(InvalidIdError {
+                id: "test/device".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn combined_device_base_is_split() {
+        let mqtt_options = MqttOptions::new("client_id", "hostname", 1234);
+        let builder = HomieDevice::builder("a/b/test-device", "Test", mqtt_options.clone());
+        assert_eq!(builder.base_topic(), "a/b");
+        assert_eq!(builder.device_id(), "test-device");
+        assert_eq!(builder.device_base(), "a/b/test-device");
+
+        let builder = HomieDevice::builder("test-device", "Test", mqtt_options);
+        assert_eq!(builder.base_topic(), "");
+        assert_eq!(builder.device_base(), "test-device");
+    }
+
     #[tokio::test]
     async fn add_node_succeeds_before_and_after_start() -> Result<(), ClientError> {
         let (mut device, rx) = make_test_device();
@@ -868,4 +2017,457 @@ mod tests {
         drop(rx);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn run_returns_futures_for_caller_to_poll() -> Result<(), ClientError> {
+        // Nothing is listening on this port, so the event loop fails as soon as it is polled.
+        let mqtt_options = MqttOptions::new("homie_test", "127.0.0.1", 1);
+        let (device, futures) =
+            HomieDevice::builder_for("homie", "test-device", "Test", mqtt_options)
+                .unwrap()
+                .run()
+                .await?;
+        assert_eq!(device.state, State::Init);
+        assert!(futures.logger.is_none());
+
+        assert!(matches!(
+            futures.join().await,
+            Err(SpawnError::Connection(_))
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn json_config_takes_precedence_over_callback() {
+        let mut builder = HomieDevice::builder_for(
+            "homie",
+            "test-device",
+            "Test device",
+            MqttOptions::new("client_id", "hostname", 1234),
+        )
+        .unwrap();
+        let config = JsonConfig::new("node", "config", vec![1, 2]);
+        builder.add_json_config(&config);
+        builder.set_update_callback(|_node_id, _property_id, value| async move { Some(value) });
+
+        let (
+            _event_loop,
+            _additional_event_loops,
+            _homie,
+            _stats,
+            _firmware,
+            callback,
+            _broadcast_callback,
+        ) = builder.build();
+        let mut callback = callback.unwrap();
+
+        // Values for the config property are handled by the config.
+        assert_eq!(
+            callback("node".to_owned(), "config".to_owned(), "[ 3 ]".to_owned()).await,
+            UpdateOutcome::Accepted("[3]".to_owned())
+        );
+        assert_eq!(config.get(), vec![3]);
+        assert_eq!(
+            callback("node".to_owned(), "config".to_owned(), "invalid".to_owned()).await,
+            UpdateOutcome::Deferred
+        );
+        assert_eq!(config.get(), vec![3]);
+
+        // Values for other properties are passed to the callback.
+        assert_eq!(
+            callback("node".to_owned(), "other".to_owned(), "value".to_owned()).await,
+            UpdateOutcome::Accepted("value".to_owned())
+        );
+    }
+
+    /// Check that aliased properties are published under their old IDs too.
+    #[tokio::test]
+    async fn property_aliases_published() -> Result<(), ClientError> {
+        let (mut device, rx) = make_test_device();
+
+        device
+            .add_node(Node::new(
+                "node",
+                "Node",
+                "type",
+                vec![Property::integer("level", "Level", true, true, None, None)
+                    .with_alias("old")
+                    .with_category(PropertyCategory::Config)],
+            ))
+            .await?;
+        let requests: Vec<_> = rx.drain().collect();
+        let published_topics: Vec<_> = requests
+            .iter()
+            .filter_map(|request| match request {
+                Request::Publish(publish) => Some(publish.topic.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert!(published_topics.contains(&"homie/test-device/node/level/$name"));
+        assert!(published_topics.contains(&"homie/test-device/node/old/$name"));
+        assert!(published_topics.contains(&"homie/test-device/node/level/$category"));
+        assert!(published_topics.contains(&"homie/test-device/node/old/$category"));
+        let subscribed_topics: Vec<_> = requests
+            .iter()
+            .filter_map(|request| match request {
+                Request::Subscribe(subscribe) => Some(subscribe.filters[0].path.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            subscribed_topics,
+            vec![
+                "homie/test-device/node/level/set",
+                "homie/test-device/node/old/set"
+            ]
+        );
+        assert!(requests.iter().any(|request| matches!(
+            request,
+            Request::Publish(publish)
+                if publish.topic == "homie/test-device/node/$properties"
+                    && publish.payload == "level,old"
+        )));
+
+        assert_eq!(device.publisher.aliases.resolve("node", "old"), "level");
+        assert_eq!(device.publisher.aliases.resolve("node", "level"), "level");
+        assert_eq!(device.publisher.aliases.resolve("other", "old"), "old");
+
+        device.publish_value("node", "level", 42).await?;
+        let published_values: Vec<_> = rx
+            .drain()
+            .map(|request| match request {
+                Request::Publish(publish) => (publish.topic, publish.payload.to_vec()),
+                _ => panic!("Unexpected request {:?}", request),
+            })
+            .collect();
+        assert_eq!(
+            published_values,
+            vec![
+                ("homie/test-device/node/level".to_owned(), b"42".to_vec()),
+                ("homie/test-device/node/old".to_owned(), b"42".to_vec()),
+            ]
+        );
+
+        device.remove_node("node").await?;
+        assert_eq!(device.publisher.aliases.resolve("node", "old"), "old");
+
+        Ok(())
+    }
+
+    /// Check that derived properties are published whenever their source property is.
+    #[tokio::test]
+    async fn derived_properties_published() -> Result<(), ClientError> {
+        let (mut device, rx) = make_test_device();
+
+        device
+            .add_node(Node::new(
+                "node",
+                "Node",
+                "type",
+                vec![
+                    Property::float("celsius", "Celsius", false, true, Some("°C"), None),
+                    Property::float("fahrenheit", "Fahrenheit", false, true, Some("°F"), None),
+                ],
+            ))
+            .await?;
+        device.publish_value("node", "celsius", 100).await?;
+        rx.drain();
+
+        // The current value is derived immediately.
+        device
+            .add_derived_property("node", "celsius", "node", "fahrenheit", |value| {
+                let celsius: f64 = value.parse().ok()?;
+                Some((celsius * 9.0 / 5.0 + 32.0).to_string())
+            })
+            .await?;
+        device.publish_value("node", "celsius", 0).await?;
+        // Nothing is published for a value which can't be transformed.
+        device.publish_value("node", "celsius", "invalid").await?;
+        let published_values: Vec<_> = rx
+            .drain()
+            .map(|request| match request {
+                Request::Publish(publish) => (publish.topic, publish.payload.to_vec()),
+                _ => panic!("Unexpected request {:?}", request),
+            })
+            .collect();
+        assert_eq!(
+            published_values,
+            vec![
+                (
+                    "homie/test-device/node/fahrenheit".to_owned(),
+                    b"212".to_vec()
+                ),
+                ("homie/test-device/node/celsius".to_owned(), b"0".to_vec()),
+                (
+                    "homie/test-device/node/fahrenheit".to_owned(),
+                    b"32".to_vec()
+                ),
+                (
+                    "homie/test-device/node/celsius".to_owned(),
+                    b"invalid".to_vec()
+                ),
+            ]
+        );
+        assert_eq!(
+            device.value_publisher().value("node", "fahrenheit"),
+            Some("32".to_owned())
+        );
+
+        Ok(())
+    }
+
+    /// Check that set values are validated according to the datatype and format of the property,
+    /// and parsed for the typed callback.
+    #[tokio::test]
+    async fn set_values_validated() -> Result<(), ClientError> {
+        let (mut device, _rx) = make_test_device();
+        device
+            .add_node(Node::new(
+                "node",
+                "Node",
+                "type",
+                vec![Property::integer(
+                    "level",
+                    "Level",
+                    true,
+                    true,
+                    None,
+                    Some(0..10),
+                )],
+            ))
+            .await?;
+        let properties = &device.publisher.properties;
+
+        assert_eq!(
+            properties.validate("node", "level", "banana", InvalidSetPayloads::Allow),
+            Ok("banana".to_owned())
+        );
+        assert!(properties
+            .validate("node", "level", "banana", InvalidSetPayloads::Reject)
+            .is_err());
+        assert!(properties
+            .validate("node", "level", "11", InvalidSetPayloads::Reject)
+            .is_err());
+        assert_eq!(
+            properties.validate("node", "level", "11", InvalidSetPayloads::Clamp),
+            Ok("10".to_owned())
+        );
+        assert_eq!(
+            properties.validate("node", "level", "5", InvalidSetPayloads::Reject),
+            Ok("5".to_owned())
+        );
+        // Values for unknown properties are passed through.
+        assert_eq!(
+            properties.validate("node", "other", "banana", InvalidSetPayloads::Reject),
+            Ok("banana".to_owned())
+        );
+
+        // The last published value is remembered so it can be republished.
+        assert_eq!(properties.value("node", "level"), None);
+        device.publish_value("node", "level", 3).await?;
+        assert_eq!(
+            device.publisher.properties.value("node", "level"),
+            Some("3".to_owned())
+        );
+
+        let mut callback = with_typed_values(
+            device.publisher.properties.clone(),
+            Box::new(|_node_id, _property_id, value| {
+                assert_eq!(value, SetValue::Integer(7));
+                async move { Some(value.to_string()) }.boxed()
+            }),
+        );
+        assert_eq!(
+            callback("node".to_owned(), "level".to_owned(), "7".to_owned()).await,
+            UpdateOutcome::Accepted("7".to_owned())
+        );
+        assert_eq!(
+            callback("node".to_owned(), "other".to_owned(), "7".to_owned()).await,
+            UpdateOutcome::Deferred
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejected_set_republishes_value() -> Result<(), ClientError> {
+        let (mut device, rx) = make_test_device();
+        device
+            .add_node(Node::new(
+                "node",
+                "Node",
+                "type",
+                vec![Property::integer("level", "Level", true, true, None, None)],
+            ))
+            .await?;
+        device.publish_value("node", "level", 3).await?;
+        rx.drain();
+
+        device
+            .publisher
+            .reject_set("node", "level", "Too high", false)
+            .await?;
+        device
+            .publisher
+            .reject_set("node", "level", "Too high", true)
+            .await?;
+
+        let published: Vec<_> = rx
+            .drain()
+            .map(|request| match request {
+                Request::Publish(publish) => (publish.topic, publish.retain, publish.payload),
+                _ => panic!("Unexpected request {:?}", request),
+            })
+            .collect();
+        assert_eq!(
+            published,
+            vec![
+                ("homie/test-device/node/level".to_owned(), true, "3".into()),
+                ("homie/test-device/node/level".to_owned(), true, "3".into()),
+                (
+                    "homie/test-device/node/level/$error".to_owned(),
+                    false,
+                    "Too high".into()
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broadcast_callback_called() {
+        let port = mqtt_test_broker::spawn_mqtt_broker();
+        let mut builder = HomieDevice::builder_for(
+            "homie",
+            "test-device",
+            "Test device",
+            MqttOptions::new("test-device", "localhost", port),
+        )
+        .unwrap();
+        let (tx, rx) = flume::unbounded();
+        builder.set_broadcast_callback(move |level, payload| {
+            let tx = tx.clone();
+            async move { tx.send((level, payload)).unwrap() }
+        });
+        let (_homie, homie_handle) = builder.spawn().await.unwrap();
+        let homie_handle = tokio::spawn(homie_handle);
+
+        let (client, mut event_loop) =
+            AsyncClient::new(MqttOptions::new("test-controller", "localhost", port), 10);
+        tokio::spawn(async move { while event_loop.poll().await.is_ok() {} });
+
+        // The device may not have subscribed yet, so keep broadcasting until it gets one.
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                client
+                    .publish("homie/$broadcast/alert", QoS::AtLeastOnce, false, "Fire!")
+                    .await
+                    .unwrap();
+                if let Ok(received) =
+                    tokio::time::timeout(Duration::from_millis(100), rx.recv_async()).await
+                {
+                    return received.unwrap();
+                }
+            }
+        })
+        .await
+        .expect("Broadcast callback not called");
+        assert_eq!(received, ("alert".to_owned(), "Fire!".to_owned()));
+
+        homie_handle.abort();
+    }
+
+    #[test]
+    fn broadcast_prefix_from_device_base() {
+        assert_eq!(broadcast_prefix("homie/test-device"), "homie/$broadcast/");
+        assert_eq!(
+            broadcast_prefix("some/base/test-device"),
+            "some/base/$broadcast/"
+        );
+    }
+
+    /// Check that rapid value updates are coalesced when a minimum publish interval is set.
+    #[tokio::test(start_paused = true)]
+    async fn publish_value_rate_limited() -> Result<(), ClientError> {
+        let (mut device, rx) = make_test_device();
+        device.publisher.rate_limiter = Some(Arc::new(RateLimiter::new(
+            Duration::from_secs(60),
+            Arc::new(TokioExecutor),
+        )));
+
+        device.publish_value("node", "property", 1).await?;
+        device.publish_value("node", "property", 2).await?;
+        device.publish_value("node", "property", 3).await?;
+        device.publish_value("node", "other", 4).await?;
+
+        // The first value for each property is published immediately, the rest are held back.
+        let published_values = || {
+            rx.drain()
+                .map(|request| match request {
+                    Request::Publish(publish) => (publish.topic, publish.payload.to_vec()),
+                    _ => panic!("Unexpected request {:?}", request),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            published_values(),
+            vec![
+                ("homie/test-device/node/property".to_owned(), b"1".to_vec()),
+                ("homie/test-device/node/other".to_owned(), b"4".to_vec()),
+            ]
+        );
+
+        // Once the interval has elapsed, only the latest value is published.
+        tokio::time::advance(Duration::from_secs(60)).await;
+        task::yield_now().await;
+        assert_eq!(
+            published_values(),
+            vec![("homie/test-device/node/property".to_owned(), b"3".to_vec())]
+        );
+
+        Ok(())
+    }
+
+    /// An executor which counts what it is asked to do, and delegates to Tokio.
+    #[derive(Debug, Default)]
+    struct CountingExecutor {
+        spawned: AtomicUsize,
+        slept: AtomicUsize,
+    }
+
+    impl Executor for Arc<CountingExecutor> {
+        fn spawn(&self, future: BoxFuture<'static, ()>) {
+            self.spawned.fetch_add(1, Ordering::SeqCst);
+            TokioExecutor.spawn(future);
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            self.slept.fetch_add(1, Ordering::SeqCst);
+            TokioExecutor.sleep(duration)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limiter_uses_executor() -> Result<(), ClientError> {
+        let executor = Arc::new(CountingExecutor::default());
+        let (mut device, rx) = make_test_device();
+        device.publisher.rate_limiter = Some(Arc::new(RateLimiter::new(
+            Duration::from_secs(60),
+            Arc::new(executor.clone()),
+        )));
+
+        device.publish_value("node", "property", 1).await?;
+        device.publish_value("node", "property", 2).await?;
+        device.publish_value("node", "property", 3).await?;
+        assert_eq!(executor.spawned.load(Ordering::SeqCst), 1);
+        assert_eq!(executor.slept.load(Ordering::SeqCst), 1);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        task::yield_now().await;
+        assert_eq!(rx.drain().count(), 2);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,312 @@
+use crate::{DevicePublisher, Executor, HomieDevice, Property, SpawnError};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use rumqttc::ClientError;
+use std::collections::VecDeque;
+use std::fmt::{self, Debug, Formatter};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// The subtopic of the device to which log lines are published.
+const LOG_SUBTOPIC: &str = "$implementation/log";
+const DEFAULT_CAPACITY: usize = 100;
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(1);
+const LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// A logger which publishes recent log lines from the device over MQTT, to the non-retained
+/// `$implementation/log` subtopic of the device.
+///
+/// Register it with [`HomieDeviceBuilder::add_logger`](crate::HomieDeviceBuilder::add_logger),
+/// install it as the global logger with [`install`](Self::install), and include
+/// [`property`](Self::property) in one of the device's nodes so that a controller can change the
+/// level at runtime.
+///
+/// Lines are kept in a ring buffer until they can be published, so if more are logged than can be
+/// sent then the oldest are dropped. They are published in batches, at most once per minimum
+/// interval.
+///
+/// Records from `rumqttc`, and debug and trace records from this crate, are never published, as
+/// publishing them would log more of them.
+///
+/// `HomieLogger` is cheap to clone, and all clones share the same level and buffer.
+#[derive(Clone)]
+pub struct HomieLogger {
+    node_id: String,
+    property_id: String,
+    capacity: usize,
+    min_interval: Duration,
+    fallback: Option<Arc<dyn Log>>,
+    shared: Arc<Shared>,
+}
+
+/// State shared between clones of a `HomieLogger`.
+#[derive(Debug)]
+struct Shared {
+    level: Mutex<LevelFilter>,
+    lines: Mutex<VecDeque<String>>,
+    /// Notified when a line is added to the buffer.
+    new_lines: Notify,
+}
+
+impl Debug for HomieLogger {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("HomieLogger")
+            .field("node_id", &self.node_id)
+            .field("property_id", &self.property_id)
+            .field("capacity", &self.capacity)
+            .field("min_interval", &self.min_interval)
+            .field("fallback", &self.fallback.as_ref().map(|_| "..."))
+            .field("level", &self.level())
+            .finish()
+    }
+}
+
+impl HomieLogger {
+    /// Create a new logger which publishes records at or above the given level.
+    ///
+    /// # Arguments
+    /// * `node_id`: The ID of the node to which the level property will be added.
+    /// * `property_id`: The subtopic ID for the level property. This must be unique per node, and
+    ///   follow the Homie [ID format](https://homieiot.github.io/specification/#topic-ids).
+    /// * `level`: The initial level.
+    pub fn new(node_id: &str, property_id: &str, level: LevelFilter) -> Self {
+        Self {
+            node_id: node_id.to_owned(),
+            property_id: property_id.to_owned(),
+            capacity: DEFAULT_CAPACITY,
+            min_interval: DEFAULT_MIN_INTERVAL,
+            fallback: None,
+            shared: Arc::new(Shared {
+                level: Mutex::new(level),
+                lines: Mutex::new(VecDeque::new()),
+                new_lines: Notify::new(),
+            }),
+        }
+    }
+
+    /// Set the maximum number of lines to keep waiting to be published. Defaults to 100.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Set the minimum interval between publishing batches of lines. Defaults to 1 second.
+    pub fn with_min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    /// Also pass all records to the given logger, such as one which writes to the console. It is
+    /// responsible for its own filtering.
+    pub fn with_fallback(mut self, fallback: impl Log + 'static) -> Self {
+        self.fallback = Some(Arc::new(fallback));
+        self
+    }
+
+    /// Install this as the global logger.
+    ///
+    /// This sets the global maximum level to `Trace`, so that the level can be raised at runtime.
+    pub fn install(self) -> Result<(), SetLoggerError> {
+        log::set_logger(Box::leak(Box::new(self)))?;
+        log::set_max_level(LevelFilter::Trace);
+        Ok(())
+    }
+
+    /// The ID of the node to which the level property belongs.
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// The ID of the level property.
+    pub fn property_id(&self) -> &str {
+        &self.property_id
+    }
+
+    /// Get the current level at or above which records are published.
+    pub fn level(&self) -> LevelFilter {
+        *self.shared.level.lock().unwrap()
+    }
+
+    /// Change the level at or above which records are published. This doesn't publish the new value
+    /// of the level property.
+    pub fn set_level(&self, level: LevelFilter) {
+        *self.shared.level.lock().unwrap() = level;
+    }
+
+    /// Create the settable, retained enum property for the level, to be added to the node.
+    pub fn property(&self, name: &str) -> Property {
+        Property::enumeration(&self.property_id, name, true, true, None, &LEVELS)
+    }
+
+    /// Publish the current level as the value of the property. This should be called after adding
+    /// the node containing the property to the device.
+    pub async fn publish(&self, homie: &HomieDevice) -> Result<(), ClientError> {
+        homie
+            .publish_value(&self.node_id, &self.property_id, level_str(self.level()))
+            .await
+    }
+
+    /// Handle a value set by a controller, returning the value to publish if it was applied.
+    pub(crate) fn handle_set(&self, value: &str) -> Option<String> {
+        match value.parse() {
+            Ok(level) => {
+                self.set_level(level);
+                Some(level_str(level))
+            }
+            Err(_) => {
+                log::warn!(
+                    "Invalid log level {:?} for {}/{}",
+                    value,
+                    self.node_id,
+                    self.property_id
+                );
+                None
+            }
+        }
+    }
+
+    /// Whether records with the given metadata should be published.
+    fn publishes(&self, metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        metadata.level() <= self.level()
+            && !target.starts_with("rumqttc")
+            && !(target.starts_with("homie_device") && metadata.level() > Level::Info)
+    }
+
+    /// Take all the lines waiting to be published.
+    fn take_lines(&self) -> Vec<String> {
+        self.shared.lines.lock().unwrap().drain(..).collect()
+    }
+
+    /// Publish batches of lines as they are logged.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn run(
+        &self,
+        publisher: DevicePublisher,
+        executor: Arc<dyn Executor>,
+    ) -> impl Future<Output = Result<(), SpawnError>> {
+        let logger = self.clone();
+        async move {
+            loop {
+                logger.shared.new_lines.notified().await;
+                let lines = logger.take_lines();
+                if !lines.is_empty() {
+                    publisher
+                        .publish_nonretained(LOG_SUBTOPIC, lines.join("\n"))
+                        .await?;
+                }
+                executor.sleep(logger.min_interval).await;
+            }
+        }
+    }
+}
+
+impl Log for HomieLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.publishes(metadata)
+            || self
+                .fallback
+                .as_ref()
+                .is_some_and(|fallback| fallback.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(fallback) = &self.fallback {
+            fallback.log(record);
+        }
+        if self.capacity == 0 || !self.publishes(record.metadata()) {
+            return;
+        }
+        let line = format!("{} {}: {}", record.level(), record.target(), record.args());
+        {
+            let mut lines = self.shared.lines.lock().unwrap();
+            if lines.len() >= self.capacity {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+        self.shared.new_lines.notify_one();
+    }
+
+    fn flush(&self) {
+        if let Some(fallback) = &self.fallback {
+            fallback.flush();
+        }
+    }
+}
+
+/// Formats the given level as one of the values of the level property.
+fn level_str(level: LevelFilter) -> String {
+    level.to_string().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(logger: &HomieLogger, level: Level, target: &str, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn filters_by_level() {
+        let logger = HomieLogger::new("node", "log_level", LevelFilter::Info);
+        log(&logger, Level::Info, "app", "one");
+        log(&logger, Level::Debug, "app", "two");
+        log(&logger, Level::Error, "app", "three");
+        assert_eq!(
+            logger.take_lines(),
+            vec!["INFO app: one", "ERROR app: three"]
+        );
+        assert!(logger.take_lines().is_empty());
+
+        assert_eq!(logger.handle_set("debug"), Some("debug".to_owned()));
+        assert_eq!(logger.level(), LevelFilter::Debug);
+        log(&logger, Level::Debug, "app", "four");
+        assert_eq!(logger.take_lines(), vec!["DEBUG app: four"]);
+
+        assert_eq!(logger.handle_set("loud"), None);
+        assert_eq!(logger.level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn excludes_mqtt_records() {
+        let logger = HomieLogger::new("node", "log_level", LevelFilter::Trace);
+        log(&logger, Level::Info, "rumqttc::state", "one");
+        log(&logger, Level::Trace, "homie_device", "two");
+        log(&logger, Level::Warn, "homie_device", "three");
+        assert_eq!(logger.take_lines(), vec!["WARN homie_device: three"]);
+    }
+
+    #[test]
+    fn drops_oldest_lines() {
+        let logger = HomieLogger::new("node", "log_level", LevelFilter::Info).with_capacity(2);
+        for message in ["one", "two", "three"] {
+            log(&logger, Level::Info, "app", message);
+        }
+        assert_eq!(
+            logger.take_lines(),
+            vec!["INFO app: two", "INFO app: three"]
+        );
+    }
+
+    #[test]
+    fn level_property() {
+        let logger = HomieLogger::new("node", "log_level", LevelFilter::Warn);
+        let property = logger.property("Log level");
+        assert_eq!(property.id, "log_level");
+        assert!(property.settable);
+        assert_eq!(
+            property.format.as_deref(),
+            Some("off,error,warn,info,debug,trace")
+        );
+        assert_eq!(level_str(logger.level()), "warn");
+    }
+}
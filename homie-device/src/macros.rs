@@ -0,0 +1,127 @@
+/// Declare a [`Node`](crate::Node) and its properties, checking at compile time that all their IDs
+/// follow the Homie [ID format](https://homieiot.github.io/specification/#topic-ids).
+///
+/// The node is given by its ID, name and type, followed by one `property` line for each of its
+/// properties with the ID, name and [`Datatype`](crate::Datatype) variant. Any other attributes of
+/// the property can be set in braces after the datatype, using the names of the corresponding
+/// [`PropertyBuilder`](crate::PropertyBuilder) methods. As for the builder, properties are not
+/// settable and are retained unless otherwise specified.
+///
+/// IDs and aliases must be constant expressions, such as string literals or `const` items, so that
+/// they can be checked when the macro is compiled.
+///
+/// # Example
+///
+/// ```
+/// use homie_device::{homie_node, PropertyCategory};
+///
+/// const NODE_ID: &str = "climate";
+///
+/// let node = homie_node! {
+///     NODE_ID, "Climate", "Sensor";
+///     property "temperature", "Temperature", Float { unit: "°C" };
+///     property "target", "Target temperature", Float { unit: "°C", settable: true, alias: "setpoint" };
+///     property "mode", "Mode", Enum { format: "heat,cool,off", settable: true };
+///     property "battery", "Battery level", Integer { unit: "%", category: PropertyCategory::Diagnostic };
+/// };
+/// assert_eq!(node.properties.len(), 4);
+/// ```
+///
+/// An invalid ID is a compile error:
+///
+/// ```compile_fail
+/// use homie_device::homie_node;
+///
+/// let node = homie_node! {
+///     "climate", "Climate", "Sensor";
+///     property "Temperature", "Temperature", Float;
+/// };
+/// ```
+#[macro_export]
+macro_rules! homie_node {
+    (
+        $id:expr, $name:expr, $node_type:expr;
+        $(
+            property $property_id:expr, $property_name:expr, $datatype:ident
+            $({ $($attribute:ident: $value:expr),* $(,)? })?;
+        )*
+    ) => {{
+        const _: () = assert!($crate::is_valid_id($id), "Invalid Homie node ID");
+        $crate::Node::new(
+            $id,
+            $name,
+            $node_type,
+            vec![$({
+                const _: () = assert!($crate::is_valid_id($property_id), "Invalid Homie property ID");
+                $($($crate::__homie_check_attribute!($attribute, $value);)*)?
+                $crate::Property::builder($property_id, $property_name, $crate::Datatype::$datatype)
+                    $($(.$attribute($value))*)?
+                    .build()
+                    .expect("Property IDs were checked at compile time")
+            }),*],
+        )
+    }};
+}
+
+/// Check at compile time that an attribute given to [`homie_node`] is valid, if it is an ID.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __homie_check_attribute {
+    (alias, $alias:expr) => {
+        const _: () = assert!($crate::is_valid_id($alias), "Invalid Homie property alias");
+    };
+    ($attribute:ident, $value:expr) => {};
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Datatype, Node, Property, PropertyCategory};
+
+    const NODE_ID: &str = "node";
+
+    #[test]
+    fn node_with_properties() {
+        let node = homie_node! {
+            NODE_ID, "Node", "Type";
+            property "plain", "Plain", Boolean;
+            property "empty", "Empty", String {};
+            property "attributes", "Attributes", Float {
+                settable: true,
+                retained: false,
+                unit: "°C",
+                format: "0:100",
+                category: PropertyCategory::Config,
+                alias: "old",
+            };
+        };
+        assert_eq!(
+            node,
+            Node::new(
+                "node",
+                "Node",
+                "Type",
+                vec![
+                    Property::boolean("plain", "Plain", false, true, None),
+                    Property::string("empty", "Empty", false, true, None),
+                    Property::make(
+                        "attributes",
+                        "Attributes",
+                        Datatype::Float,
+                        true,
+                        false,
+                        Some("°C"),
+                        Some("0:100".to_owned())
+                    )
+                    .with_category(PropertyCategory::Config)
+                    .with_alias("old"),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn node_without_properties() {
+        let node = homie_node! { "node", "Node", "Type"; };
+        assert_eq!(node, Node::new("node", "Node", "Type", vec![]));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::clients::MqttClients;
+use crate::Executor;
+use rumqttc::ClientError;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Limits how often values are published to each property topic.
+///
+/// A value published within the minimum interval of the previous one for the same topic is held
+/// back, and replaced by any newer value for the topic, until the interval has elapsed. Only the
+/// latest value is then published.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    min_interval: Duration,
+    executor: Arc<dyn Executor>,
+    topics: Mutex<HashMap<String, TopicState>>,
+}
+
+#[derive(Debug, Default)]
+struct TopicState {
+    /// The last time a value was published to the topic.
+    last_published: Option<Instant>,
+    /// The newest value which has been held back, if any.
+    pending: Option<Vec<u8>>,
+    /// Whether a task has been spawned to publish the pending value.
+    flush_scheduled: bool,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(min_interval: Duration, executor: Arc<dyn Executor>) -> Self {
+        Self {
+            min_interval,
+            executor,
+            topics: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Publish the given retained value to the given topic, or hold it back to be published later
+    /// if a value was published to the same topic too recently.
+    pub(crate) async fn publish_retained(
+        self: &Arc<Self>,
+        clients: &MqttClients,
+        topic: String,
+        value: Vec<u8>,
+    ) -> Result<(), ClientError> {
+        let now = Instant::now();
+        let mut value = Some(value);
+        let flush_at = {
+            let mut topics = self.topics.lock().unwrap();
+            let state = topics.entry(topic.clone()).or_default();
+            if state.flush_scheduled {
+                state.pending = value;
+                return Ok(());
+            }
+            match state.last_published {
+                Some(last_published) if now < last_published + self.min_interval => {
+                    state.pending = value.take();
+                    state.flush_scheduled = true;
+                    last_published + self.min_interval
+                }
+                _ => {
+                    state.last_published = Some(now);
+                    now
+                }
+            }
+        };
+        if let Some(value) = value {
+            return clients.publish(topic, true, value).await;
+        }
+
+        let limiter = self.clone();
+        let clients = clients.clone();
+        let delay = self.executor.sleep(flush_at - now);
+        self.executor.spawn(Box::pin(async move {
+            delay.await;
+            let value = {
+                let mut topics = limiter.topics.lock().unwrap();
+                let state = topics.entry(topic.clone()).or_default();
+                state.flush_scheduled = false;
+                state.last_published = Some(Instant::now());
+                state.pending.take()
+            };
+            if let Some(value) = value {
+                if let Err(e) = clients.publish(topic.clone(), true, value).await {
+                    log::error!("Failed to publish rate-limited value to {}: {}", topic, e);
+                }
+            }
+        }));
+        Ok(())
+    }
+}
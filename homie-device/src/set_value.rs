@@ -0,0 +1,306 @@
+use crate::types::{Datatype, Property};
+use crate::values::{ColorFormat, ColorHsv, ColorRgb};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// How values set by a controller which are not valid for the datatype and format of the property
+/// should be handled.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum InvalidSetPayloads {
+    /// Pass them to the update callback anyway.
+    #[default]
+    Allow,
+    /// Log a warning and republish the current value of the property (if it has been published),
+    /// rather than calling the update callback.
+    Reject,
+    /// Clamp integer and float values which are outside the range given by the property format
+    /// into that range. Reject other invalid values.
+    Clamp,
+}
+
+/// The outcome of handling a value set on a property by a controller, returned by a callback set
+/// with `HomieDeviceBuilder::set_acknowledged_update_callback`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UpdateOutcome {
+    /// The value was accepted, and the given value should be published as the new value of the
+    /// property.
+    Accepted(String),
+    /// The value was rejected for the given reason. The reason is logged, and the current value of
+    /// the property (if it has been published) is republished so that the controller sees that it
+    /// hasn't changed.
+    Rejected(String),
+    /// Nothing should be published for now, e.g. because the new value will only be known once some
+    /// slow operation completes, at which point it should be published with
+    /// `HomieDevice::publish_value`.
+    Deferred,
+}
+
+impl From<Option<String>> for UpdateOutcome {
+    /// Converts the return value of a callback set with `HomieDeviceBuilder::set_update_callback`.
+    fn from(value: Option<String>) -> Self {
+        match value {
+            Some(value) => Self::Accepted(value),
+            None => Self::Deferred,
+        }
+    }
+}
+
+/// A value set on a property by a controller, parsed according to the datatype of the property.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SetValue {
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    String(String),
+    /// One of the values listed in the format of an enum property.
+    Enum(String),
+    ColorRgb(ColorRgb),
+    ColorHsv(ColorHsv),
+    /// An ISO 8601 date and time. This is only checked to be roughly the right shape.
+    DateTime(String),
+    /// An ISO 8601 duration. This is only checked to be roughly the right shape.
+    Duration(String),
+}
+
+impl Display for SetValue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Integer(value) => value.fmt(f),
+            Self::Float(value) => value.fmt(f),
+            Self::Boolean(value) => value.fmt(f),
+            Self::String(value)
+            | Self::Enum(value)
+            | Self::DateTime(value)
+            | Self::Duration(value) => f.write_str(value),
+            Self::ColorRgb(value) => value.fmt(f),
+            Self::ColorHsv(value) => value.fmt(f),
+        }
+    }
+}
+
+/// An error parsing a value set by a controller for a property.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub enum InvalidValueError {
+    /// The value couldn't be parsed as the datatype of the property.
+    #[error("'{value}' is not a valid {datatype}")]
+    WrongDatatype { value: String, datatype: Datatype },
+    /// The value is not allowed by the format of the property, e.g. out of range or not one of the
+    /// enum values.
+    #[error("'{value}' doesn't match format '{format}'")]
+    WrongFormat { value: String, format: String },
+}
+
+impl SetValue {
+    /// Parses the given payload according to the datatype and format of the property.
+    ///
+    /// If `clamp` is true then integer and float values outside the range given by the format are
+    /// clamped into it, rather than rejected.
+    pub(crate) fn parse(
+        property: &Property,
+        payload: &str,
+        clamp: bool,
+    ) -> Result<Self, InvalidValueError> {
+        let wrong_datatype = || InvalidValueError::WrongDatatype {
+            value: payload.to_owned(),
+            datatype: property.datatype,
+        };
+        let wrong_format = || InvalidValueError::WrongFormat {
+            value: payload.to_owned(),
+            format: property.format.clone().unwrap_or_default(),
+        };
+
+        match property.datatype {
+            Datatype::Integer => {
+                let value = payload.parse().map_err(|_| wrong_datatype())?;
+                check_range(property, value, clamp)
+                    .map(Self::Integer)
+                    .ok_or_else(wrong_format)
+            }
+            Datatype::Float => {
+                let value: f64 = payload.parse().map_err(|_| wrong_datatype())?;
+                if !value.is_finite() {
+                    return Err(wrong_datatype());
+                }
+                check_range(property, value, clamp)
+                    .map(Self::Float)
+                    .ok_or_else(wrong_format)
+            }
+            Datatype::Boolean => match payload {
+                "true" => Ok(Self::Boolean(true)),
+                "false" => Ok(Self::Boolean(false)),
+                _ => Err(wrong_datatype()),
+            },
+            Datatype::String => Ok(Self::String(payload.to_owned())),
+            Datatype::Enum => {
+                let format = property.format.as_deref().unwrap_or_default();
+                if format.split(',').any(|value| value == payload) {
+                    Ok(Self::Enum(payload.to_owned()))
+                } else {
+                    Err(wrong_format())
+                }
+            }
+            Datatype::Color => match property.format.as_deref() {
+                Some(format) if format == ColorFormat::Rgb.to_string() => payload
+                    .parse()
+                    .map(Self::ColorRgb)
+                    .map_err(|_| wrong_datatype()),
+                Some(format) if format == ColorFormat::Hsv.to_string() => payload
+                    .parse()
+                    .map(Self::ColorHsv)
+                    .map_err(|_| wrong_datatype()),
+                _ => Err(wrong_format()),
+            },
+            Datatype::DateTime => {
+                let bytes = payload.as_bytes();
+                if bytes.len() >= 10
+                    && bytes[..4].iter().all(u8::is_ascii_digit)
+                    && bytes[4] == b'-'
+                {
+                    Ok(Self::DateTime(payload.to_owned()))
+                } else {
+                    Err(wrong_datatype())
+                }
+            }
+            Datatype::Duration => {
+                let rest = payload.strip_prefix('-').unwrap_or(payload);
+                if rest.len() > 1 && rest.starts_with('P') {
+                    Ok(Self::Duration(payload.to_owned()))
+                } else {
+                    Err(wrong_datatype())
+                }
+            }
+        }
+    }
+}
+
+/// Checks that the given value is within the range given by the format of the property, if any.
+///
+/// Returns the value, clamped into the range if `clamp` is true, or `None` if it is out of range
+/// and `clamp` is false.
+fn check_range<T: Copy + FromStr + PartialOrd>(
+    property: &Property,
+    value: T,
+    clamp: bool,
+) -> Option<T> {
+    let Some((start, end)) = property.format.as_deref().and_then(parse_range) else {
+        return Some(value);
+    };
+    if value < start {
+        clamp.then_some(start)
+    } else if value > end {
+        clamp.then_some(end)
+    } else {
+        Some(value)
+    }
+}
+
+fn parse_range<T: FromStr>(format: &str) -> Option<(T, T)> {
+    let (start, end) = format.split_once(':')?;
+    Some((start.parse().ok()?, end.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_integer() {
+        let property = Property::integer("id", "Name", true, true, None, Some(0..10));
+        assert_eq!(
+            SetValue::parse(&property, "5", false),
+            Ok(SetValue::Integer(5))
+        );
+        assert_eq!(
+            SetValue::parse(&property, "banana", true),
+            Err(InvalidValueError::WrongDatatype {
+                value: "banana".to_owned(),
+                datatype: Datatype::Integer
+            })
+        );
+        assert_eq!(
+            SetValue::parse(&property, "11", false),
+            Err(InvalidValueError::WrongFormat {
+                value: "11".to_owned(),
+                format: "0:10".to_owned()
+            })
+        );
+        assert_eq!(
+            SetValue::parse(&property, "11", true),
+            Ok(SetValue::Integer(10))
+        );
+        assert_eq!(
+            SetValue::parse(&property, "-3", true),
+            Ok(SetValue::Integer(0))
+        );
+    }
+
+    #[test]
+    fn parse_float() {
+        let property = Property::float("id", "Name", true, true, None, Some(-1.5..1.5));
+        assert_eq!(
+            SetValue::parse(&property, "0.25", false),
+            Ok(SetValue::Float(0.25))
+        );
+        assert_eq!(
+            SetValue::parse(&property, "2", true),
+            Ok(SetValue::Float(1.5))
+        );
+        assert!(SetValue::parse(&property, "2", false).is_err());
+        assert!(SetValue::parse(&property, "NaN", true).is_err());
+
+        let unbounded = Property::float("id", "Name", true, true, None, None);
+        assert_eq!(
+            SetValue::parse(&unbounded, "1e6", false),
+            Ok(SetValue::Float(1e6))
+        );
+    }
+
+    #[test]
+    fn parse_boolean_and_enum() {
+        let boolean = Property::boolean("id", "Name", true, true, None);
+        assert_eq!(
+            SetValue::parse(&boolean, "true", false),
+            Ok(SetValue::Boolean(true))
+        );
+        assert!(SetValue::parse(&boolean, "yes", false).is_err());
+
+        let enumeration = Property::enumeration("id", "Name", true, true, None, &["a", "b"]);
+        assert_eq!(
+            SetValue::parse(&enumeration, "b", false),
+            Ok(SetValue::Enum("b".to_owned()))
+        );
+        assert!(SetValue::parse(&enumeration, "c", true).is_err());
+    }
+
+    #[test]
+    fn parse_color() {
+        let rgb = Property::color("id", "Name", true, true, None, ColorFormat::Rgb);
+        assert_eq!(
+            SetValue::parse(&rgb, "1,2,3", false),
+            Ok(SetValue::ColorRgb(ColorRgb::new(1, 2, 3)))
+        );
+        assert!(SetValue::parse(&rgb, "1,2", false).is_err());
+
+        let hsv = Property::color("id", "Name", true, true, None, ColorFormat::Hsv);
+        assert_eq!(
+            SetValue::parse(&hsv, "360,100,0", false),
+            Ok(SetValue::ColorHsv(ColorHsv::new(360, 100, 0)))
+        );
+        assert!(SetValue::parse(&hsv, "361,0,0", false).is_err());
+    }
+
+    #[test]
+    fn parse_datetime_and_duration() {
+        let datetime = Property::datetime("id", "Name", true, true, None);
+        assert!(SetValue::parse(&datetime, "2021-03-04T05:06:07Z", false).is_ok());
+        assert!(SetValue::parse(&datetime, "yesterday", false).is_err());
+
+        let duration = Property::duration("id", "Name", true, true, None);
+        assert_eq!(
+            SetValue::parse(&duration, "PT5M", false),
+            Ok(SetValue::Duration("PT5M".to_owned()))
+        );
+        assert!(SetValue::parse(&duration, "5 minutes", false).is_err());
+    }
+}
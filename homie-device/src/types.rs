@@ -1,6 +1,7 @@
 use std::fmt::{self, Debug, Display, Formatter};
 use std::ops::Range;
 
+use crate::id::{validate_id, InvalidIdError};
 use crate::values::ColorFormat;
 
 /// The data type for a Homie property.
@@ -20,6 +21,10 @@ pub enum Datatype {
     /// An RGB or HSV [color](https://homieiot.github.io/specification/#color), depending on the
     /// property format.
     Color,
+    /// An [ISO 8601 date and time](https://homieiot.github.io/specification/#datetime).
+    DateTime,
+    /// An [ISO 8601 duration](https://homieiot.github.io/specification/#duration).
+    Duration,
 }
 
 impl Datatype {
@@ -31,6 +36,8 @@ impl Datatype {
             Self::String => "string",
             Self::Enum => "enum",
             Self::Color => "color",
+            Self::DateTime => "datetime",
+            Self::Duration => "duration",
         }
     }
 }
@@ -47,7 +54,48 @@ impl From<Datatype> for Vec<u8> {
     }
 }
 
+/// The category of a Homie property, to help user interfaces group properties appropriately.
+///
+/// This isn't part of the Homie specification, but is published as the `$category` attribute of
+/// the property.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PropertyCategory {
+    /// A user-facing reading or state, such as the temperature measured by a sensor or whether a
+    /// light is on.
+    Telemetry,
+    /// Information about the health of the device, such as its battery level or signal strength.
+    Diagnostic,
+    /// A setting which changes how the device behaves, such as a reporting interval.
+    Config,
+}
+
+impl PropertyCategory {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Telemetry => "telemetry",
+            Self::Diagnostic => "diagnostic",
+            Self::Config => "config",
+        }
+    }
+}
+
+impl Display for PropertyCategory {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<PropertyCategory> for Vec<u8> {
+    fn from(category: PropertyCategory) -> Self {
+        category.as_str().into()
+    }
+}
+
 /// A [property](https://homieiot.github.io/specification/#properties) of a Homie node.
+///
+/// The constructors for properties with common units, such as [`Property::percentage`] and
+/// [`Property::temperature_c`], create retained properties and take `id`, `name` and `settable`
+/// arguments as described for [`Property::new`].
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Property {
     /// The subtopic ID of the property. This must be unique per node, and should follow the Homie
@@ -77,6 +125,14 @@ pub struct Property {
     /// The format of the property, if any. This must be specified if the datatype is `Enum` or
     /// `Color`, and may be specified if the datatype is `Integer` or `Float`.
     pub format: Option<String>,
+
+    /// The category of the property, if any, published as the non-standard `$category` attribute.
+    pub category: Option<PropertyCategory>,
+
+    /// Former IDs of the property, which are still published alongside it so that controllers
+    /// using the old IDs keep working. Values are published to each alias as well as the main ID,
+    /// and values set via an alias are passed to the update callback under the main ID.
+    pub aliases: Vec<String>,
 }
 
 impl Property {
@@ -314,6 +370,120 @@ impl Property {
         )
     }
 
+    /// Create a new datetime property with the given attributes.
+    ///
+    /// Values can be formatted with [`format_datetime`](crate::format_datetime).
+    ///
+    /// # Arguments
+    /// * `id`: The subtopic ID for the property. This must be unique per node, and follow the Homie
+    ///   [ID format](https://homieiot.github.io/specification/#topic-ids).
+    /// * `name`: The human-readable name of the property.
+    /// * `settable`: Whether the property can be set by the Homie controller. This should be true
+    ///   for properties like the brightness or power state of a light, and false for things like
+    ///   the temperature reading of a sensor.
+    /// * `retained`: Whether the property value is persisted by the MQTT broker. A non-retained
+    ///   property can be used for a momentary event, like a doorbell being pressed.
+    /// * `unit`: The unit for the property, if any. This may be one of the
+    ///   [recommended units](https://homieiot.github.io/specification/#property-attributes), or
+    ///   any other custom unit.
+    pub fn datetime(
+        id: &str,
+        name: &str,
+        settable: bool,
+        retained: bool,
+        unit: Option<&str>,
+    ) -> Property {
+        Property::make(
+            id,
+            name,
+            Datatype::DateTime,
+            settable,
+            retained,
+            unit,
+            None::<String>,
+        )
+    }
+
+    /// Create a new duration property with the given attributes.
+    ///
+    /// Values can be formatted with [`format_duration`](crate::format_duration).
+    ///
+    /// # Arguments
+    /// * `id`: The subtopic ID for the property. This must be unique per node, and follow the Homie
+    ///   [ID format](https://homieiot.github.io/specification/#topic-ids).
+    /// * `name`: The human-readable name of the property.
+    /// * `settable`: Whether the property can be set by the Homie controller. This should be true
+    ///   for properties like the brightness or power state of a light, and false for things like
+    ///   the temperature reading of a sensor.
+    /// * `retained`: Whether the property value is persisted by the MQTT broker. A non-retained
+    ///   property can be used for a momentary event, like a doorbell being pressed.
+    /// * `unit`: The unit for the property, if any. This may be one of the
+    ///   [recommended units](https://homieiot.github.io/specification/#property-attributes), or
+    ///   any other custom unit.
+    pub fn duration(
+        id: &str,
+        name: &str,
+        settable: bool,
+        retained: bool,
+        unit: Option<&str>,
+    ) -> Property {
+        Property::make(
+            id,
+            name,
+            Datatype::Duration,
+            settable,
+            retained,
+            unit,
+            None::<String>,
+        )
+    }
+
+    /// Create a new retained integer property for a percentage, with the unit `%` and range 0 to 100.
+    pub fn percentage(id: &str, name: &str, settable: bool) -> Property {
+        Property::integer(id, name, settable, true, Some("%"), Some(0..100))
+    }
+
+    /// Create a new retained floating-point property for a temperature in degrees Celsius.
+    pub fn temperature_c(id: &str, name: &str, settable: bool) -> Property {
+        Property::float(id, name, settable, true, Some("°C"), None)
+    }
+
+    /// Create a new retained floating-point property for a temperature in degrees Fahrenheit.
+    pub fn temperature_f(id: &str, name: &str, settable: bool) -> Property {
+        Property::float(id, name, settable, true, Some("°F"), None)
+    }
+
+    /// Create a new retained floating-point property for a power in watts.
+    pub fn power_w(id: &str, name: &str, settable: bool) -> Property {
+        Property::float(id, name, settable, true, Some("W"), None)
+    }
+
+    /// Create a new retained floating-point property for a voltage in volts.
+    pub fn voltage_v(id: &str, name: &str, settable: bool) -> Property {
+        Property::float(id, name, settable, true, Some("V"), None)
+    }
+
+    /// Create a new retained floating-point property for a current in amperes.
+    pub fn current_a(id: &str, name: &str, settable: bool) -> Property {
+        Property::float(id, name, settable, true, Some("A"), None)
+    }
+
+    /// Start building a new property with the given attributes. Unlike the other constructors, this
+    /// checks that the ID is valid when the property is built.
+    ///
+    /// The property is not settable and is retained unless otherwise specified.
+    ///
+    /// # Arguments
+    /// * `id`: The subtopic ID for the property. This must be unique per node, and follow the Homie
+    ///   [ID format](https://homieiot.github.io/specification/#topic-ids).
+    /// * `name`: The human-readable name of the property.
+    /// * `datatype`: The data type of the property.
+    pub fn builder(id: &str, name: &str, datatype: Datatype) -> PropertyBuilder {
+        PropertyBuilder {
+            property: Property::make(id, name, datatype, false, true, None, None),
+        }
+    }
+
     pub fn make(
         id: &str,
         name: &str,
@@ -331,7 +501,83 @@ impl Property {
             retained,
             unit: unit.map(|s| s.to_owned()),
             format,
+            category: None,
+            aliases: vec![],
+        }
+    }
+
+    /// Set the category of the property, so that user interfaces can group it with similar
+    /// properties.
+    pub fn with_category(mut self, category: PropertyCategory) -> Self {
+        self.category = Some(category);
+        self
+    }
+
+    /// Add an alias for the property, to keep publishing it under an old ID after it has been
+    /// renamed. The alias is published as a separate property with the same attributes, and values
+    /// are published to both. Values set by a controller on the alias are treated as if they were
+    /// set on this property.
+    ///
+    /// Remove the alias once controllers have had a chance to migrate to the new ID.
+    pub fn with_alias(mut self, alias: &str) -> Self {
+        self.aliases.push(alias.to_owned());
+        self
+    }
+}
+
+/// A builder for a [`Property`], which validates its ID.
+#[derive(Clone, Debug)]
+pub struct PropertyBuilder {
+    property: Property,
+}
+
+impl PropertyBuilder {
+    /// Set whether the property can be set by the Homie controller.
+    pub fn settable(mut self, settable: bool) -> Self {
+        self.property.settable = settable;
+        self
+    }
+
+    /// Set whether the property value is persisted by the MQTT broker.
+    pub fn retained(mut self, retained: bool) -> Self {
+        self.property.retained = retained;
+        self
+    }
+
+    /// Set the unit of the property. This may be one of the
+    /// [recommended units](https://homieiot.github.io/specification/#property-attributes), or any
+    /// other custom unit.
+    pub fn unit(mut self, unit: &str) -> Self {
+        self.property.unit = Some(unit.to_owned());
+        self
+    }
+
+    /// Set the format of the property. This must be specified if the datatype is `Enum` or
+    /// `Color`, and may be specified if the datatype is `Integer` or `Float`.
+    pub fn format(mut self, format: &str) -> Self {
+        self.property.format = Some(format.to_owned());
+        self
+    }
+
+    /// Set the category of the property.
+    pub fn category(mut self, category: PropertyCategory) -> Self {
+        self.property.category = Some(category);
+        self
+    }
+
+    /// Add an alias for the property. See [`Property::with_alias`].
+    pub fn alias(mut self, alias: &str) -> Self {
+        self.property.aliases.push(alias.to_owned());
+        self
+    }
+
+    /// Build the property, or return an error if its ID or any of its aliases is not valid.
+    pub fn build(self) -> Result<Property, InvalidIdError> {
+        validate_id(&self.property.id)?;
+        for alias in &self.property.aliases {
+            validate_id(alias)?;
         }
+        Ok(self.property)
     }
 }
 
@@ -369,6 +615,47 @@ impl Node {
             properties,
         }
     }
+
+    /// Start building a new node with the given attributes. Unlike `new`, this checks that the
+    /// IDs of the node and its properties are valid when the node is built.
+    ///
+    /// # Arguments
+    /// * `id`: The subtopic ID for the node. This must be unique per device, and follow the Homie
+    ///   [ID format](https://homieiot.github.io/specification/#topic-ids).
+    /// * `name`: The human-readable name of the node.
+    /// * `type`: The type of the node. This is an arbitrary string.
+    pub fn builder(id: &str, name: &str, node_type: &str) -> NodeBuilder {
+        NodeBuilder {
+            node: Node::new(id, name, node_type, vec![]),
+        }
+    }
+}
+
+/// A builder for a [`Node`], which validates its ID and the IDs of its properties.
+#[derive(Clone, Debug)]
+pub struct NodeBuilder {
+    node: Node,
+}
+
+impl NodeBuilder {
+    /// Add a property to the node.
+    pub fn property(mut self, property: Property) -> Self {
+        self.node.properties.push(property);
+        self
+    }
+
+    /// Build the node, or return an error if the ID of the node or any of its properties is not
+    /// valid.
+    pub fn build(self) -> Result<Node, InvalidIdError> {
+        validate_id(&self.node.id)?;
+        for property in &self.node.properties {
+            validate_id(&property.id)?;
+            for alias in &property.aliases {
+                validate_id(alias)?;
+            }
+        }
+        Ok(self.node)
+    }
 }
 
 #[cfg(test)]
@@ -387,6 +674,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn datetime_duration_datatype() {
+        let datetime = Property::datetime("id", "name", false, true, None);
+        assert_eq!(datetime.datatype.to_string(), "datetime");
+        assert_eq!(datetime.format, None);
+        let duration = Property::duration("id", "name", false, true, None);
+        assert_eq!(duration.datatype.to_string(), "duration");
+        assert_eq!(duration.format, None);
+    }
+
+    #[test]
+    fn property_builder() {
+        assert_eq!(
+            Property::builder("brightness", "Brightness", Datatype::Integer)
+                .settable(true)
+                .unit("%")
+                .format("0:100")
+                .category(PropertyCategory::Telemetry)
+                .build(),
+            Ok(Property::new(
+                "brightness",
+                "Brightness",
+                Datatype::Integer,
+                true,
+                true,
+                Some("%"),
+                Some("0:100")
+            )
+            .with_category(PropertyCategory::Telemetry))
+        );
+        assert_eq!(
+            Property::builder("Brightness", "Brightness", Datatype::Integer).build(),
+            Err(InvalidIdError {
+                id: "Brightness".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn node_builder_validates_property_ids() {
+        let node = Node::builder("light", "Light", "light")
+            .property(Property::boolean("on", "On", true, true, None))
+            .build()
+            .unwrap();
+        assert_eq!(node.properties.len(), 1);
+
+        assert_eq!(
+            Node::builder("light_1", "Light", "light").build(),
+            Err(InvalidIdError {
+                id: "light_1".to_owned()
+            })
+        );
+        assert_eq!(
+            Node::builder("light", "Light", "light")
+                .property(Property::boolean("is_on", "On", true, true, None))
+                .build(),
+            Err(InvalidIdError {
+                id: "is_on".to_owned()
+            })
+        );
+    }
+
     #[test]
     fn integer_property_format() {
         assert_eq!(
@@ -411,6 +760,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn unit_property_constructors() {
+        let percentage = Property::percentage("level", "Level", true);
+        assert_eq!(percentage.datatype, Datatype::Integer);
+        assert!(percentage.settable);
+        assert!(percentage.retained);
+        assert_eq!(percentage.unit.as_deref(), Some("%"));
+        assert_eq!(percentage.format.as_deref(), Some("0:100"));
+
+        let temperature = Property::temperature_c("temperature", "Temperature", false);
+        assert_eq!(temperature.datatype, Datatype::Float);
+        assert!(!temperature.settable);
+        assert_eq!(temperature.unit.as_deref(), Some("°C"));
+        assert_eq!(temperature.format, None);
+
+        assert_eq!(
+            Property::power_w("power", "Power", false).unit.as_deref(),
+            Some("W")
+        );
+    }
+
     #[test]
     fn enum_property_format() {
         assert_eq!(
@@ -0,0 +1,257 @@
+use crate::{DevicePublisher, Property};
+use rumqttc::ClientError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use thiserror::Error;
+
+/// An error registering or using a [`PropertyUpdater`].
+#[derive(Debug, Error)]
+pub enum PropertyUpdateError {
+    /// The updater was registered with a device builder which already has it, or with another one.
+    #[error("PropertyUpdater is already registered with a device")]
+    AlreadyRegistered,
+    /// The updater was used before the device it is registered with was spawned.
+    #[error("PropertyUpdater used before the device was spawned")]
+    NotSpawned,
+    /// The device has no such property.
+    #[error("Unknown property {node_id}/{property_id}")]
+    UnknownProperty {
+        node_id: String,
+        property_id: String,
+    },
+    #[error("{0}")]
+    Client(#[from] ClientError),
+}
+
+/// A handle for changing the name, unit or format of properties of a device while it is running,
+/// such as from the update callback when setting one property changes the valid range of another.
+///
+/// Register it with
+/// [`HomieDeviceBuilder::add_property_updater`](crate::HomieDeviceBuilder::add_property_updater)
+/// before spawning the device, and move a clone of it into the update callback. Values set by
+/// controllers are validated against the new attributes as soon as an update returns.
+///
+/// `PropertyUpdater` is cheap to clone, and all clones update the same device.
+#[derive(Clone, Debug, Default)]
+pub struct PropertyUpdater {
+    registered: Arc<AtomicBool>,
+    publisher: Arc<OnceLock<DevicePublisher>>,
+}
+
+impl PropertyUpdater {
+    /// Create a new updater, not yet registered with any device.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Change the human-readable name of the given property, and republish it.
+    ///
+    /// Returns an error if the updater hasn't been registered with a device which has been spawned,
+    /// or if the device has no such property.
+    pub async fn set_name(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        name: &str,
+    ) -> Result<(), PropertyUpdateError> {
+        self.update(node_id, property_id, "$name", Some(name), |property| {
+            property.name = name.to_owned()
+        })
+        .await
+    }
+
+    /// Change or remove the unit of the given property, and republish it.
+    ///
+    /// Returns an error if the updater hasn't been registered with a device which has been spawned,
+    /// or if the device has no such property.
+    pub async fn set_unit(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        unit: Option<&str>,
+    ) -> Result<(), PropertyUpdateError> {
+        self.update(node_id, property_id, "$unit", unit, |property| {
+            property.unit = unit.map(ToOwned::to_owned)
+        })
+        .await
+    }
+
+    /// Change or remove the format of the given property, and republish it.
+    ///
+    /// Returns an error if the updater hasn't been registered with a device which has been spawned,
+    /// or if the device has no such property.
+    pub async fn set_format(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        format: Option<&str>,
+    ) -> Result<(), PropertyUpdateError> {
+        self.update(node_id, property_id, "$format", format, |property| {
+            property.format = format.map(ToOwned::to_owned)
+        })
+        .await
+    }
+
+    /// Mark the updater as registered with a device builder, or return an error if it already is.
+    pub(crate) fn register(&self) -> Result<(), PropertyUpdateError> {
+        if self.registered.swap(true, Ordering::SeqCst) {
+            Err(PropertyUpdateError::AlreadyRegistered)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Use the given publisher for the device which this updater has been registered with.
+    pub(crate) fn bind(&self, publisher: DevicePublisher) {
+        // Registration ensures that this is only called once.
+        let _ = self.publisher.set(publisher);
+    }
+
+    /// Apply the given change to the property, and publish the new value of the given attribute of
+    /// it and any aliases. Attributes with no value are removed from the broker.
+    async fn update(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        attribute: &str,
+        value: Option<&str>,
+        change: impl Fn(&mut Property),
+    ) -> Result<(), PropertyUpdateError> {
+        let publisher = self
+            .publisher
+            .get()
+            .ok_or(PropertyUpdateError::NotSpawned)?;
+        {
+            let mut nodes = publisher.nodes.lock().unwrap();
+            let property = nodes
+                .iter_mut()
+                .filter(|node| node.id == node_id)
+                .flat_map(|node| node.properties.iter_mut())
+                .find(|property| property.id == property_id)
+                .ok_or_else(|| PropertyUpdateError::UnknownProperty {
+                    node_id: node_id.to_owned(),
+                    property_id: property_id.to_owned(),
+                })?;
+            change(property);
+            publisher.properties.update(node_id, property_id, change);
+        }
+        for subtopic in publisher.aliases.subtopics(node_id, property_id) {
+            publisher
+                .publish_retained(
+                    &format!("{}/{}", subtopic, attribute),
+                    value.unwrap_or_default(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InvalidSetPayloads, Node};
+    use rumqttc::{AsyncClient, Request};
+
+    #[tokio::test]
+    async fn format_updated() -> Result<(), PropertyUpdateError> {
+        let (requests_tx, requests_rx) = flume::unbounded();
+        let client = AsyncClient::from_senders(requests_tx);
+        let publisher = DevicePublisher::new(client, "homie/test-device".to_string());
+        let node = Node::new(
+            "node",
+            "Node",
+            "type",
+            vec![
+                Property::integer("setpoint", "Setpoint", true, true, None, Some(0..10))
+                    .with_alias("old"),
+            ],
+        );
+        publisher.aliases.add_node(&node);
+        publisher.properties.add_node(&node);
+        publisher.nodes.lock().unwrap().push(node);
+        let updater = PropertyUpdater::new();
+        updater.register().unwrap();
+        updater.bind(publisher.clone());
+
+        assert!(publisher
+            .properties
+            .validate("node", "setpoint", "15", InvalidSetPayloads::Reject)
+            .is_err());
+        updater.set_format("node", "setpoint", Some("0:20")).await?;
+        assert_eq!(
+            publisher
+                .properties
+                .validate("node", "setpoint", "15", InvalidSetPayloads::Reject),
+            Ok("15".to_owned())
+        );
+        updater.set_unit("node", "setpoint", None).await?;
+        let nodes = publisher.nodes.lock().unwrap().clone();
+        assert_eq!(nodes[0].properties[0].format.as_deref(), Some("0:20"));
+        assert_eq!(nodes[0].properties[0].unit, None);
+
+        let published: Vec<_> = requests_rx
+            .drain()
+            .map(|request| match request {
+                Request::Publish(publish) => (publish.topic, publish.payload.to_vec()),
+                _ => panic!("Unexpected request {:?}", request),
+            })
+            .collect();
+        assert_eq!(
+            published,
+            vec![
+                (
+                    "homie/test-device/node/setpoint/$format".to_owned(),
+                    b"0:20".to_vec()
+                ),
+                (
+                    "homie/test-device/node/old/$format".to_owned(),
+                    b"0:20".to_vec()
+                ),
+                (
+                    "homie/test-device/node/setpoint/$unit".to_owned(),
+                    b"".to_vec()
+                ),
+                ("homie/test-device/node/old/$unit".to_owned(), b"".to_vec()),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_fails_before_spawn() {
+        let updater = PropertyUpdater::new();
+        assert!(matches!(
+            updater.set_name("node", "property", "Name").await,
+            Err(PropertyUpdateError::NotSpawned)
+        ));
+    }
+
+    #[tokio::test]
+    async fn update_fails_for_unknown_property() {
+        let (requests_tx, requests_rx) = flume::unbounded();
+        let client = AsyncClient::from_senders(requests_tx);
+        let publisher = DevicePublisher::new(client, "homie/test-device".to_string());
+        let updater = PropertyUpdater::new();
+        updater.register().unwrap();
+        updater.bind(publisher);
+
+        assert!(matches!(
+            updater.set_name("node", "property", "Name").await,
+            Err(PropertyUpdateError::UnknownProperty { node_id, property_id })
+                if node_id == "node" && property_id == "property"
+        ));
+        assert!(requests_rx.is_empty());
+    }
+
+    #[test]
+    fn register_twice_fails() {
+        let updater = PropertyUpdater::new();
+        updater.register().unwrap();
+        assert!(matches!(
+            updater.clone().register(),
+            Err(PropertyUpdateError::AlreadyRegistered)
+        ));
+    }
+}
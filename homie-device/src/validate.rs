@@ -0,0 +1,294 @@
+use crate::id::is_valid_id;
+use crate::types::{Datatype, Node, Property};
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use thiserror::Error;
+
+/// A way in which a device, node or property doesn't conform to the Homie specification, found by
+/// [`HomieDevice::validate`](crate::HomieDevice::validate) or [`Node::validate`].
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub struct Violation {
+    /// The subtopic relative to the device topic which the problem is with, such as
+    /// `"node/property/$format"`. This is empty for the device ID.
+    pub subtopic: String,
+    /// What is wrong with it.
+    pub kind: ViolationKind,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.subtopic.is_empty() {
+            self.kind.fmt(f)
+        } else {
+            write!(f, "{}: {}", self.subtopic, self.kind)
+        }
+    }
+}
+
+/// The kind of a [`Violation`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ViolationKind {
+    /// The ID doesn't follow the Homie [ID format](https://homieiot.github.io/specification/#topic-ids).
+    InvalidId(String),
+    /// The ID is used by more than one node of the device, or by more than one property or alias of
+    /// the node.
+    DuplicateId(String),
+    /// A required attribute is empty.
+    EmptyAttribute,
+    /// The node has no properties.
+    NoProperties,
+    /// The property has no format, but its datatype requires one.
+    MissingFormat,
+    /// The format of the property isn't valid for its datatype.
+    InvalidFormat(String),
+}
+
+impl Display for ViolationKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidId(id) => write!(f, "Invalid Homie ID '{}'", id),
+            Self::DuplicateId(id) => write!(f, "Duplicate ID '{}'", id),
+            Self::EmptyAttribute => f.write_str("Required attribute is empty"),
+            Self::NoProperties => f.write_str("Node has no properties"),
+            Self::MissingFormat => f.write_str("Format is required for this datatype"),
+            Self::InvalidFormat(format) => {
+                write!(f, "Invalid format '{}' for this datatype", format)
+            }
+        }
+    }
+}
+
+impl Violation {
+    fn new(subtopic: impl Into<String>, kind: ViolationKind) -> Self {
+        Self {
+            subtopic: subtopic.into(),
+            kind,
+        }
+    }
+}
+
+/// Check the device ID, name and nodes of a device.
+pub(crate) fn validate_device(
+    device_id: &str,
+    device_name: &str,
+    nodes: &[Node],
+) -> Vec<Violation> {
+    let mut violations = vec![];
+    if !is_valid_id(device_id) {
+        violations.push(Violation::new(
+            "",
+            ViolationKind::InvalidId(device_id.to_owned()),
+        ));
+    }
+    if device_name.is_empty() {
+        violations.push(Violation::new("$name", ViolationKind::EmptyAttribute));
+    }
+    let mut node_ids = HashSet::new();
+    for node in nodes {
+        if !node_ids.insert(node.id.as_str()) {
+            violations.push(Violation::new(
+                node.id.as_str(),
+                ViolationKind::DuplicateId(node.id.clone()),
+            ));
+        }
+        violations.extend(node.validate());
+    }
+    violations
+}
+
+impl Node {
+    /// Check that the node and its properties conform to the Homie specification: that their IDs
+    /// are valid and unique, their names are not empty, and the formats of the properties are
+    /// valid for their datatypes. Returns all the problems found, or an empty list if there are
+    /// none.
+    pub fn validate(&self) -> Vec<Violation> {
+        let mut violations = vec![];
+        if !is_valid_id(&self.id) {
+            violations.push(Violation::new(
+                self.id.as_str(),
+                ViolationKind::InvalidId(self.id.clone()),
+            ));
+        }
+        if self.name.is_empty() {
+            violations.push(Violation::new(
+                format!("{}/$name", self.id),
+                ViolationKind::EmptyAttribute,
+            ));
+        }
+        if self.properties.is_empty() {
+            violations.push(Violation::new(
+                format!("{}/$properties", self.id),
+                ViolationKind::NoProperties,
+            ));
+        }
+        let mut property_ids = HashSet::new();
+        for property in &self.properties {
+            for property_id in std::iter::once(&property.id).chain(&property.aliases) {
+                let subtopic = format!("{}/{}", self.id, property_id);
+                if !is_valid_id(property_id) {
+                    violations.push(Violation::new(
+                        subtopic.as_str(),
+                        ViolationKind::InvalidId(property_id.clone()),
+                    ));
+                }
+                if !property_ids.insert(property_id.as_str()) {
+                    violations.push(Violation::new(
+                        subtopic,
+                        ViolationKind::DuplicateId(property_id.clone()),
+                    ));
+                }
+            }
+            violations.extend(validate_property_attributes(&self.id, property));
+        }
+        violations
+    }
+}
+
+/// Check the name and format of a property.
+fn validate_property_attributes(node_id: &str, property: &Property) -> Vec<Violation> {
+    let mut violations = vec![];
+    let prefix = format!("{}/{}", node_id, property.id);
+    if property.name.is_empty() {
+        violations.push(Violation::new(
+            format!("{}/$name", prefix),
+            ViolationKind::EmptyAttribute,
+        ));
+    }
+    let format_subtopic = format!("{}/$format", prefix);
+    match (property.datatype, &property.format) {
+        (Datatype::Enum | Datatype::Color, None) => {
+            violations.push(Violation::new(
+                format_subtopic,
+                ViolationKind::MissingFormat,
+            ));
+        }
+        (datatype, Some(format)) if !format_valid(datatype, format) => {
+            violations.push(Violation::new(
+                format_subtopic,
+                ViolationKind::InvalidFormat(format.clone()),
+            ));
+        }
+        _ => {}
+    }
+    violations
+}
+
+/// Check whether the given format is valid for a property of the given datatype.
+fn format_valid(datatype: Datatype, format: &str) -> bool {
+    match datatype {
+        Datatype::Integer => range_valid::<i64>(format),
+        Datatype::Float => range_valid::<f64>(format),
+        Datatype::Enum => format.split(',').all(|value| !value.is_empty()),
+        Datatype::Color => format == "rgb" || format == "hsv",
+        Datatype::Boolean | Datatype::String | Datatype::DateTime | Datatype::Duration => false,
+    }
+}
+
+/// Check whether the given format is a range `from:to` of numbers of the given type, with `from` no
+/// greater than `to`.
+fn range_valid<T: std::str::FromStr + PartialOrd>(format: &str) -> bool {
+    match format.split_once(':') {
+        Some((from, to)) => match (from.parse::<T>(), to.parse::<T>()) {
+            (Ok(from), Ok(to)) => from <= to,
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ColorFormat;
+
+    #[test]
+    fn valid_node() {
+        let node = Node::new(
+            "node",
+            "Node",
+            "type",
+            vec![
+                Property::integer("level", "Level", true, true, None, Some(0..101)),
+                Property::float("ratio", "Ratio", false, true, None, None),
+                Property::enumeration("mode", "Mode", true, true, None, &["on", "off"]),
+                Property::color("colour", "Colour", true, true, None, ColorFormat::Hsv)
+                    .with_alias("color"),
+            ],
+        );
+        assert_eq!(node.validate(), vec![]);
+    }
+
+    #[test]
+    fn invalid_node() {
+        let node = Node::new(
+            "Node",
+            "",
+            "type",
+            vec![
+                Property::new("level", "Level", Datatype::Integer, false, true, None, None)
+                    .with_alias("level"),
+                Property::new("mode", "", Datatype::Enum, false, true, None, None),
+                Property::new(
+                    "colour",
+                    "Colour",
+                    Datatype::Color,
+                    false,
+                    true,
+                    None,
+                    Some("xyz"),
+                ),
+                Property::new(
+                    "range",
+                    "Range",
+                    Datatype::Float,
+                    false,
+                    true,
+                    None,
+                    Some("10:1"),
+                ),
+            ],
+        );
+        assert_eq!(
+            node.validate(),
+            vec![
+                Violation::new("Node", ViolationKind::InvalidId("Node".to_owned())),
+                Violation::new("Node/$name", ViolationKind::EmptyAttribute),
+                Violation::new("Node/level", ViolationKind::DuplicateId("level".to_owned())),
+                Violation::new("Node/mode/$name", ViolationKind::EmptyAttribute),
+                Violation::new("Node/mode/$format", ViolationKind::MissingFormat),
+                Violation::new(
+                    "Node/colour/$format",
+                    ViolationKind::InvalidFormat("xyz".to_owned())
+                ),
+                Violation::new(
+                    "Node/range/$format",
+                    ViolationKind::InvalidFormat("10:1".to_owned())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_nodes() {
+        let node = Node::new(
+            "node",
+            "Node",
+            "type",
+            vec![Property::boolean("on", "On", true, true, None)],
+        );
+        assert_eq!(
+            validate_device("device", "Device", &[node.clone(), node]),
+            vec![Violation::new(
+                "node",
+                ViolationKind::DuplicateId("node".to_owned())
+            )]
+        );
+        assert_eq!(
+            validate_device("Device", "", &[]),
+            vec![
+                Violation::new("", ViolationKind::InvalidId("Device".to_owned())),
+                Violation::new("$name", ViolationKind::EmptyAttribute),
+            ]
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use crate::DevicePublisher;
+use rumqttc::ClientError;
+
+/// A handle for publishing the values of properties of a [`HomieDevice`](crate::HomieDevice), which
+/// can be cloned and sent to other tasks so that they can publish concurrently without sharing the
+/// device itself. Get one with
+/// [`HomieDevice::value_publisher`](crate::HomieDevice::value_publisher).
+///
+/// Adding and removing nodes and changing the state of the device still require the `HomieDevice`.
+/// The handle doesn't refer back to the device, so it can be stored anywhere without creating a
+/// reference cycle, and keeps working after the device is dropped for as long as the MQTT
+/// connection task is running.
+#[derive(Clone, Debug)]
+pub struct ValuePublisher {
+    publisher: DevicePublisher,
+}
+
+impl ValuePublisher {
+    pub(crate) fn new(publisher: DevicePublisher) -> Self {
+        Self { publisher }
+    }
+
+    /// Publish a new value for the given retained property of the given node of the device. The
+    /// caller is responsible for ensuring that the value is of the correct type.
+    ///
+    /// If a minimum publish interval was set on the builder, the value may be held back and
+    /// published later, or replaced by a newer value before being published.
+    ///
+    /// If the property has any aliases, the value is published to them too, and the values of any
+    /// [derived properties](crate::HomieDevice::add_derived_property) are published.
+    pub async fn publish_value(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        value: impl ToString,
+    ) -> Result<(), ClientError> {
+        let value = value.to_string();
+        self.publisher
+            .properties
+            .set_value(node_id, property_id, &value);
+        for subtopic in self.publisher.aliases.subtopics(node_id, property_id) {
+            self.publisher
+                .publish_value(&subtopic, value.as_str())
+                .await?;
+        }
+        self.publisher
+            .publish_derived(node_id, property_id, &value)
+            .await
+    }
+
+    /// Publish a new value for the given non-retained property of the given node of the device. The
+    /// caller is responsible for ensuring that the value is of the correct type.
+    ///
+    /// If the property has any aliases, the value is published to them too, and the values of any
+    /// [derived properties](crate::HomieDevice::add_derived_property) are published.
+    pub async fn publish_nonretained_value(
+        &self,
+        node_id: &str,
+        property_id: &str,
+        value: impl ToString,
+    ) -> Result<(), ClientError> {
+        let value = value.to_string();
+        for subtopic in self.publisher.aliases.subtopics(node_id, property_id) {
+            self.publisher
+                .publish_nonretained(&subtopic, value.as_str())
+                .await?;
+        }
+        self.publisher
+            .publish_derived(node_id, property_id, &value)
+            .await
+    }
+
+    /// Get the last retained value published for the given property, if any.
+    pub fn value(&self, node_id: &str, property_id: &str) -> Option<String> {
+        self.publisher.properties.value(node_id, property_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HomieDevice, Node, Property};
+    use rumqttc::{AsyncClient, Request};
+
+    #[tokio::test]
+    async fn publish_from_other_task() -> Result<(), Box<dyn std::error::Error>> {
+        let (requests_tx, requests_rx) = flume::unbounded();
+        let client = AsyncClient::from_senders(requests_tx);
+        let publisher = DevicePublisher::new(client, "homie/test-device".to_string());
+        let mut device = HomieDevice::new(publisher, "Test device".to_string(), &[]);
+        device
+            .add_node(Node::new(
+                "node",
+                "Node",
+                "type",
+                vec![Property::integer("level", "Level", false, true, None, None)],
+            ))
+            .await?;
+        requests_rx.drain();
+
+        let value_publisher = device.value_publisher();
+        tokio::spawn(async move { value_publisher.publish_value("node", "level", 42).await })
+            .await??;
+
+        assert_eq!(
+            device.value_publisher().value("node", "level"),
+            Some("42".to_owned())
+        );
+        let published: Vec<_> = requests_rx
+            .drain()
+            .map(|request| match request {
+                Request::Publish(publish) => (publish.topic, publish.payload.to_vec()),
+                _ => panic!("Unexpected request {:?}", request),
+            })
+            .collect();
+        assert_eq!(
+            published,
+            vec![("homie/test-device/node/level".to_owned(), b"42".to_vec())]
+        );
+
+        Ok(())
+    }
+}
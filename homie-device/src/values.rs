@@ -1,6 +1,7 @@
 use std::fmt::{self, Debug, Display, Formatter};
 use std::num::ParseIntError;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
 /// The format of a [colour](https://homieiot.github.io/specification/#color) property, either RGB
@@ -140,6 +141,90 @@ impl Color for ColorHsv {
     }
 }
 
+/// Format the given time as an ISO 8601 date and time in UTC, suitable for the value of a
+/// [datetime](https://homieiot.github.io/specification/#datetime) property.
+///
+/// Sub-second precision is included as milliseconds only if it is non-zero, e.g.
+/// `2021-03-04T05:06:07Z` or `2021-03-04T05:06:07.890Z`.
+pub fn format_datetime(time: SystemTime) -> String {
+    let (seconds, millis) = match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => (since_epoch.as_secs() as i64, since_epoch.subsec_millis()),
+        Err(e) => {
+            let before_epoch = e.duration();
+            let millis = before_epoch.subsec_millis();
+            if millis == 0 {
+                (-(before_epoch.as_secs() as i64), 0)
+            } else {
+                (-(before_epoch.as_secs() as i64) - 1, 1000 - millis)
+            }
+        }
+    };
+    let days = seconds.div_euclid(86400);
+    let second_of_day = seconds.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let mut formatted = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        second_of_day / 3600,
+        second_of_day / 60 % 60,
+        second_of_day % 60
+    );
+    if millis != 0 {
+        formatted += &format!(".{:03}", millis);
+    }
+    formatted.push('Z');
+    formatted
+}
+
+/// Convert a number of days since the Unix epoch to a year, month and day in the proleptic
+/// Gregorian calendar.
+///
+/// This uses the algorithm from <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Format the given duration as an ISO 8601 duration, suitable for the value of a
+/// [duration](https://homieiot.github.io/specification/#duration) property.
+///
+/// Only hours, minutes and seconds are used, as longer units don't have a fixed length. Zero
+/// components are omitted, and sub-second precision is included as milliseconds only if it is
+/// non-zero, e.g. `PT12H5M46S`, `PT1.5S` or `PT0S`.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let millis = duration.subsec_millis();
+    let hours = seconds / 3600;
+    let minutes = seconds / 60 % 60;
+    let seconds = seconds % 60;
+
+    let mut formatted = "PT".to_string();
+    if hours != 0 {
+        formatted += &format!("{}H", hours);
+    }
+    if minutes != 0 {
+        formatted += &format!("{}M", minutes);
+    }
+    if millis != 0 {
+        formatted += format!("{}.{:03}", seconds, millis).trim_end_matches('0');
+        formatted.push('S');
+    } else if seconds != 0 || (hours == 0 && minutes == 0) {
+        formatted += &format!("{}S", seconds);
+    }
+    formatted
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -156,6 +241,33 @@ mod tests {
         assert_eq!(color.to_string().parse(), Ok(color));
     }
 
+    #[test]
+    fn format_datetime_utc() {
+        assert_eq!(format_datetime(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            format_datetime(UNIX_EPOCH + Duration::from_secs(1_614_834_367)),
+            "2021-03-04T05:06:07Z"
+        );
+        assert_eq!(
+            format_datetime(UNIX_EPOCH + Duration::from_millis(951_782_400_890)),
+            "2000-02-29T00:00:00.890Z"
+        );
+        assert_eq!(
+            format_datetime(UNIX_EPOCH - Duration::from_millis(500)),
+            "1969-12-31T23:59:59.500Z"
+        );
+    }
+
+    #[test]
+    fn format_duration_components() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "PT0S");
+        assert_eq!(format_duration(Duration::from_secs(43546)), "PT12H5M46S");
+        assert_eq!(format_duration(Duration::from_secs(3600)), "PT1H");
+        assert_eq!(format_duration(Duration::from_secs(3660)), "PT1H1M");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "PT1.5S");
+        assert_eq!(format_duration(Duration::from_millis(60_025)), "PT1M0.025S");
+    }
+
     #[test]
     fn color_rgb_parse_invalid() {
         assert_eq!("".parse::<ColorRgb>(), Err(ParseColorError()));
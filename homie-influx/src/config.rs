@@ -1,3 +1,6 @@
+use crate::downsample::DownsampleConfig;
+use crate::retry::RetryQueue;
+use crate::template::Template;
 use eyre::Report;
 use influx_db_client::reqwest::Url;
 use influx_db_client::Client;
@@ -7,7 +10,10 @@ use serde::{Deserialize as _, Deserializer};
 use serde_derive::Deserialize;
 use stable_eyre::eyre;
 use stable_eyre::eyre::WrapErr;
-use std::fs::read_to_string;
+use std::collections::BTreeMap;
+use std::fs::{self, read_to_string, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -16,9 +22,13 @@ const DEFAULT_MQTT_HOST: &str = "test.mosquitto.org";
 const DEFAULT_MQTT_PORT: u16 = 1883;
 const DEFAULT_MQTT_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
 const DEFAULT_INFLUXDB_URL: &str = "http://localhost:8086";
+const DEFAULT_POSTGRES_CONNECTION_STRING: &str = "host=localhost user=homie-influx dbname=homie";
 const CONFIG_FILENAME: &str = "homie-influx.toml";
 const DEFAULT_MAPPINGS_FILENAME: &str = "mappings.toml";
 const KEEP_ALIVE: Duration = Duration::from_secs(5);
+const DEFAULT_INFLUXDB_RETRY_QUEUE_SIZE: usize = 10000;
+const DEFAULT_INFLUXDB_RETRY_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_INFLUXDB_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
 
 #[derive(Clone, Debug, Default, Deserialize)]
 #[serde(default, deny_unknown_fields)]
@@ -26,6 +36,7 @@ pub struct Config {
     pub mqtt: MqttConfig,
     pub homie: HomieConfig,
     pub influxdb: InfluxDbConfig,
+    pub postgres: PostgresConfig,
 }
 
 impl Config {
@@ -36,7 +47,11 @@ impl Config {
     fn read(filename: &str) -> Result<Config, Report> {
         let config_file =
             read_to_string(filename).wrap_err_with(|| format!("Reading {}", filename))?;
-        Ok(toml::from_str(&config_file)?)
+        let config: Config = toml::from_str(&config_file)?;
+        if config.homie.workers == 0 {
+            eyre::bail!("homie.workers in {} must be at least 1.", filename);
+        }
+        Ok(config)
     }
 }
 
@@ -57,7 +72,7 @@ pub struct MqttConfig {
 }
 
 /// Deserialize an integer as a number of seconds.
-fn de_duration_seconds<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+pub fn de_duration_seconds<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
     let seconds = u64::deserialize(d)?;
     Ok(Duration::from_secs(seconds))
 }
@@ -80,12 +95,16 @@ impl Default for MqttConfig {
 #[serde(default, deny_unknown_fields)]
 pub struct HomieConfig {
     pub mappings_filename: String,
+    /// The number of workers per mapping to write property values in parallel. Values are assigned
+    /// to workers by device ID, so those for each device are always written in order.
+    pub workers: usize,
 }
 
 impl Default for HomieConfig {
     fn default() -> HomieConfig {
         HomieConfig {
             mappings_filename: DEFAULT_MAPPINGS_FILENAME.to_owned(),
+            workers: 1,
         }
     }
 }
@@ -96,6 +115,27 @@ pub struct InfluxDbConfig {
     pub url: Url,
     pub username: Option<String>,
     pub password: Option<String>,
+    /// The maximum number of points to keep in memory per mapping and worker to retry if writing
+    /// fails.
+    pub retry_queue_size: usize,
+    /// How long to wait before retrying after the first failure. This doubles after each
+    /// consecutive failure, up to `retry_max_backoff`.
+    #[serde(
+        deserialize_with = "de_duration_seconds",
+        rename = "retry_initial_backoff_seconds"
+    )]
+    pub retry_initial_backoff: Duration,
+    #[serde(
+        deserialize_with = "de_duration_seconds",
+        rename = "retry_max_backoff_seconds"
+    )]
+    pub retry_max_backoff: Duration,
+    /// A directory in which to write points which don't fit in the retry queue, so they can be
+    /// retried later. If this is not set they are dropped.
+    pub spill_directory: Option<String>,
+    /// Whether to also write the values of enum properties as a tag, so that queries can group by
+    /// them.
+    pub enums_as_tags: bool,
 }
 
 impl Default for InfluxDbConfig {
@@ -104,17 +144,47 @@ impl Default for InfluxDbConfig {
             url: DEFAULT_INFLUXDB_URL.parse().unwrap(),
             username: None,
             password: None,
+            retry_queue_size: DEFAULT_INFLUXDB_RETRY_QUEUE_SIZE,
+            retry_initial_backoff: DEFAULT_INFLUXDB_RETRY_INITIAL_BACKOFF,
+            retry_max_backoff: DEFAULT_INFLUXDB_RETRY_MAX_BACKOFF,
+            spill_directory: None,
+            enums_as_tags: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostgresConfig {
+    /// The connection string, either in key-value format or as a URL.
+    pub connection_string: String,
+}
+
+impl Default for PostgresConfig {
+    fn default() -> PostgresConfig {
+        PostgresConfig {
+            connection_string: DEFAULT_POSTGRES_CONNECTION_STRING.to_owned(),
         }
     }
 }
 
-/// A mapping from a Homie prefix to monitor to an InfluxDB database where its data should be
-/// stored.
+/// A mapping from a Homie prefix to monitor to an InfluxDB database or Postgres table where its
+/// data should be stored. Exactly one of `influxdb_database` and `postgres_table` must be set.
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Mapping {
     pub homie_prefix: String,
-    pub influxdb_database: String,
+    pub influxdb_database: Option<String>,
+    pub postgres_table: Option<String>,
+    /// A template for the InfluxDB measurement name, instead of the datatype of the property.
+    #[serde(default)]
+    pub measurement: Option<Template>,
+    /// Templates for additional InfluxDB tags, keyed by tag name.
+    #[serde(default)]
+    pub tags: BTreeMap<String, Template>,
+    /// Downsampling of numeric property values before they are written to InfluxDB.
+    #[serde(default)]
+    pub downsample: Option<DownsampleConfig>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -137,6 +207,37 @@ fn mappings_from_file(filename: &str) -> Result<Vec<Mapping>, Report> {
     if mappings.mappings.is_empty() {
         eyre::bail!("At least one mapping must be configured in {}.", filename);
     }
+    for mapping in &mappings.mappings {
+        if mapping.influxdb_database.is_some() == mapping.postgres_table.is_some() {
+            eyre::bail!(
+                "Mapping for {} in {} must specify exactly one of influxdb_database or \
+                 postgres_table.",
+                mapping.homie_prefix,
+                filename
+            );
+        }
+        if mapping.postgres_table.is_some()
+            && (mapping.measurement.is_some()
+                || !mapping.tags.is_empty()
+                || mapping.downsample.is_some())
+        {
+            eyre::bail!(
+                "Mapping for {} in {} can only specify measurement, tags or downsample for \
+                 InfluxDB.",
+                mapping.homie_prefix,
+                filename
+            );
+        }
+        if let Some(downsample) = &mapping.downsample {
+            if downsample.window.is_zero() {
+                eyre::bail!(
+                    "Mapping for {} in {} must have a non-zero downsample window.",
+                    mapping.homie_prefix,
+                    filename
+                );
+            }
+        }
+    }
     Ok(mappings.mappings)
 }
 
@@ -150,6 +251,86 @@ pub fn get_influxdb_client(config: &InfluxDbConfig, database: &str) -> Result<Cl
     Ok(influxdb_client)
 }
 
+/// Construct a queue for retrying failed writes for the given mapping, based on the given
+/// configuration options.
+///
+/// If the mapping has several workers then each has its own queue, identified by `worker`, so that
+/// they don't share a spill file. The first worker also takes over any spill files left by workers
+/// beyond the given number of `workers`, from a previous run with more workers.
+pub fn get_retry_queue(
+    config: &InfluxDbConfig,
+    mapping: &Mapping,
+    worker: usize,
+    workers: usize,
+) -> RetryQueue {
+    let spill_file = config.spill_directory.as_ref().map(|directory| {
+        if worker == 0 {
+            adopt_orphaned_spill_files(directory, mapping, workers);
+        }
+        spill_file_path(directory, mapping, worker)
+    });
+    RetryQueue::new(
+        config.retry_queue_size,
+        config.retry_initial_backoff,
+        config.retry_max_backoff,
+        spill_file,
+    )
+}
+
+/// Get the path of the spill file in the given directory for the given mapping and worker.
+///
+/// The first worker's spill file is named the same as when there is only one worker, so that it is
+/// picked up again if the number of workers changes.
+fn spill_file_path(directory: &str, mapping: &Mapping, worker: usize) -> PathBuf {
+    let prefix = mapping.homie_prefix.replace('/', "_");
+    let filename = if worker == 0 {
+        format!("{}.jsonl", prefix)
+    } else {
+        format!("{}-{}.jsonl", prefix, worker)
+    };
+    Path::new(directory).join(filename)
+}
+
+/// Append the spill files in the given directory for workers of the given mapping numbered
+/// `workers` or above to the first worker's spill file, so that their points aren't lost when the
+/// number of workers is reduced. This also picks up `-0` files, which older versions used for the
+/// first worker.
+fn adopt_orphaned_spill_files(directory: &str, mapping: &Mapping, workers: usize) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return;
+    };
+    let prefix = mapping.homie_prefix.replace('/', "_");
+    let first = spill_file_path(directory, mapping, 0);
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let worker = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|name| name.strip_prefix('-'))
+            .and_then(|name| name.strip_suffix(".jsonl"))
+            .and_then(|worker| worker.parse::<usize>().ok());
+        if worker.is_some_and(|worker| worker == 0 || worker >= workers) {
+            if let Err(e) = append_file(&path, &first) {
+                log::error!(
+                    "Failed to move spilled points from {:?} to {:?}: {}",
+                    path,
+                    first,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Append the contents of one file to another, and then remove the first.
+fn append_file(from: &Path, to: &Path) -> io::Result<()> {
+    let mut source = fs::File::open(from)?;
+    let mut destination = OpenOptions::new().create(true).append(true).open(to)?;
+    io::copy(&mut source, &mut destination)?;
+    fs::remove_file(from)
+}
+
 /// Construct a `ClientConfig` for TLS connections to the MQTT broker, if TLS is enabled.
 pub fn get_tls_client_config(config: &MqttConfig) -> Option<Arc<ClientConfig>> {
     if config.use_tls {
@@ -194,6 +375,7 @@ pub fn get_mqtt_options(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::downsample::Aggregation;
 
     /// Parsing the example config file should not give any errors.
     #[test]
@@ -204,7 +386,67 @@ mod tests {
     /// Parsing an empty config file should not give any errors.
     #[test]
     fn empty_config() {
-        toml::from_str::<Config>("").unwrap();
+        let config = toml::from_str::<Config>("").unwrap();
+        assert_eq!(config.homie.workers, 1);
+    }
+
+    #[test]
+    fn spill_file_per_worker() {
+        let mapping = Mapping {
+            homie_prefix: "building/homie".to_owned(),
+            influxdb_database: Some("test".to_owned()),
+            postgres_table: None,
+            measurement: None,
+            tags: BTreeMap::new(),
+            downsample: None,
+        };
+        assert_eq!(
+            spill_file_path("/var/lib/homie-influx", &mapping, 0),
+            Path::new("/var/lib/homie-influx/building_homie.jsonl")
+        );
+        assert_eq!(
+            spill_file_path("/var/lib/homie-influx", &mapping, 2),
+            Path::new("/var/lib/homie-influx/building_homie-2.jsonl")
+        );
+    }
+
+    #[test]
+    fn orphaned_spill_files_adopted() {
+        let directory =
+            std::env::temp_dir().join(format!("homie-influx-orphan-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let directory = directory.to_str().unwrap();
+        let mapping = Mapping {
+            homie_prefix: "homie".to_owned(),
+            influxdb_database: Some("test".to_owned()),
+            postgres_table: None,
+            measurement: None,
+            tags: BTreeMap::new(),
+            downsample: None,
+        };
+        for (filename, contents) in [
+            ("homie.jsonl", "a\n"),
+            ("homie-0.jsonl", "b\n"),
+            ("homie-1.jsonl", "c\n"),
+            ("homie-2.jsonl", "d\n"),
+            ("homie-other-2.jsonl", "e\n"),
+        ] {
+            fs::write(Path::new(directory).join(filename), contents).unwrap();
+        }
+
+        adopt_orphaned_spill_files(directory, &mapping, 2);
+
+        let first = read_to_string(spill_file_path(directory, &mapping, 0)).unwrap();
+        let mut lines: Vec<_> = first.lines().collect();
+        lines.sort();
+        assert_eq!(lines, vec!["a", "b", "d"]);
+        assert!(!Path::new(directory).join("homie-0.jsonl").exists());
+        assert!(Path::new(directory).join("homie-1.jsonl").exists());
+        assert!(!Path::new(directory).join("homie-2.jsonl").exists());
+        assert!(Path::new(directory).join("homie-other-2.jsonl").exists());
+
+        fs::remove_dir_all(directory).unwrap();
     }
 
     /// Parsing the example mappings file should not give any errors.
@@ -213,4 +455,94 @@ mod tests {
         let mappings = mappings_from_file("mappings.example.toml").unwrap();
         assert_eq!(mappings.len(), 1);
     }
+
+    #[test]
+    fn mapping_sinks() {
+        let mappings = toml::from_str::<MappingsConfig>(
+            r#"
+            [[mappings]]
+            homie_prefix="homie"
+            influxdb_database="test"
+
+            [[mappings]]
+            homie_prefix="other"
+            postgres_table="property_values"
+            "#,
+        )
+        .unwrap()
+        .mappings;
+        assert_eq!(mappings[0].influxdb_database.as_deref(), Some("test"));
+        assert_eq!(mappings[0].postgres_table, None);
+        assert_eq!(mappings[1].influxdb_database, None);
+        assert_eq!(
+            mappings[1].postgres_table.as_deref(),
+            Some("property_values")
+        );
+    }
+
+    #[test]
+    fn mapping_templates() {
+        let mappings = toml::from_str::<MappingsConfig>(
+            r#"
+            [[mappings]]
+            homie_prefix="homie"
+            influxdb_database="test"
+            measurement="{node.type|lower}"
+            tags.room="{device.name}"
+            "#,
+        )
+        .unwrap()
+        .mappings;
+        assert_eq!(
+            mappings[0].measurement,
+            Some("{node.type|lower}".parse().unwrap())
+        );
+        assert_eq!(
+            mappings[0].tags.get("room"),
+            Some(&"{device.name}".parse().unwrap())
+        );
+
+        assert!(toml::from_str::<MappingsConfig>(
+            r#"
+            [[mappings]]
+            homie_prefix="homie"
+            influxdb_database="test"
+            measurement="{node.colour}"
+            "#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn mapping_downsample() {
+        let mappings = toml::from_str::<MappingsConfig>(
+            r#"
+            [[mappings]]
+            homie_prefix="homie"
+            influxdb_database="test"
+            downsample.window_seconds=60
+            downsample.aggregation="max"
+            "#,
+        )
+        .unwrap()
+        .mappings;
+        assert_eq!(
+            mappings[0].downsample,
+            Some(DownsampleConfig {
+                window: Duration::from_secs(60),
+                aggregation: Aggregation::Max,
+            })
+        );
+
+        assert!(toml::from_str::<MappingsConfig>(
+            r#"
+            [[mappings]]
+            homie_prefix="homie"
+            influxdb_database="test"
+            downsample.window_seconds=60
+            downsample.aggregation="median"
+            "#,
+        )
+        .is_err());
+    }
 }
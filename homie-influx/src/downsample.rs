@@ -0,0 +1,270 @@
+use crate::config::de_duration_seconds;
+use influx_db_client::{Point, Value};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// How the values of a property within a downsampling window are combined.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Mean,
+    Min,
+    Max,
+    Last,
+}
+
+/// Configuration for downsampling the values of numeric properties before writing them.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DownsampleConfig {
+    /// The length of each window. Windows are aligned to multiples of this since the Unix epoch.
+    #[serde(deserialize_with = "de_duration_seconds", rename = "window_seconds")]
+    pub window: Duration,
+    pub aggregation: Aggregation,
+}
+
+/// The IDs of the device, node and property of a series of values.
+type SeriesKey = (String, String, String);
+
+/// Combines the points for each integer or float property within each window into a single point,
+/// timestamped with the start of the window. Points for properties of other datatypes are passed
+/// through unchanged.
+#[derive(Debug)]
+pub struct Downsampler {
+    window_millis: i64,
+    aggregation: Aggregation,
+    windows: HashMap<SeriesKey, Window>,
+}
+
+/// The values of a property so far within the current window.
+#[derive(Debug)]
+struct Window {
+    /// The number of windows between the Unix epoch and the start of this one.
+    index: i64,
+    /// The most recent point in the window, from which the measurement and tags of the aggregated
+    /// point are taken.
+    point: Point,
+    integer: bool,
+    count: u32,
+    sum: f64,
+    min: f64,
+    max: f64,
+    last: f64,
+}
+
+impl Downsampler {
+    pub fn new(config: &DownsampleConfig) -> Self {
+        Self {
+            window_millis: (config.window.as_millis() as i64).max(1),
+            aggregation: config.aggregation,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Adds the given point for the given property to its window.
+    ///
+    /// Returns the point to write now, if any: either the aggregate of the previous window of the
+    /// property if the new point is in a different window, or the point itself if it can't be
+    /// downsampled.
+    pub fn add(
+        &mut self,
+        device_id: &str,
+        node_id: &str,
+        property_id: &str,
+        point: Point,
+    ) -> Option<Point> {
+        let (value, integer) = match point.fields.get("value") {
+            Some(Value::Integer(value)) => (*value as f64, true),
+            Some(Value::Float(value)) => (*value, false),
+            _ => return Some(point),
+        };
+        let index = match point.timestamp {
+            Some(timestamp) => timestamp.div_euclid(self.window_millis),
+            None => return Some(point),
+        };
+
+        let key = (
+            device_id.to_owned(),
+            node_id.to_owned(),
+            property_id.to_owned(),
+        );
+        if let Some(window) = self.windows.get_mut(&key) {
+            if window.index == index && window.integer == integer {
+                window.add(value, point);
+                return None;
+            }
+        }
+        let window = Window {
+            index,
+            point,
+            integer,
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+            last: value,
+        };
+        self.windows
+            .insert(key, window)
+            .map(|previous| self.aggregate(previous))
+    }
+
+    /// Removes and returns the aggregated points for all windows which ended at or before the given
+    /// time.
+    pub fn flush_closed(&mut self, now: SystemTime) -> Vec<Point> {
+        let now_millis = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let window_millis = self.window_millis;
+        let closed: Vec<SeriesKey> = self
+            .windows
+            .iter()
+            .filter(|(_, window)| (window.index + 1) * window_millis <= now_millis)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let windows: Vec<Window> = closed
+            .into_iter()
+            .filter_map(|key| self.windows.remove(&key))
+            .collect();
+        let mut points: Vec<Point> = windows
+            .into_iter()
+            .map(|window| self.aggregate(window))
+            .collect();
+        points.sort_by_key(|point| point.timestamp);
+        points
+    }
+
+    /// Removes and returns the aggregated points for all windows, including those which haven't
+    /// ended yet, such as when shutting down.
+    pub fn flush_all(&mut self) -> Vec<Point> {
+        let windows: Vec<Window> = self.windows.drain().map(|(_, window)| window).collect();
+        let mut points: Vec<Point> = windows
+            .into_iter()
+            .map(|window| self.aggregate(window))
+            .collect();
+        points.sort_by_key(|point| point.timestamp);
+        points
+    }
+
+    /// Constructs the point for the given window. The values of integer properties are rounded, so
+    /// that the field type in InfluxDB doesn't change.
+    fn aggregate(&self, window: Window) -> Point {
+        let value = match self.aggregation {
+            Aggregation::Mean => window.sum / f64::from(window.count),
+            Aggregation::Min => window.min,
+            Aggregation::Max => window.max,
+            Aggregation::Last => window.last,
+        };
+        let mut point = window.point;
+        let value = if window.integer {
+            Value::Integer(value.round() as i64)
+        } else {
+            Value::Float(value)
+        };
+        point.fields.insert("value".to_owned(), value);
+        point.timestamp = Some(window.index * self.window_millis);
+        point
+    }
+}
+
+impl Window {
+    fn add(&mut self, value: f64, point: Point) {
+        self.point = point;
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.last = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(value: Value, timestamp: i64) -> Point {
+        Point::new("float")
+            .add_field("value", value)
+            .add_tag("property_id", "power".to_owned())
+            .add_timestamp(timestamp)
+    }
+
+    fn downsampler(aggregation: Aggregation) -> Downsampler {
+        Downsampler::new(&DownsampleConfig {
+            window: Duration::from_secs(60),
+            aggregation,
+        })
+    }
+
+    #[test]
+    fn aggregates_each_window() {
+        for (aggregation, expected) in [
+            (Aggregation::Mean, 2.0),
+            (Aggregation::Min, 1.0),
+            (Aggregation::Max, 3.5),
+            (Aggregation::Last, 1.5),
+        ] {
+            let mut downsampler = downsampler(aggregation);
+            for (value, timestamp) in [(1.0, 60_000), (3.5, 70_000), (1.5, 119_999)] {
+                assert_eq!(
+                    downsampler.add(
+                        "device",
+                        "node",
+                        "power",
+                        point(Value::Float(value), timestamp)
+                    ),
+                    None
+                );
+            }
+            // A value in the next window causes the previous one to be written.
+            assert_eq!(
+                downsampler.add("device", "node", "power", point(Value::Float(5.0), 120_000)),
+                Some(point(Value::Float(expected), 60_000)),
+                "{:?}",
+                aggregation
+            );
+            assert_eq!(
+                downsampler.flush_all(),
+                vec![point(Value::Float(5.0), 120_000)]
+            );
+        }
+    }
+
+    #[test]
+    fn integers_stay_integers() {
+        let mut downsampler = downsampler(Aggregation::Mean);
+        downsampler.add("device", "node", "power", point(Value::Integer(1), 0));
+        downsampler.add("device", "node", "power", point(Value::Integer(2), 1_000));
+        assert_eq!(downsampler.flush_all(), vec![point(Value::Integer(2), 0)]);
+    }
+
+    #[test]
+    fn other_datatypes_pass_through() {
+        let mut downsampler = downsampler(Aggregation::Mean);
+        let boolean = point(Value::Boolean(true), 1_000);
+        assert_eq!(
+            downsampler.add("device", "node", "on", boolean.clone()),
+            Some(boolean)
+        );
+        assert_eq!(downsampler.flush_all(), vec![]);
+    }
+
+    #[test]
+    fn flushes_closed_windows() {
+        let mut downsampler = downsampler(Aggregation::Max);
+        downsampler.add("device", "node", "a", point(Value::Float(1.0), 0));
+        downsampler.add("device", "node", "b", point(Value::Float(2.0), 60_000));
+
+        assert_eq!(
+            downsampler.flush_closed(SystemTime::UNIX_EPOCH + Duration::from_secs(90)),
+            vec![point(Value::Float(1.0), 0)]
+        );
+        assert_eq!(
+            downsampler.flush_closed(SystemTime::UNIX_EPOCH + Duration::from_secs(120)),
+            vec![point(Value::Float(2.0), 60_000)]
+        );
+        assert_eq!(downsampler.flush_all(), vec![]);
+    }
+}
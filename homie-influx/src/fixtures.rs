@@ -0,0 +1,67 @@
+//! Helpers to build Homie devices for tests, so that each test only needs to fill in the fields it
+//! cares about.
+
+use homie_controller::{Datatype, Device, Node, Property, RecentValues, State};
+use std::collections::HashMap;
+
+/// Create a property with the given ID, datatype and value, and no other attributes.
+pub fn property(id: &str, datatype: Datatype, value: &str) -> Property {
+    Property {
+        id: id.to_owned(),
+        name: None,
+        datatype: Some(datatype),
+        settable: false,
+        retained: true,
+        unit: None,
+        format: None,
+        category: None,
+        value: Some(value.to_owned()),
+        last_updated: None,
+        timestamp: None,
+        recent_values: RecentValues::default(),
+    }
+}
+
+/// Create a node with the given ID and properties, and no other attributes.
+pub fn node(id: &str, properties: Vec<Property>) -> Node {
+    Node {
+        id: id.to_owned(),
+        name: None,
+        node_type: None,
+        properties: properties
+            .into_iter()
+            .map(|property| (property.id.clone(), property))
+            .collect(),
+    }
+}
+
+/// Create a Homie 4.0 device with the given ID and nodes, and no other attributes.
+pub fn device(id: &str, nodes: Vec<Node>) -> Device {
+    Device {
+        id: id.to_owned(),
+        homie_version: "4.0".to_owned(),
+        name: None,
+        state: State::Unknown,
+        implementation: None,
+        nodes: nodes
+            .into_iter()
+            .map(|node| (node.id.clone(), node))
+            .collect(),
+        extensions: vec![],
+        local_ip: None,
+        mac: None,
+        firmware_name: None,
+        firmware_version: None,
+        stats_interval: None,
+        stats_uptime: None,
+        stats_received: None,
+        stats_signal: None,
+        stats_cputemp: None,
+        stats_cpuload: None,
+        stats_battery: None,
+        stats_freeheap: None,
+        stats_supply: None,
+        attributes: HashMap::new(),
+        validation_errors: vec![],
+    }
+}
@@ -1,33 +1,144 @@
+use crate::downsample::Downsampler;
+use crate::retry::RetryQueue;
+use crate::template::PointTemplates;
 use eyre::WrapErr;
-use homie_controller::{Datatype, Device, HomieController, Node, Property};
+use homie_controller::{Datatype, Device, Node, Property};
 use influx_db_client::{Client, Point, Precision, Value};
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
+use tokio::sync::Mutex;
 
 const INFLUXDB_PRECISION: Option<Precision> = Some(Precision::Milliseconds);
 
-pub async fn send_property_value(
-    controller: &HomieController,
-    influx_db_client: &Client,
-    device_id: String,
-    node_id: String,
-    property_id: String,
-) -> Result<(), eyre::Report> {
-    if let Some(device) = controller.devices().get(&device_id) {
-        if let Some(node) = device.nodes.get(&node_id) {
-            if let Some(property) = node.properties.get(&property_id) {
-                if let Some(point) =
-                    point_for_property_value(device, node, property, SystemTime::now())
-                {
-                    // Passing None for rp should use the default retention policy for the database.
-                    influx_db_client
-                        .write_point(point, INFLUXDB_PRECISION, None)
+/// An InfluxDB database to which property values are written, with a queue of points to retry if
+/// writing fails.
+pub struct InfluxSink {
+    client: Client,
+    retry_queue: Mutex<RetryQueue>,
+    /// Whether to also write the values of enum properties as a tag.
+    enums_as_tags: bool,
+    /// Templates for the measurement name and extra tags of each point.
+    templates: PointTemplates,
+    /// Combines the values of numeric properties within each window, if downsampling is enabled.
+    downsampler: Option<Mutex<Downsampler>>,
+}
+
+impl InfluxSink {
+    pub fn new(
+        client: Client,
+        retry_queue: RetryQueue,
+        enums_as_tags: bool,
+        templates: PointTemplates,
+        downsampler: Option<Downsampler>,
+    ) -> Self {
+        Self {
+            client,
+            retry_queue: Mutex::new(retry_queue),
+            enums_as_tags,
+            templates,
+            downsampler: downsampler.map(Mutex::new),
+        }
+    }
+
+    /// Write the current value of the given property to InfluxDB, if it is known, with the time at
+    /// which the value was measured.
+    ///
+    /// If downsampling is enabled then values of numeric properties are only written once their
+    /// window has closed, aggregated with the other values in the window.
+    ///
+    /// If writing fails then the point is queued, and retried along with the next point to be
+    /// written once the backoff has elapsed.
+    pub async fn send_property_value(
+        &self,
+        device: &Device,
+        node: &Node,
+        property: &Property,
+    ) -> Result<(), eyre::Report> {
+        if let Some(point) = point_for_property_value(
+            device,
+            node,
+            property,
+            property.value_time().unwrap_or_else(SystemTime::now),
+            self.enums_as_tags,
+        ) {
+            let point = self.templates.apply(point, device, node, property);
+            let point = match &self.downsampler {
+                Some(downsampler) => {
+                    downsampler
+                        .lock()
                         .await
-                        .wrap_err("Failed to send property value update to InfluxDB")?;
+                        .add(&device.id, &node.id, &property.id, point)
                 }
+                None => Some(point),
+            };
+            if let Some(point) = point {
+                self.write_points(vec![point]).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the aggregated points for downsampling windows which have closed, or for all windows
+    /// if `all` is true, such as when shutting down.
+    pub async fn flush_downsampled(&self, all: bool) -> Result<(), eyre::Report> {
+        let points = match &self.downsampler {
+            Some(downsampler) => {
+                let mut downsampler = downsampler.lock().await;
+                if all {
+                    downsampler.flush_all()
+                } else {
+                    downsampler.flush_closed(SystemTime::now())
+                }
+            }
+            None => return Ok(()),
+        };
+        if points.is_empty() {
+            Ok(())
+        } else {
+            self.write_points(points).await
+        }
+    }
+
+    /// Write the points still waiting to be retried to the spill file, if there is one, so they
+    /// aren't lost when shutting down.
+    pub async fn spill_pending(&self) {
+        self.retry_queue.lock().await.spill_all();
+    }
+
+    async fn write_points(&self, new_points: Vec<Point>) -> Result<(), eyre::Report> {
+        let retry_queue = &mut *self.retry_queue.lock().await;
+        let now = Instant::now();
+        if !retry_queue.is_empty() && !retry_queue.ready(now) {
+            // Keep points in order by waiting until the queued points can be retried.
+            for point in new_points {
+                retry_queue.push(point);
+            }
+            return Ok(());
+        }
+
+        let mut points = retry_queue.take_pending();
+        points.extend(new_points);
+        // Passing None for rp should use the default retention policy for the database.
+        match self
+            .client
+            .write_points(&points, INFLUXDB_PRECISION, None)
+            .await
+        {
+            Ok(()) => {
+                retry_queue.succeeded();
+                Ok(())
+            }
+            Err(e) => {
+                let count = points.len();
+                retry_queue.failed(points, now);
+                Err(e).wrap_err_with(|| {
+                    format!(
+                        "Failed to send {} property value updates to InfluxDB, will retry",
+                        count
+                    )
+                })
             }
         }
     }
-    Ok(())
 }
 
 /// Convert the value of the given Homie property to an InfluxDB value of the appropriate type, if
@@ -44,11 +155,15 @@ fn influx_value_for_homie_property(property: &Property) -> Option<Value> {
 }
 
 /// Construct an InfluxDB `Point` corresponding to the given Homie property value update.
+///
+/// If `enums_as_tags` is true then the values of enum properties are also added as the `value_tag`
+/// tag, so that queries can group by them.
 fn point_for_property_value(
     device: &Device,
     node: &Node,
     property: &Property,
     timestamp: SystemTime,
+    enums_as_tags: bool,
 ) -> Option<Point> {
     let datatype = property.datatype?;
     let value = influx_value_for_homie_property(property)?;
@@ -79,6 +194,9 @@ fn point_for_property_value(
     if let Some(node_type) = node.node_type.to_owned() {
         point = point.add_tag("node_type", node_type)
     }
+    if enums_as_tags && datatype == Datatype::Enum {
+        point = point.add_tag("value_tag", property.value.to_owned()?);
+    }
     if let Some(Datatype::Boolean) = property.datatype {
         // Grafana is unable to display booleans directly, so add an integer for convenience.
         // https://github.com/grafana/grafana/issues/8152
@@ -95,22 +213,12 @@ fn point_for_property_value(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use homie_controller::State;
-    use std::collections::HashMap;
+    use crate::fixtures::{device, node, property};
     use std::time::Duration;
 
     #[test]
     fn influx_value_for_integer() {
-        let property = Property {
-            id: "property_id".to_owned(),
-            name: None,
-            datatype: Some(Datatype::Integer),
-            settable: false,
-            retained: true,
-            unit: None,
-            format: None,
-            value: Some("42".to_owned()),
-        };
+        let property = property("property_id", Datatype::Integer, "42");
         assert_eq!(
             influx_value_for_homie_property(&property).unwrap(),
             Value::Integer(42),
@@ -119,16 +227,7 @@ mod tests {
 
     #[test]
     fn influx_value_for_float() {
-        let property = Property {
-            id: "property_id".to_owned(),
-            name: None,
-            datatype: Some(Datatype::Float),
-            settable: false,
-            retained: true,
-            unit: None,
-            format: None,
-            value: Some("42.3".to_owned()),
-        };
+        let property = property("property_id", Datatype::Float, "42.3");
         assert_eq!(
             influx_value_for_homie_property(&property).unwrap(),
             Value::Float(42.3),
@@ -137,16 +236,7 @@ mod tests {
 
     #[test]
     fn influx_value_for_boolean() {
-        let property = Property {
-            id: "property_id".to_owned(),
-            name: None,
-            datatype: Some(Datatype::Boolean),
-            settable: false,
-            retained: true,
-            unit: None,
-            format: None,
-            value: Some("true".to_owned()),
-        };
+        let property = property("property_id", Datatype::Boolean, "true");
         assert_eq!(
             influx_value_for_homie_property(&property).unwrap(),
             Value::Boolean(true),
@@ -155,16 +245,7 @@ mod tests {
 
     #[test]
     fn influx_value_for_string() {
-        let property = Property {
-            id: "property_id".to_owned(),
-            name: None,
-            datatype: Some(Datatype::String),
-            settable: false,
-            retained: true,
-            unit: None,
-            format: None,
-            value: Some("abc".to_owned()),
-        };
+        let property = property("property_id", Datatype::String, "abc");
         assert_eq!(
             influx_value_for_homie_property(&property).unwrap(),
             Value::from("abc".to_owned()),
@@ -173,16 +254,7 @@ mod tests {
 
     #[test]
     fn influx_value_for_enum() {
-        let property = Property {
-            id: "property_id".to_owned(),
-            name: None,
-            datatype: Some(Datatype::Enum),
-            settable: false,
-            retained: true,
-            unit: None,
-            format: None,
-            value: Some("abc".to_owned()),
-        };
+        let property = property("property_id", Datatype::Enum, "abc");
         assert_eq!(
             influx_value_for_homie_property(&property).unwrap(),
             Value::from("abc".to_owned()),
@@ -191,78 +263,21 @@ mod tests {
 
     #[test]
     fn influx_value_for_color() {
-        let property = Property {
-            id: "property_id".to_owned(),
-            name: None,
-            datatype: Some(Datatype::Color),
-            settable: false,
-            retained: true,
-            unit: None,
-            format: None,
-            value: Some("12,34,56".to_owned()),
-        };
+        let property = property("property_id", Datatype::Color, "12,34,56");
         assert_eq!(
             influx_value_for_homie_property(&property).unwrap(),
             Value::from("12,34,56".to_owned()),
         );
     }
 
-    fn property_set(properties: Vec<Property>) -> HashMap<String, Property> {
-        properties
-            .into_iter()
-            .map(|property| (property.id.clone(), property))
-            .collect()
-    }
-
-    fn node_set(nodes: Vec<Node>) -> HashMap<String, Node> {
-        nodes
-            .into_iter()
-            .map(|node| (node.id.clone(), node))
-            .collect()
-    }
-
     #[test]
     fn point_for_minimal_property() {
-        let property = Property {
-            id: "property_id".to_owned(),
-            name: None,
-            datatype: Some(Datatype::Integer),
-            settable: false,
-            retained: true,
-            unit: None,
-            format: None,
-            value: Some("42".to_owned()),
-        };
-        let node = Node {
-            id: "node_id".to_owned(),
-            name: None,
-            node_type: None,
-            properties: property_set(vec![property.clone()]),
-        };
-        let device = Device {
-            id: "device_id".to_owned(),
-            homie_version: "4.0".to_owned(),
-            name: None,
-            state: State::Unknown,
-            implementation: None,
-            nodes: node_set(vec![node.clone()]),
-            extensions: Vec::default(),
-            local_ip: None,
-            mac: None,
-            firmware_name: None,
-            firmware_version: None,
-            stats_interval: None,
-            stats_uptime: None,
-            stats_signal: None,
-            stats_cputemp: None,
-            stats_cpuload: None,
-            stats_battery: None,
-            stats_freeheap: None,
-            stats_supply: None,
-        };
+        let property = property("property_id", Datatype::Integer, "42");
+        let node = node("node_id", vec![property.clone()]);
+        let device = device("device_id", vec![node.clone()]);
         let timestamp_millis = 123456789;
         let timestamp = SystemTime::UNIX_EPOCH + Duration::from_millis(timestamp_millis as u64);
-        let point = point_for_property_value(&device, &node, &property, timestamp).unwrap();
+        let point = point_for_property_value(&device, &node, &property, timestamp, false).unwrap();
         assert_eq!(
             point,
             Point::new("integer")
@@ -277,46 +292,22 @@ mod tests {
     #[test]
     fn point_for_full_property() {
         let property = Property {
-            id: "property_id".to_owned(),
             name: Some("Property name".to_owned()),
-            datatype: Some(Datatype::Integer),
-            settable: false,
-            retained: true,
-            unit: None,
-            format: None,
-            value: Some("42".to_owned()),
+            ..property("property_id", Datatype::Integer, "42")
         };
         let node = Node {
-            id: "node_id".to_owned(),
             name: Some("Node name".to_owned()),
             node_type: Some("node type".to_owned()),
-            properties: property_set(vec![property.clone()]),
+            ..node("node_id", vec![property.clone()])
         };
         let device = Device {
-            id: "device_id".to_owned(),
-            homie_version: "4.0".to_owned(),
             name: Some("Device name".to_owned()),
-            state: State::Unknown,
-            implementation: None,
-            nodes: node_set(vec![node.clone()]),
-            extensions: Vec::default(),
-            local_ip: None,
-            mac: None,
-            firmware_name: None,
-            firmware_version: None,
-            stats_interval: None,
-            stats_uptime: None,
-            stats_signal: None,
-            stats_cputemp: None,
-            stats_cpuload: None,
-            stats_battery: None,
-            stats_freeheap: None,
-            stats_supply: None,
+            ..device("device_id", vec![node.clone()])
         };
 
         let timestamp_millis = 123456789;
         let timestamp = SystemTime::UNIX_EPOCH + Duration::from_millis(timestamp_millis as u64);
-        let point = point_for_property_value(&device, &node, &property, timestamp).unwrap();
+        let point = point_for_property_value(&device, &node, &property, timestamp, false).unwrap();
         assert_eq!(
             point,
             Point::new("integer")
@@ -334,46 +325,12 @@ mod tests {
 
     #[test]
     fn point_for_boolean_property() {
-        let property = Property {
-            id: "property_id".to_owned(),
-            name: None,
-            datatype: Some(Datatype::Boolean),
-            settable: false,
-            retained: true,
-            unit: None,
-            format: None,
-            value: Some("true".to_owned()),
-        };
-        let node = Node {
-            id: "node_id".to_owned(),
-            name: None,
-            node_type: None,
-            properties: property_set(vec![property.clone()]),
-        };
-        let device = Device {
-            id: "device_id".to_owned(),
-            homie_version: "4.0".to_owned(),
-            name: None,
-            state: State::Unknown,
-            implementation: None,
-            nodes: node_set(vec![node.clone()]),
-            extensions: Vec::default(),
-            local_ip: None,
-            mac: None,
-            firmware_name: None,
-            firmware_version: None,
-            stats_interval: None,
-            stats_uptime: None,
-            stats_signal: None,
-            stats_cputemp: None,
-            stats_cpuload: None,
-            stats_battery: None,
-            stats_freeheap: None,
-            stats_supply: None,
-        };
+        let property = property("property_id", Datatype::Boolean, "true");
+        let node = node("node_id", vec![property.clone()]);
+        let device = device("device_id", vec![node.clone()]);
         let timestamp_millis = 123456789;
         let timestamp = SystemTime::UNIX_EPOCH + Duration::from_millis(timestamp_millis as u64);
-        let point = point_for_property_value(&device, &node, &property, timestamp).unwrap();
+        let point = point_for_property_value(&device, &node, &property, timestamp, false).unwrap();
         assert_eq!(
             point,
             Point::new("boolean")
@@ -385,4 +342,32 @@ mod tests {
                 .add_field("value_int", 1),
         );
     }
+
+    #[test]
+    fn point_for_enum_property_as_tag() {
+        let mut property = property("property_id", Datatype::Enum, "cool");
+        property.format = Some("heat,cool,off".to_owned());
+        let node = node("node_id", vec![property.clone()]);
+        let device = device("device_id", vec![node.clone()]);
+        let timestamp_millis = 123456789;
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_millis(timestamp_millis as u64);
+        let point = point_for_property_value(&device, &node, &property, timestamp, true).unwrap();
+        assert_eq!(
+            point,
+            Point::new("enum")
+                .add_timestamp(timestamp_millis)
+                .add_tag("device_id", "device_id".to_owned())
+                .add_tag("node_id", "node_id".to_owned())
+                .add_tag("property_id", "property_id".to_owned())
+                .add_tag("value_tag", "cool".to_owned())
+                .add_field("value", "cool".to_owned()),
+        );
+        assert_eq!(
+            point_for_property_value(&device, &node, &property, timestamp, false)
+                .unwrap()
+                .tags
+                .get("value_tag"),
+            None
+        );
+    }
 }
@@ -1,19 +1,32 @@
 mod config;
+mod downsample;
+#[cfg(test)]
+mod fixtures;
 mod influx;
+mod postgres;
+mod retry;
+mod sink;
+mod template;
+mod workers;
 
-use crate::config::{
-    get_influxdb_client, get_mqtt_options, get_tls_client_config, read_mappings, Config,
-};
-use crate::influx::send_property_value;
-use futures::future::try_join_all;
-use homie_controller::{Event, HomieController, HomieEventLoop, PollError};
-use influx_db_client::Client;
+use crate::config::{get_mqtt_options, get_tls_client_config, read_mappings, Config};
+use crate::sink::Sink;
+use crate::workers::Workers;
+use futures::future::{try_join_all, FusedFuture};
+use futures::{pin_mut, FutureExt};
+use homie_controller::{Event, HomieController, HomieEventLoop, MultiHomieController, PollError};
 use rumqttc::ConnectionError;
 use stable_eyre::eyre;
+use std::collections::HashMap;
+use std::io;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::signal;
 use tokio::task::{self, JoinHandle};
-use tokio::time::sleep;
+use tokio::time::{interval, sleep};
+
+/// How often to check for downsampling windows which have closed.
+const DOWNSAMPLE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
 
 #[tokio::main]
 async fn main() -> Result<(), eyre::Report> {
@@ -26,53 +39,136 @@ async fn main() -> Result<(), eyre::Report> {
 
     let tls_client_config = get_tls_client_config(&config.mqtt);
 
-    // Start a task per mapping to poll the Homie MQTT connection and send values to InfluxDB.
-    let mut join_handles: Vec<_> = Vec::new();
+    // Several mappings may share a Homie base topic, but each base topic only needs to be
+    // subscribed to once.
+    let mut base_topics: Vec<&str> = Vec::new();
     for mapping in &mappings {
-        // Include Homie base topic in client name, because client name must be unique.
-        let mqtt_options = get_mqtt_options(
-            &config.mqtt,
-            &mapping.homie_prefix,
-            tls_client_config.clone(),
-        );
-        let (controller, event_loop) = HomieController::new(mqtt_options, &mapping.homie_prefix);
-        let controller = Arc::new(controller);
+        if !base_topics.contains(&mapping.homie_prefix.as_str()) {
+            base_topics.push(&mapping.homie_prefix);
+        }
+    }
 
-        let influxdb_client = get_influxdb_client(&config.influxdb, &mapping.influxdb_database)?;
+    // Use a single MQTT connection for all base topics. Include them in the client name, because
+    // client name must be unique.
+    let mqtt_options = get_mqtt_options(&config.mqtt, &base_topics.join("-"), tls_client_config);
+    let (controller, event_loop) = HomieController::new_multi(mqtt_options, &base_topics);
 
-        let handle = spawn_homie_poll_loop(
-            event_loop,
-            controller.clone(),
-            influxdb_client,
-            config.mqtt.reconnect_interval,
-        );
-        join_handles.push(handle);
+    // Start workers for each mapping to send values to InfluxDB or Postgres.
+    let mut join_handles: Vec<_> = Vec::new();
+    let mut worker_handles: Vec<_> = Vec::new();
+    let mut sinks = Vec::new();
+    let mut mapping_workers: HashMap<String, Vec<Workers>> = HashMap::new();
+    for mapping in &mappings {
+        // Each worker has its own sink, so they don't wait for each other's writes.
+        let workers = config.homie.workers;
+        let mapping_sinks = (0..workers)
+            .map(|worker| Ok(Arc::new(Sink::for_mapping(&config, mapping, worker)?)))
+            .collect::<Result<Vec<_>, eyre::Report>>()?;
+        if mapping.downsample.is_some() {
+            for sink in &mapping_sinks {
+                join_handles.push(spawn_downsample_flush_loop(sink.clone()));
+            }
+        }
+        let (workers, handles) = Workers::spawn(&mapping_sinks);
+        worker_handles.extend(handles);
+        mapping_workers
+            .entry(mapping.homie_prefix.clone())
+            .or_default()
+            .push(workers);
+        sinks.extend(mapping_sinks);
     }
 
-    try_join_all(join_handles).await?;
+    let poll_handle = spawn_homie_poll_loop(
+        event_loop,
+        controller,
+        mapping_workers,
+        config.mqtt.reconnect_interval,
+    );
+    let poll_abort_handle = poll_handle.abort_handle();
+    join_handles.push(poll_handle);
+
+    let workers_finished = try_join_all(worker_handles).fuse();
+    pin_mut!(workers_finished);
+    tokio::select! {
+        result = try_join_all(join_handles) => {
+            result?;
+        }
+        result = &mut workers_finished => {
+            result?;
+        }
+        result = shutdown_signal() => {
+            result?;
+            log::info!("Shutting down");
+        }
+    }
+    // Stop receiving new values, and wait for the workers to write those they have already been
+    // sent.
+    poll_abort_handle.abort();
+    if !workers_finished.is_terminated() {
+        workers_finished.await?;
+    }
+    // Write values which are still waiting for their downsampling window to close, so they aren't
+    // lost, and then spill any which couldn't be written to be retried by the next run.
+    for sink in &sinks {
+        if let Err(e) = sink.flush_downsampled(true).await {
+            log::error!("{:?}", e);
+        }
+        sink.spill_pending().await;
+    }
     Ok(())
 }
 
+/// Waits until the process is asked to stop, by Ctrl-C or by SIGTERM such as from systemd.
+async fn shutdown_signal() -> Result<(), io::Error> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await
+}
+
+/// Periodically writes downsampled values whose window has closed, in case no more values arrive
+/// for the property to trigger it.
+fn spawn_downsample_flush_loop(sink: Arc<Sink>) -> JoinHandle<()> {
+    task::spawn(async move {
+        let mut interval = interval(DOWNSAMPLE_FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = sink.flush_downsampled(false).await {
+                log::error!("{:?}", e);
+            }
+        }
+    })
+}
+
+/// Polls the Homie MQTT connection, and sends values for each base topic to the workers of the
+/// mappings for that base topic.
 fn spawn_homie_poll_loop(
     mut event_loop: HomieEventLoop,
-    controller: Arc<HomieController>,
-    influx_db_client: Client,
+    controller: MultiHomieController,
+    mapping_workers: HashMap<String, Vec<Workers>>,
     reconnect_interval: Duration,
 ) -> JoinHandle<()> {
     task::spawn(async move {
         loop {
             match controller.poll(&mut event_loop).await {
                 Ok(events) => {
-                    for event in events {
-                        handle_event(controller.as_ref(), &influx_db_client, event).await;
+                    for tagged in events {
+                        let Some(base_controller) = controller.controller(&tagged.base_topic)
+                        else {
+                            continue;
+                        };
+                        let workers = &mapping_workers[&tagged.base_topic];
+                        handle_event(base_controller, workers, tagged.event).await;
                     }
                 }
                 Err(e) => {
-                    log::error!(
-                        "Failed to poll HomieController for base topic '{}': {}",
-                        controller.base_topic(),
-                        e
-                    );
+                    log::error!("Failed to poll MultiHomieController: {}", e);
                     if let PollError::Connection(ConnectionError::Io(_)) = e {
                         sleep(reconnect_interval).await;
                     }
@@ -82,7 +178,7 @@ fn spawn_homie_poll_loop(
     })
 }
 
-async fn handle_event(controller: &HomieController, influx_db_client: &Client, event: Event) {
+async fn handle_event(controller: &HomieController, workers: &[Workers], event: Event) {
     match event {
         Event::PropertyValueChanged {
             device_id,
@@ -101,16 +197,15 @@ async fn handle_event(controller: &HomieController, influx_db_client: &Client, e
                 fresh
             );
             if fresh {
-                if let Err(e) = send_property_value(
-                    controller,
-                    influx_db_client,
-                    device_id,
-                    node_id,
-                    property_id,
-                )
-                .await
-                {
-                    log::error!("{:?}", e);
+                for workers in workers {
+                    workers
+                        .send_property_value(
+                            controller.devices(),
+                            device_id.clone(),
+                            node_id.clone(),
+                            property_id.clone(),
+                        )
+                        .await;
                 }
             }
         }
@@ -0,0 +1,226 @@
+use crate::config::PostgresConfig;
+use eyre::WrapErr;
+use homie_controller::{Datatype, Device, Node, Property};
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+use tokio::task;
+use tokio_postgres::{Client, NoTls, Statement};
+
+/// Writes property values as rows of a Postgres or TimescaleDB table, connecting lazily and
+/// reconnecting if the connection is lost.
+///
+/// The table must already exist, with columns `time`, `device_id`, `node_id`, `property_id`,
+/// `datatype`, `value` and `value_float`.
+pub struct PostgresSink {
+    connection_string: String,
+    table: String,
+    connection: Mutex<Option<Connection>>,
+}
+
+/// A connection to Postgres along with the prepared statement to insert a row.
+struct Connection {
+    client: Client,
+    insert: Statement,
+}
+
+/// A row to insert in the Postgres table for a property value update.
+#[derive(Clone, Debug, PartialEq)]
+struct Row<'a> {
+    time: SystemTime,
+    device_id: &'a str,
+    node_id: &'a str,
+    property_id: &'a str,
+    datatype: String,
+    value: &'a str,
+    value_float: Option<f64>,
+}
+
+impl PostgresSink {
+    /// Construct a new sink to write to the given table. This doesn't connect until a value is
+    /// written.
+    pub fn new(config: &PostgresConfig, table: &str) -> Self {
+        Self {
+            connection_string: config.connection_string.to_owned(),
+            table: table.to_owned(),
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Write the current value of the given property to Postgres, if it is known.
+    pub async fn send_property_value(
+        &self,
+        device: &Device,
+        node: &Node,
+        property: &Property,
+    ) -> Result<(), eyre::Report> {
+        let row = match row_for_property_value(
+            device,
+            node,
+            property,
+            property.value_time().unwrap_or_else(SystemTime::now),
+        ) {
+            Some(row) => row,
+            None => return Ok(()),
+        };
+
+        let mut connection = self.connection.lock().await;
+        if connection
+            .as_ref()
+            .is_none_or(|connection| connection.client.is_closed())
+        {
+            *connection = Some(self.connect().await?);
+        }
+        let connection = connection.as_ref().unwrap();
+        connection
+            .client
+            .execute(
+                &connection.insert,
+                &[
+                    &row.time,
+                    &row.device_id,
+                    &row.node_id,
+                    &row.property_id,
+                    &row.datatype,
+                    &row.value,
+                    &row.value_float,
+                ],
+            )
+            .await
+            .wrap_err("Failed to send property value update to Postgres")?;
+        Ok(())
+    }
+
+    async fn connect(&self) -> Result<Connection, eyre::Report> {
+        let (client, connection) = tokio_postgres::connect(&self.connection_string, NoTls)
+            .await
+            .wrap_err("Failed to connect to Postgres")?;
+        task::spawn(async move {
+            if let Err(e) = connection.await {
+                log::error!("Postgres connection error: {}", e);
+            }
+        });
+        let insert = client
+            .prepare(&insert_statement(&self.table))
+            .await
+            .wrap_err_with(|| format!("Failed to prepare insert into {}", self.table))?;
+        Ok(Connection { client, insert })
+    }
+}
+
+/// Construct the SQL statement to insert a row into the given table, which may be qualified with a
+/// schema name.
+fn insert_statement(table: &str) -> String {
+    let table = table
+        .split('.')
+        .map(|part| format!("\"{}\"", part.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(".");
+    format!(
+        "INSERT INTO {} (time, device_id, node_id, property_id, datatype, value, value_float) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        table
+    )
+}
+
+/// Construct the row to insert for the given Homie property value update. Numeric and boolean
+/// values are also converted to a float, for convenience in queries.
+fn row_for_property_value<'a>(
+    device: &'a Device,
+    node: &'a Node,
+    property: &'a Property,
+    time: SystemTime,
+) -> Option<Row<'a>> {
+    let datatype = property.datatype?;
+    let value = property.value.as_deref()?;
+    let value_float = match datatype {
+        Datatype::Integer => Some(property.value::<i64>().ok()? as f64),
+        Datatype::Float => Some(property.value().ok()?),
+        Datatype::Boolean => Some(if property.value().ok()? { 1.0 } else { 0.0 }),
+        _ => None,
+    };
+
+    Some(Row {
+        time,
+        device_id: &device.id,
+        node_id: &node.id,
+        property_id: &property.id,
+        datatype: datatype.to_string(),
+        value,
+        value_float,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{device, node, property};
+    use homie_controller::State;
+    use std::time::Duration;
+
+    fn make_device(datatype: Datatype, value: &str) -> Device {
+        let property = Property {
+            name: Some("Property name".to_owned()),
+            ..property("property_id", datatype, value)
+        };
+        Device {
+            state: State::Ready,
+            ..device("device_id", vec![node("node_id", vec![property])])
+        }
+    }
+
+    fn row_for_device(device: &Device, time: SystemTime) -> Option<Row<'_>> {
+        let node = &device.nodes["node_id"];
+        row_for_property_value(device, node, &node.properties["property_id"], time)
+    }
+
+    #[test]
+    fn row_for_float_property() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(42);
+        let device = make_device(Datatype::Float, "12.5");
+        assert_eq!(
+            row_for_device(&device, time),
+            Some(Row {
+                time,
+                device_id: "device_id",
+                node_id: "node_id",
+                property_id: "property_id",
+                datatype: "float".to_owned(),
+                value: "12.5",
+                value_float: Some(12.5),
+            })
+        );
+    }
+
+    #[test]
+    fn row_for_boolean_property() {
+        let device = make_device(Datatype::Boolean, "true");
+        let row = row_for_device(&device, SystemTime::now()).unwrap();
+        assert_eq!(row.value, "true");
+        assert_eq!(row.value_float, Some(1.0));
+    }
+
+    #[test]
+    fn row_for_string_property() {
+        let device = make_device(Datatype::String, "hello");
+        let row = row_for_device(&device, SystemTime::now()).unwrap();
+        assert_eq!(row.datatype, "string");
+        assert_eq!(row.value, "hello");
+        assert_eq!(row.value_float, None);
+    }
+
+    #[test]
+    fn row_for_invalid_integer_property() {
+        let device = make_device(Datatype::Integer, "not a number");
+        assert_eq!(row_for_device(&device, SystemTime::now()), None);
+    }
+
+    #[test]
+    fn insert_statement_quotes_table() {
+        assert_eq!(
+            insert_statement("homie.property_values"),
+            "INSERT INTO \"homie\".\"property_values\" (time, device_id, node_id, property_id, \
+             datatype, value, value_float) VALUES ($1, $2, $3, $4, $5, $6, $7)"
+        );
+        assert!(insert_statement("a\"b").starts_with("INSERT INTO \"a\"\"b\" "));
+    }
+}
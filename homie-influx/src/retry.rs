@@ -0,0 +1,281 @@
+use influx_db_client::Point;
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// A bounded queue of points which failed to be written, to be retried with exponential backoff.
+///
+/// If the queue is full then the oldest points are appended to the spill file if there is one, or
+/// dropped otherwise. Points in the spill file are retried along with the rest of the queue.
+#[derive(Debug)]
+pub struct RetryQueue {
+    points: VecDeque<Point>,
+    capacity: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    /// How long to wait after the next failure before retrying.
+    backoff: Duration,
+    /// Don't retry before this time.
+    next_attempt: Option<Instant>,
+    spill_file: Option<PathBuf>,
+    /// Whether any points have been written to the spill file since it was last read.
+    spilled: bool,
+}
+
+impl RetryQueue {
+    pub fn new(
+        capacity: usize,
+        initial_backoff: Duration,
+        max_backoff: Duration,
+        spill_file: Option<PathBuf>,
+    ) -> Self {
+        // Pick up anything left over from a previous run.
+        let spilled = spill_file.as_ref().is_some_and(|path| path.exists());
+        Self {
+            points: VecDeque::new(),
+            capacity,
+            initial_backoff,
+            max_backoff,
+            backoff: initial_backoff,
+            next_attempt: None,
+            spill_file,
+            spilled,
+        }
+    }
+
+    /// Returns true if there are no points waiting to be retried.
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty() && !self.spilled
+    }
+
+    /// Returns true if the backoff since the last failure has elapsed.
+    pub fn ready(&self, now: Instant) -> bool {
+        self.next_attempt
+            .is_none_or(|next_attempt| now >= next_attempt)
+    }
+
+    /// Adds the given point to the end of the queue, spilling or dropping the oldest point if it
+    /// is full.
+    pub fn push(&mut self, point: Point) {
+        self.points.push_back(point);
+        if self.points.len() > self.capacity {
+            let overflow: Vec<_> = self
+                .points
+                .drain(..self.points.len() - self.capacity)
+                .collect();
+            self.spill(overflow);
+        }
+    }
+
+    /// Takes all the points waiting to be retried, oldest first, including any in the spill file.
+    pub fn take_pending(&mut self) -> Vec<Point> {
+        let mut points = if self.spilled {
+            self.spilled = false;
+            self.read_spill_file()
+        } else {
+            vec![]
+        };
+        points.extend(self.points.drain(..));
+        points
+    }
+
+    /// Records that writing the given points failed, and queues them to be retried after the
+    /// backoff.
+    pub fn failed(&mut self, points: Vec<Point>, now: Instant) {
+        for point in points {
+            self.push(point);
+        }
+        self.next_attempt = Some(now + self.backoff);
+        self.backoff = (self.backoff * 2).min(self.max_backoff);
+    }
+
+    /// Records that writing succeeded, so the backoff can be reset.
+    pub fn succeeded(&mut self) {
+        self.backoff = self.initial_backoff;
+        self.next_attempt = None;
+    }
+
+    /// Writes all the points still waiting in the queue to the spill file, such as when shutting
+    /// down, so they can be retried by the next run. They are dropped if there is no spill file.
+    pub fn spill_all(&mut self) {
+        if self.points.is_empty() {
+            return;
+        }
+        if self.spill_file.is_none() {
+            log::error!(
+                "Dropping {} points which are waiting to be retried",
+                self.points.len()
+            );
+            self.points.clear();
+            return;
+        }
+        let points = self.points.drain(..).collect();
+        self.spill(points);
+    }
+
+    fn spill(&mut self, points: Vec<Point>) {
+        let count = points.len();
+        match &self.spill_file {
+            Some(path) => match append_points(path, &points) {
+                Ok(()) => self.spilled = true,
+                Err(e) => log::error!(
+                    "Dropping {} points which couldn't be written to {:?}: {}",
+                    count,
+                    path,
+                    e
+                ),
+            },
+            None => log::error!("Retry queue full, dropping {} points", count),
+        }
+    }
+
+    /// Reads and removes the spill file, if any.
+    fn read_spill_file(&self) -> Vec<Point> {
+        let Some(path) = &self.spill_file else {
+            return vec![];
+        };
+        match read_points(path).and_then(|points| {
+            fs::remove_file(path)?;
+            Ok(points)
+        }) {
+            Ok(points) => points,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => {
+                log::error!("Failed to read spilled points from {:?}: {}", path, e);
+                vec![]
+            }
+        }
+    }
+}
+
+/// Appends the given points to the given file as JSON, one per line.
+fn append_points(path: &PathBuf, points: &[Point]) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    for point in points {
+        serde_json::to_writer(&mut file, point)?;
+        writeln!(file)?;
+    }
+    Ok(())
+}
+
+/// Reads points from the given file, as written by `append_points`. Lines which can't be parsed are
+/// skipped with a warning.
+fn read_points(path: &PathBuf) -> io::Result<Vec<Point>> {
+    let file = BufReader::new(fs::File::open(path)?);
+    let mut points = vec![];
+    for line in file.lines() {
+        match serde_json::from_str(&line?) {
+            Ok(point) => points.push(point),
+            Err(e) => log::warn!("Skipping invalid spilled point in {:?}: {}", path, e),
+        }
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn point(value: i64) -> Point {
+        Point::new("integer")
+            .add_tag("device_id", "device")
+            .add_field("value", value)
+    }
+
+    #[test]
+    fn backoff() {
+        let mut queue = RetryQueue::new(10, Duration::from_secs(1), Duration::from_secs(3), None);
+        let start = Instant::now();
+        assert!(queue.is_empty());
+        assert!(queue.ready(start));
+
+        queue.failed(vec![point(1)], start);
+        assert!(!queue.is_empty());
+        assert!(!queue.ready(start));
+        assert!(queue.ready(start + Duration::from_secs(1)));
+
+        // The backoff doubles up to the maximum.
+        let points = queue.take_pending();
+        queue.failed(points, start);
+        assert!(!queue.ready(start + Duration::from_secs(1)));
+        assert!(queue.ready(start + Duration::from_secs(2)));
+        for _ in 0..2 {
+            let points = queue.take_pending();
+            queue.failed(points, start);
+        }
+        assert!(!queue.ready(start + Duration::from_secs(2)));
+        assert!(queue.ready(start + Duration::from_secs(3)));
+
+        queue.push(point(2));
+        assert_eq!(queue.take_pending(), vec![point(1), point(2)]);
+        queue.succeeded();
+        assert!(queue.is_empty());
+        assert!(queue.ready(start));
+    }
+
+    #[test]
+    fn overflow_dropped() {
+        let mut queue = RetryQueue::new(2, Duration::from_secs(1), Duration::from_secs(1), None);
+        queue.failed(vec![point(1), point(2), point(3)], Instant::now());
+        assert_eq!(queue.take_pending(), vec![point(2), point(3)]);
+    }
+
+    #[test]
+    fn overflow_spilled() {
+        let path = env::temp_dir().join(format!("homie-influx-spill-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut queue = RetryQueue::new(
+            2,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            Some(path.clone()),
+        );
+        queue.failed(vec![point(1), point(2), point(3)], Instant::now());
+        queue.push(point(4));
+        assert!(path.exists());
+
+        // Spilled points are picked up by a new queue, e.g. after a restart.
+        let mut restarted = RetryQueue::new(
+            2,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            Some(path.clone()),
+        );
+        assert!(!restarted.is_empty());
+        assert_eq!(restarted.take_pending(), vec![point(1), point(2)]);
+        assert!(!path.exists());
+        assert!(restarted.is_empty());
+
+        assert_eq!(queue.take_pending(), vec![point(3), point(4)]);
+    }
+
+    #[test]
+    fn spill_all_on_shutdown() {
+        let path =
+            env::temp_dir().join(format!("homie-influx-shutdown-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut queue = RetryQueue::new(
+            2,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            Some(path.clone()),
+        );
+        queue.failed(vec![point(1), point(2), point(3)], Instant::now());
+        queue.spill_all();
+        assert!(path.exists());
+
+        let mut restarted = RetryQueue::new(
+            2,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            Some(path.clone()),
+        );
+        assert_eq!(restarted.take_pending(), vec![point(1), point(2), point(3)]);
+        assert!(!path.exists());
+    }
+}
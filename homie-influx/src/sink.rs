@@ -0,0 +1,87 @@
+use crate::config::{get_influxdb_client, get_retry_queue, Config, Mapping};
+use crate::downsample::Downsampler;
+use crate::influx::InfluxSink;
+use crate::postgres::PostgresSink;
+use crate::template::PointTemplates;
+use homie_controller::Devices;
+use stable_eyre::eyre;
+
+/// A database to which property values are written.
+pub enum Sink {
+    InfluxDb(Box<InfluxSink>),
+    Postgres(Box<PostgresSink>),
+}
+
+impl Sink {
+    /// Construct the sink selected by the given mapping. If the mapping has several workers then
+    /// each should have its own sink, identified by `worker`.
+    pub fn for_mapping(
+        config: &Config,
+        mapping: &Mapping,
+        worker: usize,
+    ) -> Result<Sink, eyre::Report> {
+        match (&mapping.influxdb_database, &mapping.postgres_table) {
+            (Some(database), None) => Ok(Sink::InfluxDb(Box::new(InfluxSink::new(
+                get_influxdb_client(&config.influxdb, database)?,
+                get_retry_queue(&config.influxdb, mapping, worker, config.homie.workers),
+                config.influxdb.enums_as_tags,
+                PointTemplates {
+                    measurement: mapping.measurement.clone(),
+                    tags: mapping.tags.clone(),
+                },
+                mapping.downsample.as_ref().map(Downsampler::new),
+            )))),
+            (None, Some(table)) => Ok(Sink::Postgres(Box::new(PostgresSink::new(
+                &config.postgres,
+                table,
+            )))),
+            _ => eyre::bail!(
+                "Mapping for {} must specify exactly one of influxdb_database or postgres_table.",
+                mapping.homie_prefix
+            ),
+        }
+    }
+
+    /// Write the value of the given property in the given snapshot of devices to the sink, if it is
+    /// known.
+    pub async fn send_property_value(
+        &self,
+        devices: &Devices,
+        device_id: &str,
+        node_id: &str,
+        property_id: &str,
+    ) -> Result<(), eyre::Report> {
+        if let Some(device) = devices.get(device_id) {
+            if let Some(node) = device.nodes.get(node_id) {
+                if let Some(property) = node.properties.get(property_id) {
+                    match self {
+                        Sink::InfluxDb(sink) => {
+                            sink.send_property_value(device, node, property).await?
+                        }
+                        Sink::Postgres(sink) => {
+                            sink.send_property_value(device, node, property).await?
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write any downsampled values whose window has closed, or all of them if `all` is true.
+    pub async fn flush_downsampled(&self, all: bool) -> Result<(), eyre::Report> {
+        match self {
+            Sink::InfluxDb(sink) => sink.flush_downsampled(all).await,
+            Sink::Postgres(_) => Ok(()),
+        }
+    }
+
+    /// Write any values still waiting to be retried to the spill file, if there is one, so they
+    /// aren't lost when shutting down.
+    pub async fn spill_pending(&self) {
+        match self {
+            Sink::InfluxDb(sink) => sink.spill_pending().await,
+            Sink::Postgres(_) => {}
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use homie_controller::{Device, Node, Property};
+use influx_db_client::Point;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// A template for a measurement or tag name, evaluated against the device tree for each point.
+///
+/// Templates are literal text with expressions in braces, such as `"{node.type|lower}"` or
+/// `"room-{device.id|split:-:0}"`. Each expression names an attribute of the device, node or
+/// property, followed by any number of filters to apply to its value.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Part {
+    Literal(String),
+    Expression {
+        variable: Variable,
+        filters: Vec<Filter>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Variable {
+    DeviceId,
+    DeviceName,
+    NodeId,
+    NodeName,
+    NodeType,
+    PropertyId,
+    PropertyName,
+    PropertyUnit,
+    PropertyDatatype,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Filter {
+    Lower,
+    Upper,
+    /// Split the value on the separator and take the part with the given index.
+    Split {
+        separator: String,
+        index: usize,
+    },
+}
+
+impl FromStr for Variable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "device.id" => Self::DeviceId,
+            "device.name" => Self::DeviceName,
+            "node.id" => Self::NodeId,
+            "node.name" => Self::NodeName,
+            "node.type" => Self::NodeType,
+            "property.id" => Self::PropertyId,
+            "property.name" => Self::PropertyName,
+            "property.unit" => Self::PropertyUnit,
+            "property.datatype" => Self::PropertyDatatype,
+            _ => return Err(format!("Unknown template variable '{}'", s)),
+        })
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lower" => Ok(Self::Lower),
+            "upper" => Ok(Self::Upper),
+            _ => {
+                // The separator may itself contain ':', so take the index from the end.
+                if let Some(arguments) = s.strip_prefix("split:") {
+                    if let Some((separator, index)) = arguments.rsplit_once(':') {
+                        if let (false, Ok(index)) = (separator.is_empty(), index.parse()) {
+                            return Ok(Self::Split {
+                                separator: separator.to_owned(),
+                                index,
+                            });
+                        }
+                    }
+                }
+                Err(format!("Unknown template filter '{}'", s))
+            }
+        }
+    }
+}
+
+impl Variable {
+    fn value(self, device: &Device, node: &Node, property: &Property) -> Option<String> {
+        match self {
+            Self::DeviceId => Some(device.id.clone()),
+            Self::DeviceName => device.name.clone(),
+            Self::NodeId => Some(node.id.clone()),
+            Self::NodeName => node.name.clone(),
+            Self::NodeType => node.node_type.clone(),
+            Self::PropertyId => Some(property.id.clone()),
+            Self::PropertyName => property.name.clone(),
+            Self::PropertyUnit => property.unit.clone(),
+            Self::PropertyDatatype => property.datatype.map(|datatype| datatype.to_string()),
+        }
+    }
+}
+
+impl Filter {
+    fn apply(&self, value: String) -> Option<String> {
+        match self {
+            Self::Lower => Some(value.to_lowercase()),
+            Self::Upper => Some(value.to_uppercase()),
+            Self::Split { separator, index } => value
+                .split(separator.as_str())
+                .nth(*index)
+                .map(ToOwned::to_owned),
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed '{{' in template '{}'", s))?;
+            let mut expression = rest[start + 1..start + end].split('|');
+            let variable = expression.next().unwrap_or_default().trim().parse()?;
+            let filters = expression
+                .map(|filter| filter.trim().parse())
+                .collect::<Result<_, _>>()?;
+            parts.push(Part::Expression { variable, filters });
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("Unmatched '}}' in template '{}'", s));
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Template {
+    /// Evaluate the template for the given property.
+    ///
+    /// Returns `None` if any attribute it refers to is not set, or a `split` filter doesn't have
+    /// enough parts, or the result is empty.
+    pub fn render(&self, device: &Device, node: &Node, property: &Property) -> Option<String> {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Expression { variable, filters } => {
+                    let mut value = variable.value(device, node, property)?;
+                    for filter in filters {
+                        value = filter.apply(value)?;
+                    }
+                    rendered.push_str(&value);
+                }
+            }
+        }
+        if rendered.is_empty() {
+            None
+        } else {
+            Some(rendered)
+        }
+    }
+}
+
+/// Templates for the measurement name and extra tags of the points written for a mapping.
+#[derive(Clone, Debug, Default)]
+pub struct PointTemplates {
+    /// The measurement name, instead of the datatype of the property.
+    pub measurement: Option<Template>,
+    /// Additional tags to add to each point.
+    pub tags: BTreeMap<String, Template>,
+}
+
+impl PointTemplates {
+    /// Apply the templates to the given point for the given property.
+    ///
+    /// If the measurement template can't be rendered then the measurement is left unchanged, and
+    /// any tags which can't be rendered are omitted.
+    pub fn apply(
+        &self,
+        mut point: Point,
+        device: &Device,
+        node: &Node,
+        property: &Property,
+    ) -> Point {
+        if let Some(measurement) = self
+            .measurement
+            .as_ref()
+            .and_then(|template| template.render(device, node, property))
+        {
+            point.measurement = measurement;
+        }
+        for (tag, template) in &self.tags {
+            if let Some(value) = template.render(device, node, property) {
+                point = point.add_tag(tag.to_owned(), value);
+            }
+        }
+        point
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use homie_controller::{Datatype, RecentValues, State};
+    use std::collections::HashMap;
+
+    fn test_device() -> (Device, Node, Property) {
+        let property = Property {
+            id: "temperature".to_owned(),
+            name: Some("Temperature".to_owned()),
+            datatype: Some(Datatype::Float),
+            settable: false,
+            retained: true,
+            unit: Some("ºC".to_owned()),
+            format: None,
+            category: None,
+            value: Some("21.5".to_owned()),
+            last_updated: None,
+            timestamp: None,
+            recent_values: RecentValues::default(),
+        };
+        let node = Node {
+            id: "sensor".to_owned(),
+            name: None,
+            node_type: Some("Mijia Sensor".to_owned()),
+            properties: HashMap::new(),
+        };
+        let device = Device {
+            id: "kitchen-sensors".to_owned(),
+            homie_version: "4.0".to_owned(),
+            name: Some("Kitchen".to_owned()),
+            state: State::Ready,
+            implementation: None,
+            nodes: HashMap::new(),
+            extensions: Vec::default(),
+            local_ip: None,
+            mac: None,
+            firmware_name: None,
+            firmware_version: None,
+            stats_interval: None,
+            stats_uptime: None,
+            stats_received: None,
+            stats_signal: None,
+            stats_cputemp: None,
+            stats_cpuload: None,
+            stats_battery: None,
+            stats_freeheap: None,
+            stats_supply: None,
+            attributes: HashMap::new(),
+            validation_errors: vec![],
+        };
+        (device, node, property)
+    }
+
+    fn render(template: &str) -> Option<String> {
+        let (device, node, property) = test_device();
+        template
+            .parse::<Template>()
+            .unwrap()
+            .render(&device, &node, &property)
+    }
+
+    #[test]
+    fn render_templates() {
+        assert_eq!(render("plain").as_deref(), Some("plain"));
+        assert_eq!(render("{node.type|lower}").as_deref(), Some("mijia sensor"));
+        assert_eq!(
+            render("{device.name|upper}/{property.id}").as_deref(),
+            Some("KITCHEN/temperature")
+        );
+        assert_eq!(
+            render("room-{ device.id | split:-:0 }").as_deref(),
+            Some("room-kitchen")
+        );
+        assert_eq!(render("{property.unit}").as_deref(), Some("ºC"));
+        assert_eq!(render("{property.datatype}").as_deref(), Some("float"));
+
+        // Missing attributes and parts mean the template can't be rendered.
+        assert_eq!(render("{node.name}"), None);
+        assert_eq!(render("{device.id|split:-:2}"), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!("{node.type".parse::<Template>().is_err());
+        assert!("node.type}".parse::<Template>().is_err());
+        assert!("{node.colour}".parse::<Template>().is_err());
+        assert!("{node.type|title}".parse::<Template>().is_err());
+        assert!("{device.id|split:-}".parse::<Template>().is_err());
+    }
+
+    #[test]
+    fn apply_to_point() {
+        let (device, node, property) = test_device();
+        let mut tags = BTreeMap::new();
+        tags.insert("room".to_owned(), "{device.name}".parse().unwrap());
+        tags.insert("missing".to_owned(), "{node.name}".parse().unwrap());
+        let templates = PointTemplates {
+            measurement: Some("{node.type|lower}".parse().unwrap()),
+            tags,
+        };
+        assert_eq!(
+            templates.apply(Point::new("float"), &device, &node, &property),
+            Point::new("mijia sensor").add_tag("room", "Kitchen".to_owned())
+        );
+    }
+}
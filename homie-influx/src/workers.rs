@@ -0,0 +1,115 @@
+use crate::sink::Sink;
+use homie_controller::Devices;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
+
+/// The number of updates which may be waiting for each worker before the MQTT poll loop waits for
+/// it to catch up.
+const WORKER_QUEUE_SIZE: usize = 1000;
+
+/// A property value update waiting to be written by a worker.
+struct Update {
+    /// The devices as they were when the update was received, so that the value written is the one
+    /// from the update even if the worker has fallen behind.
+    devices: Arc<Devices>,
+    device_id: String,
+    node_id: String,
+    property_id: String,
+}
+
+/// A set of tasks which each write property value updates to their own sink, so that writes can
+/// happen in parallel.
+///
+/// Updates are assigned to workers by device ID, so those for each device are always written in
+/// order.
+pub struct Workers {
+    senders: Vec<mpsc::Sender<Update>>,
+}
+
+impl Workers {
+    /// Spawn a worker task for each of the given sinks. The tasks finish once the `Workers` is
+    /// dropped and they have written all the updates waiting for them.
+    pub fn spawn(sinks: &[Arc<Sink>]) -> (Self, Vec<JoinHandle<()>>) {
+        let (senders, join_handles) = sinks
+            .iter()
+            .map(|sink| {
+                let (sender, receiver) = mpsc::channel(WORKER_QUEUE_SIZE);
+                (sender, spawn_worker(sink.clone(), receiver))
+            })
+            .unzip();
+        (Self { senders }, join_handles)
+    }
+
+    /// Queue the value of the given property in the given snapshot of devices to be written by the
+    /// worker for its device, waiting if that worker's queue is full.
+    pub async fn send_property_value(
+        &self,
+        devices: Arc<Devices>,
+        device_id: String,
+        node_id: String,
+        property_id: String,
+    ) {
+        let worker = worker_index(&device_id, self.senders.len());
+        let update = Update {
+            devices,
+            device_id,
+            node_id,
+            property_id,
+        };
+        if self.senders[worker].send(update).await.is_err() {
+            log::error!("Worker {} has stopped, dropping update", worker);
+        }
+    }
+}
+
+fn spawn_worker(sink: Arc<Sink>, mut receiver: mpsc::Receiver<Update>) -> JoinHandle<()> {
+    task::spawn(async move {
+        while let Some(update) = receiver.recv().await {
+            if let Err(e) = sink
+                .send_property_value(
+                    &update.devices,
+                    &update.device_id,
+                    &update.node_id,
+                    &update.property_id,
+                )
+                .await
+            {
+                log::error!("{:?}", e);
+            }
+        }
+    })
+}
+
+/// Get the index of the worker which should handle updates for the device with the given ID.
+fn worker_index(device_id: &str, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    device_id.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_worker_for_device() {
+        for device_id in ["device-a", "device-b", "device-c", ""] {
+            assert_eq!(worker_index(device_id, 1), 0);
+            let index = worker_index(device_id, 4);
+            assert!(index < 4);
+            assert_eq!(worker_index(device_id, 4), index);
+        }
+    }
+
+    #[test]
+    fn devices_spread_across_workers() {
+        let mut counts = [0; 4];
+        for i in 0..100 {
+            counts[worker_index(&format!("device-{}", i), 4)] += 1;
+        }
+        assert!(counts.iter().all(|&count| count > 0), "{:?}", counts);
+    }
+}
@@ -0,0 +1,307 @@
+//! Alerts when sensor readings cross configured thresholds, sent to a webhook and/or published over
+//! MQTT.
+
+use crate::config::{AlertsConfig, ThresholdConfig};
+use eyre::{bail, Report};
+use homie_device::{HomieDevice, Node, Property};
+use mijia::bluetooth::MacAddress;
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::time::Instant;
+use url::Url;
+
+const NODE_ID_ALERTS: &str = "alerts";
+const PROPERTY_ID_ALERT: &str = "alert";
+
+/// An alert that a reading from a sensor is outside the range of one of the configured thresholds.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Alert {
+    pub mac_address: String,
+    pub sensor_name: String,
+    pub property: String,
+    pub value: f64,
+    /// The minimum or maximum which was crossed.
+    pub limit: f64,
+    /// A human-readable description of the alert. This is called `text` so that the webhook payload
+    /// is understood by Slack incoming webhooks.
+    pub text: String,
+}
+
+/// The state of one threshold for one sensor.
+#[derive(Clone, Debug, Default)]
+struct ThresholdState {
+    /// When the sensor's readings first went outside the threshold, if they currently are.
+    breached_since: Option<Instant>,
+    /// Whether an alert has already been sent for the current breach.
+    alerted: bool,
+}
+
+/// Checks readings against the configured thresholds and sends alerts.
+#[derive(Debug)]
+pub struct Alerts {
+    config: AlertsConfig,
+    states: HashMap<(MacAddress, usize), ThresholdState>,
+    client: reqwest::Client,
+}
+
+impl Alerts {
+    pub fn new(config: AlertsConfig) -> Result<Self, Report> {
+        for threshold in &config.thresholds {
+            if !crate::Sensor::PROPERTY_IDS.contains(&threshold.property.as_str()) {
+                bail!(
+                    "Unknown property '{}' for alert threshold",
+                    threshold.property
+                );
+            }
+        }
+        Ok(Self {
+            config,
+            states: HashMap::new(),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// The node to add to the Homie device for alerts to be published to, if MQTT alerts are
+    /// enabled.
+    pub fn node(&self) -> Option<Node> {
+        self.config.mqtt.then(|| {
+            Node::new(
+                NODE_ID_ALERTS,
+                "Alerts",
+                "Alerts",
+                vec![Property::string(
+                    PROPERTY_ID_ALERT,
+                    "Alert",
+                    false,
+                    false,
+                    None,
+                )],
+            )
+        })
+    }
+
+    /// Check the given values of properties of a sensor against the thresholds, and send any
+    /// resulting alerts.
+    ///
+    /// Failing to send an alert to the webhook is logged but not treated as an error.
+    pub async fn check(
+        &mut self,
+        homie: &HomieDevice,
+        mac_address: MacAddress,
+        sensor_name: &str,
+        values: &[(&str, f64)],
+    ) -> Result<(), Report> {
+        for alert in self.evaluate(mac_address, sensor_name, values, Instant::now()) {
+            log::warn!("Alert: {}", alert.text);
+            if self.config.mqtt {
+                homie
+                    .publish_nonretained_value(
+                        NODE_ID_ALERTS,
+                        PROPERTY_ID_ALERT,
+                        alert.text.clone(),
+                    )
+                    .await?;
+            }
+            if let Some(url) = &self.config.webhook_url {
+                tokio::spawn(post_webhook(self.client.clone(), url.clone(), alert));
+            }
+        }
+        Ok(())
+    }
+
+    /// Update the state of each threshold for the given values, returning alerts for any which have
+    /// been breached for at least their debounce time and not already alerted on.
+    fn evaluate(
+        &mut self,
+        mac_address: MacAddress,
+        sensor_name: &str,
+        values: &[(&str, f64)],
+        now: Instant,
+    ) -> Vec<Alert> {
+        let mut alerts = vec![];
+        for (index, threshold) in self.config.thresholds.iter().enumerate() {
+            let Some(&(property, value)) = values
+                .iter()
+                .find(|(property, _)| *property == threshold.property)
+            else {
+                continue;
+            };
+            let state = self.states.entry((mac_address, index)).or_default();
+            match breached_limit(threshold, value) {
+                Some((limit, description)) => {
+                    let since = *state.breached_since.get_or_insert(now);
+                    if !state.alerted && now.duration_since(since) >= threshold.debounce {
+                        state.alerted = true;
+                        alerts.push(Alert {
+                            mac_address: mac_address.to_string(),
+                            sensor_name: sensor_name.to_owned(),
+                            property: property.to_owned(),
+                            value,
+                            limit,
+                            text: format!(
+                                "{} {} is {}, {} {}",
+                                sensor_name, property, value, description, limit
+                            ),
+                        });
+                    }
+                }
+                None => *state = ThresholdState::default(),
+            }
+        }
+        alerts
+    }
+}
+
+/// If the value is outside the threshold, returns the limit it crossed and a description of it.
+fn breached_limit(threshold: &ThresholdConfig, value: f64) -> Option<(f64, &'static str)> {
+    match (threshold.min, threshold.max) {
+        (Some(min), _) if value < min => Some((min, "below the minimum of")),
+        (_, Some(max)) if value > max => Some((max, "above the maximum of")),
+        _ => None,
+    }
+}
+
+async fn post_webhook(client: reqwest::Client, url: Url, alert: Alert) {
+    let result = client
+        .post(url)
+        .json(&alert)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(e) = result {
+        log::error!("Failed to send alert to webhook: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn mac_address() -> MacAddress {
+        "A4:C1:38:00:00:01".parse().unwrap()
+    }
+
+    fn alerts(debounce: Duration) -> Alerts {
+        Alerts::new(AlertsConfig {
+            thresholds: vec![
+                ThresholdConfig {
+                    property: "battery".to_owned(),
+                    min: Some(5.0),
+                    max: None,
+                    debounce,
+                },
+                ThresholdConfig {
+                    property: "temperature".to_owned(),
+                    min: Some(5.0),
+                    max: Some(30.0),
+                    debounce,
+                },
+            ],
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn unknown_property() {
+        let config = AlertsConfig {
+            thresholds: vec![ThresholdConfig {
+                property: "pressure".to_owned(),
+                min: Some(1.0),
+                max: None,
+                debounce: Duration::ZERO,
+            }],
+            ..Default::default()
+        };
+        assert!(Alerts::new(config).is_err());
+    }
+
+    #[test]
+    fn alerts_once_per_breach() {
+        let mut alerts = alerts(Duration::ZERO);
+        let now = Instant::now();
+
+        assert_eq!(
+            alerts.evaluate(
+                mac_address(),
+                "Kitchen",
+                &[("temperature", 20.0), ("battery", 50.0)],
+                now
+            ),
+            vec![]
+        );
+        let raised = alerts.evaluate(
+            mac_address(),
+            "Kitchen",
+            &[("temperature", 31.5), ("battery", 4.0)],
+            now,
+        );
+        assert_eq!(raised.len(), 2);
+        assert_eq!(raised[0].property, "battery");
+        assert_eq!(raised[0].limit, 5.0);
+        assert_eq!(
+            raised[1].text,
+            "Kitchen temperature is 31.5, above the maximum of 30"
+        );
+
+        // Still breached, so no new alert.
+        assert_eq!(
+            alerts.evaluate(mac_address(), "Kitchen", &[("temperature", 32.0)], now),
+            vec![]
+        );
+
+        // Back in range and then out again alerts again.
+        alerts.evaluate(mac_address(), "Kitchen", &[("temperature", 20.0)], now);
+        assert_eq!(
+            alerts
+                .evaluate(mac_address(), "Kitchen", &[("temperature", 2.0)], now)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn debounce() {
+        let mut alerts = alerts(Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert_eq!(
+            alerts.evaluate(mac_address(), "Kitchen", &[("battery", 4.0)], start),
+            vec![]
+        );
+        assert_eq!(
+            alerts.evaluate(
+                mac_address(),
+                "Kitchen",
+                &[("battery", 4.0)],
+                start + Duration::from_secs(30)
+            ),
+            vec![]
+        );
+        assert_eq!(
+            alerts
+                .evaluate(
+                    mac_address(),
+                    "Kitchen",
+                    &[("battery", 3.0)],
+                    start + Duration::from_secs(60)
+                )
+                .len(),
+            1
+        );
+
+        // A reading back in range resets the debounce time.
+        let restart = start + Duration::from_secs(120);
+        alerts.evaluate(mac_address(), "Kitchen", &[("battery", 6.0)], restart);
+        assert_eq!(
+            alerts.evaluate(
+                mac_address(),
+                "Kitchen",
+                &[("battery", 4.0)],
+                restart + Duration::from_secs(30)
+            ),
+            vec![]
+        );
+    }
+}
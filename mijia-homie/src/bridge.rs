@@ -0,0 +1,124 @@
+//! A settable node on the bridge device itself, so that operators can control the bridge over MQTT.
+
+use futures::future::{ready, Ready};
+use homie_device::{HomieDeviceBuilder, Node, Property};
+use mijia::bluetooth::MacAddress;
+use tokio::sync::mpsc::UnboundedSender;
+
+pub const NODE_ID_BRIDGE: &str = "bridge";
+const PROPERTY_ID_RESCAN: &str = "rescan";
+pub const PROPERTY_ID_PAUSE_SCANNING: &str = "pause-scanning";
+const PROPERTY_ID_RECONNECT: &str = "reconnect";
+
+/// A command sent to the connection loop by setting a property of the bridge node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BridgeCommand {
+    /// Scan for sensors now, even if scanning is paused or all named sensors have been found.
+    Rescan,
+    /// Stop or resume scanning for sensors periodically.
+    PauseScanning(bool),
+    /// Disconnect from the sensor with the given MAC address if it is connected, and try to connect
+    /// again straight away, ignoring any backoff or blacklisting.
+    Reconnect(MacAddress),
+}
+
+impl BridgeCommand {
+    /// Parse a value set on the given property into a command, or `None` if it isn't a valid value
+    /// for a property of the bridge node.
+    fn parse(node_id: &str, property_id: &str, value: &str) -> Option<Self> {
+        if node_id != NODE_ID_BRIDGE {
+            return None;
+        }
+        match property_id {
+            PROPERTY_ID_RESCAN => (value == "true").then_some(Self::Rescan),
+            PROPERTY_ID_PAUSE_SCANNING => value.parse().ok().map(Self::PauseScanning),
+            PROPERTY_ID_RECONNECT => value.parse().ok().map(Self::Reconnect),
+            _ => None,
+        }
+    }
+}
+
+/// The node to add to the Homie device for controlling the bridge.
+pub fn node() -> Node {
+    Node::new(
+        NODE_ID_BRIDGE,
+        "Bridge",
+        "Bridge control",
+        vec![
+            Property::boolean(PROPERTY_ID_RESCAN, "Rescan", true, false, None),
+            Property::boolean(
+                PROPERTY_ID_PAUSE_SCANNING,
+                "Pause scanning",
+                true,
+                true,
+                None,
+            ),
+            Property::string(
+                PROPERTY_ID_RECONNECT,
+                "Reconnect sensor by MAC address",
+                true,
+                false,
+                None,
+            ),
+        ],
+    )
+}
+
+/// Set the update callback of the given builder to send commands for values set on the bridge node
+/// to the given channel. Invalid values are logged and ignored.
+///
+/// New values aren't published by the callback, but by the connection loop once it has acted on
+/// them.
+pub fn set_update_callback(
+    builder: &mut HomieDeviceBuilder,
+    commands: UnboundedSender<BridgeCommand>,
+) {
+    builder.set_update_callback(
+        move |node_id, property_id, value| -> Ready<Option<String>> {
+            match BridgeCommand::parse(&node_id, &property_id, &value) {
+                Some(command) => {
+                    if commands.send(command).is_err() {
+                        log::error!("Connection loop stopped, ignoring {:?}", value);
+                    }
+                }
+                None => log::warn!(
+                    "Ignoring invalid value {:?} for {}/{}",
+                    value,
+                    node_id,
+                    property_id
+                ),
+            }
+            ready(None)
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            BridgeCommand::parse("bridge", "rescan", "true"),
+            Some(BridgeCommand::Rescan)
+        );
+        assert_eq!(BridgeCommand::parse("bridge", "rescan", "false"), None);
+        assert_eq!(
+            BridgeCommand::parse("bridge", "pause-scanning", "false"),
+            Some(BridgeCommand::PauseScanning(false))
+        );
+        assert_eq!(
+            BridgeCommand::parse("bridge", "pause-scanning", "yes"),
+            None
+        );
+        assert_eq!(
+            BridgeCommand::parse("bridge", "reconnect", "A4:C1:38:00:00:00"),
+            Some(BridgeCommand::Reconnect(
+                "A4:C1:38:00:00:00".parse().unwrap()
+            ))
+        );
+        assert_eq!(BridgeCommand::parse("bridge", "reconnect", "kitchen"), None);
+        assert_eq!(BridgeCommand::parse("A4C138000000", "rescan", "true"), None);
+    }
+}
@@ -8,6 +8,7 @@ use stable_eyre::eyre::WrapErr;
 use std::collections::HashMap;
 use std::fs::read_to_string;
 use std::time::Duration;
+use url::Url;
 
 const DEFAULT_MQTT_PREFIX: &str = "homie";
 const DEFAULT_DEVICE_ID: &str = "mijia-bridge";
@@ -17,12 +18,24 @@ const DEFAULT_PORT: u16 = 1883;
 const DEFAULT_SENSOR_NAMES_FILENAME: &str = "sensor-names.toml";
 const CONFIG_FILENAME: &str = "mijia-homie.toml";
 const KEEP_ALIVE: Duration = Duration::from_secs(5);
+const DEFAULT_HISTORY_EXPORT_DIRECTORY: &str = "history";
+const DEFAULT_HISTORY_EXPORT_UTC_HOUR: u8 = 3;
+const DEFAULT_HEALTH_ADDRESS: &str = "0.0.0.0:8080";
+const DEFAULT_ROOMS_UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_ROOMS_STALE_AFTER: Duration = Duration::from_secs(10 * 60);
 
 #[derive(Clone, Debug, Default, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub mqtt: MqttConfig,
+    /// Additional MQTT brokers to which the Homie device is mirrored, e.g. while migrating from one
+    /// broker to another.
+    pub mirror_mqtt: Vec<MqttConfig>,
     pub homie: HomieConfig,
+    pub history_export: Option<HistoryExportConfig>,
+    pub health: Option<HealthConfig>,
+    pub alerts: Option<AlertsConfig>,
+    pub rooms: Option<RoomsConfig>,
 }
 
 impl Config {
@@ -74,6 +87,24 @@ pub struct HomieConfig {
         rename = "min_update_period_seconds"
     )]
     pub min_update_period: Duration,
+    /// Whether to add extra properties to each sensor node for debugging connection problems.
+    /// Sensor nodes are then kept while the sensor is disconnected, so that its status can be seen.
+    pub diagnostics: bool,
+    /// Whether to add dew point and absolute humidity properties to each sensor node, computed from
+    /// its temperature and humidity readings.
+    pub psychrometrics: bool,
+    /// The number of consecutive failed connection attempts after which to stop trying to connect
+    /// to a sensor for a while, or until an advertisement is seen from it after the maximum
+    /// backoff. 0 means never.
+    pub blacklist_after_failures: u32,
+    /// Whether to bridge sensors which advertise readings in a supported format even if they are
+    /// not listed in the sensor names file.
+    pub bridge_unnamed_sensors: bool,
+    /// Whether to name unnamed sensors after the name they advertise, rather than their MAC
+    /// address.
+    pub use_advertised_names: bool,
+    /// Whether to add a settable node to the bridge device to control it over MQTT.
+    pub bridge_control: bool,
 }
 
 pub fn de_duration_seconds<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
@@ -89,6 +120,111 @@ impl Default for HomieConfig {
             prefix: DEFAULT_MQTT_PREFIX.to_owned(),
             sensor_names_filename: DEFAULT_SENSOR_NAMES_FILENAME.to_owned(),
             min_update_period: Duration::from_secs(0),
+            diagnostics: false,
+            psychrometrics: false,
+            blacklist_after_failures: 10,
+            bridge_unnamed_sensors: false,
+            use_advertised_names: false,
+            bridge_control: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryExportConfig {
+    /// The directory in which to write exported history files.
+    pub directory: String,
+    pub format: HistoryExportFormat,
+    /// The hour of the day, in UTC, at which to export history from sensors each day.
+    pub utc_hour: u8,
+}
+
+impl Default for HistoryExportConfig {
+    fn default() -> HistoryExportConfig {
+        HistoryExportConfig {
+            directory: DEFAULT_HISTORY_EXPORT_DIRECTORY.to_owned(),
+            format: HistoryExportFormat::Csv,
+            utc_hour: DEFAULT_HISTORY_EXPORT_UTC_HOUR,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryExportFormat {
+    /// Append records to a CSV file per sensor.
+    Csv,
+    /// Write a new Parquet file per sensor for each export. This requires the `parquet` feature.
+    Parquet,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// The address and port on which to serve the health endpoints.
+    pub address: String,
+}
+
+impl Default for HealthConfig {
+    fn default() -> HealthConfig {
+        HealthConfig {
+            address: DEFAULT_HEALTH_ADDRESS.to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertsConfig {
+    /// A URL to which to POST a JSON description of each alert.
+    pub webhook_url: Option<Url>,
+    /// Whether to publish alerts to an `alerts` node on the Homie device.
+    pub mqtt: bool,
+    pub thresholds: Vec<ThresholdConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThresholdConfig {
+    /// The ID of the sensor property to check, e.g. `temperature`, `humidity` or `battery`.
+    pub property: String,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// How long readings must stay outside the threshold before an alert is sent.
+    #[serde(
+        default,
+        deserialize_with = "de_duration_seconds",
+        rename = "debounce_seconds"
+    )]
+    pub debounce: Duration,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsConfig {
+    /// How often to publish the aggregated values for each room.
+    #[serde(
+        deserialize_with = "de_duration_seconds",
+        rename = "update_interval_seconds"
+    )]
+    pub update_interval: Duration,
+    /// How long after a sensor's last reading it is left out of the values for its room.
+    #[serde(
+        deserialize_with = "de_duration_seconds",
+        rename = "stale_after_seconds"
+    )]
+    pub stale_after: Duration,
+    /// The MAC addresses of the sensors in each room, keyed by room name.
+    pub members: HashMap<String, Vec<String>>,
+}
+
+impl Default for RoomsConfig {
+    fn default() -> RoomsConfig {
+        RoomsConfig {
+            update_interval: DEFAULT_ROOMS_UPDATE_INTERVAL,
+            stale_after: DEFAULT_ROOMS_STALE_AFTER,
+            members: HashMap::new(),
         }
     }
 }
@@ -142,6 +278,104 @@ mod tests {
     /// Parsing an empty config file should not give any errors.
     #[test]
     fn empty_config() {
-        toml::from_str::<Config>("").unwrap();
+        let config = toml::from_str::<Config>("").unwrap();
+        assert!(config.history_export.is_none());
+        assert!(config.health.is_none());
+        assert!(config.alerts.is_none());
+        assert!(config.rooms.is_none());
+        assert!(config.mirror_mqtt.is_empty());
+    }
+
+    #[test]
+    fn mirror_mqtt_config() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [mqtt]
+            host = "old.example.com"
+
+            [[mirror_mqtt]]
+            host = "new.example.com"
+            use_tls = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mqtt.host, "old.example.com");
+        assert_eq!(config.mirror_mqtt.len(), 1);
+        assert_eq!(config.mirror_mqtt[0].host, "new.example.com");
+        assert_eq!(config.mirror_mqtt[0].port, DEFAULT_PORT);
+        assert!(config.mirror_mqtt[0].use_tls);
+    }
+
+    /// An empty history export section should enable history export with the default options.
+    #[test]
+    fn history_export_config() {
+        let config = toml::from_str::<Config>("[history_export]").unwrap();
+        let history_export = config.history_export.unwrap();
+        assert_eq!(history_export.directory, DEFAULT_HISTORY_EXPORT_DIRECTORY);
+        assert_eq!(history_export.format, HistoryExportFormat::Csv);
+        assert_eq!(history_export.utc_hour, DEFAULT_HISTORY_EXPORT_UTC_HOUR);
+
+        let config = toml::from_str::<Config>(
+            "[history_export]\ndirectory=\"/tmp/history\"\nformat=\"parquet\"\nutc_hour=22",
+        )
+        .unwrap();
+        let history_export = config.history_export.unwrap();
+        assert_eq!(history_export.directory, "/tmp/history");
+        assert_eq!(history_export.format, HistoryExportFormat::Parquet);
+        assert_eq!(history_export.utc_hour, 22);
+    }
+
+    #[test]
+    fn alerts_config() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [alerts]
+            webhook_url = "https://example.com/hook"
+            mqtt = true
+
+            [[alerts.thresholds]]
+            property = "temperature"
+            max = 30.0
+            debounce_seconds = 300
+
+            [[alerts.thresholds]]
+            property = "battery"
+            min = 10
+            "#,
+        )
+        .unwrap();
+        let alerts = config.alerts.unwrap();
+        assert_eq!(
+            alerts.webhook_url.unwrap().as_str(),
+            "https://example.com/hook"
+        );
+        assert!(alerts.mqtt);
+        assert_eq!(alerts.thresholds.len(), 2);
+        assert_eq!(alerts.thresholds[0].property, "temperature");
+        assert_eq!(alerts.thresholds[0].min, None);
+        assert_eq!(alerts.thresholds[0].max, Some(30.0));
+        assert_eq!(alerts.thresholds[0].debounce, Duration::from_secs(300));
+        assert_eq!(alerts.thresholds[1].min, Some(10.0));
+        assert_eq!(alerts.thresholds[1].debounce, Duration::ZERO);
+    }
+
+    #[test]
+    fn rooms_config() {
+        let config = toml::from_str::<Config>(
+            r#"
+            [rooms]
+            stale_after_seconds = 300
+
+            [rooms.members]
+            "Living room" = ["A4:C1:38:00:00:01", "A4:C1:38:00:00:02"]
+            Kitchen = ["A4:C1:38:00:00:03"]
+            "#,
+        )
+        .unwrap();
+        let rooms = config.rooms.unwrap();
+        assert_eq!(rooms.update_interval, DEFAULT_ROOMS_UPDATE_INTERVAL);
+        assert_eq!(rooms.stale_after, Duration::from_secs(300));
+        assert_eq!(rooms.members.len(), 2);
+        assert_eq!(rooms.members["Living room"].len(), 2);
     }
 }
@@ -0,0 +1,223 @@
+use crate::config::HealthConfig;
+use futures::Future;
+use mijia::bluetooth::BluetoothSession;
+use stable_eyre::eyre::{self, WrapErr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// How long to wait for a client to send its request before giving up on it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: usize = 1024;
+/// How long the sensor loop may go without finishing an iteration before the bridge is reported as
+/// unhealthy. Connecting to a sensor or scanning can hold up an iteration for a while, so this is
+/// generous.
+const SENSOR_LOOP_STALL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How long to keep serving the health endpoints after an internal task fails, before exiting, so
+/// that the failure can be seen on `/healthz`.
+pub const FAILURE_REPORT_PERIOD: Duration = Duration::from_secs(30);
+
+/// Tracks whether the bridge's internal tasks are still running and the sensor loop is making
+/// progress, for the liveness endpoint.
+#[derive(Debug)]
+pub struct Health {
+    alive: AtomicBool,
+    /// When the sensor loop last finished an iteration.
+    sensor_loop_ran: Mutex<Instant>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            alive: AtomicBool::new(true),
+            sensor_loop_ran: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+
+    /// Record that the sensor loop has finished an iteration.
+    pub fn sensor_loop_ran(&self) {
+        *self.sensor_loop_ran.lock().unwrap() = Instant::now();
+    }
+
+    /// Returns true if the sensor loop hasn't finished an iteration for too long.
+    pub fn sensor_loop_stalled(&self) -> bool {
+        self.sensor_loop_ran.lock().unwrap().elapsed() > SENSOR_LOOP_STALL_TIMEOUT
+    }
+
+    /// Run the given task, marking the bridge as no longer alive if it ever finishes.
+    pub async fn watch<T, E>(
+        &self,
+        name: &str,
+        task: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let result = task.await;
+        log::error!("{} task finished", name);
+        self.alive.store(false, Ordering::SeqCst);
+        result
+    }
+}
+
+/// Serve `/healthz` and `/readyz` over HTTP on the configured address.
+///
+/// The bridge is live as long as none of its tasks have finished and the sensor loop hasn't
+/// stalled, and ready if it is live and at least one Bluetooth adapter is present. The MQTT
+/// connection isn't checked separately, as the Homie device task finishes if it is lost.
+pub async fn serve_health(
+    config: HealthConfig,
+    health: &Health,
+    bt_session: &BluetoothSession,
+) -> Result<(), eyre::Report> {
+    let listener = TcpListener::bind(&config.address)
+        .await
+        .wrap_err_with(|| format!("Binding health endpoint to {}", config.address))?;
+    log::info!("Serving health endpoints on {}", config.address);
+    loop {
+        let (stream, address) = listener.accept().await?;
+        // Requests are handled one at a time, as they are cheap and only expected occasionally.
+        if let Err(e) = handle_connection(stream, health, bt_session).await {
+            log::warn!("Error handling health request from {}: {}", address, e);
+        }
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    health: &Health,
+    bt_session: &BluetoothSession,
+) -> Result<(), eyre::Report> {
+    let mut request = vec![0; MAX_REQUEST_SIZE];
+    let length = timeout(REQUEST_TIMEOUT, stream.read(&mut request)).await??;
+    let request = String::from_utf8_lossy(&request[..length]);
+
+    let alive = health.is_alive();
+    let sensor_loop_stalled = health.sensor_loop_stalled();
+    let adapters_present = match bt_session.get_adapters().await {
+        Ok(adapters) => !adapters.is_empty(),
+        Err(e) => {
+            log::warn!("Failed to get Bluetooth adapters: {}", e);
+            false
+        }
+    };
+    let (status, body) = response(
+        request_path(&request),
+        alive,
+        sensor_loop_stalled,
+        adapters_present,
+    );
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Gets the path from the request line of the given HTTP GET request.
+fn request_path(request: &str) -> Option<&str> {
+    let mut parts = request.lines().next()?.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => Some(path),
+        _ => None,
+    }
+}
+
+/// Gets the status line and body of the response for the given path.
+fn response(
+    path: Option<&str>,
+    alive: bool,
+    sensor_loop_stalled: bool,
+    adapters_present: bool,
+) -> (&'static str, &'static str) {
+    match path {
+        Some("/healthz" | "/readyz") if !alive => {
+            ("503 Service Unavailable", "internal task finished\n")
+        }
+        Some("/healthz" | "/readyz") if sensor_loop_stalled => {
+            ("503 Service Unavailable", "sensor loop stalled\n")
+        }
+        Some("/healthz") => ("200 OK", "ok\n"),
+        Some("/readyz") if !adapters_present => {
+            ("503 Service Unavailable", "no Bluetooth adapter\n")
+        }
+        Some("/readyz") => ("200 OK", "ok\n"),
+        Some(_) => ("404 Not Found", "not found\n"),
+        None => ("400 Bad Request", "bad request\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_path() {
+        assert_eq!(
+            request_path("GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            Some("/healthz")
+        );
+        assert_eq!(request_path("POST /readyz HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(request_path(""), None);
+    }
+
+    #[test]
+    fn responses() {
+        assert_eq!(response(Some("/healthz"), true, false, false).0, "200 OK");
+        assert_eq!(
+            response(Some("/healthz"), false, false, true).0,
+            "503 Service Unavailable"
+        );
+        assert_eq!(
+            response(Some("/healthz"), true, true, true),
+            ("503 Service Unavailable", "sensor loop stalled\n")
+        );
+        assert_eq!(response(Some("/readyz"), true, false, true).0, "200 OK");
+        assert_eq!(
+            response(Some("/readyz"), true, false, false),
+            ("503 Service Unavailable", "no Bluetooth adapter\n")
+        );
+        assert_eq!(
+            response(Some("/readyz"), false, false, true).0,
+            "503 Service Unavailable"
+        );
+        assert_eq!(
+            response(Some("/readyz"), true, true, true).0,
+            "503 Service Unavailable"
+        );
+        assert_eq!(
+            response(Some("/metrics"), true, false, true).0,
+            "404 Not Found"
+        );
+        assert_eq!(response(None, true, false, true).0, "400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn watch_marks_dead() {
+        let health = Health::new();
+        assert!(health.is_alive());
+        let result: Result<(), ()> = health.watch("test", async { Ok(()) }).await;
+        assert_eq!(result, Ok(()));
+        assert!(!health.is_alive());
+    }
+
+    #[test]
+    fn sensor_loop_stall() {
+        let health = Health::new();
+        assert!(!health.sensor_loop_stalled());
+        *health.sensor_loop_ran.lock().unwrap() =
+            Instant::now() - SENSOR_LOOP_STALL_TIMEOUT - Duration::from_secs(1);
+        assert!(health.sensor_loop_stalled());
+        health.sensor_loop_ran();
+        assert!(!health.sensor_loop_stalled());
+    }
+}
@@ -0,0 +1,443 @@
+//! Scheduled export of the historical data stored on sensors to files.
+
+use crate::config::{HistoryExportConfig, HistoryExportFormat};
+use crate::{ConnectionStatus, SensorState};
+use eyre::{bail, Report};
+use mijia::bluetooth::{DeviceId, MacAddress};
+use mijia::{HistoryRecord, MijiaSession};
+use stable_eyre::eyre::WrapErr;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+use tokio::time;
+
+/// The name of the file in the export directory in which to store the index of the last record
+/// exported from each sensor.
+const STATE_FILENAME: &str = "history-export-state.toml";
+const CSV_HEADER: &str =
+    "time,index,mac_address,name,temperature_min,temperature_max,humidity_min,humidity_max";
+const SECONDS_PER_HOUR: u64 = 60 * 60;
+const SECONDS_PER_DAY: u64 = 24 * SECONDS_PER_HOUR;
+
+/// Once a day at the configured time, download new history records from all connected sensors and
+/// write them to files in the configured directory.
+pub async fn history_export_loop(
+    state: Arc<Mutex<SensorState>>,
+    session: &MijiaSession,
+    config: HistoryExportConfig,
+) -> Result<(), Report> {
+    if config.utc_hour >= 24 {
+        bail!("Invalid history export utc_hour {}", config.utc_hour);
+    }
+    if config.format == HistoryExportFormat::Parquet && !cfg!(feature = "parquet") {
+        bail!("Parquet history export requires mijia-homie to be built with the parquet feature");
+    }
+    let directory = PathBuf::from(&config.directory);
+    fs::create_dir_all(&directory).wrap_err_with(|| format!("Creating {}", directory.display()))?;
+
+    loop {
+        time::sleep(duration_until_utc_hour(SystemTime::now(), config.utc_hour)).await;
+        export_all(&state, session, &directory, config.format).await?;
+    }
+}
+
+/// Export new history records from all currently connected sensors.
+async fn export_all(
+    state: &Mutex<SensorState>,
+    session: &MijiaSession,
+    directory: &Path,
+    format: HistoryExportFormat,
+) -> Result<(), Report> {
+    // Don't hold the lock while talking to the sensors, as downloading history may take a while.
+    let sensors: Vec<(MacAddress, String, DeviceId)> = state
+        .lock()
+        .await
+        .sensors
+        .values()
+        .filter_map(|sensor| match &sensor.connection_status {
+            ConnectionStatus::Connected { id } => {
+                Some((sensor.mac_address, sensor.name.clone(), id.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let state_path = directory.join(STATE_FILENAME);
+    let mut last_indices = load_last_indices(&state_path);
+    for (mac_address, name, id) in sensors {
+        println!("Exporting history from {} ({})...", name, mac_address);
+        let key = mac_address.to_string();
+        let last_index = last_indices.get(&key).copied();
+        match export_sensor(
+            session,
+            &id,
+            &mac_address,
+            &name,
+            last_index,
+            directory,
+            format,
+        )
+        .await
+        {
+            Ok(Some(index)) => {
+                last_indices.insert(key, index);
+                save_last_indices(&state_path, &last_indices);
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("Failed to export history from {}: {:?}", name, e),
+        }
+    }
+    Ok(())
+}
+
+/// Download the history from the given sensor, and write any records after `last_index` to a file.
+///
+/// Returns the index of the last record exported, if any.
+async fn export_sensor(
+    session: &MijiaSession,
+    id: &DeviceId,
+    mac_address: &MacAddress,
+    name: &str,
+    last_index: Option<u32>,
+    directory: &Path,
+    format: HistoryExportFormat,
+) -> Result<Option<u32>, Report> {
+    let history: Vec<HistoryRecord> = session
+        .get_all_history(id)
+        .await?
+        .into_iter()
+        .flatten()
+        .collect();
+    let records = new_records(history, last_index);
+    println!("Got {} new history records from {}.", records.len(), name);
+    let last_exported = match records.last() {
+        Some(record) => record.index,
+        None => return Ok(last_index),
+    };
+
+    let node_id = mac_address.to_string().replace(':', "");
+    match format {
+        HistoryExportFormat::Csv => {
+            let path = directory.join(format!("{}.csv", node_id));
+            append_csv(&path, mac_address, name, &records)
+                .wrap_err_with(|| format!("Writing {}", path.display()))?;
+        }
+        #[cfg(feature = "parquet")]
+        HistoryExportFormat::Parquet => {
+            let path = directory.join(format!(
+                "{}-{}-{}.parquet",
+                node_id, records[0].index, last_exported
+            ));
+            parquet_export::write_parquet(&path, mac_address, name, &records)
+                .wrap_err_with(|| format!("Writing {}", path.display()))?;
+        }
+        #[cfg(not(feature = "parquet"))]
+        HistoryExportFormat::Parquet => unreachable!("Parquet support checked on startup"),
+    }
+    Ok(Some(last_exported))
+}
+
+/// Filter the given records to those after `last_index`, sorted by index.
+///
+/// If the sensor has no records as recent as `last_index` then its history must have been cleared
+/// since the last export, so all records are returned.
+fn new_records(mut history: Vec<HistoryRecord>, last_index: Option<u32>) -> Vec<HistoryRecord> {
+    history.sort_by_key(|record| record.index);
+    let newest_index = history.last().map(|record| record.index);
+    match (last_index, newest_index) {
+        (Some(last_index), Some(newest_index)) if newest_index >= last_index => history
+            .into_iter()
+            .filter(|record| record.index > last_index)
+            .collect(),
+        _ => history,
+    }
+}
+
+/// Append the given records to the CSV file at the given path, creating it with a header if it
+/// doesn't already exist.
+fn append_csv(
+    path: &Path,
+    mac_address: &MacAddress,
+    name: &str,
+    records: &[HistoryRecord],
+) -> Result<(), Report> {
+    let is_new = !path.exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut contents = String::new();
+    if is_new {
+        contents.push_str(CSV_HEADER);
+        contents.push('\n');
+    }
+    for record in records {
+        contents.push_str(&csv_line(mac_address, name, record));
+        contents.push('\n');
+    }
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+fn csv_line(mac_address: &MacAddress, name: &str, record: &HistoryRecord) -> String {
+    format!(
+        "{},{},{},\"{}\",{:.1},{:.1},{},{}",
+        unix_seconds(record.corrected_time),
+        record.index,
+        mac_address,
+        name.replace('"', "\"\""),
+        record.temperature_min,
+        record.temperature_max,
+        record.humidity_min,
+        record.humidity_max,
+    )
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Read the index of the last record exported from each sensor. If the state file can't be read then
+/// the error is logged rather than stopping the bridge, and all history is exported again.
+fn load_last_indices(path: &Path) -> HashMap<String, u32> {
+    read_last_indices(path).unwrap_or_else(|e| {
+        log::error!(
+            "Failed to read history export state, exporting all history: {:?}",
+            e
+        );
+        HashMap::new()
+    })
+}
+
+/// Write the index of the last record exported from each sensor. If the state file can't be written
+/// then the error is logged rather than stopping the bridge, and the records will be exported again
+/// next time.
+fn save_last_indices(path: &Path, last_indices: &HashMap<String, u32>) {
+    if let Err(e) = write_last_indices(path, last_indices) {
+        log::error!("Failed to save history export state: {:?}", e);
+    }
+}
+
+fn read_last_indices(path: &Path) -> Result<HashMap<String, u32>, Report> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let contents =
+        fs::read_to_string(path).wrap_err_with(|| format!("Reading {}", path.display()))?;
+    Ok(toml::from_str(&contents)?)
+}
+
+fn write_last_indices(path: &Path, last_indices: &HashMap<String, u32>) -> Result<(), Report> {
+    fs::write(path, toml::to_string(last_indices)?)
+        .wrap_err_with(|| format!("Writing {}", path.display()))
+}
+
+/// Returns how long it is from `now` until the next time it is the start of the given hour of the
+/// day in UTC.
+fn duration_until_utc_hour(now: SystemTime, utc_hour: u8) -> Duration {
+    let seconds_into_day = unix_seconds(now) % SECONDS_PER_DAY;
+    let target = u64::from(utc_hour) * SECONDS_PER_HOUR;
+    Duration::from_secs(if target > seconds_into_day {
+        target - seconds_into_day
+    } else {
+        SECONDS_PER_DAY - seconds_into_day + target
+    })
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_export {
+    use super::unix_seconds;
+    use mijia::bluetooth::MacAddress;
+    use mijia::HistoryRecord;
+    use parquet::data_type::{ByteArray, ByteArrayType, DataType, FloatType, Int32Type, Int64Type};
+    use parquet::errors::ParquetError;
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+    use parquet::schema::parser::parse_message_type;
+    use std::fs::File;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    const SCHEMA: &str = "
+        message history_record {
+            REQUIRED INT64 time (TIMESTAMP(MILLIS, true));
+            REQUIRED INT32 index (INTEGER(32, false));
+            REQUIRED BYTE_ARRAY mac_address (UTF8);
+            REQUIRED BYTE_ARRAY name (UTF8);
+            REQUIRED FLOAT temperature_min;
+            REQUIRED FLOAT temperature_max;
+            REQUIRED INT32 humidity_min (INTEGER(8, false));
+            REQUIRED INT32 humidity_max (INTEGER(8, false));
+        }
+    ";
+
+    /// Write the given records to a new Parquet file at the given path.
+    pub fn write_parquet(
+        path: &Path,
+        mac_address: &MacAddress,
+        name: &str,
+        records: &[HistoryRecord],
+    ) -> Result<(), ParquetError> {
+        let schema = Arc::new(parse_message_type(SCHEMA)?);
+        let properties = Arc::new(WriterProperties::builder().build());
+        let file = File::create(path)?;
+        let mut writer = SerializedFileWriter::new(file, schema, properties)?;
+        let mut row_group = writer.next_row_group()?;
+
+        let times: Vec<i64> = records
+            .iter()
+            .map(|record| unix_seconds(record.corrected_time) as i64 * 1000)
+            .collect();
+        write_column::<Int64Type>(&mut row_group, &times)?;
+        let indices: Vec<i32> = records.iter().map(|record| record.index as i32).collect();
+        write_column::<Int32Type>(&mut row_group, &indices)?;
+        let mac_addresses = vec![ByteArray::from(mac_address.to_string().as_str()); records.len()];
+        write_column::<ByteArrayType>(&mut row_group, &mac_addresses)?;
+        let names = vec![ByteArray::from(name); records.len()];
+        write_column::<ByteArrayType>(&mut row_group, &names)?;
+        let temperatures_min: Vec<f32> = records
+            .iter()
+            .map(|record| record.temperature_min)
+            .collect();
+        write_column::<FloatType>(&mut row_group, &temperatures_min)?;
+        let temperatures_max: Vec<f32> = records
+            .iter()
+            .map(|record| record.temperature_max)
+            .collect();
+        write_column::<FloatType>(&mut row_group, &temperatures_max)?;
+        let humidities_min: Vec<i32> = records
+            .iter()
+            .map(|record| record.humidity_min.into())
+            .collect();
+        write_column::<Int32Type>(&mut row_group, &humidities_min)?;
+        let humidities_max: Vec<i32> = records
+            .iter()
+            .map(|record| record.humidity_max.into())
+            .collect();
+        write_column::<Int32Type>(&mut row_group, &humidities_max)?;
+
+        row_group.close()?;
+        writer.close()?;
+        Ok(())
+    }
+
+    /// Write the given values to the next column of the row group.
+    fn write_column<T: DataType>(
+        row_group: &mut SerializedRowGroupWriter<File>,
+        values: &[T::T],
+    ) -> Result<(), ParquetError> {
+        let mut column = row_group
+            .next_column()?
+            .ok_or_else(|| ParquetError::General("Too many columns".to_owned()))?;
+        column.typed::<T>().write_batch(values, None, None)?;
+        column.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(index: u32) -> HistoryRecord {
+        HistoryRecord {
+            index,
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000 + 3600 * index as u64),
+            corrected_time: SystemTime::UNIX_EPOCH
+                + Duration::from_secs(1_600_000_000 + 3600 * index as u64),
+            temperature_min: 20.1,
+            temperature_max: 22.5,
+            humidity_min: 40,
+            humidity_max: 55,
+        }
+    }
+
+    #[test]
+    fn new_records_after_last_index() {
+        let history = vec![record(3), record(1), record(2), record(4)];
+        assert_eq!(new_records(history.clone(), None).len(), 4);
+        assert_eq!(
+            new_records(history.clone(), Some(2)),
+            vec![record(3), record(4)]
+        );
+        assert_eq!(new_records(history, Some(4)), vec![]);
+    }
+
+    #[test]
+    fn new_records_after_history_cleared() {
+        let history = vec![record(0), record(1)];
+        assert_eq!(new_records(history.clone(), Some(10)), history);
+    }
+
+    #[test]
+    fn csv_line_escapes_name() {
+        let mac_address = "A4:C1:38:01:02:03".parse().unwrap();
+        assert_eq!(
+            csv_line(&mac_address, "Bob's \"room\"", &record(1)),
+            "1600003600,1,A4:C1:38:01:02:03,\"Bob's \"\"room\"\"\",20.1,22.5,40,55"
+        );
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn write_parquet_file() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let path = std::env::temp_dir().join("mijia-homie-history-export-test.parquet");
+        let mac_address = "A4:C1:38:01:02:03".parse().unwrap();
+        parquet_export::write_parquet(&path, &mac_address, "Name", &[record(1), record(2)])
+            .unwrap();
+
+        let reader = SerializedFileReader::new(fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+        assert_eq!(
+            reader
+                .metadata()
+                .file_metadata()
+                .schema_descr()
+                .num_columns(),
+            8
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_or_unwritable_state() {
+        let directory = std::env::temp_dir().join("mijia-homie-history-export-state-test");
+        fs::create_dir_all(&directory).unwrap();
+
+        let corrupt_path = directory.join(STATE_FILENAME);
+        fs::write(&corrupt_path, "not = [valid").unwrap();
+        assert_eq!(load_last_indices(&corrupt_path), HashMap::new());
+
+        // A directory can't be written to as a file.
+        let mut last_indices = HashMap::new();
+        last_indices.insert("A4:C1:38:01:02:03".to_owned(), 42);
+        save_last_indices(&directory, &last_indices);
+        assert!(directory.is_dir());
+
+        save_last_indices(&corrupt_path, &last_indices);
+        assert_eq!(load_last_indices(&corrupt_path), last_indices);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn duration_until_next_export() {
+        let midnight = SystemTime::UNIX_EPOCH + Duration::from_secs(100 * SECONDS_PER_DAY);
+        assert_eq!(
+            duration_until_utc_hour(midnight, 3),
+            Duration::from_secs(3 * SECONDS_PER_HOUR)
+        );
+        assert_eq!(
+            duration_until_utc_hour(midnight + Duration::from_secs(4 * SECONDS_PER_HOUR), 3),
+            Duration::from_secs(23 * SECONDS_PER_HOUR)
+        );
+        // If it's exactly the target time, wait until the next day.
+        assert_eq!(
+            duration_until_utc_hour(midnight, 0),
+            Duration::from_secs(SECONDS_PER_DAY)
+        );
+    }
+}
@@ -1,8 +1,19 @@
 #![type_length_limit = "1138969"]
 
+mod alerts;
+mod bridge;
 mod config;
+mod health;
+mod history_export;
+mod psychrometrics;
+mod rooms;
 
+use crate::alerts::Alerts;
+use crate::bridge::{BridgeCommand, NODE_ID_BRIDGE, PROPERTY_ID_PAUSE_SCANNING};
 use crate::config::{get_mqtt_options, read_sensor_names, Config};
+use crate::health::{serve_health, Health, FAILURE_REPORT_PERIOD};
+use crate::history_export::history_export_loop;
+use crate::rooms::{rooms_loop, Rooms};
 use backoff::future::retry;
 use backoff::ExponentialBackoff;
 use btsensor::bthome::{self, v1::Element};
@@ -10,20 +21,22 @@ use btsensor::Reading;
 use eyre::{eyre, Report};
 use futures::stream::StreamExt;
 use futures::TryFutureExt;
-use homie_device::{HomieDevice, Node, Property};
+use homie_device::{format_datetime, HomieDevice, Node, Property};
 use itertools::Itertools;
 use log::{debug, info};
 use mijia::bluetooth::{
-    BluetoothError, BluetoothEvent, BluetoothSession, DeviceEvent, DeviceId, MacAddress,
+    AdapterId, BluetoothError, BluetoothEvent, BluetoothSession, DeviceEvent, DeviceId, DeviceInfo,
+    MacAddress,
 };
-use mijia::{MijiaEvent, MijiaSession, Readings, SensorProps};
+use mijia::{MijiaEvent, MijiaSession, Readings, ReadingsField, SensorProps};
 use stable_eyre::eyre;
 use stable_eyre::eyre::WrapErr;
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
 use tokio::sync::Mutex;
-use tokio::{time, try_join};
+use tokio::{pin, time, try_join};
 
 const SCAN_INTERVAL: Duration = Duration::from_secs(15);
 const CONNECT_INTERVAL: Duration = Duration::from_secs(1);
@@ -33,6 +46,13 @@ const UPDATE_TIMEOUT: Duration = Duration::from_secs(60);
 // order to avoid races.
 const SENSOR_CONNECT_RESERVATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 const SENSOR_CONNECT_RETRY_TIMEOUT: Duration = Duration::from_secs(60);
+// After a failed connection attempt, wait this long before trying again, doubling for each
+// consecutive failure up to SENSOR_CONNECT_BACKOFF_MAX.
+const SENSOR_CONNECT_BACKOFF_INITIAL: Duration = Duration::from_secs(10);
+const SENSOR_CONNECT_BACKOFF_MAX: Duration = Duration::from_secs(10 * 60);
+// How long to stop trying to connect to a sensor once it has been blacklisted, unless we see an
+// advertisement from it after SENSOR_CONNECT_BACKOFF_MAX has passed.
+const SENSOR_BLACKLIST_TIMEOUT: Duration = Duration::from_secs(60 * 60);
 
 #[tokio::main]
 async fn main() -> Result<(), eyre::Report> {
@@ -43,30 +63,70 @@ async fn main() -> Result<(), eyre::Report> {
     let config = Config::from_file()?;
     let sensor_names = read_sensor_names(&config.homie.sensor_names_filename)?;
 
-    let mqtt_options = get_mqtt_options(config.mqtt, &config.homie.device_id);
-    let device_base = format!("{}/{}", config.homie.prefix, config.homie.device_id);
-    let mut homie_builder =
-        HomieDevice::builder(&device_base, &config.homie.device_name, mqtt_options);
+    let mqtt_options = get_mqtt_options(config.mqtt.clone(), &config.homie.device_id);
+    let device_id = &config.homie.device_id;
+    let mut homie_builder = HomieDevice::builder_for(
+        &config.homie.prefix,
+        device_id,
+        &config.homie.device_name,
+        mqtt_options,
+    )?;
     homie_builder.set_firmware(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    for mirror_mqtt in &config.mirror_mqtt {
+        homie_builder.add_additional_broker(get_mqtt_options(mirror_mqtt.clone(), device_id));
+    }
+    let (bridge_commands_tx, bridge_commands_rx) = mpsc::unbounded_channel();
+    let bridge_control = config.homie.bridge_control;
+    if bridge_control {
+        bridge::set_update_callback(&mut homie_builder, bridge_commands_tx);
+    }
     let (homie, homie_handle) = homie_builder.spawn().await?;
+    let bridge_commands = bridge_control.then_some(bridge_commands_rx);
 
     // Connect a Bluetooth session.
     let (dbus_handle, session) = MijiaSession::new().await?;
 
-    let min_update_period = config.homie.min_update_period;
-    let sensor_handle = run_sensor_system(homie, &session, &sensor_names, min_update_period);
+    let health = Health::new();
+    let sensor_handle = run_sensor_system(
+        homie,
+        &session,
+        &sensor_names,
+        &config,
+        bridge_commands,
+        &health,
+    );
 
     // Poll everything to completion, until the first one bombs out.
-    let res: Result<_, eyre::Report> = try_join! {
-        // If this ever finishes, we lost connection to D-Bus.
-        dbus_handle.err_into(),
-        // Bluetooth finished first. Convert error and get on with your life.
-        sensor_handle.err_into(),
-        // MQTT event loop finished first.
-        homie_handle.err_into(),
+    let tasks = async {
+        let res: Result<_, eyre::Report> = try_join! {
+            // If this ever finishes, we lost connection to D-Bus.
+            health.watch("D-Bus", dbus_handle).err_into(),
+            // Bluetooth finished first. Convert error and get on with your life.
+            health.watch("Sensor", sensor_handle).err_into(),
+            // MQTT event loop finished first. Mirror connections are retried independently, so this
+            // only finishes if the primary connection fails.
+            health.watch("MQTT", homie_handle).err_into(),
+        };
+        res.map(|((), (), ())| ())
     };
-    res?;
-    Ok(())
+    match config.health.clone() {
+        Some(health_config) => {
+            let health_handle = serve_health(health_config, &health, &session.bt_session);
+            pin!(health_handle);
+            tokio::select! {
+                res = tasks => {
+                    if res.is_err() {
+                        // Keep serving the health endpoints for a while so that the orchestrator
+                        // can see what happened before the bridge exits.
+                        let _ = time::timeout(FAILURE_REPORT_PERIOD, &mut health_handle).await;
+                    }
+                    res
+                }
+                res = &mut health_handle => res,
+            }
+        }
+        None => tasks.await,
+    }
 }
 
 #[derive(Debug, Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -88,6 +148,34 @@ enum ConnectionStatus {
     Connected { id: DeviceId },
     /// This sensor gives updates by advertisements only, there is no need to connect.
     AdvertisementOnly,
+    /// We failed to connect too many times in a row, so don't try again until the timeout expires,
+    /// or until we see an advertisement from the sensor after the maximum backoff.
+    Blacklisted { until: Instant },
+}
+
+impl ConnectionStatus {
+    /// The possible values of the connection status diagnostics property.
+    const VALUES: &'static [&'static str] = &[
+        "unknown",
+        "connecting",
+        "disconnected",
+        "marked-disconnected",
+        "connected",
+        "advertisement-only",
+        "blacklisted",
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::Connecting { .. } => "connecting",
+            Self::Disconnected => "disconnected",
+            Self::MarkedDisconnected => "marked-disconnected",
+            Self::Connected { .. } => "connected",
+            Self::AdvertisementOnly => "advertisement-only",
+            Self::Blacklisted { .. } => "blacklisted",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -100,23 +188,43 @@ struct Sensor {
     /// `last_update_timestamp` if the `min_update_time` config parameter is set.
     last_sent_timestamp: Instant,
     connection_status: ConnectionStatus,
+    /// The number of connection attempts which have failed since the last successful one.
+    consecutive_failures: u32,
+    /// Don't try to connect to the sensor again before this time.
+    next_connect_attempt: Instant,
     ids: Vec<DeviceId>,
+    /// Whether to publish diagnostics properties for the sensor.
+    diagnostics: bool,
+    /// Whether to publish dew point and absolute humidity properties for the sensor.
+    psychrometrics: bool,
+    /// The number of values from the sensor which couldn't be decoded.
+    decode_failures: u64,
 }
 
 impl Sensor {
     const PROPERTY_ID_TEMPERATURE: &'static str = "temperature";
     const PROPERTY_ID_HUMIDITY: &'static str = "humidity";
     const PROPERTY_ID_BATTERY: &'static str = "battery";
+    const PROPERTY_ID_CONNECTION_STATUS: &'static str = "connection-status";
+    const PROPERTY_ID_LAST_UPDATE: &'static str = "last-update";
+    const PROPERTY_ID_ADAPTER: &'static str = "adapter";
+    const PROPERTY_ID_DECODE_FAILURES: &'static str = "decode-failures";
+    const PROPERTY_ID_DEW_POINT: &'static str = "dew-point";
+    const PROPERTY_ID_ABSOLUTE_HUMIDITY: &'static str = "absolute-humidity";
+    /// The properties for which alert thresholds can be configured.
+    const PROPERTY_IDS: &'static [&'static str] = &[
+        Self::PROPERTY_ID_TEMPERATURE,
+        Self::PROPERTY_ID_HUMIDITY,
+        Self::PROPERTY_ID_BATTERY,
+    ];
 
     pub fn new(
         props: SensorProps,
-        sensor_names: &HashMap<MacAddress, String>,
+        name: String,
         connection_status: ConnectionStatus,
+        diagnostics: bool,
+        psychrometrics: bool,
     ) -> Self {
-        let name = sensor_names
-            .get(&props.mac_address)
-            .cloned()
-            .unwrap_or_else(|| props.mac_address.to_string());
         Self {
             mac_address: props.mac_address,
             name,
@@ -125,7 +233,12 @@ impl Sensor {
             // one hour in the past should be more than enough.
             last_sent_timestamp: Instant::now() - Duration::from_secs(3600),
             connection_status,
+            consecutive_failures: 0,
+            next_connect_attempt: Instant::now(),
             ids: vec![props.id],
+            diagnostics,
+            psychrometrics,
+            decode_failures: 0,
         }
     }
 
@@ -134,37 +247,258 @@ impl Sensor {
     }
 
     fn as_node(&self) -> Node {
-        Node::new(
-            &self.node_id(),
-            &self.name,
-            "Mijia sensor",
-            vec![
-                Property::float(
-                    Self::PROPERTY_ID_TEMPERATURE,
-                    "Temperature",
-                    false,
-                    true,
-                    Some("ºC"),
-                    None,
-                ),
-                Property::integer(
-                    Self::PROPERTY_ID_HUMIDITY,
-                    "Humidity",
-                    false,
-                    true,
-                    Some("%"),
-                    None,
-                ),
-                Property::integer(
-                    Self::PROPERTY_ID_BATTERY,
-                    "Battery level",
-                    false,
-                    true,
-                    Some("%"),
-                    None,
-                ),
+        let mut properties = vec![
+            Property::float(
+                Self::PROPERTY_ID_TEMPERATURE,
+                "Temperature",
+                false,
+                true,
+                Some("ºC"),
+                None,
+            ),
+            Property::integer(
+                Self::PROPERTY_ID_HUMIDITY,
+                "Humidity",
+                false,
+                true,
+                Some("%"),
+                None,
+            ),
+            Property::integer(
+                Self::PROPERTY_ID_BATTERY,
+                "Battery level",
+                false,
+                true,
+                Some("%"),
+                None,
+            ),
+        ];
+        if self.psychrometrics {
+            properties.push(Property::float(
+                Self::PROPERTY_ID_DEW_POINT,
+                "Dew point",
+                false,
+                true,
+                Some("ºC"),
+                None,
+            ));
+            properties.push(Property::float(
+                Self::PROPERTY_ID_ABSOLUTE_HUMIDITY,
+                "Absolute humidity",
+                false,
+                true,
+                Some("g/m³"),
+                None,
+            ));
+        }
+        if self.diagnostics {
+            properties.push(Property::enumeration(
+                Self::PROPERTY_ID_CONNECTION_STATUS,
+                "Connection status",
+                false,
+                true,
+                None,
+                ConnectionStatus::VALUES,
+            ));
+            properties.push(Property::datetime(
+                Self::PROPERTY_ID_LAST_UPDATE,
+                "Last update",
+                false,
+                true,
+                None,
+            ));
+            properties.push(Property::string(
+                Self::PROPERTY_ID_ADAPTER,
+                "Bluetooth adapter",
+                false,
+                true,
+                None,
+            ));
+            properties.push(Property::integer(
+                Self::PROPERTY_ID_DECODE_FAILURES,
+                "Decode failures",
+                false,
+                true,
+                None,
+                None,
+            ));
+        }
+        Node::new(&self.node_id(), &self.name, "Mijia sensor", properties)
+    }
+
+    /// The Bluetooth adapter via which the sensor is connected, or was last seen.
+    fn adapter(&self) -> Option<AdapterId> {
+        match &self.connection_status {
+            ConnectionStatus::Connected { id } => Some(id.adapter()),
+            _ => self.ids.first().map(DeviceId::adapter),
+        }
+    }
+
+    /// Publish the values of all diagnostics properties, if enabled.
+    async fn publish_diagnostics(&self, homie: &mut HomieDevice) -> Result<(), eyre::Report> {
+        if self.diagnostics {
+            self.publish_connection_status(homie).await?;
+            self.publish_last_update(homie).await?;
+            let node_id = self.node_id();
+            if let Some(adapter) = self.adapter() {
+                homie
+                    .publish_value(&node_id, Self::PROPERTY_ID_ADAPTER, adapter)
+                    .await?;
+            }
+            self.publish_decode_failures(homie).await?;
+        }
+        Ok(())
+    }
+
+    /// Publish the connection status diagnostics property, if enabled. The node for the sensor is
+    /// added first if it isn't there already, so that the status can be seen even before the
+    /// sensor has connected.
+    async fn publish_connection_status(&self, homie: &mut HomieDevice) -> Result<(), eyre::Report> {
+        if self.diagnostics {
+            if !homie.has_node(&self.node_id()) {
+                homie.add_node(self.as_node()).await?;
+            }
+            homie
+                .publish_value(
+                    &self.node_id(),
+                    Self::PROPERTY_ID_CONNECTION_STATUS,
+                    self.connection_status.as_str(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Publish the last update time diagnostics property, if enabled.
+    async fn publish_last_update(&self, homie: &HomieDevice) -> Result<(), eyre::Report> {
+        if self.diagnostics {
+            let last_update = SystemTime::now() - self.last_update_timestamp.elapsed();
+            homie
+                .publish_value(
+                    &self.node_id(),
+                    Self::PROPERTY_ID_LAST_UPDATE,
+                    format_datetime(last_update),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Publish the number of decode failures diagnostics property, if enabled.
+    async fn publish_decode_failures(&self, homie: &HomieDevice) -> Result<(), eyre::Report> {
+        if self.diagnostics {
+            homie
+                .publish_value(
+                    &self.node_id(),
+                    Self::PROPERTY_ID_DECODE_FAILURES,
+                    self.decode_failures,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Publish the dew point and absolute humidity computed from the temperature and humidity in the
+    /// given values, if enabled and both are present.
+    async fn publish_psychrometrics(
+        &self,
+        homie: &HomieDevice,
+        values: &[(&'static str, f64)],
+    ) -> Result<(), eyre::Report> {
+        if !self.psychrometrics {
+            return Ok(());
+        }
+        let value = |property_id| {
+            values
+                .iter()
+                .find(|(id, _)| *id == property_id)
+                .map(|(_, value)| *value)
+        };
+        let (Some(temperature), Some(humidity)) = (
+            value(Self::PROPERTY_ID_TEMPERATURE),
+            value(Self::PROPERTY_ID_HUMIDITY),
+        ) else {
+            return Ok(());
+        };
+        let node_id = self.node_id();
+        if let Some(dew_point) = psychrometrics::dew_point(temperature, humidity) {
+            homie
+                .publish_value(
+                    &node_id,
+                    Self::PROPERTY_ID_DEW_POINT,
+                    format!("{:.2}", dew_point),
+                )
+                .await?;
+        }
+        if let Some(absolute_humidity) = psychrometrics::absolute_humidity(temperature, humidity) {
+            homie
+                .publish_value(
+                    &node_id,
+                    Self::PROPERTY_ID_ABSOLUTE_HUMIDITY,
+                    format!("{:.2}", absolute_humidity),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Get the numeric values of the properties in the given readings, to check against alert
+    /// thresholds. Values which are out of range are omitted.
+    fn readings_values(readings: &Readings) -> Vec<(&'static str, f64)> {
+        let mut values = vec![];
+        if readings.is_valid(ReadingsField::Temperature) {
+            values.push((Self::PROPERTY_ID_TEMPERATURE, readings.temperature.into()));
+        }
+        if readings.is_valid(ReadingsField::Humidity) {
+            values.push((Self::PROPERTY_ID_HUMIDITY, readings.humidity.into()));
+        }
+        if readings.is_valid(ReadingsField::BatteryVoltage) {
+            values.push((Self::PROPERTY_ID_BATTERY, readings.battery_percent.into()));
+        }
+        values
+    }
+
+    /// Get the numeric values of the properties in the given advertised reading, to check against
+    /// alert thresholds.
+    fn reading_values(reading: &Reading) -> Vec<(&'static str, f64)> {
+        match reading {
+            Reading::Atc(atc) => vec![
+                (Self::PROPERTY_ID_TEMPERATURE, atc.temperature().into()),
+                (Self::PROPERTY_ID_HUMIDITY, atc.humidity().into()),
+                (Self::PROPERTY_ID_BATTERY, atc.battery_percent().into()),
             ],
-        )
+            Reading::BtHomeV1(elements) => elements
+                .iter()
+                .filter_map(|element| match element {
+                    Element::Sensor(sensor) => match sensor.property {
+                        bthome::v1::Property::Temperature => {
+                            Some((Self::PROPERTY_ID_TEMPERATURE, sensor.value_float()))
+                        }
+                        bthome::v1::Property::Humidity | bthome::v1::Property::HumidityShort => {
+                            Some((Self::PROPERTY_ID_HUMIDITY, sensor.value_float()))
+                        }
+                        bthome::v1::Property::Battery => {
+                            Some((Self::PROPERTY_ID_BATTERY, sensor.value_float()))
+                        }
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect(),
+            Reading::BtHomeV2(bthome) => bthome
+                .elements
+                .iter()
+                .filter_map(|element| {
+                    let property_id = match element.name() {
+                        "temperature" => Self::PROPERTY_ID_TEMPERATURE,
+                        "humidity" => Self::PROPERTY_ID_HUMIDITY,
+                        "battery" => Self::PROPERTY_ID_BATTERY,
+                        _ => return None,
+                    };
+                    Some((property_id, element.value_float()?))
+                })
+                .collect(),
+        }
     }
 
     async fn publish_readings(
@@ -179,23 +513,34 @@ impl Sensor {
 
         if now > self.last_sent_timestamp + min_update_period {
             let node_id = self.node_id();
-            homie
-                .publish_value(
-                    &node_id,
-                    Self::PROPERTY_ID_TEMPERATURE,
-                    format!("{:.2}", readings.temperature),
-                )
-                .await?;
-            homie
-                .publish_value(&node_id, Self::PROPERTY_ID_HUMIDITY, readings.humidity)
-                .await?;
-            homie
-                .publish_value(
-                    &node_id,
-                    Self::PROPERTY_ID_BATTERY,
-                    readings.battery_percent,
-                )
+            // Values which are out of range are probably corrupt, so skip them but still publish
+            // the others.
+            if readings.is_valid(ReadingsField::Temperature) {
+                homie
+                    .publish_value(
+                        &node_id,
+                        Self::PROPERTY_ID_TEMPERATURE,
+                        format!("{:.2}", readings.temperature),
+                    )
+                    .await?;
+            }
+            if readings.is_valid(ReadingsField::Humidity) {
+                homie
+                    .publish_value(&node_id, Self::PROPERTY_ID_HUMIDITY, readings.humidity)
+                    .await?;
+            }
+            if readings.is_valid(ReadingsField::BatteryVoltage) {
+                homie
+                    .publish_value(
+                        &node_id,
+                        Self::PROPERTY_ID_BATTERY,
+                        readings.battery_percent,
+                    )
+                    .await?;
+            }
+            self.publish_psychrometrics(homie, &Self::readings_values(readings))
                 .await?;
+            self.publish_last_update(homie).await?;
             self.last_sent_timestamp = now;
         } else {
             log::trace!(
@@ -322,6 +667,9 @@ impl Sensor {
                     }
                 }
             }
+            self.publish_psychrometrics(homie, &Self::reading_values(reading))
+                .await?;
+            self.publish_last_update(homie).await?;
             self.last_sent_timestamp = now;
         } else {
             log::trace!(
@@ -339,11 +687,83 @@ impl Sensor {
         id: DeviceId,
     ) -> Result<(), eyre::Report> {
         assert!(self.ids.contains(&id));
-        homie.add_node(self.as_node()).await?;
+        if !homie.has_node(&self.node_id()) {
+            homie.add_node(self.as_node()).await?;
+        }
         self.connection_status = ConnectionStatus::Connected { id };
+        self.consecutive_failures = 0;
+        self.publish_diagnostics(homie).await?;
         Ok(())
     }
 
+    /// Records a failed connection attempt, backing off exponentially before the next attempt, and
+    /// blacklists the sensor if it has failed `blacklist_after_failures` times in a row.
+    async fn mark_connect_failed(
+        &mut self,
+        homie: &mut HomieDevice,
+        blacklist_after_failures: u32,
+    ) -> Result<(), eyre::Report> {
+        self.consecutive_failures += 1;
+        let now = Instant::now();
+        if blacklist_after_failures != 0 && self.consecutive_failures >= blacklist_after_failures {
+            println!(
+                "Failed to connect to {} {} times in a row, blacklisting for {:?}",
+                self.name, self.consecutive_failures, SENSOR_BLACKLIST_TIMEOUT
+            );
+            self.connection_status = ConnectionStatus::Blacklisted {
+                until: now + SENSOR_BLACKLIST_TIMEOUT,
+            };
+            self.next_connect_attempt = now + SENSOR_CONNECT_BACKOFF_MAX;
+        } else {
+            let backoff = SENSOR_CONNECT_BACKOFF_INITIAL
+                .saturating_mul(1 << (self.consecutive_failures - 1).min(16))
+                .min(SENSOR_CONNECT_BACKOFF_MAX);
+            self.connection_status = ConnectionStatus::Disconnected;
+            self.next_connect_attempt = now + backoff;
+        }
+        self.publish_connection_status(homie).await
+    }
+
+    /// Records that an advertisement was seen from the sensor, so it is probably in range and has
+    /// power. If it is blacklisted and the maximum backoff has passed since it was, it is taken off
+    /// the blacklist so we will try to connect again without waiting for the blacklist to expire.
+    ///
+    /// The failure count is kept, so a sensor which advertises but still can't be connected to is
+    /// blacklisted again after one more failure.
+    async fn mark_advertisement_seen(
+        &mut self,
+        homie: &mut HomieDevice,
+    ) -> Result<(), eyre::Report> {
+        if matches!(self.connection_status, ConnectionStatus::Blacklisted { .. })
+            && self.next_connect_attempt <= Instant::now()
+        {
+            println!(
+                "Saw advertisement from {}, removing from blacklist",
+                self.name
+            );
+            self.connection_status = ConnectionStatus::Disconnected;
+            self.publish_connection_status(homie).await?;
+        }
+        Ok(())
+    }
+
+    /// Records that the sensor has been disconnected with the given status. Its Homie node is removed
+    /// unless diagnostics are enabled, in which case it is kept so that the connection status can
+    /// still be seen.
+    async fn mark_disconnected(
+        &mut self,
+        homie: &mut HomieDevice,
+        status: ConnectionStatus,
+    ) -> Result<(), eyre::Report> {
+        self.connection_status = status;
+        if self.diagnostics {
+            self.publish_connection_status(homie).await
+        } else {
+            homie.remove_node(&self.node_id()).await?;
+            Ok(())
+        }
+    }
+
     /// Adds a Homie node for an advertisement-only sensor.
     async fn publish_advertisement_only(
         &mut self,
@@ -351,6 +771,7 @@ impl Sensor {
     ) -> Result<(), eyre::Report> {
         assert_eq!(self.connection_status, ConnectionStatus::AdvertisementOnly);
         homie.add_node(self.as_node()).await?;
+        self.publish_diagnostics(homie).await?;
         Ok(())
     }
 }
@@ -359,28 +780,75 @@ async fn run_sensor_system(
     mut homie: HomieDevice,
     session: &MijiaSession,
     sensor_names: &HashMap<MacAddress, String>,
-    min_update_period: Duration,
+    config: &Config,
+    bridge_commands: Option<UnboundedReceiver<BridgeCommand>>,
+    health: &Health,
 ) -> Result<(), eyre::Report> {
+    let homie_config = &config.homie;
+    let alerts = config.alerts.clone().map(Alerts::new).transpose()?;
+    if let Some(node) = alerts.as_ref().and_then(Alerts::node) {
+        homie.add_node(node).await?;
+    }
+    let rooms = config.rooms.clone().map(Rooms::new).transpose()?;
+    for node in rooms.iter().flat_map(Rooms::nodes) {
+        homie.add_node(node).await?;
+    }
+    if bridge_commands.is_some() {
+        homie.add_node(bridge::node()).await?;
+        homie
+            .publish_value(NODE_ID_BRIDGE, PROPERTY_ID_PAUSE_SCANNING, false)
+            .await?;
+    }
     homie.ready().await?;
 
     let state = Arc::new(Mutex::new(SensorState {
         sensors: HashMap::new(),
         homie,
-        min_update_period,
+        min_update_period: homie_config.min_update_period,
+        diagnostics: homie_config.diagnostics,
+        psychrometrics: homie_config.psychrometrics,
+        blacklist_after_failures: homie_config.blacklist_after_failures,
+        bridge_unnamed_sensors: homie_config.bridge_unnamed_sensors,
+        use_advertised_names: homie_config.use_advertised_names,
+        alerts,
+        rooms,
     }));
 
-    let connection_loop_handle = bluetooth_connection_loop(state.clone(), session, sensor_names);
+    let connection_loop_handle = bluetooth_connection_loop(
+        state.clone(),
+        session,
+        sensor_names,
+        bridge_commands,
+        health,
+    );
     let bluetooth_event_loop_handle =
         service_bluetooth_event_queue(state.clone(), &session.bt_session, sensor_names);
-    try_join!(connection_loop_handle, bluetooth_event_loop_handle).map(|((), ())| ())
+    let history_export_handle = async {
+        match config.history_export.clone() {
+            Some(config) => history_export_loop(state.clone(), session, config).await,
+            None => Ok(()),
+        }
+    };
+    let rooms_handle = rooms_loop(state.clone());
+    try_join!(
+        connection_loop_handle,
+        bluetooth_event_loop_handle,
+        history_export_handle,
+        rooms_handle
+    )
+    .map(|((), (), (), ())| ())
 }
 
 async fn bluetooth_connection_loop(
     state: Arc<Mutex<SensorState>>,
     session: &MijiaSession,
     sensor_names: &HashMap<MacAddress, String>,
+    mut bridge_commands: Option<UnboundedReceiver<BridgeCommand>>,
+    health: &Health,
 ) -> Result<(), eyre::Report> {
     let mut next_scan_due = Instant::now();
+    let mut scanning_paused = false;
+    let mut rescan_requested = false;
     loop {
         // Print count and list of sensors in each state.
         {
@@ -397,7 +865,12 @@ async fn bluetooth_connection_loop(
 
         // Look for more sensors if enough time has elapsed since last time we tried.
         let now = Instant::now();
-        if now > next_scan_due && state.lock().await.sensors.len() < sensor_names.len() {
+        if rescan_requested
+            || (!scanning_paused
+                && now > next_scan_due
+                && state.lock().await.named_sensor_count(sensor_names) < sensor_names.len())
+        {
+            rescan_requested = false;
             next_scan_due = now + SCAN_INTERVAL;
             check_for_sensors(state.clone(), session, sensor_names).await?;
         }
@@ -407,20 +880,66 @@ async fn bluetooth_connection_loop(
             let mac_addresses: Vec<MacAddress> =
                 state.lock().await.sensors.keys().cloned().collect();
             for mac_address in mac_addresses {
-                let connection_status = state
+                let (connection_status, next_connect_attempt) = state
                     .lock()
                     .await
                     .sensors
                     .get(&mac_address)
                     .map(|sensor| {
                         log::trace!("State of {} is {:?}", sensor.name, sensor.connection_status);
-                        sensor.connection_status.to_owned()
+                        (
+                            sensor.connection_status.to_owned(),
+                            sensor.next_connect_attempt,
+                        )
                     })
                     .expect("sensors cannot be deleted");
-                action_sensor(state.clone(), session, &mac_address, connection_status).await?;
+                action_sensor(
+                    state.clone(),
+                    session,
+                    &mac_address,
+                    connection_status,
+                    next_connect_attempt,
+                )
+                .await?;
             }
         }
-        time::sleep(CONNECT_INTERVAL).await;
+        health.sensor_loop_ran();
+
+        // Wait before checking again, unless a command arrives from the bridge node meanwhile.
+        let command = match &mut bridge_commands {
+            Some(commands) => match time::timeout(CONNECT_INTERVAL, commands.recv()).await {
+                Ok(Some(command)) => Some(command),
+                Ok(None) => {
+                    bridge_commands = None;
+                    None
+                }
+                Err(_) => None,
+            },
+            None => {
+                time::sleep(CONNECT_INTERVAL).await;
+                None
+            }
+        };
+        match command {
+            Some(BridgeCommand::Rescan) => {
+                println!("Rescan requested");
+                rescan_requested = true;
+            }
+            Some(BridgeCommand::PauseScanning(paused)) => {
+                println!("Scanning {}", if paused { "paused" } else { "resumed" });
+                scanning_paused = paused;
+                state
+                    .lock()
+                    .await
+                    .homie
+                    .publish_value(NODE_ID_BRIDGE, PROPERTY_ID_PAUSE_SCANNING, paused)
+                    .await?;
+            }
+            Some(BridgeCommand::Reconnect(mac_address)) => {
+                reconnect_sensor(state.clone(), session, &mac_address).await?;
+            }
+            None => {}
+        }
     }
 }
 
@@ -429,6 +948,21 @@ struct SensorState {
     sensors: HashMap<MacAddress, Sensor>,
     homie: HomieDevice,
     min_update_period: Duration,
+    /// Whether to publish diagnostics properties for each sensor.
+    diagnostics: bool,
+    /// Whether to publish dew point and absolute humidity properties for each sensor.
+    psychrometrics: bool,
+    /// The number of consecutive connection failures after which to blacklist a sensor, or 0 to
+    /// never blacklist.
+    blacklist_after_failures: u32,
+    /// Whether to add sensors which advertise readings even if they don't have a name.
+    bridge_unnamed_sensors: bool,
+    /// Whether to name unnamed sensors after their advertised name rather than their MAC address.
+    use_advertised_names: bool,
+    /// Alert thresholds to check readings against, if configured.
+    alerts: Option<Alerts>,
+    /// Rooms to aggregate readings for, if configured.
+    rooms: Option<Rooms>,
 }
 
 impl SensorState {
@@ -445,24 +979,78 @@ impl SensorState {
         props: SensorProps,
         connection_status: ConnectionStatus,
     ) -> bool {
-        if sensor_names.contains_key(&props.mac_address) {
-            if let Some(sensor) = self.sensors.get_mut(&props.mac_address) {
-                if !sensor.ids.contains(&props.id) {
-                    // If we already know about the sensor but on a different Bluetooth adapter, add
-                    // this one too.
-                    sensor.ids.push(props.id);
-                }
-                false
-            } else {
-                // If we don't know about the sensor on any adapter, add it.
-                let sensor = Sensor::new(props, sensor_names, connection_status);
-                self.sensors.insert(sensor.mac_address, sensor);
-                true
+        match sensor_names.get(&props.mac_address) {
+            Some(name) => self.add_sensor(props, name.to_owned(), connection_status),
+            None => false,
+        }
+    }
+
+    /// Adds the given sensor to the list of sensors with the given name, if it's not already
+    /// present.
+    ///
+    /// If it's already present with a different ID, adds this ID.
+    ///
+    /// Returns true if the sensor was added to list of sensors, or false if it was already there.
+    fn add_sensor(
+        &mut self,
+        props: SensorProps,
+        name: String,
+        connection_status: ConnectionStatus,
+    ) -> bool {
+        if let Some(sensor) = self.sensors.get_mut(&props.mac_address) {
+            if !sensor.ids.contains(&props.id) {
+                // If we already know about the sensor but on a different Bluetooth adapter, add
+                // this one too.
+                sensor.ids.push(props.id);
             }
-        } else {
             false
+        } else {
+            // If we don't know about the sensor on any adapter, add it.
+            let sensor = Sensor::new(
+                props,
+                name,
+                connection_status,
+                self.diagnostics,
+                self.psychrometrics,
+            );
+            self.sensors.insert(sensor.mac_address, sensor);
+            true
         }
     }
+
+    /// Returns the name to use for a sensor which has advertised a reading, or `None` if it
+    /// shouldn't be bridged.
+    ///
+    /// Sensors in `sensor_names` use the name given there. Other sensors are only bridged if
+    /// `bridge_unnamed_sensors` is set, in which case they are named after their advertised name
+    /// if `use_advertised_names` is set and they have one, or their MAC address otherwise.
+    fn advertised_sensor_name(
+        &self,
+        sensor_names: &HashMap<MacAddress, String>,
+        device_info: &DeviceInfo,
+    ) -> Option<String> {
+        if let Some(name) = sensor_names.get(&device_info.mac_address) {
+            Some(name.to_owned())
+        } else if self.bridge_unnamed_sensors {
+            Some(
+                device_info
+                    .name
+                    .clone()
+                    .filter(|_| self.use_advertised_names)
+                    .unwrap_or_else(|| device_info.mac_address.to_string()),
+            )
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of sensors which are listed in `sensor_names` that have been found.
+    fn named_sensor_count(&self, sensor_names: &HashMap<MacAddress, String>) -> usize {
+        self.sensors
+            .keys()
+            .filter(|mac_address| sensor_names.contains_key(mac_address))
+            .count()
+    }
 }
 
 /// Get the sensor entry for the given id, if any.
@@ -478,15 +1066,22 @@ async fn action_sensor(
     session: &MijiaSession,
     mac_address: &MacAddress,
     status: ConnectionStatus,
+    next_connect_attempt: Instant,
 ) -> Result<(), eyre::Report> {
+    let now = Instant::now();
     match status {
-        ConnectionStatus::Connecting { reserved_until } if reserved_until > Instant::now() => {
+        ConnectionStatus::Connecting { reserved_until } if reserved_until > now => Ok(()),
+        ConnectionStatus::Blacklisted { until } if until > now => Ok(()),
+        ConnectionStatus::Disconnected | ConnectionStatus::MarkedDisconnected
+            if next_connect_attempt > now =>
+        {
             Ok(())
         }
         ConnectionStatus::Unknown
         | ConnectionStatus::Connecting { .. }
         | ConnectionStatus::Disconnected
-        | ConnectionStatus::MarkedDisconnected => {
+        | ConnectionStatus::MarkedDisconnected
+        | ConnectionStatus::Blacklisted { .. } => {
             connect_sensor_with_id(state, session, mac_address).await?;
             Ok(())
         }
@@ -520,7 +1115,7 @@ async fn connect_sensor_with_id(
     mac_address: &MacAddress,
 ) -> Result<(), eyre::Report> {
     let (name, ids) = {
-        let mut state = state.lock().await;
+        let state = &mut *state.lock().await;
         let sensor = state.sensors.get_mut(mac_address).unwrap();
 
         // Update the state of the sensor to `Connecting`.
@@ -531,6 +1126,7 @@ async fn connect_sensor_with_id(
         sensor.connection_status = ConnectionStatus::Connecting {
             reserved_until: Instant::now() + SENSOR_CONNECT_RESERVATION_TIMEOUT,
         };
+        sensor.publish_connection_status(&mut state.homie).await?;
         (sensor.name.clone(), sensor.ids.clone())
     };
     let result = connect_and_subscribe_sensor_or_disconnect(session, &name, ids).await;
@@ -545,7 +1141,9 @@ async fn connect_sensor_with_id(
         }
         Err(e) => {
             println!("Failed to connect to {}: {:?}", sensor.name, e);
-            sensor.connection_status = ConnectionStatus::Disconnected;
+            sensor
+                .mark_connect_failed(&mut state.homie, state.blacklist_after_failures)
+                .await?;
         }
     }
     Ok(())
@@ -614,8 +1212,9 @@ async fn check_for_stale_sensor(
             sensor.name,
             now - sensor.last_update_timestamp
         );
-        sensor.connection_status = ConnectionStatus::Disconnected;
-        state.homie.remove_node(&sensor.node_id()).await?;
+        sensor
+            .mark_disconnected(&mut state.homie, ConnectionStatus::Disconnected)
+            .await?;
         // We could drop our state lock at this point, if it ends up taking
         // too long. As it is, it's quite nice that we can't attempt to connect
         // while we're in the middle of disconnecting.
@@ -628,6 +1227,49 @@ async fn check_for_stale_sensor(
     Ok(())
 }
 
+/// Disconnect from the given sensor if it is connected, and clear any backoff or blacklisting, so
+/// that the connection loop tries to connect to it again straight away.
+async fn reconnect_sensor(
+    state: Arc<Mutex<SensorState>>,
+    session: &MijiaSession,
+    mac_address: &MacAddress,
+) -> Result<(), eyre::Report> {
+    let state = &mut *state.lock().await;
+    let sensor = match state.sensors.get_mut(mac_address) {
+        Some(sensor) => sensor,
+        None => {
+            println!("Reconnect requested for unknown sensor {}", mac_address);
+            return Ok(());
+        }
+    };
+    println!(
+        "Reconnect requested for {} from status: {:?}",
+        sensor.name, sensor.connection_status
+    );
+    sensor.consecutive_failures = 0;
+    sensor.next_connect_attempt = Instant::now();
+    match sensor.connection_status.clone() {
+        // Sensors which are connecting will be retried anyway if it fails, and advertisement-only
+        // sensors don't need a connection.
+        ConnectionStatus::Connecting { .. } | ConnectionStatus::AdvertisementOnly => {}
+        ConnectionStatus::Connected { id } => {
+            sensor
+                .mark_disconnected(&mut state.homie, ConnectionStatus::Disconnected)
+                .await?;
+            session
+                .bt_session
+                .disconnect(&id)
+                .await
+                .wrap_err_with(|| format!("disconnecting from {}", id))?;
+        }
+        _ => {
+            sensor.connection_status = ConnectionStatus::Disconnected;
+            sensor.publish_connection_status(&mut state.homie).await?;
+        }
+    }
+    Ok(())
+}
+
 /// Waits for and handles events from the `BluetoothSession`.
 async fn service_bluetooth_event_queue(
     state: Arc<Mutex<SensorState>>,
@@ -657,6 +1299,20 @@ async fn handle_bluetooth_event(
         handle_mijia_event(state.clone(), mijia_event).await?;
     }
 
+    if let BluetoothEvent::Device {
+        id,
+        event:
+            DeviceEvent::Rssi { .. }
+            | DeviceEvent::ManufacturerData { .. }
+            | DeviceEvent::ServiceData { .. },
+    } = &event
+    {
+        let state = &mut *state.lock().await;
+        if let Some(sensor) = get_mut_sensor_by_id(&mut state.sensors, id) {
+            sensor.mark_advertisement_seen(&mut state.homie).await?;
+        }
+    }
+
     if let BluetoothEvent::Device {
         id,
         event: DeviceEvent::ServiceData { service_data },
@@ -664,19 +1320,23 @@ async fn handle_bluetooth_event(
     {
         if let Some(reading) = Reading::decode(&service_data) {
             info!("{}: {}", id, reading);
-            let mac_address = session.get_device_info(&id).await?.mac_address;
+            let device_info = session.get_device_info(&id).await?;
             let state = &mut *state.lock().await;
-            let is_new = state.add_sensor_if_named(
-                sensor_names,
-                SensorProps {
-                    id: id.clone(),
-                    mac_address,
-                },
-                ConnectionStatus::AdvertisementOnly,
-            );
+            let is_new = match state.advertised_sensor_name(sensor_names, &device_info) {
+                Some(name) => state.add_sensor(
+                    SensorProps {
+                        id: id.clone(),
+                        mac_address: device_info.mac_address,
+                    },
+                    name,
+                    ConnectionStatus::AdvertisementOnly,
+                ),
+                None => false,
+            };
             let homie = &mut state.homie;
             let sensors = &mut state.sensors;
-            // This will only return None if the sensor doesn't have a name.
+            // This will only return None if the sensor doesn't have a name and unnamed sensors
+            // aren't being bridged.
             if let Some(sensor) = get_mut_sensor_by_id(sensors, &id) {
                 assert_eq!(
                     sensor.connection_status,
@@ -688,6 +1348,15 @@ async fn handle_bluetooth_event(
                 sensor
                     .publish_reading(homie, &reading, state.min_update_period)
                     .await?;
+                let values = Sensor::reading_values(&reading);
+                if let Some(rooms) = &mut state.rooms {
+                    rooms.record(sensor.mac_address, &values);
+                }
+                if let Some(alerts) = &mut state.alerts {
+                    alerts
+                        .check(homie, sensor.mac_address, &sensor.name, &values)
+                        .await?;
+                }
             }
         }
     }
@@ -708,6 +1377,15 @@ async fn handle_mijia_event(
                 sensor
                     .publish_readings(homie, &readings, state.min_update_period)
                     .await?;
+                let values = Sensor::readings_values(&readings);
+                if let Some(rooms) = &mut state.rooms {
+                    rooms.record(sensor.mac_address, &values);
+                }
+                if let Some(alerts) = &mut state.alerts {
+                    alerts
+                        .check(homie, sensor.mac_address, &sensor.name, &values)
+                        .await?;
+                }
                 match &sensor.connection_status {
                     ConnectionStatus::Connected { id: connected_id } => {
                         if id != *connected_id {
@@ -735,8 +1413,9 @@ async fn handle_mijia_event(
                 {
                     if id == *connected_id {
                         println!("{} disconnected", sensor.name);
-                        sensor.connection_status = ConnectionStatus::MarkedDisconnected;
-                        homie.remove_node(&sensor.node_id()).await?;
+                        sensor
+                            .mark_disconnected(homie, ConnectionStatus::MarkedDisconnected)
+                            .await?;
                     } else {
                         println!(
                             "{} ({}) disconnected but was connected as {}.",
@@ -753,8 +1432,170 @@ async fn handle_mijia_event(
                 println!("Unknown device {} disconnected.", id);
             }
         }
+        MijiaEvent::DecodeFailed { id, raw, error } => {
+            if let Some(sensor) = get_mut_sensor_by_id(sensors, &id) {
+                sensor.decode_failures += 1;
+                log::warn!(
+                    "Error decoding value {:02x?} from {}: {}",
+                    raw,
+                    sensor.name,
+                    error
+                );
+                sensor.publish_decode_failures(homie).await?;
+            } else {
+                log::warn!("Error decoding value {:02x?} from {}: {}", raw, id, error);
+            }
+        }
         _ => {}
     };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mijia::mock::MockMijiaSession;
+    use mqtt_test_broker::spawn_mqtt_broker;
+    use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
+    use std::collections::BTreeMap;
+    use tokio::time::timeout;
+
+    const DEVICE_BASE: &str = "homie/mijia-test";
+    /// How long to wait for more messages before assuming that everything has been published.
+    const QUIET_PERIOD: Duration = Duration::from_millis(500);
+    /// How long to wait for the observer to subscribe before failing the test.
+    const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Run the bridge's handling of sensor readings against a mock sensor and an in-process MQTT
+    /// broker, and check the exact Homie topic tree which it publishes.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn publishes_mock_sensor_readings() {
+        let port = spawn_mqtt_broker();
+
+        // Subscribe before the device is created, so that every message is seen.
+        let (client, mut client_event_loop) =
+            AsyncClient::new(MqttOptions::new("test-observer", "localhost", port), 100);
+        client
+            .subscribe(format!("{}/#", DEVICE_BASE), QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        timeout(SUBSCRIBE_TIMEOUT, async {
+            while !matches!(
+                client_event_loop.poll().await.unwrap(),
+                Event::Incoming(Incoming::SubAck(_))
+            ) {}
+        })
+        .await
+        .expect("Timed out waiting to subscribe");
+
+        let mut homie_builder = HomieDevice::builder_for(
+            "homie",
+            "mijia-test",
+            "Test bridge",
+            MqttOptions::new("mijia-homie", "localhost", port),
+        )
+        .unwrap();
+        homie_builder.set_firmware(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        let (homie, homie_handle) = homie_builder.spawn().await.unwrap();
+        tokio::spawn(homie_handle);
+
+        let (mock_handle, session) = MockMijiaSession::with_sensors(1, Duration::from_millis(10));
+        tokio::spawn(mock_handle);
+        let props = session.get_sensors().await.unwrap().remove(0);
+        let mut events = session.event_stream().await.unwrap();
+
+        let mut state = SensorState {
+            sensors: HashMap::new(),
+            homie,
+            min_update_period: Duration::from_secs(0),
+            diagnostics: false,
+            psychrometrics: false,
+            blacklist_after_failures: 0,
+            bridge_unnamed_sensors: false,
+            use_advertised_names: false,
+            alerts: None,
+            rooms: None,
+        };
+        state.homie.ready().await.unwrap();
+        state.add_sensor(
+            props.clone(),
+            "Kitchen".to_owned(),
+            ConnectionStatus::Unknown,
+        );
+        let sensor = state.sensors.get_mut(&props.mac_address).unwrap();
+        sensor
+            .mark_connected(&mut state.homie, props.id.clone())
+            .await
+            .unwrap();
+        session.start_notify_sensor(&props.id).await.unwrap();
+        let state = Arc::new(Mutex::new(state));
+
+        // The mock's readings depend on how many it generated before notifications were started,
+        // so the expected values are taken from the event.
+        let event = events.next().await.unwrap();
+        let readings = match &event {
+            MijiaEvent::Readings { readings, .. } => readings.clone(),
+            _ => panic!("Unexpected event {:?}", event),
+        };
+        handle_mijia_event(state, event).await.unwrap();
+
+        let mut topics = BTreeMap::new();
+        while let Ok(event) = timeout(QUIET_PERIOD, client_event_loop.poll()).await {
+            if let Event::Incoming(Incoming::Publish(publish)) = event.unwrap() {
+                let subtopic = publish.topic[DEVICE_BASE.len() + 1..].to_owned();
+                topics.insert(
+                    subtopic,
+                    String::from_utf8(publish.payload.to_vec()).unwrap(),
+                );
+            }
+        }
+
+        // These depend on the machine and timing rather than the bridge.
+        for subtopic in ["$localip", "$mac", "$stats/uptime"] {
+            assert!(topics.remove(subtopic).is_some(), "Missing {}", subtopic);
+        }
+        let temperature = format!("{:.2}", readings.temperature);
+        let humidity = readings.humidity.to_string();
+        let battery = readings.battery_percent.to_string();
+        let expected: BTreeMap<String, String> = [
+            ("$homie", "4.0"),
+            (
+                "$extensions",
+                "org.homie.legacy-stats:0.1.1:[4.x],org.homie.legacy-firmware:0.1.1:[4.x]",
+            ),
+            ("$fw/name", env!("CARGO_PKG_NAME")),
+            ("$fw/version", env!("CARGO_PKG_VERSION")),
+            ("$implementation", "homie-rs"),
+            ("$name", "Test bridge"),
+            ("$nodes", "A4C138000000"),
+            ("$state", "ready"),
+            ("$stats/interval", "60"),
+            ("A4C138000000/$name", "Kitchen"),
+            ("A4C138000000/$type", "Mijia sensor"),
+            ("A4C138000000/$properties", "temperature,humidity,battery"),
+            ("A4C138000000/temperature", &temperature),
+            ("A4C138000000/temperature/$name", "Temperature"),
+            ("A4C138000000/temperature/$datatype", "float"),
+            ("A4C138000000/temperature/$settable", "false"),
+            ("A4C138000000/temperature/$retained", "true"),
+            ("A4C138000000/temperature/$unit", "ºC"),
+            ("A4C138000000/humidity", &humidity),
+            ("A4C138000000/humidity/$name", "Humidity"),
+            ("A4C138000000/humidity/$datatype", "integer"),
+            ("A4C138000000/humidity/$settable", "false"),
+            ("A4C138000000/humidity/$retained", "true"),
+            ("A4C138000000/humidity/$unit", "%"),
+            ("A4C138000000/battery", &battery),
+            ("A4C138000000/battery/$name", "Battery level"),
+            ("A4C138000000/battery/$datatype", "integer"),
+            ("A4C138000000/battery/$settable", "false"),
+            ("A4C138000000/battery/$retained", "true"),
+            ("A4C138000000/battery/$unit", "%"),
+        ]
+        .iter()
+        .map(|(subtopic, value)| (subtopic.to_string(), value.to_string()))
+        .collect();
+        assert_eq!(topics, expected);
+    }
+}
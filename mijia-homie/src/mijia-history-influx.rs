@@ -101,7 +101,7 @@ fn point_for_record(
     Point::new(measurement)
         .add_timestamp(
             record
-                .time
+                .corrected_time
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap()
                 .as_millis() as i64,
@@ -0,0 +1,70 @@
+//! Quantities derived from temperature and relative humidity, so that consumers of the Homie device
+//! don't need to compute them.
+
+/// Coefficients for the Magnus formula over water, from Sonntag (1990).
+const MAGNUS_A: f64 = 17.62;
+const MAGNUS_B: f64 = 243.12;
+/// The saturation vapour pressure of water at 0 °C, in hPa.
+const SATURATION_PRESSURE_0C: f64 = 6.112;
+/// The specific gas constant for water vapour, in J/(kg·K).
+const WATER_VAPOUR_GAS_CONSTANT: f64 = 461.5;
+const ZERO_CELSIUS_IN_KELVIN: f64 = 273.15;
+
+/// Returns `ln(rh / 100) + a·t / (b + t)`, which both quantities are computed from, or `None` if
+/// the relative humidity is not positive.
+fn magnus_gamma(temperature: f64, relative_humidity: f64) -> Option<f64> {
+    (relative_humidity > 0.0).then(|| {
+        (relative_humidity / 100.0).ln() + MAGNUS_A * temperature / (MAGNUS_B + temperature)
+    })
+}
+
+/// Computes the dew point in °C from the temperature in °C and relative humidity in %.
+///
+/// Returns `None` if the relative humidity is not positive, as there is then no dew point.
+pub fn dew_point(temperature: f64, relative_humidity: f64) -> Option<f64> {
+    let gamma = magnus_gamma(temperature, relative_humidity)?;
+    Some(MAGNUS_B * gamma / (MAGNUS_A - gamma))
+}
+
+/// Computes the absolute humidity in g/m³ from the temperature in °C and relative humidity in %.
+///
+/// Returns `None` if the relative humidity is not positive.
+pub fn absolute_humidity(temperature: f64, relative_humidity: f64) -> Option<f64> {
+    let gamma = magnus_gamma(temperature, relative_humidity)?;
+    // The vapour pressure in Pa, from which the ideal gas law gives the density in kg/m³.
+    let vapour_pressure = SATURATION_PRESSURE_0C * 100.0 * gamma.exp();
+    let density =
+        vapour_pressure / (WATER_VAPOUR_GAS_CONSTANT * (temperature + ZERO_CELSIUS_IN_KELVIN));
+    Some(density * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.unwrap();
+        assert!(
+            (actual - expected).abs() < 0.05,
+            "{} is not close to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn dew_point_values() {
+        assert_close(dew_point(20.0, 100.0), 20.0);
+        assert_close(dew_point(20.0, 50.0), 9.26);
+        assert_close(dew_point(-5.0, 80.0), -7.9);
+        assert_eq!(dew_point(20.0, 0.0), None);
+    }
+
+    #[test]
+    fn absolute_humidity_values() {
+        assert_close(absolute_humidity(20.0, 100.0), 17.24);
+        assert_close(absolute_humidity(20.0, 50.0), 8.62);
+        assert_close(absolute_humidity(0.0, 100.0), 4.85);
+        assert_eq!(absolute_humidity(20.0, -1.0), None);
+    }
+}
@@ -0,0 +1,329 @@
+//! Synthetic nodes for rooms with several sensors, publishing the average temperature and humidity
+//! and the lowest battery level of the sensors in each room.
+
+use crate::config::RoomsConfig;
+use crate::{Sensor, SensorState};
+use eyre::{bail, Report};
+use homie_device::{slugify, Node, Property};
+use mijia::bluetooth::MacAddress;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time;
+
+const PROPERTY_ID_SENSORS: &str = "sensors";
+
+/// The values of the properties of a room, aggregated from the recent readings of its sensors.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Aggregates {
+    temperature: Option<f64>,
+    humidity: Option<f64>,
+    battery: Option<f64>,
+    /// The number of sensors in the room which have sent any readings recently.
+    sensors: usize,
+}
+
+/// A room with several sensors.
+#[derive(Debug)]
+struct Room {
+    name: String,
+    node_id: String,
+    /// The latest value of each property from each sensor in the room, and when it was received.
+    latest: HashMap<(MacAddress, &'static str), (f64, Instant)>,
+}
+
+impl Room {
+    /// Aggregate the values of each property from sensors which have sent it within `stale_after`
+    /// of `now`. Properties which no sensor has sent recently are `None`.
+    fn aggregate(&self, now: Instant, stale_after: Duration) -> Aggregates {
+        let fresh: Vec<_> = self
+            .latest
+            .iter()
+            .filter(|(_, (_, received))| now.duration_since(*received) <= stale_after)
+            .map(|(&(mac_address, property), &(value, _))| (mac_address, property, value))
+            .collect();
+        let values = |property_id: &'static str| {
+            fresh
+                .iter()
+                .filter(move |(_, property, _)| *property == property_id)
+                .map(|(_, _, value)| *value)
+        };
+        let mean = |property_id: &'static str| {
+            let (sum, count) =
+                values(property_id).fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+            (count > 0).then(|| sum / f64::from(count))
+        };
+        let mut sensors: Vec<MacAddress> = fresh.iter().map(|(mac, _, _)| *mac).collect();
+        sensors.sort();
+        sensors.dedup();
+        Aggregates {
+            temperature: mean(Sensor::PROPERTY_ID_TEMPERATURE),
+            humidity: mean(Sensor::PROPERTY_ID_HUMIDITY),
+            battery: values(Sensor::PROPERTY_ID_BATTERY).reduce(f64::min),
+            sensors: sensors.len(),
+        }
+    }
+
+    fn as_node(&self) -> Node {
+        Node::new(
+            &self.node_id,
+            &self.name,
+            "Room",
+            vec![
+                Property::float(
+                    Sensor::PROPERTY_ID_TEMPERATURE,
+                    "Average temperature",
+                    false,
+                    true,
+                    Some("ºC"),
+                    None,
+                ),
+                Property::float(
+                    Sensor::PROPERTY_ID_HUMIDITY,
+                    "Average humidity",
+                    false,
+                    true,
+                    Some("%"),
+                    None,
+                ),
+                Property::integer(
+                    Sensor::PROPERTY_ID_BATTERY,
+                    "Lowest battery level",
+                    false,
+                    true,
+                    Some("%"),
+                    None,
+                ),
+                Property::integer(
+                    PROPERTY_ID_SENSORS,
+                    "Sensors reporting",
+                    false,
+                    true,
+                    None,
+                    None,
+                ),
+            ],
+        )
+    }
+}
+
+/// Aggregates readings from the sensors in each configured room.
+#[derive(Debug)]
+pub struct Rooms {
+    rooms: Vec<Room>,
+    /// The index in `rooms` of the room which each sensor is in.
+    sensor_rooms: HashMap<MacAddress, usize>,
+    update_interval: Duration,
+    stale_after: Duration,
+}
+
+impl Rooms {
+    pub fn new(config: RoomsConfig) -> Result<Self, Report> {
+        if config.update_interval.is_zero() {
+            bail!("Room update_interval_seconds must be greater than 0");
+        }
+        let mut rooms: Vec<Room> = vec![];
+        let mut sensor_rooms = HashMap::new();
+        let mut members: Vec<_> = config.members.into_iter().collect();
+        members.sort();
+        for (name, mac_addresses) in members {
+            let node_id = format!("room-{}", slugify(&name));
+            if node_id == "room-" {
+                bail!("Room name '{}' must contain a letter or digit", name);
+            }
+            if let Some(other) = rooms.iter().find(|room| room.node_id == node_id) {
+                bail!(
+                    "Rooms '{}' and '{}' would have the same node ID",
+                    other.name,
+                    name
+                );
+            }
+            for mac_address in mac_addresses {
+                let mac_address: MacAddress = mac_address.parse()?;
+                if sensor_rooms.insert(mac_address, rooms.len()).is_some() {
+                    bail!("Sensor {} is in more than one room", mac_address);
+                }
+            }
+            rooms.push(Room {
+                name,
+                node_id,
+                latest: HashMap::new(),
+            });
+        }
+        Ok(Self {
+            rooms,
+            sensor_rooms,
+            update_interval: config.update_interval,
+            stale_after: config.stale_after,
+        })
+    }
+
+    /// The nodes to add to the Homie device for the rooms.
+    pub fn nodes(&self) -> Vec<Node> {
+        self.rooms.iter().map(Room::as_node).collect()
+    }
+
+    /// Record the given values of properties of a sensor, if it is in a room.
+    pub fn record(&mut self, mac_address: MacAddress, values: &[(&'static str, f64)]) {
+        self.record_at(mac_address, values, Instant::now());
+    }
+
+    fn record_at(&mut self, mac_address: MacAddress, values: &[(&'static str, f64)], now: Instant) {
+        if let Some(&index) = self.sensor_rooms.get(&mac_address) {
+            let latest = &mut self.rooms[index].latest;
+            for &(property, value) in values {
+                latest.insert((mac_address, property), (value, now));
+            }
+        }
+    }
+}
+
+/// Periodically publish the aggregated values for each room. Properties for which no sensor in the
+/// room has sent a value recently are left unchanged, but the number of sensors reporting is always
+/// updated, so that stale values can be recognised.
+pub async fn rooms_loop(state: Arc<Mutex<SensorState>>) -> Result<(), Report> {
+    let Some(update_interval) = state
+        .lock()
+        .await
+        .rooms
+        .as_ref()
+        .map(|rooms| rooms.update_interval)
+    else {
+        return Ok(());
+    };
+    let mut interval = time::interval(update_interval);
+    loop {
+        interval.tick().await;
+        let state = &*state.lock().await;
+        let Some(rooms) = &state.rooms else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        for room in &rooms.rooms {
+            let aggregates = room.aggregate(now, rooms.stale_after);
+            let homie = &state.homie;
+            if let Some(temperature) = aggregates.temperature {
+                homie
+                    .publish_value(
+                        &room.node_id,
+                        Sensor::PROPERTY_ID_TEMPERATURE,
+                        format!("{:.2}", temperature),
+                    )
+                    .await?;
+            }
+            if let Some(humidity) = aggregates.humidity {
+                homie
+                    .publish_value(
+                        &room.node_id,
+                        Sensor::PROPERTY_ID_HUMIDITY,
+                        format!("{:.1}", humidity),
+                    )
+                    .await?;
+            }
+            if let Some(battery) = aggregates.battery {
+                homie
+                    .publish_value(&room.node_id, Sensor::PROPERTY_ID_BATTERY, battery.round())
+                    .await?;
+            }
+            homie
+                .publish_value(&room.node_id, PROPERTY_ID_SENSORS, aggregates.sensors)
+                .await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac_address(last: u8) -> MacAddress {
+        format!("A4:C1:38:00:00:{:02X}", last).parse().unwrap()
+    }
+
+    fn rooms() -> Rooms {
+        Rooms::new(RoomsConfig {
+            members: HashMap::from([
+                (
+                    "Living room".to_owned(),
+                    vec![mac_address(1).to_string(), mac_address(2).to_string()],
+                ),
+                ("Kitchen".to_owned(), vec![mac_address(3).to_string()]),
+            ]),
+            stale_after: Duration::from_secs(600),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn invalid_config() {
+        let config = |members: &[(&str, &[u8])]| RoomsConfig {
+            members: members
+                .iter()
+                .map(|(name, macs)| {
+                    let macs = macs.iter().map(|&mac| mac_address(mac).to_string());
+                    (name.to_string(), macs.collect())
+                })
+                .collect(),
+            ..Default::default()
+        };
+        assert!(Rooms::new(config(&[("Kitchen", &[1]), ("Hall", &[1])])).is_err());
+        assert!(Rooms::new(config(&[("Kitchen", &[1]), ("kitchen", &[2])])).is_err());
+        assert!(Rooms::new(config(&[("???", &[1])])).is_err());
+        assert!(Rooms::new(config(&[("Kitchen", &[1]), ("Hall", &[2])])).is_ok());
+    }
+
+    #[test]
+    fn nodes() {
+        let node_ids: Vec<_> = rooms().nodes().into_iter().map(|node| node.id).collect();
+        assert_eq!(node_ids, vec!["room-kitchen", "room-living-room"]);
+    }
+
+    #[test]
+    fn aggregates_fresh_values() {
+        let mut rooms = rooms();
+        let start = Instant::now();
+        rooms.record_at(
+            mac_address(1),
+            &[("temperature", 20.0), ("humidity", 40.0), ("battery", 80.0)],
+            start,
+        );
+        rooms.record_at(
+            mac_address(2),
+            &[("temperature", 22.0), ("battery", 30.0)],
+            start + Duration::from_secs(300),
+        );
+        // Sensors which aren't in any room are ignored.
+        rooms.record_at(mac_address(9), &[("temperature", 100.0)], start);
+
+        let living_room = &rooms.rooms[1];
+        assert_eq!(living_room.name, "Living room");
+        assert_eq!(
+            living_room.aggregate(start + Duration::from_secs(300), rooms.stale_after),
+            Aggregates {
+                temperature: Some(21.0),
+                humidity: Some(40.0),
+                battery: Some(30.0),
+                sensors: 2,
+            }
+        );
+
+        // The first sensor's values are now stale, so only the second sensor counts.
+        assert_eq!(
+            living_room.aggregate(start + Duration::from_secs(700), rooms.stale_after),
+            Aggregates {
+                temperature: Some(22.0),
+                humidity: None,
+                battery: Some(30.0),
+                sensors: 1,
+            }
+        );
+
+        // No sensor in the kitchen has sent anything.
+        assert_eq!(
+            rooms.rooms[0].aggregate(start, rooms.stale_after),
+            Aggregates::default()
+        );
+    }
+}
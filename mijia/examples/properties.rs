@@ -36,10 +36,12 @@ async fn main() -> Result<(), Report> {
             let comfort_level = session.get_comfort_level(&sensor.id).await?;
             let history_range = session.get_history_range(&sensor.id).await?;
             let last_record = session.get_last_history_record(&sensor.id).await?;
+            let device_information = session.get_device_information(&sensor.id).await?;
             println!(
                 "Time: {}, Unit: {}, Comfort level: {}, Range: {:?} Last value: {}",
                 sensor_time, temperature_unit, comfort_level, history_range, last_record
             );
+            println!("{}", device_information);
             let history = session.get_all_history(&sensor.id).await?;
             println!("History: {:?}", history);
         }
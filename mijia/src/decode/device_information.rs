@@ -0,0 +1,74 @@
+use crate::decode::DecodeError;
+use std::fmt::{self, Display, Formatter};
+
+/// The contents of the standard Bluetooth Device Information Service of a sensor.
+///
+/// Each field is `None` if the sensor doesn't expose the corresponding characteristic.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceInformation {
+    /// The name of the manufacturer of the sensor.
+    pub manufacturer_name: Option<String>,
+    /// The model number of the sensor, e.g. "LYWSD03MMC".
+    pub model_number: Option<String>,
+    /// The serial number of the sensor.
+    pub serial_number: Option<String>,
+    /// The firmware revision running on the sensor.
+    pub firmware_revision: Option<String>,
+    /// The hardware revision of the sensor.
+    pub hardware_revision: Option<String>,
+    /// The software revision running on the sensor.
+    pub software_revision: Option<String>,
+}
+
+impl Display for DeviceInformation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Manufacturer: {} Model: {} Serial: {} Firmware: {} Hardware: {} Software: {}",
+            self.manufacturer_name.as_deref().unwrap_or("?"),
+            self.model_number.as_deref().unwrap_or("?"),
+            self.serial_number.as_deref().unwrap_or("?"),
+            self.firmware_revision.as_deref().unwrap_or("?"),
+            self.hardware_revision.as_deref().unwrap_or("?"),
+            self.software_revision.as_deref().unwrap_or("?"),
+        )
+    }
+}
+
+/// Decode a UTF-8 string characteristic value, ignoring any trailing NUL padding.
+pub(crate) fn decode_string(value: &[u8]) -> Result<String, DecodeError> {
+    let end = value
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |index| index + 1);
+    String::from_utf8(value[..end].to_vec())
+        .map_err(|e| DecodeError::InvalidValue(format!("Invalid UTF-8 string: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_empty() {
+        assert_eq!(decode_string(&[]), Ok("".to_owned()));
+    }
+
+    #[test]
+    fn decode_valid() {
+        assert_eq!(decode_string(b"LYWSD03MMC"), Ok("LYWSD03MMC".to_owned()));
+    }
+
+    #[test]
+    fn decode_nul_padded() {
+        assert_eq!(decode_string(b"B1.4\0\0"), Ok("B1.4".to_owned()));
+    }
+
+    #[test]
+    fn decode_invalid_utf8() {
+        assert!(matches!(
+            decode_string(&[0x41, 0xff]),
+            Err(DecodeError::InvalidValue(_))
+        ));
+    }
+}
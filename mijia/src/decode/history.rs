@@ -1,5 +1,6 @@
 use crate::decode::time::decode_time;
 use crate::decode::{check_length, DecodeError};
+use crate::SignedDuration;
 use std::convert::TryInto;
 use std::fmt::{self, Display, Formatter};
 use std::ops::Range;
@@ -23,8 +24,13 @@ pub(crate) fn decode_range(value: &[u8]) -> Result<Range<u32>, DecodeError> {
 pub struct HistoryRecord {
     /// The index of the record.
     pub index: u32,
-    /// The time at which the record was created.
+    /// The time at which the record was created, according to the sensor's clock.
     pub time: SystemTime,
+    /// The time at which the record was created, corrected for the offset between the sensor's
+    /// clock and the system clock when the record was read. Records from `MijiaSession` are
+    /// corrected automatically, but for records created otherwise this is the same as `time` unless
+    /// they have been corrected with `correct_time`.
+    pub corrected_time: SystemTime,
     /// Minimum temperature in ºC, with 1 decimal place of precision
     pub temperature_min: f32,
     /// Maximum temperature in ºC, with 1 decimal place of precision
@@ -49,12 +55,19 @@ impl HistoryRecord {
         Ok(HistoryRecord {
             index,
             time,
+            corrected_time: time,
             temperature_min,
             temperature_max,
             humidity_min,
             humidity_max,
         })
     }
+
+    /// Sets `corrected_time` based on the given offset of the system clock from the sensor's clock,
+    /// as returned by `MijiaSession::get_clock_offset`.
+    pub fn correct_time(&mut self, clock_offset: &SignedDuration) {
+        self.corrected_time = clock_offset.add_to(self.time);
+    }
 }
 
 impl Display for HistoryRecord {
@@ -122,6 +135,7 @@ mod tests {
             HistoryRecord {
                 index: 329,
                 time: SystemTime::UNIX_EPOCH + Duration::from_secs(1582632000),
+                corrected_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1582632000),
                 temperature_min: 21.3,
                 temperature_max: 22.1,
                 humidity_min: 60,
@@ -129,4 +143,29 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn correct_time() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1582632000);
+        let mut record = HistoryRecord {
+            index: 329,
+            time,
+            corrected_time: time,
+            temperature_min: 21.3,
+            temperature_max: 22.1,
+            humidity_min: 60,
+            humidity_max: 67,
+        };
+
+        record.correct_time(&Duration::from_secs(30).into());
+        assert_eq!(record.time, time);
+        assert_eq!(record.corrected_time, time + Duration::from_secs(30));
+
+        record.correct_time(&SignedDuration {
+            positive: false,
+            duration: Duration::from_secs(5),
+        });
+        assert_eq!(record.time, time);
+        assert_eq!(record.corrected_time, time - Duration::from_secs(5));
+    }
 }
@@ -1,10 +1,12 @@
 pub mod comfort_level;
+pub mod device_information;
 pub mod history;
 pub mod readings;
 pub mod temperature_unit;
 pub mod time;
+pub mod trigger;
 
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
 const TEMPERATURE_MAX: f32 = i16::MAX as f32 * 0.01;
@@ -33,6 +35,9 @@ pub enum EncodeError {
     /// The time value given is out of the range which can be encoded.
     #[error("Time {0:?} out of range.")]
     TimeOutOfRange(SystemTime),
+    /// The duration value given is out of the range which can be encoded.
+    #[error("Duration {0:?} out of range.")]
+    DurationOutOfRange(Duration),
 }
 
 fn decode_temperature(bytes: [u8; 2]) -> f32 {
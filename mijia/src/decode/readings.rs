@@ -2,6 +2,23 @@ use crate::decode::{check_length, decode_temperature, DecodeError};
 use std::cmp::max;
 use std::convert::TryInto;
 use std::fmt::{self, Display, Formatter};
+use std::ops::RangeInclusive;
+
+/// The range of temperatures in ºC which the sensor can measure. Values outside this are probably
+/// corrupt.
+const TEMPERATURE_RANGE: RangeInclusive<f32> = -40.0..=85.0;
+/// The range of percent humidity which makes sense.
+const HUMIDITY_RANGE: RangeInclusive<u8> = 0..=100;
+/// The range of battery voltages in millivolts which a coin cell could give.
+const BATTERY_VOLTAGE_RANGE: RangeInclusive<u16> = 1..=3600;
+
+/// One of the values in a set of readings from a Mijia sensor.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReadingsField {
+    Temperature,
+    Humidity,
+    BatteryVoltage,
+}
 
 /// A set of readings from a Mijia sensor.
 #[derive(Clone, Debug, PartialEq)]
@@ -14,6 +31,9 @@ pub struct Readings {
     pub battery_voltage: u16,
     /// Inferred from `battery_voltage` with a bit of hand-waving.
     pub battery_percent: u16,
+    /// Any values which were decoded but are outside the range which the sensor can measure, so
+    /// are probably corrupt and should be ignored. The other values may still be used.
+    pub out_of_range: Vec<ReadingsField>,
 }
 
 impl Display for Readings {
@@ -22,14 +42,23 @@ impl Display for Readings {
             f,
             "Temperature: {:.2}ºC Humidity: {:?}% Battery: {:?} mV ({:?}%)",
             self.temperature, self.humidity, self.battery_voltage, self.battery_percent
-        )
+        )?;
+        if !self.out_of_range.is_empty() {
+            write!(f, " Out of range: {:?}", self.out_of_range)?;
+        }
+        Ok(())
     }
 }
 
 impl Readings {
+    /// Returns whether the given value was within the range which the sensor can measure.
+    pub fn is_valid(&self, field: ReadingsField) -> bool {
+        !self.out_of_range.contains(&field)
+    }
+
     /// Decode the readings from the raw bytes of the Bluetooth characteristic value, if they are
-    /// valid.
-    /// Returns `None` if the value is not valid.
+    /// the right length. Values which are out of range are flagged in `out_of_range` rather than
+    /// failing the whole decode.
     pub(crate) fn decode(value: &[u8]) -> Result<Readings, DecodeError> {
         check_length(value.len(), 5)?;
 
@@ -39,11 +68,22 @@ impl Readings {
         let humidity = value[2];
         let battery_voltage = u16::from_le_bytes(value[3..5].try_into().unwrap());
         let battery_percent = (max(battery_voltage, 2100) - 2100) / 10;
+        let mut out_of_range = vec![];
+        if !TEMPERATURE_RANGE.contains(&temperature) {
+            out_of_range.push(ReadingsField::Temperature);
+        }
+        if !HUMIDITY_RANGE.contains(&humidity) {
+            out_of_range.push(ReadingsField::Humidity);
+        }
+        if !BATTERY_VOLTAGE_RANGE.contains(&battery_voltage) {
+            out_of_range.push(ReadingsField::BatteryVoltage);
+        }
         Ok(Readings {
             temperature,
             humidity,
             battery_voltage,
             battery_percent,
+            out_of_range,
         })
     }
 }
@@ -93,8 +133,21 @@ mod tests {
                 temperature: 5.13,
                 humidity: 3,
                 battery_voltage: 2564,
-                battery_percent: 46
+                battery_percent: 46,
+                out_of_range: vec![],
             })
         );
     }
+
+    #[test]
+    fn decode_out_of_range() {
+        let readings = Readings::decode(&[0x10, 0x08, 200, 0, 0]).unwrap();
+        assert_eq!(readings.temperature, 20.64);
+        assert_eq!(
+            readings.out_of_range,
+            vec![ReadingsField::Humidity, ReadingsField::BatteryVoltage]
+        );
+        assert!(readings.is_valid(ReadingsField::Temperature));
+        assert!(!readings.is_valid(ReadingsField::Humidity));
+    }
 }
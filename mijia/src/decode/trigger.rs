@@ -0,0 +1,315 @@
+use crate::decode::{
+    check_length, decode_temperature, encode_temperature, DecodeError, EncodeError,
+};
+use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+/// The pvvx command ID to get or set the trigger configuration.
+pub(crate) const TRIGGER_COMMAND: u8 = 0x44;
+
+const FLAG_REED_SWITCH: u8 = 0x01;
+const FLAG_OUTPUT: u8 = 0x02;
+const FLAG_TRIGGER_ON: u8 = 0x04;
+const FLAG_TEMPERATURE_TRIGGERED: u8 = 0x08;
+const FLAG_HUMIDITY_TRIGGERED: u8 = 0x10;
+const FLAG_REED_SWITCH_2: u8 = 0x20;
+
+/// What a reed switch (RDS) input on a sensor with pvvx firmware is used for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReedSwitchMode {
+    /// The input is not used.
+    None,
+    /// The input is reported as a switch which is either open or closed.
+    Switch,
+    /// The input is used to count pulses.
+    Counter,
+    /// The input is used to trigger a connection.
+    Connect,
+}
+
+impl ReedSwitchMode {
+    fn decode(value: u8) -> Result<Self, DecodeError> {
+        match value {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Switch),
+            2 => Ok(Self::Counter),
+            3 => Ok(Self::Connect),
+            _ => Err(DecodeError::InvalidValue(format!(
+                "Invalid reed switch mode {:#04x}",
+                value
+            ))),
+        }
+    }
+
+    fn encode(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Switch => 1,
+            Self::Counter => 2,
+            Self::Connect => 3,
+        }
+    }
+}
+
+/// The current state of the trigger output and reed switch inputs of a sensor with pvvx firmware.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TriggerState {
+    /// The state of the first reed switch input.
+    pub reed_switch: bool,
+    /// The state of the trigger output pin.
+    pub output: bool,
+    /// Whether the trigger output is controlled by the temperature and humidity thresholds. If this
+    /// is false then the output can be set directly.
+    pub trigger_on: bool,
+    /// Whether the temperature threshold has been crossed.
+    pub temperature_triggered: bool,
+    /// Whether the humidity threshold has been crossed.
+    pub humidity_triggered: bool,
+    /// The state of the second reed switch input.
+    pub reed_switch_2: bool,
+}
+
+impl TriggerState {
+    fn decode(flags: u8) -> Self {
+        Self {
+            reed_switch: flags & FLAG_REED_SWITCH != 0,
+            output: flags & FLAG_OUTPUT != 0,
+            trigger_on: flags & FLAG_TRIGGER_ON != 0,
+            temperature_triggered: flags & FLAG_TEMPERATURE_TRIGGERED != 0,
+            humidity_triggered: flags & FLAG_HUMIDITY_TRIGGERED != 0,
+            reed_switch_2: flags & FLAG_REED_SWITCH_2 != 0,
+        }
+    }
+
+    fn encode(&self) -> u8 {
+        let mut flags = 0;
+        for (set, flag) in [
+            (self.reed_switch, FLAG_REED_SWITCH),
+            (self.output, FLAG_OUTPUT),
+            (self.trigger_on, FLAG_TRIGGER_ON),
+            (self.temperature_triggered, FLAG_TEMPERATURE_TRIGGERED),
+            (self.humidity_triggered, FLAG_HUMIDITY_TRIGGERED),
+            (self.reed_switch_2, FLAG_REED_SWITCH_2),
+        ] {
+            if set {
+                flags |= flag;
+            }
+        }
+        flags
+    }
+}
+
+impl Display for TriggerState {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Output: {} (trigger {}) Temperature: {} Humidity: {} Reed switches: {}/{}",
+            on_off(self.output),
+            on_off(self.trigger_on),
+            on_off(self.temperature_triggered),
+            on_off(self.humidity_triggered),
+            on_off(self.reed_switch),
+            on_off(self.reed_switch_2),
+        )
+    }
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Configuration of the trigger output and reed switch inputs of a sensor with
+/// [pvvx firmware](https://github.com/pvvx/ATC_MiThermometer).
+#[derive(Clone, Debug, PartialEq)]
+pub struct TriggerConfig {
+    /// Temperature threshold in ºC, with 2 decimal places of precision.
+    pub temperature_threshold: f32,
+    /// Percent humidity threshold, with 2 decimal places of precision.
+    pub humidity_threshold: f32,
+    /// Temperature hysteresis in ºC, with 2 decimal places of precision. A positive value turns
+    /// the output on above the threshold, a negative value turns it on below the threshold, and 0
+    /// disables the temperature trigger.
+    pub temperature_hysteresis: f32,
+    /// Percent humidity hysteresis, with 2 decimal places of precision. The sign has the same
+    /// meaning as for `temperature_hysteresis`.
+    pub humidity_hysteresis: f32,
+    /// How often to report the state of the reed switches, with a precision of 1 second.
+    pub reed_switch_report_interval: Duration,
+    /// What the first reed switch input is used for.
+    pub reed_switch_mode: ReedSwitchMode,
+    /// What the second reed switch input is used for.
+    pub reed_switch_2_mode: ReedSwitchMode,
+    /// The current trigger state. When writing the config only `trigger_on`, and `output` if
+    /// `trigger_on` is false, have any effect.
+    pub state: TriggerState,
+}
+
+impl TriggerConfig {
+    const LENGTH: usize = 14;
+
+    /// Decodes a response to the trigger command, which is the command ID followed by the config.
+    pub(crate) fn decode(value: &[u8]) -> Result<TriggerConfig, DecodeError> {
+        check_length(value.len(), Self::LENGTH)?;
+        if value[0] != TRIGGER_COMMAND {
+            return Err(DecodeError::InvalidValue(format!(
+                "Expected trigger command {:#04x} but got {:#04x}",
+                TRIGGER_COMMAND, value[0]
+            )));
+        }
+
+        Ok(TriggerConfig {
+            temperature_threshold: decode_temperature(value[1..3].try_into().unwrap()),
+            humidity_threshold: decode_temperature(value[3..5].try_into().unwrap()),
+            temperature_hysteresis: decode_temperature(value[5..7].try_into().unwrap()),
+            humidity_hysteresis: decode_temperature(value[7..9].try_into().unwrap()),
+            reed_switch_report_interval: Duration::from_secs(
+                u16::from_le_bytes(value[9..11].try_into().unwrap()).into(),
+            ),
+            reed_switch_mode: ReedSwitchMode::decode(value[11])?,
+            reed_switch_2_mode: ReedSwitchMode::decode(value[12])?,
+            state: TriggerState::decode(value[13]),
+        })
+    }
+
+    /// Encodes the trigger command to set this config.
+    pub(crate) fn encode(&self) -> Result<[u8; Self::LENGTH], EncodeError> {
+        let mut bytes = [0; Self::LENGTH];
+        bytes[0] = TRIGGER_COMMAND;
+        bytes[1..3].copy_from_slice(&encode_temperature(self.temperature_threshold)?);
+        bytes[3..5].copy_from_slice(&encode_temperature(self.humidity_threshold)?);
+        bytes[5..7].copy_from_slice(&encode_temperature(self.temperature_hysteresis)?);
+        bytes[7..9].copy_from_slice(&encode_temperature(self.humidity_hysteresis)?);
+        let report_interval: u16 = self
+            .reed_switch_report_interval
+            .as_secs()
+            .try_into()
+            .map_err(|_| EncodeError::DurationOutOfRange(self.reed_switch_report_interval))?;
+        bytes[9..11].copy_from_slice(&report_interval.to_le_bytes());
+        bytes[11] = self.reed_switch_mode.encode();
+        bytes[12] = self.reed_switch_2_mode.encode();
+        bytes[13] = self.state.encode();
+        Ok(bytes)
+    }
+}
+
+impl Display for TriggerConfig {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Temperature: {:.2}ºC ±{:.2} Humidity: {:.2}% ±{:.2} Reed switches: {:?}/{:?} every {:?} {}",
+            self.temperature_threshold,
+            self.temperature_hysteresis,
+            self.humidity_threshold,
+            self.humidity_hysteresis,
+            self.reed_switch_mode,
+            self.reed_switch_2_mode,
+            self.reed_switch_report_interval,
+            self.state,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_wrong_length() {
+        assert_eq!(
+            TriggerConfig::decode(&[TRIGGER_COMMAND, 0x01, 0x02]),
+            Err(DecodeError::WrongLength {
+                length: 3,
+                expected_length: 14
+            })
+        );
+    }
+
+    #[test]
+    fn decode_wrong_command() {
+        assert!(matches!(
+            TriggerConfig::decode(&[0x33; 14]),
+            Err(DecodeError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn decode_valid() {
+        assert_eq!(
+            TriggerConfig::decode(&[
+                0x44, 0xc4, 0x09, 0x88, 0x13, 0x32, 0x00, 0x9c, 0xff, 0x3c, 0x00, 0x01, 0x00, 0x0d
+            ])
+            .unwrap(),
+            TriggerConfig {
+                temperature_threshold: 25.0,
+                humidity_threshold: 50.0,
+                temperature_hysteresis: 0.5,
+                humidity_hysteresis: -1.0,
+                reed_switch_report_interval: Duration::from_secs(60),
+                reed_switch_mode: ReedSwitchMode::Switch,
+                reed_switch_2_mode: ReedSwitchMode::None,
+                state: TriggerState {
+                    reed_switch: true,
+                    output: false,
+                    trigger_on: true,
+                    temperature_triggered: true,
+                    humidity_triggered: false,
+                    reed_switch_2: false,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn decode_invalid_mode() {
+        assert!(matches!(
+            TriggerConfig::decode(&[
+                0x44, 0xc4, 0x09, 0x88, 0x13, 0x32, 0x00, 0x9c, 0xff, 0x3c, 0x00, 0x07, 0x00, 0x0d
+            ]),
+            Err(DecodeError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn encode_decode() {
+        let config = TriggerConfig {
+            temperature_threshold: -5.5,
+            humidity_threshold: 80.0,
+            temperature_hysteresis: -0.25,
+            humidity_hysteresis: 2.0,
+            reed_switch_report_interval: Duration::from_secs(3600),
+            reed_switch_mode: ReedSwitchMode::Counter,
+            reed_switch_2_mode: ReedSwitchMode::Connect,
+            state: TriggerState {
+                output: true,
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            TriggerConfig::decode(&config.encode().unwrap()).unwrap(),
+            config
+        );
+    }
+
+    #[test]
+    fn encode_interval_out_of_range() {
+        let config = TriggerConfig {
+            temperature_threshold: 0.0,
+            humidity_threshold: 0.0,
+            temperature_hysteresis: 0.0,
+            humidity_hysteresis: 0.0,
+            reed_switch_report_interval: Duration::from_secs(100_000),
+            reed_switch_mode: ReedSwitchMode::None,
+            reed_switch_2_mode: ReedSwitchMode::None,
+            state: TriggerState::default(),
+        };
+        assert!(matches!(
+            config.encode(),
+            Err(EncodeError::DurationOutOfRange(_))
+        ));
+    }
+}
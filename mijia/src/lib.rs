@@ -8,26 +8,36 @@
 
 pub use bluez_async as bluetooth;
 use bluez_async::{
-    BluetoothError, BluetoothEvent, BluetoothSession, CharacteristicEvent, DeviceEvent, DeviceId,
-    DeviceInfo, MacAddress, SpawnError,
+    uuid_from_u16, BluetoothError, BluetoothEvent, BluetoothSession, CharacteristicEvent,
+    CharacteristicId, DeviceEvent, DeviceId, DeviceInfo, MacAddress, SpawnError,
 };
 use core::future::Future;
 use futures::Stream;
+use std::collections::HashMap;
 use std::ops::Range;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 use thiserror::Error;
 use tokio::pin;
+use tokio::time::interval;
+use tokio_stream::wrappers::IntervalStream;
 use tokio_stream::StreamExt;
 use uuid::Uuid;
 
 mod decode;
+#[cfg(feature = "mock")]
+pub mod mock;
 mod signed_duration;
 pub use decode::comfort_level::ComfortLevel;
+use decode::device_information::decode_string;
+pub use decode::device_information::DeviceInformation;
 use decode::history::decode_range;
 pub use decode::history::HistoryRecord;
-pub use decode::readings::Readings;
+pub use decode::readings::{Readings, ReadingsField};
 pub use decode::temperature_unit::TemperatureUnit;
 use decode::time::{decode_time, encode_time};
+use decode::trigger::TRIGGER_COMMAND;
+pub use decode::trigger::{ReedSwitchMode, TriggerConfig, TriggerState};
 pub use decode::{DecodeError, EncodeError};
 pub use signed_duration::SignedDuration;
 
@@ -52,10 +62,21 @@ const COMFORT_LEVEL_CHARACTERISTIC_UUID: Uuid =
     Uuid::from_u128(0xebe0ccd7_7a0a_4b0c_8a1a_6ff2997da3a6);
 const CONNECTION_INTERVAL_CHARACTERISTIC_UUID: Uuid =
     Uuid::from_u128(0xebe0ccd8_7a0a_4b0c_8a1a_6ff2997da3a6);
+const DEVICE_INFORMATION_SERVICE_UUID: Uuid = uuid_from_u16(0x180a);
+const MODEL_NUMBER_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2a24);
+const SERIAL_NUMBER_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2a25);
+const FIRMWARE_REVISION_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2a26);
+const HARDWARE_REVISION_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2a27);
+const SOFTWARE_REVISION_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2a28);
+const MANUFACTURER_NAME_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2a29);
+/// Custom service and characteristic used by pvvx firmware for commands and their responses.
+const PVVX_SERVICE_UUID: Uuid = uuid_from_u16(0x1f10);
+const PVVX_COMMAND_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x1f1f);
 /// 500 in little-endian
 const CONNECTION_INTERVAL_500_MS: [u8; 3] = [0xF4, 0x01, 0x00];
 const HISTORY_DELETE_VALUE: [u8; 1] = [0x01];
 const HISTORY_RECORD_TIMEOUT: Duration = Duration::from_secs(2);
+const COMMAND_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// An error interacting with a Mijia sensor.
 #[derive(Debug, Error)]
@@ -69,6 +90,9 @@ pub enum MijiaError {
     /// The error was with encoding a value to send to a sensor.
     #[error(transparent)]
     Encoding(#[from] EncodeError),
+    /// The sensor didn't respond to a command in time.
+    #[error("No response from sensor")]
+    NoResponse,
 }
 
 /// The MAC address and opaque connection ID of a Mijia sensor which was discovered.
@@ -91,8 +115,34 @@ pub enum MijiaEvent {
     Readings { id: DeviceId, readings: Readings },
     /// A sensor has sent a new historical record.
     HistoryRecord { id: DeviceId, record: HistoryRecord },
+    /// A sensor with pvvx firmware has sent its trigger configuration and state, either because
+    /// the state changed or in response to a request.
+    Trigger { id: DeviceId, config: TriggerConfig },
+    /// The display settings of a sensor have been changed, possibly by another client. This is
+    /// only sent for sensors subscribed to with `MijiaSession::start_notify_settings`.
+    SettingsChanged { id: DeviceId, setting: Setting },
     /// The Bluetooth connection to a sensor has been lost.
     Disconnected { id: DeviceId },
+    /// BlueZ has reported a new received signal strength for a sensor, in dBm. This can be used to
+    /// correlate dropouts with weak links. BlueZ only reports it when it receives advertisements,
+    /// so for connected sensors use `MijiaSession::event_stream_with_signal_strength` to poll it.
+    SignalStrength { id: DeviceId, rssi: i16 },
+    /// A sensor has sent a value which couldn't be decoded, such as a notification of the wrong
+    /// length. The raw bytes are included for diagnostics.
+    DecodeFailed {
+        id: DeviceId,
+        raw: Vec<u8>,
+        error: DecodeError,
+    },
+}
+
+/// A display setting of a Mijia sensor, with its new value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Setting {
+    /// The temperature unit which the sensor uses for its display.
+    TemperatureUnit(TemperatureUnit),
+    /// The comfort level configuration which determines when the sensor displays a happy face.
+    ComfortLevel(ComfortLevel),
 }
 
 impl MijiaEvent {
@@ -113,21 +163,58 @@ impl MijiaEvent {
                             id: characteristic.service().device(),
                             readings,
                         }),
-                        Err(e) => {
-                            log::error!("Error decoding readings: {:?}", e);
-                            None
-                        }
+                        Err(error) => Some(MijiaEvent::DecodeFailed {
+                            id: characteristic.service().device(),
+                            raw: value,
+                            error,
+                        }),
                     },
                     HISTORY_RECORDS_CHARACTERISTIC_UUID => match HistoryRecord::decode(&value) {
                         Ok(record) => Some(MijiaEvent::HistoryRecord {
                             id: characteristic.service().device(),
                             record,
                         }),
-                        Err(e) => {
-                            log::error!("Error decoding historical record: {:?}", e);
-                            None
-                        }
+                        Err(error) => Some(MijiaEvent::DecodeFailed {
+                            id: characteristic.service().device(),
+                            raw: value,
+                            error,
+                        }),
+                    },
+                    TEMPERATURE_UNIT_CHARACTERISTIC_UUID => match TemperatureUnit::decode(&value) {
+                        Ok(unit) => Some(MijiaEvent::SettingsChanged {
+                            id: characteristic.service().device(),
+                            setting: Setting::TemperatureUnit(unit),
+                        }),
+                        Err(error) => Some(MijiaEvent::DecodeFailed {
+                            id: characteristic.service().device(),
+                            raw: value,
+                            error,
+                        }),
                     },
+                    COMFORT_LEVEL_CHARACTERISTIC_UUID => match ComfortLevel::decode(&value) {
+                        Ok(comfort_level) => Some(MijiaEvent::SettingsChanged {
+                            id: characteristic.service().device(),
+                            setting: Setting::ComfortLevel(comfort_level),
+                        }),
+                        Err(error) => Some(MijiaEvent::DecodeFailed {
+                            id: characteristic.service().device(),
+                            raw: value,
+                            error,
+                        }),
+                    },
+                    PVVX_COMMAND_CHARACTERISTIC_UUID if value.first() == Some(&TRIGGER_COMMAND) => {
+                        match TriggerConfig::decode(&value) {
+                            Ok(config) => Some(MijiaEvent::Trigger {
+                                id: characteristic.service().device(),
+                                config,
+                            }),
+                            Err(error) => Some(MijiaEvent::DecodeFailed {
+                                id: characteristic.service().device(),
+                                raw: value,
+                                error,
+                            }),
+                        }
+                    }
                     _ => {
                         log::trace!(
                             "Got BluetoothEvent::Value for characteristic {:?} with value {:?}",
@@ -191,6 +278,9 @@ pub struct MijiaSession {
     /// The underlying `BluetoothSession`. You can use this for Bluetooth operations which are not
     /// specific to Mijia sensors, such as connecting and disconnecting.
     pub bt_session: BluetoothSession,
+    /// The clock offset of each sensor from which history records have been requested, to correct
+    /// the times of the records when they are received.
+    clock_offsets: Arc<Mutex<HashMap<DeviceId, SignedDuration>>>,
 }
 
 impl MijiaSession {
@@ -200,7 +290,13 @@ impl MijiaSession {
     pub async fn new(
     ) -> Result<(impl Future<Output = Result<(), SpawnError>>, Self), BluetoothError> {
         let (handle, bt_session) = BluetoothSession::new().await?;
-        Ok((handle, MijiaSession { bt_session }))
+        Ok((
+            handle,
+            MijiaSession {
+                bt_session,
+                clock_offsets: Default::default(),
+            },
+        ))
     }
 
     /// Get a list of all Mijia sensors which have currently been discovered.
@@ -242,6 +338,13 @@ impl MijiaSession {
         Ok(decode_time(&value)?)
     }
 
+    /// Get the offset of the system clock from the sensor's clock, i.e. the duration which should be
+    /// added to times from the sensor to get the equivalent system time.
+    pub async fn get_clock_offset(&self, id: &DeviceId) -> Result<SignedDuration, MijiaError> {
+        let sensor_time = self.get_time(id).await?;
+        Ok(SystemTime::now().duration_since(sensor_time).into())
+    }
+
     /// Set the current time of the sensor.
     pub async fn set_time(&self, id: &DeviceId, time: SystemTime) -> Result<(), MijiaError> {
         let time_bytes = encode_time(time)?;
@@ -320,6 +423,220 @@ impl MijiaSession {
             .await?)
     }
 
+    /// Assuming that the given device ID refers to a Mijia sensor device and that it has already
+    /// been connected, subscribe to notifications of changes to its temperature unit and comfort
+    /// level, such as those made by another client.
+    ///
+    /// Notifications will be delivered as `MijiaEvent::SettingsChanged` events by
+    /// `MijiaSession::event_stream()`.
+    pub async fn start_notify_settings(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        for characteristic in self.settings_characteristics(id).await? {
+            self.bt_session.start_notify(&characteristic).await?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe from notifications of changes to the sensor's temperature unit and comfort
+    /// level.
+    pub async fn stop_notify_settings(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        for characteristic in self.settings_characteristics(id).await? {
+            self.bt_session.stop_notify(&characteristic).await?;
+        }
+        Ok(())
+    }
+
+    async fn settings_characteristics(
+        &self,
+        id: &DeviceId,
+    ) -> Result<[CharacteristicId; 2], BluetoothError> {
+        let service = self
+            .bt_session
+            .get_service_by_uuid(id, SERVICE_UUID)
+            .await?;
+        let temperature_unit = self
+            .bt_session
+            .get_characteristic_by_uuid(&service.id, TEMPERATURE_UNIT_CHARACTERISTIC_UUID)
+            .await?;
+        let comfort_level = self
+            .bt_session
+            .get_characteristic_by_uuid(&service.id, COMFORT_LEVEL_CHARACTERISTIC_UUID)
+            .await?;
+        Ok([temperature_unit.id, comfort_level.id])
+    }
+
+    /// Get the trigger output and reed switch configuration of a sensor with
+    /// [pvvx firmware](https://github.com/pvvx/ATC_MiThermometer), along with the current trigger
+    /// state.
+    ///
+    /// The response is received by subscribing to notifications, which are stopped again
+    /// afterwards. Call `MijiaSession::start_notify_trigger` after this if they are wanted.
+    pub async fn get_trigger_config(&self, id: &DeviceId) -> Result<TriggerConfig, MijiaError> {
+        let characteristic = self
+            .bt_session
+            .get_service_characteristic_by_uuid(
+                id,
+                PVVX_SERVICE_UUID,
+                PVVX_COMMAND_CHARACTERISTIC_UUID,
+            )
+            .await?;
+        let events = self
+            .bt_session
+            .characteristic_event_stream(&characteristic.id)
+            .await?;
+        let events = events.timeout(COMMAND_RESPONSE_TIMEOUT);
+        pin!(events);
+        self.bt_session.start_notify(&characteristic.id).await?;
+        self.bt_session
+            .write_characteristic_value(&characteristic.id, [TRIGGER_COMMAND])
+            .await?;
+
+        // The response is sent as a notification.
+        let mut response = None;
+        while let Some(Ok(event)) = events.next().await {
+            if let BluetoothEvent::Characteristic {
+                event: CharacteristicEvent::Value { value },
+                ..
+            } = event
+            {
+                if value.first() == Some(&TRIGGER_COMMAND) {
+                    response = Some(value);
+                    break;
+                }
+            }
+        }
+
+        self.bt_session.stop_notify(&characteristic.id).await?;
+
+        let value = response.ok_or(MijiaError::NoResponse)?;
+        Ok(TriggerConfig::decode(&value)?)
+    }
+
+    /// Set the trigger output and reed switch configuration of a sensor with
+    /// [pvvx firmware](https://github.com/pvvx/ATC_MiThermometer).
+    pub async fn set_trigger_config(
+        &self,
+        id: &DeviceId,
+        config: &TriggerConfig,
+    ) -> Result<(), MijiaError> {
+        let characteristic = self
+            .bt_session
+            .get_service_characteristic_by_uuid(
+                id,
+                PVVX_SERVICE_UUID,
+                PVVX_COMMAND_CHARACTERISTIC_UUID,
+            )
+            .await?;
+        Ok(self
+            .bt_session
+            .write_characteristic_value(&characteristic.id, config.encode()?)
+            .await?)
+    }
+
+    /// Assuming that the given device ID refers to a sensor with pvvx firmware and that it has
+    /// already been connected, subscribe to notifications of its trigger state.
+    ///
+    /// Notifications will be delivered as `MijiaEvent::Trigger` events by
+    /// `MijiaSession::event_stream()`.
+    pub async fn start_notify_trigger(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        let characteristic = self
+            .bt_session
+            .get_service_characteristic_by_uuid(
+                id,
+                PVVX_SERVICE_UUID,
+                PVVX_COMMAND_CHARACTERISTIC_UUID,
+            )
+            .await?;
+        self.bt_session.start_notify(&characteristic.id).await
+    }
+
+    /// Unsubscribe from notifications of the sensor's trigger state.
+    pub async fn stop_notify_trigger(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        let characteristic = self
+            .bt_session
+            .get_service_characteristic_by_uuid(
+                id,
+                PVVX_SERVICE_UUID,
+                PVVX_COMMAND_CHARACTERISTIC_UUID,
+            )
+            .await?;
+        self.bt_session.stop_notify(&characteristic.id).await
+    }
+
+    /// Get the model number, serial number and revisions of the sensor from the standard Device
+    /// Information Service.
+    ///
+    /// Characteristics which the sensor doesn't expose are left as `None`.
+    pub async fn get_device_information(
+        &self,
+        id: &DeviceId,
+    ) -> Result<DeviceInformation, MijiaError> {
+        let service = self
+            .bt_session
+            .get_service_by_uuid(id, DEVICE_INFORMATION_SERVICE_UUID)
+            .await?;
+        let characteristics = self.bt_session.get_characteristics(&service.id).await?;
+
+        let mut device_information = DeviceInformation::default();
+        for characteristic in characteristics {
+            let field = match characteristic.uuid {
+                MANUFACTURER_NAME_CHARACTERISTIC_UUID => &mut device_information.manufacturer_name,
+                MODEL_NUMBER_CHARACTERISTIC_UUID => &mut device_information.model_number,
+                SERIAL_NUMBER_CHARACTERISTIC_UUID => &mut device_information.serial_number,
+                FIRMWARE_REVISION_CHARACTERISTIC_UUID => &mut device_information.firmware_revision,
+                HARDWARE_REVISION_CHARACTERISTIC_UUID => &mut device_information.hardware_revision,
+                SOFTWARE_REVISION_CHARACTERISTIC_UUID => &mut device_information.software_revision,
+                _ => continue,
+            };
+            let value = self
+                .bt_session
+                .read_characteristic_value(&characteristic.id)
+                .await?;
+            *field = Some(decode_string(&value)?);
+        }
+        Ok(device_information)
+    }
+
+    /// Read the raw value of an arbitrary characteristic of the sensor, identified by the UUIDs of
+    /// its service and the characteristic itself.
+    ///
+    /// This is an escape hatch for experimenting with vendor characteristics which this crate
+    /// doesn't otherwise support.
+    pub async fn read_raw(
+        &self,
+        id: &DeviceId,
+        service_uuid: Uuid,
+        characteristic_uuid: Uuid,
+    ) -> Result<Vec<u8>, BluetoothError> {
+        let characteristic = self
+            .bt_session
+            .get_service_characteristic_by_uuid(id, service_uuid, characteristic_uuid)
+            .await?;
+        self.bt_session
+            .read_characteristic_value(&characteristic.id)
+            .await
+    }
+
+    /// Write a raw value to an arbitrary characteristic of the sensor, identified by the UUIDs of
+    /// its service and the characteristic itself.
+    ///
+    /// This is an escape hatch for experimenting with vendor characteristics which this crate
+    /// doesn't otherwise support.
+    pub async fn write_raw(
+        &self,
+        id: &DeviceId,
+        service_uuid: Uuid,
+        characteristic_uuid: Uuid,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<(), BluetoothError> {
+        let characteristic = self
+            .bt_session
+            .get_service_characteristic_by_uuid(id, service_uuid, characteristic_uuid)
+            .await?;
+        self.bt_session
+            .write_characteristic_value(&characteristic.id, value)
+            .await
+    }
+
     /// Get the range of indices for historical data stored on the sensor.
     pub async fn get_history_range(&self, id: &DeviceId) -> Result<Range<u32>, MijiaError> {
         let characteristic = self
@@ -348,11 +665,13 @@ impl MijiaSession {
             .await
     }
 
-    /// Get the last historical record stored on the sensor.
+    /// Get the last historical record stored on the sensor, with its `corrected_time` set based on
+    /// the offset between the sensor's clock and the system clock.
     pub async fn get_last_history_record(
         &self,
         id: &DeviceId,
     ) -> Result<HistoryRecord, MijiaError> {
+        let clock_offset = self.get_clock_offset(id).await?;
         let characteristic = self
             .bt_session
             .get_service_characteristic_by_uuid(
@@ -365,11 +684,17 @@ impl MijiaSession {
             .bt_session
             .read_characteristic_value(&characteristic.id)
             .await?;
-        Ok(HistoryRecord::decode(&value)?)
+        let mut record = HistoryRecord::decode(&value)?;
+        record.correct_time(&clock_offset);
+        Ok(record)
     }
 
     /// Start receiving historical records from the sensor.
     ///
+    /// Records will be delivered as `MijiaEvent::HistoryRecord` events by
+    /// `MijiaSession::event_stream()`, with their `corrected_time` set based on the offset between
+    /// the sensor's clock and the system clock when this was called.
+    ///
     /// # Arguments
     /// * `id`: The ID of the sensor to request records from.
     /// * `start_index`: The record index to start at. If this is not specified then all records
@@ -378,7 +703,13 @@ impl MijiaSession {
         &self,
         id: &DeviceId,
         start_index: Option<u32>,
-    ) -> Result<(), BluetoothError> {
+    ) -> Result<(), MijiaError> {
+        let clock_offset = self.get_clock_offset(id).await?;
+        self.clock_offsets
+            .lock()
+            .unwrap()
+            .insert(id.to_owned(), clock_offset);
+
         let service = self
             .bt_session
             .get_service_by_uuid(id, SERVICE_UUID)
@@ -399,9 +730,10 @@ impl MijiaSession {
                 )
                 .await?
         }
-        self.bt_session
+        Ok(self
+            .bt_session
             .start_notify(&history_records_characteristic.id)
-            .await
+            .await?)
     }
 
     /// Stop receiving historical records from the sensor.
@@ -418,6 +750,9 @@ impl MijiaSession {
     }
 
     /// Try to get all historical records for the sensor.
+    ///
+    /// The `corrected_time` of each record is set based on the offset between the sensor's clock
+    /// and the system clock.
     pub async fn get_all_history(
         &self,
         id: &DeviceId,
@@ -439,6 +774,7 @@ impl MijiaSession {
         let events = events.timeout(HISTORY_RECORD_TIMEOUT);
         pin!(events);
         self.start_notify_history(id, Some(0)).await?;
+        let clock_offset = self.clock_offsets.lock().unwrap()[id].clone();
 
         let mut history = vec![None; history_range.len()];
         while let Some(Ok(event)) = events.next().await {
@@ -447,9 +783,10 @@ impl MijiaSession {
                 event: CharacteristicEvent::Value { value },
             } = event
             {
-                let record = HistoryRecord::decode(&value)?;
+                let mut record = HistoryRecord::decode(&value)?;
                 log::trace!("{:?}: {}", record_id, record);
                 if record_id == history_record_characteristic.id {
+                    record.correct_time(&clock_offset);
                     if history_range.contains(&record.index) {
                         let offset = record.index - history_range.start;
                         history[offset as usize] = Some(record);
@@ -506,16 +843,199 @@ impl MijiaSession {
 
     /// Get a stream of reading/history/disconnected events for all sensors.
     pub async fn event_stream(&self) -> Result<impl Stream<Item = MijiaEvent>, BluetoothError> {
+        let known_sensors = self.known_sensors().await?;
+        self.mijia_events(known_sensors).await
+    }
+
+    /// Get a stream of events for all sensors like `event_stream`, which also polls BlueZ for the
+    /// signal strength of each connected sensor at the given interval and sends it as
+    /// `MijiaEvent::SignalStrength`.
+    ///
+    /// BlueZ doesn't send signal strength updates for connected sensors, as they stop advertising,
+    /// so this reports the value which it last recorded for each of them.
+    pub async fn event_stream_with_signal_strength(
+        &self,
+        poll_interval: Duration,
+    ) -> Result<impl Stream<Item = MijiaEvent>, BluetoothError> {
+        let known_sensors = self.known_sensors().await?;
+        let events = self.mijia_events(known_sensors.clone()).await?;
+        let session = self.bt_session.clone();
+        let polls = IntervalStream::new(interval(poll_interval))
+            .then(move |_| poll_signal_strength(session.clone(), known_sensors.connected()));
+        Ok(Box::pin(events.merge(
+            futures::stream::StreamExt::flat_map(polls, futures::stream::iter),
+        )))
+    }
+
+    /// Get the Mijia sensors which BlueZ currently knows about.
+    async fn known_sensors(&self) -> Result<KnownSensors, BluetoothError> {
+        let known_sensors = KnownSensors::default();
+        for device in self.bt_session.get_devices().await? {
+            if is_mijia_sensor(&device) {
+                known_sensors.add(device.id, device.connected);
+            }
+        }
+        Ok(known_sensors)
+    }
+
+    /// Convert Bluetooth events to Mijia events, keeping track of the known sensors and correcting
+    /// the times of history records along the way.
+    async fn mijia_events(
+        &self,
+        known_sensors: KnownSensors,
+    ) -> Result<impl Stream<Item = MijiaEvent>, BluetoothError> {
         let events = self.bt_session.event_stream().await?;
         let session = self.bt_session.clone();
+        let clock_offsets = self.clock_offsets.clone();
         Ok(Box::pin(futures::stream::StreamExt::filter_map(
             events,
-            move |event| MijiaEvent::from(event, session.clone()),
+            move |event| {
+                let session = session.clone();
+                let known_sensors = known_sensors.clone();
+                let clock_offsets = clock_offsets.clone();
+                async move {
+                    if let Some(event) = known_sensors.record(&event) {
+                        return Some(event);
+                    }
+                    let mut event = MijiaEvent::from(event, session).await;
+                    match &mut event {
+                        Some(MijiaEvent::Discovered { id }) => {
+                            known_sensors.add(id.clone(), false);
+                        }
+                        Some(MijiaEvent::HistoryRecord { id, record }) => {
+                            if let Some(clock_offset) = clock_offsets.lock().unwrap().get(id) {
+                                record.correct_time(clock_offset);
+                            }
+                        }
+                        _ => {}
+                    }
+                    event
+                }
+            },
         )))
     }
 }
 
+/// The devices which are known to be Mijia sensors, and whether each is connected, so that signal
+/// strength updates for other Bluetooth devices can be ignored without asking BlueZ about them.
+#[derive(Clone, Debug, Default)]
+struct KnownSensors {
+    /// Map from the ID of each known sensor to whether it is connected.
+    sensors: Arc<Mutex<HashMap<DeviceId, bool>>>,
+}
+
+impl KnownSensors {
+    fn add(&self, id: DeviceId, connected: bool) {
+        self.sensors.lock().unwrap().entry(id).or_insert(connected);
+    }
+
+    /// Gets the IDs of the known sensors which are currently connected.
+    fn connected(&self) -> Vec<DeviceId> {
+        self.sensors
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, connected)| **connected)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Records whether a known sensor is connected, and converts signal strength updates for known
+    /// sensors to `MijiaEvent::SignalStrength`. Other events are left to `MijiaEvent::from`.
+    fn record(&self, event: &BluetoothEvent) -> Option<MijiaEvent> {
+        let mut sensors = self.sensors.lock().unwrap();
+        match event {
+            BluetoothEvent::Device {
+                id,
+                event: DeviceEvent::Connected { connected },
+            } => {
+                if let Some(sensor_connected) = sensors.get_mut(id) {
+                    *sensor_connected = *connected;
+                }
+                None
+            }
+            BluetoothEvent::Device {
+                id,
+                event: DeviceEvent::Rssi { rssi },
+            } if sensors.contains_key(id) => Some(MijiaEvent::SignalStrength {
+                id: id.clone(),
+                rssi: *rssi,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Get the signal strength which BlueZ last recorded for each of the given sensors.
+async fn poll_signal_strength(session: BluetoothSession, ids: Vec<DeviceId>) -> Vec<MijiaEvent> {
+    let mut events = vec![];
+    for id in ids {
+        match session.get_device_info(&id).await {
+            Ok(device) => {
+                if let Some(rssi) = device.rssi {
+                    events.push(MijiaEvent::SignalStrength { id, rssi });
+                }
+            }
+            Err(e) => log::warn!("Error getting signal strength of {}: {:?}", id, e),
+        }
+    }
+    events
+}
+
 /// Check whether the given Bluetooth device is a Mijia sensor which we support.
 fn is_mijia_sensor(device: &DeviceInfo) -> bool {
     device.name.as_deref() == Some(MIJIA_NAME)
 }
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::mock::mock_device_id;
+
+    #[test]
+    fn signal_strength_only_for_known_sensors() {
+        let sensor = mock_device_id(&"A4:C1:38:00:00:01".parse().unwrap());
+        let other = mock_device_id(&"11:22:33:44:55:66".parse().unwrap());
+        let known_sensors = KnownSensors::default();
+        known_sensors.add(sensor.clone(), false);
+
+        assert!(matches!(
+            known_sensors.record(&BluetoothEvent::Device {
+                id: sensor.clone(),
+                event: DeviceEvent::Rssi { rssi: -72 },
+            }),
+            Some(MijiaEvent::SignalStrength { id, rssi: -72 }) if id == sensor
+        ));
+        assert!(known_sensors
+            .record(&BluetoothEvent::Device {
+                id: other,
+                event: DeviceEvent::Rssi { rssi: -50 },
+            })
+            .is_none());
+    }
+
+    #[test]
+    fn tracks_connected_sensors() {
+        let sensor = mock_device_id(&"A4:C1:38:00:00:01".parse().unwrap());
+        let other = mock_device_id(&"11:22:33:44:55:66".parse().unwrap());
+        let known_sensors = KnownSensors::default();
+        known_sensors.add(sensor.clone(), false);
+        assert_eq!(known_sensors.connected(), vec![]);
+
+        for id in [&sensor, &other] {
+            assert!(known_sensors
+                .record(&BluetoothEvent::Device {
+                    id: id.clone(),
+                    event: DeviceEvent::Connected { connected: true },
+                })
+                .is_none());
+        }
+        assert_eq!(known_sensors.connected(), vec![sensor.clone()]);
+
+        known_sensors.record(&BluetoothEvent::Device {
+            id: sensor,
+            event: DeviceEvent::Connected { connected: false },
+        });
+        assert_eq!(known_sensors.connected(), vec![]);
+    }
+}
@@ -0,0 +1,877 @@
+//! A simulated session for developing and testing without Bluetooth hardware.
+
+use crate::{
+    ComfortLevel, DeviceInformation, HistoryRecord, MijiaError, MijiaEvent, Readings,
+    ReedSwitchMode, SensorProps, Setting, SignedDuration, TemperatureUnit, TriggerConfig,
+    TriggerState, MIJIA_NAME, SERVICE_UUID,
+};
+use bluez_async::{BluetoothError, DeviceId, MacAddress, SpawnError};
+use core::future::Future;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::Stream;
+use serde::de::value::{Error as ValueError, MapDeserializer};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time::interval;
+use uuid::Uuid;
+
+const DEFAULT_SENSOR_COUNT: u8 = 2;
+const DEFAULT_READING_INTERVAL: Duration = Duration::from_secs(6);
+/// The number of readings which are summarised in each history record.
+const READINGS_PER_HISTORY_RECORD: u32 = 10;
+/// The number of history records each mock sensor starts with.
+const INITIAL_HISTORY_RECORDS: u32 = 3;
+
+/// A simulated replacement for [`MijiaSession`](crate::MijiaSession), which provides the same
+/// methods for a fixed set of fake sensors and emits synthetic readings and history records on a
+/// schedule. This allows applications and tests to run end-to-end without Bluetooth.
+///
+/// Readings are sent every reading interval for sensors which have been subscribed to with
+/// `start_notify_sensor`, and a new history record summarising them is added every ten readings.
+#[derive(Clone, Debug)]
+pub struct MockMijiaSession {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    sensors: BTreeMap<DeviceId, MockSensor>,
+    subscribers: Vec<UnboundedSender<MijiaEvent>>,
+}
+
+#[derive(Debug)]
+struct MockSensor {
+    props: SensorProps,
+    /// The sensor time which was last set, and the instant at which it was set.
+    clock: Option<(SystemTime, Instant)>,
+    temperature_unit: TemperatureUnit,
+    comfort_level: ComfortLevel,
+    trigger_config: TriggerConfig,
+    device_information: DeviceInformation,
+    /// Values written with `write_raw`, keyed by service and characteristic UUID.
+    raw_characteristics: BTreeMap<(Uuid, Uuid), Vec<u8>>,
+    history: Vec<HistoryRecord>,
+    /// The index of the first record which hasn't been deleted.
+    history_start: u32,
+    /// Readings since the last history record was added.
+    pending_readings: Vec<Readings>,
+    /// The number of readings generated so far, used to vary them over time.
+    reading_count: u32,
+    notify_sensor: bool,
+    notify_trigger: bool,
+    notify_settings: bool,
+    /// The index of the next history record to send, if history notifications are enabled.
+    notify_history: Option<u32>,
+}
+
+impl MockMijiaSession {
+    /// Create a new mock session with two sensors, which send readings every six seconds.
+    ///
+    /// Returns a tuple of (join handle, Self), like `MijiaSession::new`. The join handle must be
+    /// polled for readings and history records to be generated, and never completes.
+    pub async fn new(
+    ) -> Result<(impl Future<Output = Result<(), SpawnError>>, Self), BluetoothError> {
+        Ok(Self::with_sensors(
+            DEFAULT_SENSOR_COUNT,
+            DEFAULT_READING_INTERVAL,
+        ))
+    }
+
+    /// Create a new mock session with the given number of sensors, which send readings at the
+    /// given interval.
+    pub fn with_sensors(
+        sensor_count: u8,
+        reading_interval: Duration,
+    ) -> (impl Future<Output = Result<(), SpawnError>>, Self) {
+        let now = SystemTime::now();
+        let sensors = (0..sensor_count)
+            .map(|index| {
+                let sensor = MockSensor::new(index, now);
+                (sensor.props.id.clone(), sensor)
+            })
+            .collect();
+        let session = MockMijiaSession {
+            state: Arc::new(Mutex::new(MockState {
+                sensors,
+                subscribers: vec![],
+            })),
+        };
+
+        let state = session.state.clone();
+        let handle = async move {
+            let mut interval = interval(reading_interval);
+            // The first tick completes immediately.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                state.lock().unwrap().tick();
+            }
+        };
+        (handle, session)
+    }
+
+    /// Get a list of all mock sensors.
+    pub async fn get_sensors(&self) -> Result<Vec<SensorProps>, BluetoothError> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .sensors
+            .values()
+            .map(|sensor| sensor.props.clone())
+            .collect())
+    }
+
+    /// Get the current time of the sensor.
+    pub async fn get_time(&self, id: &DeviceId) -> Result<SystemTime, MijiaError> {
+        self.with_sensor(id, |sensor| Ok(sensor.time()))
+    }
+
+    /// Get the offset of the system clock from the sensor's clock.
+    pub async fn get_clock_offset(&self, id: &DeviceId) -> Result<SignedDuration, MijiaError> {
+        self.with_sensor(id, |sensor| Ok(sensor.clock_offset()))
+    }
+
+    /// Set the current time of the sensor.
+    pub async fn set_time(&self, id: &DeviceId, time: SystemTime) -> Result<(), MijiaError> {
+        self.with_sensor(id, |sensor| {
+            sensor.clock = Some((time, Instant::now()));
+            Ok(())
+        })
+    }
+
+    /// Get the temperature unit which the sensor uses for its display.
+    pub async fn get_temperature_unit(&self, id: &DeviceId) -> Result<TemperatureUnit, MijiaError> {
+        self.with_sensor(id, |sensor| Ok(sensor.temperature_unit))
+    }
+
+    /// Set the temperature unit which the sensor uses for its display. If settings notifications
+    /// are enabled, the new unit is sent as a `MijiaEvent::SettingsChanged`.
+    pub async fn set_temperature_unit(
+        &self,
+        id: &DeviceId,
+        unit: TemperatureUnit,
+    ) -> Result<(), BluetoothError> {
+        let mut state = self.state.lock().unwrap();
+        let sensor = state.sensor(id)?;
+        sensor.temperature_unit = unit;
+        if sensor.notify_settings {
+            let event = MijiaEvent::SettingsChanged {
+                id: id.clone(),
+                setting: Setting::TemperatureUnit(unit),
+            };
+            state.send(vec![event]);
+        }
+        Ok(())
+    }
+
+    /// Get the comfort level configuration which determines when the sensor displays a happy face.
+    pub async fn get_comfort_level(&self, id: &DeviceId) -> Result<ComfortLevel, MijiaError> {
+        self.with_sensor(id, |sensor| Ok(sensor.comfort_level.clone()))
+    }
+
+    /// Set the comfort level configuration which determines when the sensor displays a happy face.
+    /// If settings notifications are enabled, the new configuration is sent as a
+    /// `MijiaEvent::SettingsChanged`.
+    pub async fn set_comfort_level(
+        &self,
+        id: &DeviceId,
+        comfort_level: &ComfortLevel,
+    ) -> Result<(), MijiaError> {
+        let mut state = self.state.lock().unwrap();
+        let sensor = state.sensor(id)?;
+        sensor.comfort_level = comfort_level.clone();
+        if sensor.notify_settings {
+            let event = MijiaEvent::SettingsChanged {
+                id: id.clone(),
+                setting: Setting::ComfortLevel(comfort_level.clone()),
+            };
+            state.send(vec![event]);
+        }
+        Ok(())
+    }
+
+    /// Subscribe to notifications of changes to the sensor's temperature unit and comfort level.
+    /// They will be delivered as events by `MockMijiaSession::event_stream()`.
+    pub async fn start_notify_settings(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        self.with_sensor(id, |sensor| {
+            sensor.notify_settings = true;
+            Ok(())
+        })
+    }
+
+    /// Unsubscribe from notifications of changes to the sensor's settings.
+    pub async fn stop_notify_settings(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        self.with_sensor(id, |sensor| {
+            sensor.notify_settings = false;
+            Ok(())
+        })
+    }
+
+    /// Get the trigger output and reed switch configuration of the sensor. Like on a real sensor,
+    /// this stops trigger notifications.
+    pub async fn get_trigger_config(&self, id: &DeviceId) -> Result<TriggerConfig, MijiaError> {
+        self.with_sensor(id, |sensor| {
+            sensor.notify_trigger = false;
+            Ok(sensor.trigger_config.clone())
+        })
+    }
+
+    /// Set the trigger output and reed switch configuration of the sensor. If trigger
+    /// notifications are enabled, the new config is sent as a `MijiaEvent::Trigger`.
+    pub async fn set_trigger_config(
+        &self,
+        id: &DeviceId,
+        config: &TriggerConfig,
+    ) -> Result<(), MijiaError> {
+        let mut state = self.state.lock().unwrap();
+        let sensor = state.sensor(id)?;
+        sensor.trigger_config = config.clone();
+        if sensor.notify_trigger {
+            let event = MijiaEvent::Trigger {
+                id: id.clone(),
+                config: config.clone(),
+            };
+            state.send(vec![event]);
+        }
+        Ok(())
+    }
+
+    /// Subscribe to trigger state notifications from the sensor. They will be delivered as events
+    /// by `MockMijiaSession::event_stream()`.
+    pub async fn start_notify_trigger(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        self.with_sensor(id, |sensor| {
+            sensor.notify_trigger = true;
+            Ok(())
+        })
+    }
+
+    /// Unsubscribe from trigger state notifications from the sensor.
+    pub async fn stop_notify_trigger(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        self.with_sensor(id, |sensor| {
+            sensor.notify_trigger = false;
+            Ok(())
+        })
+    }
+
+    /// Get the model number, serial number and revisions of the sensor.
+    pub async fn get_device_information(
+        &self,
+        id: &DeviceId,
+    ) -> Result<DeviceInformation, MijiaError> {
+        self.with_sensor(id, |sensor| Ok(sensor.device_information.clone()))
+    }
+
+    /// Read the raw value of a characteristic of the sensor. Only characteristics which have
+    /// previously been written with `write_raw` exist on mock sensors.
+    pub async fn read_raw(
+        &self,
+        id: &DeviceId,
+        service_uuid: Uuid,
+        characteristic_uuid: Uuid,
+    ) -> Result<Vec<u8>, BluetoothError> {
+        self.with_sensor(id, |sensor| {
+            sensor
+                .raw_characteristics
+                .get(&(service_uuid, characteristic_uuid))
+                .cloned()
+                .ok_or(BluetoothError::UuidNotFound {
+                    uuid: characteristic_uuid,
+                })
+        })
+    }
+
+    /// Write a raw value to a characteristic of the sensor, creating it if it doesn't already exist.
+    pub async fn write_raw(
+        &self,
+        id: &DeviceId,
+        service_uuid: Uuid,
+        characteristic_uuid: Uuid,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<(), BluetoothError> {
+        self.with_sensor(id, |sensor| {
+            sensor
+                .raw_characteristics
+                .insert((service_uuid, characteristic_uuid), value.into());
+            Ok(())
+        })
+    }
+
+    /// Get the range of indices for historical data stored on the sensor.
+    pub async fn get_history_range(&self, id: &DeviceId) -> Result<Range<u32>, MijiaError> {
+        self.with_sensor(id, |sensor| Ok(sensor.history_range()))
+    }
+
+    /// Delete all historical data stored on the sensor.
+    pub async fn delete_history(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        self.with_sensor(id, |sensor| {
+            sensor.history_start = sensor.history_range().end;
+            Ok(())
+        })
+    }
+
+    /// Get the last historical record stored on the sensor.
+    pub async fn get_last_history_record(
+        &self,
+        id: &DeviceId,
+    ) -> Result<HistoryRecord, MijiaError> {
+        self.with_sensor(id, |sensor| {
+            let mut record = sensor
+                .history
+                .last()
+                .cloned()
+                .ok_or(BluetoothError::UuidNotFound { uuid: SERVICE_UUID })?;
+            record.correct_time(&sensor.clock_offset());
+            Ok(record)
+        })
+    }
+
+    /// Start receiving historical records from the sensor.
+    ///
+    /// # Arguments
+    /// * `id`: The ID of the sensor to request records from.
+    /// * `start_index`: The record index to start at. If this is not specified then all records
+    ///   will be sent.
+    pub async fn start_notify_history(
+        &self,
+        id: &DeviceId,
+        start_index: Option<u32>,
+    ) -> Result<(), MijiaError> {
+        let mut state = self.state.lock().unwrap();
+        let sensor = state.sensor(id)?;
+        let start_index = start_index.unwrap_or(sensor.history_start);
+        sensor.notify_history = Some(start_index);
+        let events = sensor.take_history_events();
+        state.send(events);
+        Ok(())
+    }
+
+    /// Stop receiving historical records from the sensor.
+    pub async fn stop_notify_history(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        self.with_sensor(id, |sensor| {
+            sensor.notify_history = None;
+            Ok(())
+        })
+    }
+
+    /// Get all historical records for the sensor.
+    pub async fn get_all_history(
+        &self,
+        id: &DeviceId,
+    ) -> Result<Vec<Option<HistoryRecord>>, MijiaError> {
+        self.with_sensor(id, |sensor| {
+            let clock_offset = sensor.clock_offset();
+            Ok(sensor
+                .history
+                .iter()
+                .skip(sensor.history_start as usize)
+                .map(|record| {
+                    let mut record = record.clone();
+                    record.correct_time(&clock_offset);
+                    Some(record)
+                })
+                .collect())
+        })
+    }
+
+    /// Subscribe to readings from the sensor. They will be delivered as events by
+    /// `MockMijiaSession::event_stream()`.
+    pub async fn start_notify_sensor(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        self.with_sensor(id, |sensor| {
+            sensor.notify_sensor = true;
+            Ok(())
+        })
+    }
+
+    /// Simulate BlueZ reporting a new signal strength for the sensor, which is sent as a
+    /// `MijiaEvent::SignalStrength`.
+    pub async fn set_rssi(&self, id: &DeviceId, rssi: i16) -> Result<(), BluetoothError> {
+        let mut state = self.state.lock().unwrap();
+        state.sensor(id)?;
+        state.send(vec![MijiaEvent::SignalStrength {
+            id: id.to_owned(),
+            rssi,
+        }]);
+        Ok(())
+    }
+
+    /// Simulate the sensor disconnecting, which stops all notifications and sends a
+    /// `MijiaEvent::Disconnected`.
+    pub async fn disconnect(&self, id: &DeviceId) -> Result<(), BluetoothError> {
+        let mut state = self.state.lock().unwrap();
+        let sensor = state.sensor(id)?;
+        sensor.notify_sensor = false;
+        sensor.notify_trigger = false;
+        sensor.notify_settings = false;
+        sensor.notify_history = None;
+        state.send(vec![MijiaEvent::Disconnected { id: id.to_owned() }]);
+        Ok(())
+    }
+
+    /// Get a stream of reading/history/disconnected events for all sensors.
+    pub async fn event_stream(&self) -> Result<impl Stream<Item = MijiaEvent>, BluetoothError> {
+        let (sender, receiver) = unbounded();
+        self.state.lock().unwrap().subscribers.push(sender);
+        Ok(receiver)
+    }
+
+    /// Get a stream of events for all sensors like `event_stream`. Mock sensors have no signal
+    /// strength to poll, so `MijiaEvent::SignalStrength` is only sent for values set with
+    /// `set_rssi`.
+    pub async fn event_stream_with_signal_strength(
+        &self,
+        _poll_interval: Duration,
+    ) -> Result<impl Stream<Item = MijiaEvent>, BluetoothError> {
+        self.event_stream().await
+    }
+
+    fn with_sensor<T, E: From<BluetoothError>>(
+        &self,
+        id: &DeviceId,
+        f: impl FnOnce(&mut MockSensor) -> Result<T, E>,
+    ) -> Result<T, E> {
+        f(self.state.lock().unwrap().sensor(id)?)
+    }
+}
+
+impl MockState {
+    fn sensor(&mut self, id: &DeviceId) -> Result<&mut MockSensor, BluetoothError> {
+        // This is what a real session would return when trying to use a device without the
+        // expected service.
+        self.sensors
+            .get_mut(id)
+            .ok_or(BluetoothError::UuidNotFound { uuid: SERVICE_UUID })
+    }
+
+    /// Generate new readings and history records for all sensors, and send events for them.
+    fn tick(&mut self) {
+        let events = self
+            .sensors
+            .values_mut()
+            .flat_map(MockSensor::tick)
+            .collect();
+        self.send(events);
+    }
+
+    /// Send the given events to all subscribers, dropping any which have gone away.
+    fn send(&mut self, events: Vec<MijiaEvent>) {
+        for event in events {
+            self.subscribers
+                .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+        }
+    }
+}
+
+impl MockSensor {
+    fn new(index: u8, now: SystemTime) -> Self {
+        let mac_address = MacAddress::from([0xA4, 0xC1, 0x38, 0x00, 0x00, index]);
+        let mut sensor = MockSensor {
+            props: SensorProps {
+                id: mock_device_id(&mac_address),
+                mac_address,
+            },
+            clock: None,
+            temperature_unit: TemperatureUnit::Celcius,
+            comfort_level: ComfortLevel {
+                temperature_min: 19.0,
+                temperature_max: 27.0,
+                humidity_min: 20,
+                humidity_max: 85,
+            },
+            trigger_config: TriggerConfig {
+                temperature_threshold: 25.0,
+                humidity_threshold: 50.0,
+                temperature_hysteresis: 0.0,
+                humidity_hysteresis: 0.0,
+                reed_switch_report_interval: Duration::from_secs(60),
+                reed_switch_mode: ReedSwitchMode::None,
+                reed_switch_2_mode: ReedSwitchMode::None,
+                state: TriggerState::default(),
+            },
+            device_information: DeviceInformation {
+                manufacturer_name: Some("Mock".to_owned()),
+                model_number: Some(MIJIA_NAME.to_owned()),
+                serial_number: Some(format!("MOCK{:04}", index)),
+                firmware_revision: Some("1.0.0_0000".to_owned()),
+                hardware_revision: Some("B1.4".to_owned()),
+                software_revision: Some("0000".to_owned()),
+            },
+            raw_characteristics: BTreeMap::new(),
+            history: vec![],
+            history_start: 0,
+            pending_readings: vec![],
+            reading_count: u32::from(index) * 7,
+            notify_sensor: false,
+            notify_trigger: false,
+            notify_settings: false,
+            notify_history: None,
+        };
+        for i in 0..INITIAL_HISTORY_RECORDS {
+            let time = now - Duration::from_secs(3600 * u64::from(INITIAL_HISTORY_RECORDS - i));
+            let readings = (0..READINGS_PER_HISTORY_RECORD)
+                .map(|_| sensor.next_readings())
+                .collect::<Vec<_>>();
+            sensor.add_history_record(time, &readings);
+        }
+        sensor
+    }
+
+    /// The current time according to the sensor's clock.
+    fn time(&self) -> SystemTime {
+        match self.clock {
+            Some((time, set_at)) => time + set_at.elapsed(),
+            None => SystemTime::now(),
+        }
+    }
+
+    /// Get the offset of the system clock from the sensor's clock.
+    fn clock_offset(&self) -> SignedDuration {
+        SystemTime::now().duration_since(self.time()).into()
+    }
+
+    fn history_range(&self) -> Range<u32> {
+        self.history_start..self.history.len() as u32
+    }
+
+    /// Generate a new set of readings, varying smoothly over time.
+    fn next_readings(&mut self) -> Readings {
+        let phase = self.reading_count as f32 / 20.0;
+        self.reading_count += 1;
+        let temperature = 21.0 + 3.0 * phase.sin();
+        let battery_voltage = 3000 - (self.reading_count / 100 % 300) as u16;
+        Readings {
+            temperature: (temperature * 100.0).round() / 100.0,
+            humidity: (50.0 + 10.0 * phase.cos()).round() as u8,
+            battery_voltage,
+            battery_percent: ((battery_voltage - 2100) / 9).min(100),
+            out_of_range: vec![],
+        }
+    }
+
+    fn add_history_record(&mut self, time: SystemTime, readings: &[Readings]) {
+        let temperatures = readings.iter().map(|readings| readings.temperature);
+        let humidities = readings.iter().map(|readings| readings.humidity);
+        self.history.push(HistoryRecord {
+            index: self.history.len() as u32,
+            time,
+            corrected_time: time,
+            temperature_min: round_1dp(temperatures.clone().fold(f32::INFINITY, f32::min)),
+            temperature_max: round_1dp(temperatures.fold(f32::NEG_INFINITY, f32::max)),
+            humidity_min: humidities.clone().min().unwrap_or_default(),
+            humidity_max: humidities.max().unwrap_or_default(),
+        });
+    }
+
+    /// Generate new readings, and a history record if enough readings have accumulated, returning
+    /// events for any which have been subscribed to.
+    fn tick(&mut self) -> Vec<MijiaEvent> {
+        let readings = self.next_readings();
+        let mut events = vec![];
+        if self.notify_sensor {
+            events.push(MijiaEvent::Readings {
+                id: self.props.id.clone(),
+                readings: readings.clone(),
+            });
+        }
+        self.pending_readings.push(readings);
+        if self.pending_readings.len() as u32 >= READINGS_PER_HISTORY_RECORD {
+            let readings = std::mem::take(&mut self.pending_readings);
+            self.add_history_record(self.time(), &readings);
+            events.extend(self.take_history_events());
+        }
+        events
+    }
+
+    /// Get events for any history records which should be sent but haven't been yet.
+    fn take_history_events(&mut self) -> Vec<MijiaEvent> {
+        let next_index = match self.notify_history {
+            Some(next_index) => next_index,
+            None => return vec![],
+        };
+        let range = self.history_range();
+        let start = next_index.max(range.start);
+        self.notify_history = Some(range.end.max(next_index));
+        let clock_offset = self.clock_offset();
+        (start..range.end)
+            .map(|index| {
+                let mut record = self.history[index as usize].clone();
+                record.correct_time(&clock_offset);
+                MijiaEvent::HistoryRecord {
+                    id: self.props.id.clone(),
+                    record,
+                }
+            })
+            .collect()
+    }
+}
+
+fn round_1dp(value: f32) -> f32 {
+    (value * 10.0).round() / 10.0
+}
+
+/// Construct a `DeviceId` for a mock sensor with the given MAC address, as if it had been
+/// discovered on the first Bluetooth adapter.
+pub(crate) fn mock_device_id(mac_address: &MacAddress) -> DeviceId {
+    let object_path = format!(
+        "/org/bluez/hci0/dev_{}",
+        mac_address.to_string().replace(':', "_")
+    );
+    // `DeviceId` can't be constructed directly outside of `bluez_async`, but it can be
+    // deserialized.
+    DeviceId::deserialize(MapDeserializer::<_, ValueError>::new(std::iter::once((
+        "object_path",
+        object_path,
+    ))))
+    .expect("Mock device ID should be valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn sensors_have_distinct_ids() {
+        let (_, session) = MockMijiaSession::with_sensors(3, Duration::from_secs(1));
+        let sensors = session.get_sensors().await.unwrap();
+        assert_eq!(sensors.len(), 3);
+        assert_eq!(
+            sensors[1].mac_address,
+            "A4:C1:38:00:00:01".parse::<MacAddress>().unwrap()
+        );
+        assert_eq!(sensors[1].id.adapter().to_string(), "hci0");
+        assert_ne!(sensors[0].id, sensors[1].id);
+    }
+
+    #[tokio::test]
+    async fn set_and_get_attributes() {
+        let (_, session) = MockMijiaSession::with_sensors(1, Duration::from_secs(1));
+        let id = session.get_sensors().await.unwrap()[0].id.clone();
+
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        session.set_time(&id, time).await.unwrap();
+        let sensor_time = session.get_time(&id).await.unwrap();
+        assert!(sensor_time >= time && sensor_time < time + Duration::from_secs(1));
+
+        session
+            .set_temperature_unit(&id, TemperatureUnit::Fahrenheit)
+            .await
+            .unwrap();
+        assert_eq!(
+            session.get_temperature_unit(&id).await.unwrap(),
+            TemperatureUnit::Fahrenheit
+        );
+    }
+
+    #[tokio::test]
+    async fn trigger_config() {
+        let (_, session) = MockMijiaSession::with_sensors(1, Duration::from_secs(1));
+        let id = session.get_sensors().await.unwrap()[0].id.clone();
+        let mut events = session.event_stream().await.unwrap();
+
+        let mut config = session.get_trigger_config(&id).await.unwrap();
+        session.start_notify_trigger(&id).await.unwrap();
+        config.reed_switch_mode = ReedSwitchMode::Switch;
+        config.state.output = true;
+        session.set_trigger_config(&id, &config).await.unwrap();
+        assert!(matches!(
+            events.next().await.unwrap(),
+            MijiaEvent::Trigger { id: event_id, config: event_config }
+                if event_id == id && event_config == config
+        ));
+
+        // Reading the config stops notifications, as does stopping them explicitly.
+        assert_eq!(session.get_trigger_config(&id).await.unwrap(), config);
+        session.set_trigger_config(&id, &config).await.unwrap();
+        session.start_notify_trigger(&id).await.unwrap();
+        session.stop_notify_trigger(&id).await.unwrap();
+        session.set_trigger_config(&id, &config).await.unwrap();
+        session.disconnect(&id).await.unwrap();
+        assert!(matches!(
+            events.next().await.unwrap(),
+            MijiaEvent::Disconnected { id: event_id } if event_id == id
+        ));
+    }
+
+    #[tokio::test]
+    async fn settings_changed() {
+        let (_, session) = MockMijiaSession::with_sensors(1, Duration::from_secs(1));
+        let id = session.get_sensors().await.unwrap()[0].id.clone();
+        let mut events = session.event_stream().await.unwrap();
+
+        // No event is sent before subscribing.
+        session
+            .set_temperature_unit(&id, TemperatureUnit::Fahrenheit)
+            .await
+            .unwrap();
+        session.start_notify_settings(&id).await.unwrap();
+        session
+            .set_temperature_unit(&id, TemperatureUnit::Celcius)
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.next().await.unwrap(),
+            MijiaEvent::SettingsChanged {
+                id: event_id,
+                setting: Setting::TemperatureUnit(TemperatureUnit::Celcius)
+            } if event_id == id
+        ));
+
+        let mut comfort_level = session.get_comfort_level(&id).await.unwrap();
+        comfort_level.humidity_max = 70;
+        session
+            .set_comfort_level(&id, &comfort_level)
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.next().await.unwrap(),
+            MijiaEvent::SettingsChanged { setting: Setting::ComfortLevel(event_level), .. }
+                if event_level == comfort_level
+        ));
+    }
+
+    #[tokio::test]
+    async fn signal_strength() {
+        let (_, session) = MockMijiaSession::with_sensors(1, Duration::from_secs(1));
+        let id = session.get_sensors().await.unwrap()[0].id.clone();
+        let mut events = session.event_stream().await.unwrap();
+
+        session.set_rssi(&id, -72).await.unwrap();
+        assert!(matches!(
+            events.next().await.unwrap(),
+            MijiaEvent::SignalStrength { id: event_id, rssi: -72 } if event_id == id
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_sensor() {
+        let (_, session) = MockMijiaSession::with_sensors(1, Duration::from_secs(1));
+        let other = MockSensor::new(42, SystemTime::now()).props.id;
+        assert!(matches!(
+            session.get_history_range(&other).await,
+            Err(MijiaError::Bluetooth(BluetoothError::UuidNotFound { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn raw_characteristics() {
+        let (_, session) = MockMijiaSession::with_sensors(1, Duration::from_secs(1));
+        let id = session.get_sensors().await.unwrap()[0].id.clone();
+        let characteristic_uuid = Uuid::from_u128(0xebe0ccff_7a0a_4b0c_8a1a_6ff2997da3a6);
+
+        assert!(matches!(
+            session
+                .read_raw(&id, SERVICE_UUID, characteristic_uuid)
+                .await,
+            Err(BluetoothError::UuidNotFound { .. })
+        ));
+        session
+            .write_raw(&id, SERVICE_UUID, characteristic_uuid, [1, 2, 3])
+            .await
+            .unwrap();
+        assert_eq!(
+            session
+                .read_raw(&id, SERVICE_UUID, characteristic_uuid)
+                .await
+                .unwrap(),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn history() {
+        let (_, session) = MockMijiaSession::with_sensors(1, Duration::from_secs(1));
+        let id = session.get_sensors().await.unwrap()[0].id.clone();
+
+        assert_eq!(session.get_history_range(&id).await.unwrap(), 0..3);
+        let history = session.get_all_history(&id).await.unwrap();
+        assert_eq!(history.len(), 3);
+        let record = history[2].clone().unwrap();
+        assert_eq!(record.index, 2);
+        assert!(record.temperature_min <= record.temperature_max);
+        assert!(record.humidity_min <= record.humidity_max);
+        // The records are corrected at slightly different times, so only the raw time matches.
+        let last_record = session.get_last_history_record(&id).await.unwrap();
+        assert_eq!(last_record.index, record.index);
+        assert_eq!(last_record.time, record.time);
+
+        let mut events = session.event_stream().await.unwrap();
+        session.start_notify_history(&id, Some(1)).await.unwrap();
+        for index in 1..3 {
+            match events.next().await.unwrap() {
+                MijiaEvent::HistoryRecord { record, .. } => assert_eq!(record.index, index),
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+
+        // History times are corrected by the offset of the sensor's clock.
+        session
+            .set_time(&id, SystemTime::now() - Duration::from_secs(3600))
+            .await
+            .unwrap();
+        let corrected = session.get_all_history(&id).await.unwrap()[2]
+            .clone()
+            .unwrap();
+        assert_eq!(corrected.time, record.time);
+        let expected = record.time + Duration::from_secs(3600);
+        let is_corrected = |record: &HistoryRecord| {
+            record.corrected_time > expected - Duration::from_secs(1)
+                && record.corrected_time < expected + Duration::from_secs(1)
+        };
+        assert!(is_corrected(&corrected));
+        assert!(is_corrected(
+            &session.get_last_history_record(&id).await.unwrap()
+        ));
+        session.start_notify_history(&id, Some(2)).await.unwrap();
+        match events.next().await.unwrap() {
+            MijiaEvent::HistoryRecord { record, .. } => assert!(is_corrected(&record)),
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        session.delete_history(&id).await.unwrap();
+        assert_eq!(session.get_history_range(&id).await.unwrap(), 3..3);
+        assert!(session.get_all_history(&id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn readings_on_schedule() {
+        let (handle, session) = MockMijiaSession::with_sensors(2, Duration::from_millis(1));
+        tokio::spawn(handle);
+        let id = session.get_sensors().await.unwrap()[1].id.clone();
+        let mut events = session.event_stream().await.unwrap();
+        session.start_notify_sensor(&id).await.unwrap();
+        session.start_notify_history(&id, None).await.unwrap();
+
+        let mut readings = 0;
+        let mut history_records = 0;
+        while readings < READINGS_PER_HISTORY_RECORD {
+            match events.next().await.unwrap() {
+                MijiaEvent::Readings {
+                    id: event_id,
+                    readings: event_readings,
+                } => {
+                    assert_eq!(event_id, id);
+                    assert!(event_readings.humidity > 0);
+                    readings += 1;
+                }
+                MijiaEvent::HistoryRecord { .. } => history_records += 1,
+                event => panic!("Unexpected event {:?}", event),
+            }
+        }
+        // The initial records, and one more after the tenth reading.
+        assert_eq!(history_records, INITIAL_HISTORY_RECORDS);
+        assert!(matches!(
+            events.next().await.unwrap(),
+            MijiaEvent::HistoryRecord { record, .. } if record.index == INITIAL_HISTORY_RECORDS
+        ));
+
+        session.disconnect(&id).await.unwrap();
+        loop {
+            if let MijiaEvent::Disconnected { id: event_id } = events.next().await.unwrap() {
+                assert_eq!(event_id, id);
+                break;
+            }
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use std::fmt::{self, Debug, Formatter, Write};
-use std::time::{Duration, SystemTimeError};
+use std::time::{Duration, SystemTime, SystemTimeError};
 
 /// A duration which may be negative.
 ///
@@ -41,6 +41,18 @@ impl From<Result<Duration, SystemTimeError>> for SignedDuration {
     }
 }
 
+impl SignedDuration {
+    /// Returns the given time moved forwards by this duration if it is positive, or backwards if it
+    /// is negative.
+    pub fn add_to(&self, time: SystemTime) -> SystemTime {
+        if self.positive {
+            time + self.duration
+        } else {
+            time - self.duration
+        }
+    }
+}
+
 impl Debug for SignedDuration {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         if !self.positive {
@@ -0,0 +1,84 @@
+//! An in-process MQTT broker for the tests and benchmarks of the other crates in this workspace.
+
+use rumqttd::{Broker, Config, ConnectionSettings, RouterConfig, ServerSettings};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the broker to start listening before giving up.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Spawn an MQTT broker listening on a free port on localhost, and wait until it is accepting
+/// connections.
+///
+/// Returns the port on which the broker is listening. Panics if it doesn't start listening within
+/// `START_TIMEOUT`.
+pub fn spawn_mqtt_broker() -> u16 {
+    let port = free_port();
+    let mut v4 = HashMap::new();
+    v4.insert(
+        "1".to_string(),
+        ServerSettings {
+            name: "Test broker".to_string(),
+            listen: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+            tls: None,
+            next_connection_delay_ms: 1,
+            connections: ConnectionSettings {
+                connection_timeout_ms: 100,
+                max_payload_size: 2048,
+                max_inflight_count: 500,
+                auth: None,
+                dynamic_filters: false,
+                external_auth: None,
+            },
+        },
+    );
+    let broker_config = Config {
+        id: 0,
+        router: RouterConfig {
+            max_segment_size: 1024 * 1024,
+            max_segment_count: 10,
+            max_connections: 20,
+            initialized_filters: None,
+            max_outgoing_packet_count: 100,
+            ..Default::default()
+        },
+        v4: Some(v4),
+        console: None,
+        ..Config::default()
+    };
+    let mut broker = Broker::new(broker_config);
+    thread::spawn(move || {
+        broker.start().unwrap_or_else(|e| {
+            panic!(
+                "Failed to start MQTT broker. This may be because port {} is already in use: {}",
+                port, e
+            )
+        });
+    });
+    wait_until_listening(port);
+    port
+}
+
+/// Find a port on localhost which is not currently in use, by asking the OS for one.
+fn free_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.local_addr())
+        .expect("Failed to find a free port")
+        .port()
+}
+
+/// Wait until something is accepting connections on the given port on localhost.
+fn wait_until_listening(port: u16) {
+    let start = Instant::now();
+    while TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_err() {
+        if start.elapsed() > START_TIMEOUT {
+            panic!(
+                "MQTT broker didn't start listening on port {} within {:?}",
+                port, START_TIMEOUT
+            );
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}